@@ -0,0 +1,629 @@
+use crate::{
+    DeviceId, Disk, DiskErr, DiskErrKind, DiskInfos, Geometry, Operation, Permissions, SectorSize,
+    ZEROING_BATCH,
+};
+use alloc::{sync::Arc, vec};
+use core::{
+    future::{Future, ready},
+    pin::pin,
+    task::{Context, Poll, Waker},
+};
+
+/// The asynchronous version of `Disk`, for drivers completing their requests with interrupts.
+/// It only relies on `core::future`, and doesn't depend on any executor. See `Disk` for the
+/// meaning of each method.
+pub trait AsyncDisk {
+    fn read_sectors(
+        &self,
+        sector: u64,
+        sector_size: usize,
+        buf: &mut [u8],
+    ) -> impl Future<Output = Result<(), DiskErr>>;
+
+    fn write_sectors(
+        &self,
+        sector: u64,
+        sector_size: usize,
+        buf: &[u8],
+    ) -> impl Future<Output = Result<(), DiskErr>>;
+
+    fn disk_infos(&self) -> impl Future<Output = Result<DiskInfos, DiskErr>>;
+
+    fn flush(&self) -> impl Future<Output = Result<(), DiskErr>> {
+        ready(Ok(()))
+    }
+
+    /// The default implementation writes the sectors and then flushes the disk
+    fn write_sectors_fua(
+        &self,
+        sector: u64,
+        sector_size: usize,
+        buf: &[u8],
+    ) -> impl Future<Output = Result<(), DiskErr>> {
+        async move {
+            self.write_sectors(sector, sector_size, buf).await?;
+            self.flush().await
+        }
+    }
+
+    /// The default implementation writes zeroed sectors, by batches of `ZEROING_BATCH` sectors
+    fn discard(
+        &self,
+        sector: u64,
+        sector_size: usize,
+        count: u64,
+    ) -> impl Future<Output = Result<(), DiskErr>> {
+        async move {
+            if sector_size == 0 {
+                return Err(DiskErr::new(DiskErrKind::InvalidSectorSize {
+                    found: sector_size,
+                    supported: self.disk_infos().await?.sector_size,
+                    start: 0,
+                })
+                .at(Operation::Discard, sector));
+            }
+
+            let zeroes = vec![0; sector_size * (ZEROING_BATCH.min(count) as usize)];
+
+            let mut done = 0;
+            while done < count {
+                let batch = ZEROING_BATCH.min(count - done);
+                self.write_sectors(
+                    sector + done,
+                    sector_size,
+                    &zeroes[..(batch as usize * sector_size)],
+                )
+                .await?;
+                done += batch;
+            }
+
+            Ok(())
+        }
+    }
+}
+
+/// Forwards every method, so that the overridden ones are still used
+macro_rules! forward_async_disk_impl {
+    ($($ty:ty),*) => {$(
+        impl<T: AsyncDisk + ?Sized> AsyncDisk for $ty {
+            fn read_sectors(
+                &self,
+                sector: u64,
+                sector_size: usize,
+                buf: &mut [u8],
+            ) -> impl Future<Output = Result<(), DiskErr>> {
+                (**self).read_sectors(sector, sector_size, buf)
+            }
+
+            fn write_sectors(
+                &self,
+                sector: u64,
+                sector_size: usize,
+                buf: &[u8],
+            ) -> impl Future<Output = Result<(), DiskErr>> {
+                (**self).write_sectors(sector, sector_size, buf)
+            }
+
+            fn disk_infos(&self) -> impl Future<Output = Result<DiskInfos, DiskErr>> {
+                (**self).disk_infos()
+            }
+
+            fn flush(&self) -> impl Future<Output = Result<(), DiskErr>> {
+                (**self).flush()
+            }
+
+            fn write_sectors_fua(
+                &self,
+                sector: u64,
+                sector_size: usize,
+                buf: &[u8],
+            ) -> impl Future<Output = Result<(), DiskErr>> {
+                (**self).write_sectors_fua(sector, sector_size, buf)
+            }
+
+            fn discard(
+                &self,
+                sector: u64,
+                sector_size: usize,
+                count: u64,
+            ) -> impl Future<Output = Result<(), DiskErr>> {
+                (**self).discard(sector, sector_size, count)
+            }
+        }
+    )*};
+}
+
+forward_async_disk_impl!(&T, Arc<T>);
+
+/// Exposes any `Disk` as an `AsyncDisk`. The requests are served synchronously, the returned
+/// futures are always ready.
+pub struct AsyncAdapter<D: Disk> {
+    disk: D,
+}
+
+impl<D: Disk> AsyncAdapter<D> {
+    pub const fn new(disk: D) -> Self {
+        Self { disk }
+    }
+
+    pub const fn inner(&self) -> &D {
+        &self.disk
+    }
+
+    pub fn into_inner(self) -> D {
+        self.disk
+    }
+}
+
+impl<D: Disk> AsyncDisk for AsyncAdapter<D> {
+    fn read_sectors(
+        &self,
+        sector: u64,
+        sector_size: usize,
+        buf: &mut [u8],
+    ) -> impl Future<Output = Result<(), DiskErr>> {
+        ready(self.disk.read_sectors(sector, sector_size, buf))
+    }
+
+    fn write_sectors(
+        &self,
+        sector: u64,
+        sector_size: usize,
+        buf: &[u8],
+    ) -> impl Future<Output = Result<(), DiskErr>> {
+        ready(self.disk.write_sectors(sector, sector_size, buf))
+    }
+
+    fn disk_infos(&self) -> impl Future<Output = Result<DiskInfos, DiskErr>> {
+        ready(self.disk.disk_infos())
+    }
+
+    fn flush(&self) -> impl Future<Output = Result<(), DiskErr>> {
+        ready(self.disk.flush())
+    }
+
+    fn write_sectors_fua(
+        &self,
+        sector: u64,
+        sector_size: usize,
+        buf: &[u8],
+    ) -> impl Future<Output = Result<(), DiskErr>> {
+        ready(self.disk.write_sectors_fua(sector, sector_size, buf))
+    }
+
+    fn discard(
+        &self,
+        sector: u64,
+        sector_size: usize,
+        count: u64,
+    ) -> impl Future<Output = Result<(), DiskErr>> {
+        ready(self.disk.discard(sector, sector_size, count))
+    }
+}
+
+/// Exposes any `AsyncDisk` as a `Disk`, by polling each request in a loop until it completes. It
+/// allows to use the synchronous wrappers on top of an asynchronous driver, at the cost of
+/// spinning: the requests are polled with a no-op waker, so it is only suited to short requests,
+/// or to contexts where nothing else can run anyway (early boot, ...). The async partition tables
+/// and filesystems (`AsyncMbr`, `AsyncFat12`) don't need it.
+pub struct BlockingAdapter<D: AsyncDisk> {
+    disk: D,
+}
+
+impl<D: AsyncDisk> BlockingAdapter<D> {
+    pub const fn new(disk: D) -> Self {
+        Self { disk }
+    }
+
+    pub const fn inner(&self) -> &D {
+        &self.disk
+    }
+
+    pub fn into_inner(self) -> D {
+        self.disk
+    }
+}
+
+impl<D: AsyncDisk> Disk for BlockingAdapter<D> {
+    fn read_sector(&self, sector: u64, buf: &mut [u8]) -> Result<(), DiskErr> {
+        block_on(self.disk.read_sectors(sector, buf.len(), buf))
+    }
+
+    fn write_sector(&self, sector: u64, buf: &[u8]) -> Result<(), DiskErr> {
+        block_on(self.disk.write_sectors(sector, buf.len(), buf))
+    }
+
+    fn disk_infos(&self) -> Result<DiskInfos, DiskErr> {
+        block_on(self.disk.disk_infos())
+    }
+
+    fn read_sectors(&self, sector: u64, sector_size: usize, buf: &mut [u8]) -> Result<(), DiskErr> {
+        block_on(self.disk.read_sectors(sector, sector_size, buf))
+    }
+
+    fn write_sectors(&self, sector: u64, sector_size: usize, buf: &[u8]) -> Result<(), DiskErr> {
+        block_on(self.disk.write_sectors(sector, sector_size, buf))
+    }
+
+    fn flush(&self) -> Result<(), DiskErr> {
+        block_on(self.disk.flush())
+    }
+
+    fn write_sectors_fua(
+        &self,
+        sector: u64,
+        sector_size: usize,
+        buf: &[u8],
+    ) -> Result<(), DiskErr> {
+        block_on(self.disk.write_sectors_fua(sector, sector_size, buf))
+    }
+
+    fn discard(&self, sector: u64, sector_size: usize, count: u64) -> Result<(), DiskErr> {
+        block_on(self.disk.discard(sector, sector_size, count))
+    }
+}
+
+/// Runs a future to completion on the current thread, polling it in a loop with a no-op waker
+fn block_on<F: Future>(future: F) -> F::Output {
+    let mut future = pin!(future);
+    let mut context = Context::from_waker(Waker::noop());
+
+    loop {
+        if let Poll::Ready(v) = future.as_mut().poll(&mut context) {
+            return v;
+        }
+
+        core::hint::spin_loop();
+    }
+}
+
+/// A range of bytes of an `AsyncDisk`, seen as a disk, like the `SubDisk`s of a `DiskWrapper`.
+/// `D` is usually a reference or an `Arc`, so that several ranges of the same disk can be used
+/// together. Unlike `DiskWrapper::subdisk`, the borrows of the ranges are not tracked: nothing
+/// prevents two writable overlapping ranges.
+pub struct AsyncSubDisk<D: AsyncDisk> {
+    disk: D,
+    start: u64,
+    end: u64,
+    sector_size: SectorSize,
+    permissions: Permissions,
+    /// The geometry of the disk, seen from the start of the range
+    geometry: Geometry,
+    device_id: Option<DeviceId>,
+}
+
+impl<D: AsyncDisk> AsyncSubDisk<D> {
+    /// Exposes the bytes `start..end` of `disk`, with the given permissions
+    pub async fn new(
+        disk: D,
+        start: u64,
+        end: u64,
+        permissions: Permissions,
+    ) -> Result<Self, DiskErr> {
+        let infos = disk.disk_infos().await?;
+
+        if start > end || end > infos.disk_size {
+            return Err(DiskErr::new(DiskErrKind::InvalidDiskSize)
+                .during(Operation::CreateSubdisk)
+                .in_layer("AsyncSubDisk"));
+        }
+
+        Ok(Self {
+            disk,
+            start,
+            end,
+            // The sectors of the range must be sectors of the disk
+            sector_size: infos.sector_size.aligned_to(start),
+            permissions,
+            geometry: infos.geometry.slice(start),
+            device_id: infos.device_id.map(|id| id.slice(vec![(start, end)])),
+        })
+    }
+
+    pub const fn inner(&self) -> &D {
+        &self.disk
+    }
+
+    /// Checks the permissions and the range of a request of `len` bytes starting at the LBA
+    /// `sector`, and returns its LBA on the disk
+    fn disk_sector(
+        &self,
+        operation: Operation,
+        sector: u64,
+        sector_size: usize,
+        len: u64,
+    ) -> Result<u64, DiskErr> {
+        let allowed = match operation {
+            Operation::Read => self.permissions.read,
+            _ => self.permissions.write,
+        };
+
+        if !allowed {
+            return Err(DiskErr::new(DiskErrKind::InvalidPermission {
+                disk_permissions: self.permissions,
+            })
+            .at(operation, sector)
+            .in_layer("AsyncSubDisk"));
+        }
+
+        if sector_size == 0
+            || !self
+                .sector_size
+                .is_supported(sector_size, self.end - self.start)
+            || !self.start.is_multiple_of(sector_size as u64)
+            || !len.is_multiple_of(sector_size as u64)
+        {
+            return Err(DiskErr::new(DiskErrKind::InvalidSectorSize {
+                found: sector_size,
+                supported: self.sector_size.clone(),
+                start: self.start,
+            })
+            .at(operation, sector)
+            .in_layer("AsyncSubDisk"));
+        }
+
+        match sector
+            .checked_mul(sector_size as u64)
+            .and_then(|v| v.checked_add(self.start))
+        {
+            Some(offset) if offset.checked_add(len).is_some_and(|end| end <= self.end) => {
+                Ok(offset / sector_size as u64)
+            }
+            _ => Err(DiskErr::new(DiskErrKind::InvalidSectorIndex {
+                found: sector,
+                max: ((self.end - self.start) / sector_size as u64).saturating_sub(1),
+            })
+            .at(operation, sector)
+            .in_layer("AsyncSubDisk")),
+        }
+    }
+}
+
+impl<D: AsyncDisk> AsyncDisk for AsyncSubDisk<D> {
+    async fn read_sectors(
+        &self,
+        sector: u64,
+        sector_size: usize,
+        buf: &mut [u8],
+    ) -> Result<(), DiskErr> {
+        let sector = self.disk_sector(Operation::Read, sector, sector_size, buf.len() as u64)?;
+        self.disk.read_sectors(sector, sector_size, buf).await
+    }
+
+    async fn write_sectors(
+        &self,
+        sector: u64,
+        sector_size: usize,
+        buf: &[u8],
+    ) -> Result<(), DiskErr> {
+        let sector = self.disk_sector(Operation::Write, sector, sector_size, buf.len() as u64)?;
+        self.disk.write_sectors(sector, sector_size, buf).await
+    }
+
+    async fn disk_infos(&self) -> Result<DiskInfos, DiskErr> {
+        Ok(DiskInfos {
+            sector_size: self.sector_size.clone(),
+            disk_size: self.end - self.start,
+            permissions: self.permissions,
+            geometry: self.geometry,
+            device_id: self.device_id.clone(),
+        })
+    }
+
+    fn flush(&self) -> impl Future<Output = Result<(), DiskErr>> {
+        self.disk.flush()
+    }
+
+    async fn write_sectors_fua(
+        &self,
+        sector: u64,
+        sector_size: usize,
+        buf: &[u8],
+    ) -> Result<(), DiskErr> {
+        let sector = self.disk_sector(Operation::Write, sector, sector_size, buf.len() as u64)?;
+        self.disk.write_sectors_fua(sector, sector_size, buf).await
+    }
+
+    async fn discard(&self, sector: u64, sector_size: usize, count: u64) -> Result<(), DiskErr> {
+        let len = count.saturating_mul(sector_size as u64);
+        let sector = self.disk_sector(Operation::Discard, sector, sector_size, len)?;
+        self.disk.discard(sector, sector_size, count).await
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+    use crate::memdisk::MemDisk;
+    use alloc::task::Wake;
+    use core::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+
+    struct Flag(AtomicBool);
+
+    impl Wake for Flag {
+        fn wake(self: Arc<Self>) {
+            self.0.store(true, Ordering::SeqCst);
+        }
+    }
+
+    /// Runs `future` on the current thread. It is only polled again once woken, and panics if it
+    /// is pending without having been woken, which would hang a real executor.
+    pub(crate) fn run_local<F: Future>(future: F) -> F::Output {
+        let flag = Arc::new(Flag(AtomicBool::new(false)));
+        let waker = Waker::from(flag.clone());
+        let mut context = Context::from_waker(&waker);
+        let mut future = pin!(future);
+
+        loop {
+            if let Poll::Ready(v) = future.as_mut().poll(&mut context) {
+                return v;
+            }
+
+            assert!(
+                flag.0.swap(false, Ordering::SeqCst),
+                "pending future never woken"
+            );
+        }
+    }
+
+    /// Completes on the second poll, after waking the task
+    struct YieldOnce(bool);
+
+    impl Future for YieldOnce {
+        type Output = ();
+
+        fn poll(mut self: core::pin::Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<()> {
+            if self.0 {
+                return Poll::Ready(());
+            }
+
+            self.0 = true;
+            cx.waker().wake_by_ref();
+            Poll::Pending
+        }
+    }
+
+    /// An `AsyncDisk` over a `Disk` whose requests are all pending once, like a driver waiting
+    /// for an interrupt. Counts the requests.
+    pub(crate) struct YieldingDisk<D: Disk> {
+        pub disk: D,
+        pub requests: AtomicUsize,
+    }
+
+    impl<D: Disk> YieldingDisk<D> {
+        pub fn new(disk: D) -> Self {
+            Self {
+                disk,
+                requests: AtomicUsize::new(0),
+            }
+        }
+
+        async fn request<R>(&self, request: impl FnOnce() -> R) -> R {
+            YieldOnce(false).await;
+            self.requests.fetch_add(1, Ordering::SeqCst);
+            request()
+        }
+    }
+
+    impl<D: Disk> AsyncDisk for YieldingDisk<D> {
+        async fn read_sectors(
+            &self,
+            sector: u64,
+            sector_size: usize,
+            buf: &mut [u8],
+        ) -> Result<(), DiskErr> {
+            self.request(|| self.disk.read_sectors(sector, sector_size, buf))
+                .await
+        }
+
+        async fn write_sectors(
+            &self,
+            sector: u64,
+            sector_size: usize,
+            buf: &[u8],
+        ) -> Result<(), DiskErr> {
+            self.request(|| self.disk.write_sectors(sector, sector_size, buf))
+                .await
+        }
+
+        async fn disk_infos(&self) -> Result<DiskInfos, DiskErr> {
+            self.request(|| self.disk.disk_infos()).await
+        }
+
+        async fn flush(&self) -> Result<(), DiskErr> {
+            self.request(|| self.disk.flush()).await
+        }
+    }
+
+    fn mem_disk() -> MemDisk {
+        MemDisk::new(
+            128 * 512,
+            SectorSize::AllOf(vec![512, 4096]),
+            Permissions::read_write(),
+        )
+    }
+
+    #[test]
+    fn default_methods() {
+        let disk = YieldingDisk::new(mem_disk());
+        disk.disk.write_sectors(0, 512, &[1; 4096]).unwrap();
+
+        run_local(async {
+            disk.write_sectors_fua(8, 512, &[2; 1024]).await.unwrap();
+            // Written by batches of `ZEROING_BATCH` sectors
+            disk.discard(1, 512, 70).await.unwrap();
+        });
+
+        let mut buf = [0; 512 * 72];
+        disk.disk.read_sectors(0, 512, &mut buf).unwrap();
+        assert_eq!(buf[..512], [1; 512]);
+        assert!(buf[512..(71 * 512)].iter().all(|&b| b == 0));
+        // The FUA write is a write and a flush, the discard two writes
+        assert_eq!(disk.requests.load(Ordering::SeqCst), 4);
+    }
+
+    #[test]
+    fn adapters() {
+        let disk = AsyncAdapter::new(mem_disk());
+        run_local(async {
+            disk.write_sectors_fua(1, 4096, &[3; 4096]).await.unwrap();
+            disk.discard(9, 512, 1).await.unwrap();
+        });
+
+        let disk = BlockingAdapter::new(YieldingDisk::new(disk.into_inner()));
+        let mut buf = [0; 4096];
+        disk.read_sectors(1, 4096, &mut buf).unwrap();
+        assert_eq!(buf[..512], [3; 512]);
+        assert_eq!(buf[512..1024], [0; 512]);
+        assert_eq!(buf[1024..], [3; 3072]);
+        disk.discard(1, 4096, 1).unwrap();
+        assert_eq!(disk.disk_infos().unwrap().disk_size, 128 * 512);
+    }
+
+    #[test]
+    fn subdisks() {
+        let disk = YieldingDisk::new(mem_disk());
+
+        run_local(async {
+            let sub = AsyncSubDisk::new(&disk, 4096, 16384, Permissions::read_write())
+                .await
+                .unwrap();
+            assert_eq!(sub.disk_infos().await.unwrap().disk_size, 12288);
+
+            sub.write_sectors(1, 4096, &[4; 4096]).await.unwrap();
+            sub.discard(9, 512, 1).await.unwrap();
+            let err = sub.read_sectors(3, 4096, &mut [0; 4096]).await.unwrap_err();
+            assert_eq!(
+                err.kind,
+                DiskErrKind::InvalidSectorIndex { found: 3, max: 2 }
+            );
+            assert_eq!(err.context.layer, Some("AsyncSubDisk"));
+
+            let read_only = AsyncSubDisk::new(&disk, 0, 16384, Permissions::read_only())
+                .await
+                .unwrap();
+            let err = read_only
+                .write_sectors(0, 512, &[0; 512])
+                .await
+                .unwrap_err();
+            assert!(matches!(err.kind, DiskErrKind::InvalidPermission { .. }));
+
+            let mut buf = [0; 4096];
+            read_only.read_sectors(2, 4096, &mut buf).await.unwrap();
+            assert_eq!(buf[..512], [4; 512]);
+            assert_eq!(buf[512..1024], [0; 512]);
+
+            // A 4096-byte sector can't start at 512
+            let unaligned = AsyncSubDisk::new(&disk, 512, 16384, Permissions::read_only())
+                .await
+                .unwrap();
+            assert!(unaligned.read_sectors(0, 4096, &mut buf).await.is_err());
+
+            assert!(
+                AsyncSubDisk::new(&disk, 0, 129 * 512, Permissions::read_only())
+                    .await
+                    .is_err()
+            );
+        });
+    }
+}
@@ -0,0 +1,333 @@
+use crate::{Disk, DiskErr, DiskErrKind, Operation};
+use alloc::vec;
+
+/// Byte-addressed access over any `Disk`. Reads and writes can start and end anywhere: the partial
+/// sectors at the head and the tail of a request are handled with a read-modify-write cycle, the
+/// aligned middle part is sent to the disk as a single multi-sector request.
+pub struct ByteDisk<D: Disk> {
+    disk: D,
+    sector_size: usize,
+}
+
+impl<D: Disk> ByteDisk<D> {
+    /// Creates the adapter using the preferred sector size of the disk (see
+    /// `SectorSize::preferred`).
+    pub fn new(disk: D) -> Result<Self, DiskErr> {
+        let infos = disk.disk_infos()?;
+
+        let sector_size = match infos.sector_size.preferred(infos.disk_size) {
+            Some(v) => v,
+            None => {
+                return Err(DiskErr::new(DiskErrKind::UnsupportedDiskSectorSize)
+                    .during(Operation::DiskInfos)
+                    .in_layer("ByteDisk"));
+            }
+        };
+
+        Ok(Self { disk, sector_size })
+    }
+
+    /// Creates the adapter with an explicit sector size, which must be supported by the disk.
+    pub fn with_sector_size(disk: D, sector_size: usize) -> Result<Self, DiskErr> {
+        let infos = disk.disk_infos()?;
+
+        if sector_size == 0 || !infos.sector_size.is_supported(sector_size, infos.disk_size) {
+            return Err(DiskErr::new(DiskErrKind::InvalidSectorSize {
+                found: sector_size,
+                supported: infos.sector_size,
+                start: 0,
+            })
+            .during(Operation::DiskInfos)
+            .in_layer("ByteDisk"));
+        }
+
+        Ok(Self { disk, sector_size })
+    }
+
+    /// The sector size used to access the underlying disk
+    pub const fn sector_size(&self) -> usize {
+        self.sector_size
+    }
+
+    /// The count of bytes reachable through the adapter. This is the disk size rounded down to a
+    /// multiple of the sector size, as a partial sector at the end of the disk can't be accessed.
+    pub fn size(&self) -> Result<u64, DiskErr> {
+        let sector_size = self.sector_size as u64;
+        Ok(self.disk.disk_infos()?.disk_size / sector_size * sector_size)
+    }
+
+    pub const fn inner(&self) -> &D {
+        &self.disk
+    }
+
+    pub fn into_inner(self) -> D {
+        self.disk
+    }
+
+    /// Reads `buf.len()` bytes starting at the byte `offset` of the disk
+    pub fn read_at(&self, offset: u64, buf: &mut [u8]) -> Result<(), DiskErr> {
+        self.check_range(Operation::Read, offset, buf.len())?;
+
+        if buf.is_empty() {
+            return Ok(());
+        }
+
+        let sector_size = self.sector_size;
+        let mut sector = vec![0; sector_size];
+        let mut pos = offset;
+        let mut done = 0;
+
+        // ### PARTIAL HEAD SECTOR ###
+
+        let head = (pos % sector_size as u64) as usize;
+        if head != 0 || buf.len() < sector_size {
+            let len = (sector_size - head).min(buf.len());
+            self.disk
+                .read_sectors(pos / sector_size as u64, sector_size, &mut sector)?;
+            buf[..len].copy_from_slice(&sector[head..(head + len)]);
+            pos += len as u64;
+            done += len;
+        }
+
+        // ### ALIGNED MIDDLE PART ###
+
+        let middle = (buf.len() - done) / sector_size * sector_size;
+        if middle != 0 {
+            self.disk.read_sectors(
+                pos / sector_size as u64,
+                sector_size,
+                &mut buf[done..(done + middle)],
+            )?;
+            pos += middle as u64;
+            done += middle;
+        }
+
+        // ### PARTIAL TAIL SECTOR ###
+
+        if done < buf.len() {
+            let len = buf.len() - done;
+            self.disk
+                .read_sectors(pos / sector_size as u64, sector_size, &mut sector)?;
+            buf[done..].copy_from_slice(&sector[..len]);
+        }
+
+        Ok(())
+    }
+
+    /// Writes `buf` starting at the byte `offset` of the disk
+    pub fn write_at(&self, offset: u64, buf: &[u8]) -> Result<(), DiskErr> {
+        self.check_range(Operation::Write, offset, buf.len())?;
+
+        if buf.is_empty() {
+            return Ok(());
+        }
+
+        let sector_size = self.sector_size;
+        let mut sector = vec![0; sector_size];
+        let mut pos = offset;
+        let mut done = 0;
+
+        // ### PARTIAL HEAD SECTOR ###
+
+        let head = (pos % sector_size as u64) as usize;
+        if head != 0 || buf.len() < sector_size {
+            let len = (sector_size - head).min(buf.len());
+            self.disk
+                .read_sectors(pos / sector_size as u64, sector_size, &mut sector)?;
+            sector[head..(head + len)].copy_from_slice(&buf[..len]);
+            self.disk
+                .write_sectors(pos / sector_size as u64, sector_size, &sector)?;
+            pos += len as u64;
+            done += len;
+        }
+
+        // ### ALIGNED MIDDLE PART ###
+
+        let middle = (buf.len() - done) / sector_size * sector_size;
+        if middle != 0 {
+            self.disk.write_sectors(
+                pos / sector_size as u64,
+                sector_size,
+                &buf[done..(done + middle)],
+            )?;
+            pos += middle as u64;
+            done += middle;
+        }
+
+        // ### PARTIAL TAIL SECTOR ###
+
+        if done < buf.len() {
+            let len = buf.len() - done;
+            self.disk
+                .read_sectors(pos / sector_size as u64, sector_size, &mut sector)?;
+            sector[..len].copy_from_slice(&buf[done..]);
+            self.disk
+                .write_sectors(pos / sector_size as u64, sector_size, &sector)?;
+        }
+
+        Ok(())
+    }
+
+    /// Checks that `len` bytes starting at `offset` are inside the disk
+    fn check_range(&self, operation: Operation, offset: u64, len: usize) -> Result<(), DiskErr> {
+        let size = self.size()?;
+
+        if offset.checked_add(len as u64).is_none_or(|end| end > size) {
+            return Err(DiskErr::new(DiskErrKind::IndexOutOfRange)
+                .at(operation, offset / self.sector_size as u64)
+                .in_layer("ByteDisk"));
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{Permissions, SectorSize, memdisk::MemDisk, stats::StatsDisk};
+    use alloc::vec::Vec;
+
+    fn pattern(len: usize) -> Vec<u8> {
+        (0..len).map(|i| (i % 251) as u8).collect()
+    }
+
+    /// A disk of 8 sectors of 512 bytes, filled with `pattern`, counting the requests
+    fn disk() -> ByteDisk<StatsDisk<MemDisk>> {
+        let disk = MemDisk::from_vec(
+            pattern(8 * 512),
+            SectorSize::AllOf(vec![512, 4096]),
+            Permissions::read_write(),
+        );
+        ByteDisk::new(StatsDisk::new(disk)).unwrap()
+    }
+
+    #[test]
+    fn reads() {
+        let expected = pattern(8 * 512);
+
+        // (offset, length, count of requests)
+        let table = [
+            // Inside a single sector
+            (10, 100, 1),
+            (512, 511, 1),
+            // Head, aligned middle of 3 sectors, and tail
+            (100, 2000, 3),
+            // Aligned middle only
+            (512, 1024, 1),
+            // Head and middle, middle and tail
+            (500, 1036, 2),
+            (0, 1000, 2),
+            // Head and tail in two adjacent sectors
+            (500, 20, 2),
+            (0, 0, 0),
+            (4096, 0, 0),
+        ];
+
+        for (offset, len, requests) in table {
+            let disk = disk();
+            let mut buf = vec![0; len];
+            disk.read_at(offset, &mut buf).unwrap();
+
+            let offset = offset as usize;
+            assert_eq!(buf, expected[offset..(offset + len)], "{offset} {len}");
+            assert_eq!(disk.inner().stats().reads, requests, "{offset} {len}");
+            assert_eq!(disk.inner().stats().writes, 0);
+        }
+    }
+
+    #[test]
+    fn writes() {
+        // (offset, length, count of reads, count of writes)
+        let table = [
+            (10, 100, 1, 1),
+            (100, 2000, 2, 3),
+            (512, 1024, 0, 1),
+            (500, 1036, 1, 2),
+            (0, 1000, 1, 2),
+            (500, 20, 2, 2),
+            (4095, 1, 1, 1),
+        ];
+
+        for (offset, len, reads, writes) in table {
+            let disk = disk();
+            let data = vec![0xAA; len];
+            disk.write_at(offset, &data).unwrap();
+
+            let stats = disk.inner().stats();
+            assert_eq!(
+                (stats.reads, stats.writes),
+                (reads, writes),
+                "{offset} {len}"
+            );
+
+            // The bytes around the written range are kept
+            let mut expected = pattern(8 * 512);
+            let offset = offset as usize;
+            expected[offset..(offset + len)].copy_from_slice(&data);
+
+            let mut content = vec![0; 8 * 512];
+            disk.inner().read_sectors(0, 512, &mut content).unwrap();
+            assert_eq!(content, expected, "{offset} {len}");
+        }
+    }
+
+    #[test]
+    fn out_of_range() {
+        let disk = disk();
+        assert_eq!(disk.size().unwrap(), 4096);
+
+        for (offset, len) in [(4090, 10), (4096, 1), (u64::MAX, 1)] {
+            let err = disk.read_at(offset, &mut vec![0; len]).unwrap_err();
+            assert_eq!(err.kind, DiskErrKind::IndexOutOfRange);
+            assert_eq!(err.context.operation, Some(Operation::Read));
+            assert_eq!(err.context.layer, Some("ByteDisk"));
+
+            let err = disk.write_at(offset, &vec![0; len]).unwrap_err();
+            assert_eq!(err.kind, DiskErrKind::IndexOutOfRange);
+            assert_eq!(err.context.operation, Some(Operation::Write));
+        }
+
+        // Nothing was sent to the disk
+        assert_eq!(disk.inner().stats().reads + disk.inner().stats().writes, 0);
+
+        // The partial sector at the end of the disk can't be reached
+        let disk = MemDisk::new(
+            1000,
+            SectorSize::AllOf(vec![512]),
+            Permissions::read_write(),
+        );
+        let disk = ByteDisk::new(disk).unwrap();
+        assert_eq!(disk.size().unwrap(), 512);
+        assert!(disk.read_at(500, &mut [0; 20]).is_err());
+    }
+
+    #[test]
+    fn sector_sizes() {
+        let disk = MemDisk::new(
+            8192,
+            SectorSize::AllOf(vec![256, 4096]),
+            Permissions::read_write(),
+        );
+        assert_eq!(ByteDisk::new(&disk).unwrap().sector_size(), 4096);
+
+        let bytes = ByteDisk::with_sector_size(&disk, 256).unwrap();
+        bytes.write_at(250, &[1; 12]).unwrap();
+        let mut buf = [0; 14];
+        ByteDisk::new(&disk)
+            .unwrap()
+            .read_at(249, &mut buf)
+            .unwrap();
+        assert_eq!(buf, [0, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 0]);
+
+        let err = ByteDisk::with_sector_size(&disk, 512).err().unwrap();
+        assert!(matches!(
+            err.kind,
+            DiskErrKind::InvalidSectorSize { found: 512, .. }
+        ));
+
+        let disk = MemDisk::new(8192, SectorSize::AllOf(vec![]), Permissions::read_write());
+        let err = ByteDisk::new(&disk).err().unwrap();
+        assert_eq!(err.kind, DiskErrKind::UnsupportedDiskSectorSize);
+    }
+}
@@ -0,0 +1,741 @@
+use crate::{Disk, DiskErr, DiskErrKind, DiskInfos, Operation};
+use alloc::{collections::BTreeMap, vec::Vec};
+use core::{mem::ManuallyDrop, ptr};
+use mutex::Mutex;
+
+/// When the writes reach the underlying disk
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum WritePolicy {
+    /// The writes only update the cache. The dirty blocks are written when evicted, or on `flush`.
+    WriteBack,
+    /// The writes are sent to the disk immediately, the cache only serves the reads.
+    WriteThrough,
+}
+
+/// Counters of the cache activity, in blocks
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct CacheStats {
+    /// Blocks read from the cache
+    pub hits: u64,
+    /// Blocks read from the disk
+    pub misses: u64,
+    /// Blocks removed from the cache to make room for new ones
+    pub evictions: u64,
+    /// Dirty blocks written to the disk
+    pub write_backs: u64,
+}
+
+/// A bounded LRU cache of blocks over any `Disk`. Useful below a filesystem, whose metadata (FATs,
+/// directories) is read again and again: `Fat12::new(CachedDisk::new(disk, 64, ...)?, ...)`.
+///
+/// The cache works with blocks of a single size. The requests covering whole blocks are served by
+/// the cache, the other ones go straight to the disk (after writing back the dirty blocks they
+/// overlap). Every request is checked against the disk first, so that an invalid write fails
+/// immediately and not when its blocks are written back. With `WritePolicy::WriteBack`, the
+/// dirty blocks are written back when the cache is dropped, but the errors are lost then: use
+/// `flush` or `into_inner` to get them.
+pub struct CachedDisk<D: Disk> {
+    disk: D,
+    /// Size of a cached block, in bytes
+    block_size: usize,
+    /// Maximum count of cached blocks
+    capacity: usize,
+    policy: WritePolicy,
+    state: Mutex<CacheState>,
+}
+
+struct CacheState {
+    /// Cached blocks, by LBA (in blocks)
+    entries: BTreeMap<u64, Entry>,
+    /// LBA of the cached blocks, by last use. The first one is the least recently used.
+    lru: BTreeMap<u64, u64>,
+    /// Incremented on each use of a block
+    tick: u64,
+    stats: CacheStats,
+}
+
+struct Entry {
+    data: Vec<u8>,
+    dirty: bool,
+    /// Key of the block in `lru`
+    tick: u64,
+}
+
+impl<D: Disk> CachedDisk<D> {
+    /// Creates a cache of `capacity` blocks, using the preferred sector size of the disk (see
+    /// `SectorSize::preferred`) as block size.
+    pub fn new(disk: D, capacity: usize, policy: WritePolicy) -> Result<Self, DiskErr> {
+        let infos = disk.disk_infos()?;
+
+        match infos.sector_size.preferred(infos.disk_size) {
+            Some(block_size) => Ok(Self::build(disk, block_size, capacity, policy)),
+            None => Err(DiskErr::new(DiskErrKind::UnsupportedDiskSectorSize)
+                .during(Operation::DiskInfos)
+                .in_layer("CachedDisk")),
+        }
+    }
+
+    /// Creates a cache of `capacity` blocks of `block_size` bytes, which must be a sector size
+    /// supported by the disk.
+    pub fn with_block_size(
+        disk: D,
+        block_size: usize,
+        capacity: usize,
+        policy: WritePolicy,
+    ) -> Result<Self, DiskErr> {
+        let infos = disk.disk_infos()?;
+
+        if block_size == 0 || !infos.sector_size.is_supported(block_size, infos.disk_size) {
+            return Err(DiskErr::new(DiskErrKind::InvalidSectorSize {
+                found: block_size,
+                supported: infos.sector_size,
+                start: 0,
+            })
+            .during(Operation::DiskInfos)
+            .in_layer("CachedDisk"));
+        }
+
+        Ok(Self::build(disk, block_size, capacity, policy))
+    }
+
+    fn build(disk: D, block_size: usize, capacity: usize, policy: WritePolicy) -> Self {
+        Self {
+            disk,
+            block_size,
+            capacity: capacity.max(1),
+            policy,
+            state: Mutex::new(CacheState {
+                entries: BTreeMap::new(),
+                lru: BTreeMap::new(),
+                tick: 0,
+                stats: CacheStats::default(),
+            }),
+        }
+    }
+
+    pub const fn block_size(&self) -> usize {
+        self.block_size
+    }
+
+    pub const fn capacity(&self) -> usize {
+        self.capacity
+    }
+
+    pub const fn policy(&self) -> WritePolicy {
+        self.policy
+    }
+
+    pub fn stats(&self) -> CacheStats {
+        self.state.lock().stats
+    }
+
+    pub fn reset_stats(&self) {
+        self.state.lock().stats = CacheStats::default();
+    }
+
+    /// The count of cached blocks not written to the disk yet
+    pub fn dirty_blocks(&self) -> usize {
+        self.state
+            .lock()
+            .entries
+            .values()
+            .filter(|e| e.dirty)
+            .count()
+    }
+
+    /// Writes the dirty blocks to the disk, without flushing the disk
+    pub fn write_back(&self) -> Result<(), DiskErr> {
+        let mut state = self.state.lock();
+        self.write_back_range(&mut state, 0, u64::MAX)
+    }
+
+    /// Writes the dirty blocks to the disk and empties the cache
+    pub fn invalidate(&self) -> Result<(), DiskErr> {
+        let mut state = self.state.lock();
+        self.write_back_range(&mut state, 0, u64::MAX)?;
+        state.entries.clear();
+        state.lru.clear();
+        Ok(())
+    }
+
+    pub const fn inner(&self) -> &D {
+        &self.disk
+    }
+
+    /// Writes the dirty blocks to the disk and returns it
+    pub fn into_inner(self) -> Result<D, DiskErr> {
+        self.write_back()?;
+
+        let this = ManuallyDrop::new(self);
+        // SAFETY: the destructor of `this` never runs and `this` isn't used anymore, so the state
+        // is only dropped here and the disk only moved out here
+        unsafe {
+            drop(ptr::read(&this.state));
+            Ok(ptr::read(&this.disk))
+        }
+    }
+
+    /// Returns the range of blocks [start, end[ covered by a request of `len` bytes starting at
+    /// the LBA `sector`, if it covers whole blocks.
+    fn blocks(&self, sector: u64, sector_size: usize, len: usize) -> Option<(u64, u64)> {
+        let block_size = self.block_size as u64;
+        let start = sector.checked_mul(sector_size as u64)?;
+        let end = start.checked_add(len as u64)?;
+
+        if start.is_multiple_of(block_size) && end.is_multiple_of(block_size) {
+            Some((start / block_size, end / block_size))
+        } else {
+            None
+        }
+    }
+
+    /// Returns the range of blocks [start, end[ overlapped by a request of `len` bytes starting at
+    /// the LBA `sector`
+    fn overlapped_blocks(&self, sector: u64, sector_size: usize, len: u64) -> (u64, u64) {
+        let block_size = self.block_size as u64;
+        let start = sector.saturating_mul(sector_size as u64);
+        let end = start.saturating_add(len);
+
+        (start / block_size, end.div_ceil(block_size))
+    }
+
+    /// Writes the dirty blocks of [start, end[ to the disk. Contiguous blocks are written with a
+    /// single request.
+    fn write_back_range(
+        &self,
+        state: &mut CacheState,
+        start: u64,
+        end: u64,
+    ) -> Result<(), DiskErr> {
+        let dirty: Vec<u64> = state
+            .entries
+            .range(start..end)
+            .filter(|(_, e)| e.dirty)
+            .map(|(&block, _)| block)
+            .collect();
+
+        let mut i = 0;
+        while i < dirty.len() {
+            let mut run = 1;
+            while i + run < dirty.len() && dirty[i + run] == dirty[i] + run as u64 {
+                run += 1;
+            }
+
+            let mut buf = Vec::with_capacity(run * self.block_size);
+            for block in &dirty[i..(i + run)] {
+                buf.extend_from_slice(&state.entries[block].data);
+            }
+
+            self.disk.write_sectors(dirty[i], self.block_size, &buf)?;
+
+            for block in &dirty[i..(i + run)] {
+                if let Some(entry) = state.entries.get_mut(block) {
+                    entry.dirty = false;
+                }
+            }
+
+            state.stats.write_backs += run as u64;
+            i += run;
+        }
+
+        Ok(())
+    }
+
+    /// Removes the blocks of [start, end[ from the cache, without writing them back
+    fn drop_range(&self, state: &mut CacheState, start: u64, end: u64) {
+        let blocks: Vec<u64> = state.entries.range(start..end).map(|(&b, _)| b).collect();
+
+        for block in blocks {
+            if let Some(entry) = state.entries.remove(&block) {
+                state.lru.remove(&entry.tick);
+            }
+        }
+    }
+
+    /// Marks a cached block as the most recently used
+    fn touch(state: &mut CacheState, block: u64) {
+        state.tick += 1;
+        let tick = state.tick;
+
+        if let Some(entry) = state.entries.get_mut(&block) {
+            state.lru.remove(&entry.tick);
+            entry.tick = tick;
+            state.lru.insert(tick, block);
+        }
+    }
+
+    /// Inserts or replaces a block, evicting the least recently used ones if the cache is full
+    fn insert(
+        &self,
+        state: &mut CacheState,
+        block: u64,
+        data: &[u8],
+        dirty: bool,
+    ) -> Result<(), DiskErr> {
+        if let Some(entry) = state.entries.get_mut(&block) {
+            entry.data.copy_from_slice(data);
+            entry.dirty |= dirty;
+            Self::touch(state, block);
+            return Ok(());
+        }
+
+        while state.entries.len() >= self.capacity {
+            let Some((&tick, &victim)) = state.lru.first_key_value() else {
+                break;
+            };
+
+            if state.entries[&victim].dirty {
+                self.disk
+                    .write_sectors(victim, self.block_size, &state.entries[&victim].data)?;
+                state.stats.write_backs += 1;
+            }
+
+            state.lru.remove(&tick);
+            state.entries.remove(&victim);
+            state.stats.evictions += 1;
+        }
+
+        state.tick += 1;
+        let tick = state.tick;
+        state.lru.insert(tick, block);
+        state.entries.insert(
+            block,
+            Entry {
+                data: data.to_vec(),
+                dirty,
+                tick,
+            },
+        );
+
+        Ok(())
+    }
+
+    /// Reads the blocks [start, end[ into `buf`, from the cache or from the disk. The missing
+    /// contiguous blocks are read with a single request.
+    fn read_blocks(&self, start: u64, end: u64, buf: &mut [u8]) -> Result<(), DiskErr> {
+        let block_size = self.block_size;
+        let mut state = self.state.lock();
+        let mut block = start;
+
+        while block < end {
+            let offset = (block - start) as usize * block_size;
+
+            if let Some(entry) = state.entries.get(&block) {
+                buf[offset..(offset + block_size)].copy_from_slice(&entry.data);
+                Self::touch(&mut state, block);
+                state.stats.hits += 1;
+                block += 1;
+                continue;
+            }
+
+            let mut run_end = block + 1;
+            while run_end < end && !state.entries.contains_key(&run_end) {
+                run_end += 1;
+            }
+
+            let run = &mut buf[offset..((run_end - start) as usize * block_size)];
+            self.disk.read_sectors(block, block_size, run)?;
+            state.stats.misses += run_end - block;
+
+            for (i, data) in run.chunks_exact(block_size).enumerate() {
+                self.insert(&mut state, block + i as u64, data, false)?;
+            }
+
+            block = run_end;
+        }
+
+        Ok(())
+    }
+
+    /// Writes `buf` to the blocks [start, end[, following the write policy
+    fn write_blocks(&self, start: u64, end: u64, buf: &[u8], fua: bool) -> Result<(), DiskErr> {
+        let block_size = self.block_size;
+        let mut state = self.state.lock();
+
+        let dirty = match (self.policy, fua) {
+            (WritePolicy::WriteBack, false) => true,
+            _ => {
+                // The cached blocks are overwritten: they must not be written back over the new
+                // data when evicted
+                self.drop_range(&mut state, start, end);
+
+                if fua {
+                    self.disk.write_sectors_fua(start, block_size, buf)?;
+                } else {
+                    self.disk.write_sectors(start, block_size, buf)?;
+                }
+                false
+            }
+        };
+
+        for (block, data) in (start..end).zip(buf.chunks_exact(block_size)) {
+            self.insert(&mut state, block, data, dirty)?;
+        }
+
+        Ok(())
+    }
+
+    /// Checks a request of `len` bytes starting at the LBA `sector` like the disk would, before
+    /// the cache is used
+    fn check_request(
+        &self,
+        operation: Operation,
+        sector: u64,
+        sector_size: usize,
+        len: u64,
+    ) -> Result<(), DiskErr> {
+        let infos = self.disk.disk_infos()?;
+
+        let allowed = match operation {
+            Operation::Read => infos.permissions.read,
+            _ => infos.permissions.write,
+        };
+        if !allowed {
+            return Err(DiskErr::new(DiskErrKind::InvalidPermission {
+                disk_permissions: infos.permissions,
+            }));
+        }
+
+        if sector_size == 0
+            || !infos.sector_size.is_supported(sector_size, infos.disk_size)
+            || !len.is_multiple_of(sector_size as u64)
+        {
+            return Err(DiskErr::new(DiskErrKind::InvalidSectorSize {
+                found: sector_size,
+                supported: infos.sector_size,
+                start: 0,
+            }));
+        }
+
+        match sector
+            .checked_mul(sector_size as u64)
+            .and_then(|start| start.checked_add(len))
+        {
+            Some(end) if end <= infos.disk_size => Ok(()),
+            _ => Err(DiskErr::new(DiskErrKind::InvalidSectorIndex {
+                found: sector,
+                max: (infos.disk_size / sector_size as u64).saturating_sub(1),
+            })),
+        }
+    }
+
+    fn read_inner(&self, sector: u64, sector_size: usize, buf: &mut [u8]) -> Result<(), DiskErr> {
+        self.check_request(Operation::Read, sector, sector_size, buf.len() as u64)?;
+
+        match self.blocks(sector, sector_size, buf.len()) {
+            Some((start, end)) => self.read_blocks(start, end, buf),
+            None => {
+                // The dirty blocks overlapped by the request must reach the disk first
+                let (start, end) = self.overlapped_blocks(sector, sector_size, buf.len() as u64);
+                self.write_back_range(&mut self.state.lock(), start, end)?;
+                self.disk.read_sectors(sector, sector_size, buf)
+            }
+        }
+    }
+
+    fn write_inner(
+        &self,
+        sector: u64,
+        sector_size: usize,
+        buf: &[u8],
+        fua: bool,
+    ) -> Result<(), DiskErr> {
+        self.check_request(Operation::Write, sector, sector_size, buf.len() as u64)?;
+
+        match self.blocks(sector, sector_size, buf.len()) {
+            Some((start, end)) => self.write_blocks(start, end, buf, fua),
+            None => {
+                // The overlapped blocks are written back and dropped, as they would be stale
+                let (start, end) = self.overlapped_blocks(sector, sector_size, buf.len() as u64);
+                let mut state = self.state.lock();
+                self.write_back_range(&mut state, start, end)?;
+                self.drop_range(&mut state, start, end);
+
+                if fua {
+                    self.disk.write_sectors_fua(sector, sector_size, buf)
+                } else {
+                    self.disk.write_sectors(sector, sector_size, buf)
+                }
+            }
+        }
+    }
+
+    fn discard_inner(&self, sector: u64, sector_size: usize, count: u64) -> Result<(), DiskErr> {
+        let len = count.saturating_mul(sector_size as u64);
+        // The cached blocks are dropped, the discard must not fail afterwards
+        self.check_request(Operation::Discard, sector, sector_size, len)?;
+
+        let (start, end) = self.overlapped_blocks(sector, sector_size, len);
+        let mut state = self.state.lock();
+
+        // The blocks partially discarded keep the rest of their data, so it must be on the disk
+        let block_size = self.block_size as u64;
+        let first = sector.saturating_mul(sector_size as u64);
+        if !first.is_multiple_of(block_size) {
+            self.write_back_range(&mut state, start, start + 1)?;
+        }
+        if !first.saturating_add(len).is_multiple_of(block_size) {
+            self.write_back_range(&mut state, end.saturating_sub(1), end)?;
+        }
+
+        self.drop_range(&mut state, start, end);
+        self.disk.discard(sector, sector_size, count)
+    }
+}
+
+impl<D: Disk> Disk for CachedDisk<D> {
+    fn read_sector(&self, sector: u64, buf: &mut [u8]) -> Result<(), DiskErr> {
+        self.read_sectors(sector, buf.len(), buf)
+    }
+
+    fn write_sector(&self, sector: u64, buf: &[u8]) -> Result<(), DiskErr> {
+        self.write_sectors(sector, buf.len(), buf)
+    }
+
+    fn disk_infos(&self) -> Result<DiskInfos, DiskErr> {
+        self.disk.disk_infos()
+    }
+
+    fn read_sectors(&self, sector: u64, sector_size: usize, buf: &mut [u8]) -> Result<(), DiskErr> {
+        self.read_inner(sector, sector_size, buf)
+            .map_err(|e| e.at(Operation::Read, sector).in_layer("CachedDisk"))
+    }
+
+    fn write_sectors(&self, sector: u64, sector_size: usize, buf: &[u8]) -> Result<(), DiskErr> {
+        self.write_inner(sector, sector_size, buf, false)
+            .map_err(|e| e.at(Operation::Write, sector).in_layer("CachedDisk"))
+    }
+
+    fn write_sectors_fua(
+        &self,
+        sector: u64,
+        sector_size: usize,
+        buf: &[u8],
+    ) -> Result<(), DiskErr> {
+        self.write_inner(sector, sector_size, buf, true)
+            .map_err(|e| e.at(Operation::Write, sector).in_layer("CachedDisk"))
+    }
+
+    fn discard(&self, sector: u64, sector_size: usize, count: u64) -> Result<(), DiskErr> {
+        self.discard_inner(sector, sector_size, count)
+            .map_err(|e| e.at(Operation::Discard, sector).in_layer("CachedDisk"))
+    }
+
+    /// Writes the dirty blocks, then flushes the disk
+    fn flush(&self) -> Result<(), DiskErr> {
+        self.write_back()
+            .and_then(|_| self.disk.flush())
+            .map_err(|e| e.during(Operation::Flush).in_layer("CachedDisk"))
+    }
+}
+
+/// Writes the dirty blocks back, the errors are lost
+impl<D: Disk> Drop for CachedDisk<D> {
+    fn drop(&mut self) {
+        let _ = self.write_back();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{Permissions, SectorSize, memdisk::MemDisk};
+    use alloc::{sync::Arc, vec, vec::Vec};
+
+    /// A disk of 16 sectors of 512 bytes, sector `i` filled with `i`
+    fn disk(permissions: Permissions) -> Arc<MemDisk> {
+        let content = (0..16u8).flat_map(|i| [i; 512]).collect();
+        Arc::new(MemDisk::from_vec(
+            content,
+            SectorSize::AllOf(vec![512, 1024]),
+            permissions,
+        ))
+    }
+
+    fn cache(
+        disk: &Arc<MemDisk>,
+        capacity: usize,
+        policy: WritePolicy,
+    ) -> CachedDisk<Arc<MemDisk>> {
+        CachedDisk::with_block_size(disk.clone(), 512, capacity, policy).unwrap()
+    }
+
+    fn read(disk: &impl Disk, sector: u64) -> u8 {
+        let mut buf = [0; 512];
+        disk.read_sectors(sector, 512, &mut buf).unwrap();
+        assert!(buf.iter().all(|&b| b == buf[0]));
+        buf[0]
+    }
+
+    fn stats(hits: u64, misses: u64, evictions: u64, write_backs: u64) -> CacheStats {
+        CacheStats {
+            hits,
+            misses,
+            evictions,
+            write_backs,
+        }
+    }
+
+    #[test]
+    fn eviction_order() {
+        let disk = disk(Permissions::read_write());
+        let cache = cache(&disk, 3, WritePolicy::WriteThrough);
+
+        for sector in [0, 1, 2] {
+            assert_eq!(read(&cache, sector), sector as u8);
+        }
+        assert_eq!(cache.stats(), stats(0, 3, 0, 0));
+
+        // A hit makes 0 the most recently used, 1 is evicted first
+        assert_eq!(read(&cache, 0), 0);
+        assert_eq!(read(&cache, 3), 3);
+        assert_eq!(cache.stats(), stats(1, 4, 1, 0));
+
+        cache.reset_stats();
+        for sector in [2, 0, 3] {
+            read(&cache, sector);
+        }
+        assert_eq!(cache.stats(), stats(3, 0, 0, 0));
+
+        // 1 is read again and evicts 2, the least recently used now
+        read(&cache, 1);
+        read(&cache, 2);
+        assert_eq!(cache.stats(), stats(3, 2, 2, 0));
+
+        // A multi-block read is served from the cache (1, 2, 3) and the disk (4)
+        cache.reset_stats();
+        let mut buf = vec![0; 4 * 512];
+        cache.read_sectors(1, 512, &mut buf).unwrap();
+        assert_eq!(buf, (1..5u8).flat_map(|i| [i; 512]).collect::<Vec<_>>());
+        assert_eq!(cache.stats(), stats(3, 1, 1, 0));
+    }
+
+    #[test]
+    fn write_through() {
+        let disk = disk(Permissions::read_write());
+        let cache = cache(&disk, 4, WritePolicy::WriteThrough);
+
+        read(&cache, 5);
+        cache.write_sectors(5, 512, &[0xAA; 512]).unwrap();
+        cache.write_sectors(6, 512, &[0xBB; 512]).unwrap();
+
+        // The disk is written immediately, and the cache serves the new data
+        assert_eq!(read(&*disk, 5), 0xAA);
+        assert_eq!(read(&*disk, 6), 0xBB);
+        assert_eq!(cache.dirty_blocks(), 0);
+        assert_eq!(read(&cache, 5), 0xAA);
+        assert_eq!(read(&cache, 6), 0xBB);
+        assert_eq!(cache.stats(), stats(2, 1, 0, 0));
+    }
+
+    #[test]
+    fn write_back() {
+        let disk = disk(Permissions::read_write());
+        let cache = cache(&disk, 2, WritePolicy::WriteBack);
+
+        cache.write_sectors(2, 512, &[0xAA; 512]).unwrap();
+        cache.write_sectors(3, 512, &[0xBB; 512]).unwrap();
+        assert_eq!(cache.dirty_blocks(), 2);
+        assert_eq!(read(&*disk, 2), 2);
+        assert_eq!(read(&cache, 2), 0xAA);
+
+        // The eviction of 3 writes it back, 2 stays dirty
+        read(&cache, 7);
+        assert_eq!(read(&*disk, 3), 0xBB);
+        assert_eq!(read(&*disk, 2), 2);
+        assert_eq!(cache.dirty_blocks(), 1);
+        assert_eq!(cache.stats(), stats(1, 1, 1, 1));
+
+        cache.flush().unwrap();
+        assert_eq!(read(&*disk, 2), 0xAA);
+        assert_eq!(cache.dirty_blocks(), 0);
+        assert_eq!(cache.stats(), stats(1, 1, 1, 2));
+
+        // A FUA write reaches the disk immediately
+        cache.write_sectors_fua(4, 512, &[0xCC; 512]).unwrap();
+        assert_eq!(read(&*disk, 4), 0xCC);
+        assert_eq!(cache.dirty_blocks(), 0);
+
+        // The dirty blocks are written back on drop and by `into_inner`
+        cache.write_sectors(8, 512, &[0xDD; 512]).unwrap();
+        drop(cache);
+        assert_eq!(read(&*disk, 8), 0xDD);
+
+        let cache = self::cache(&disk, 2, WritePolicy::WriteBack);
+        cache.write_sectors(9, 512, &[0xEE; 512]).unwrap();
+        let inner = cache.into_inner().unwrap();
+        assert_eq!(read(&inner, 9), 0xEE);
+    }
+
+    #[test]
+    fn partial_requests() {
+        let disk = disk(Permissions::read_write());
+        let cache =
+            CachedDisk::with_block_size(disk.clone(), 1024, 4, WritePolicy::WriteBack).unwrap();
+
+        // A read of half a dirty block sees the data written to the cache
+        cache.write_sectors(1, 1024, &[0xAA; 1024]).unwrap();
+        assert_eq!(read(&cache, 3), 0xAA);
+        assert_eq!(read(&*disk, 3), 0xAA);
+        assert_eq!(cache.dirty_blocks(), 0);
+
+        // A write of half a cached block replaces it
+        cache.write_sectors(2, 512, &[0xBB; 512]).unwrap();
+        let mut buf = [0; 1024];
+        cache.read_sectors(1, 1024, &mut buf).unwrap();
+        assert_eq!(buf[..512], [0xBB; 512]);
+        assert_eq!(buf[512..], [0xAA; 512]);
+
+        // A discard of half a dirty block keeps the other half
+        cache.write_sectors(3, 1024, &[0xCC; 1024]).unwrap();
+        cache.discard(7, 512, 1).unwrap();
+        assert_eq!(read(&*disk, 6), 0xCC);
+        assert_eq!(read(&*disk, 7), 0);
+        assert_eq!(cache.dirty_blocks(), 0);
+    }
+
+    #[test]
+    fn invalid_writes() {
+        let disk = disk(Permissions::read_write());
+        let cache = cache(&disk, 4, WritePolicy::WriteBack);
+
+        // Out of the disk, with an unsupported sector size, or not a whole count of sectors
+        let requests: [(u64, usize, usize); 4] = [
+            (16, 512, 512),
+            (15, 512, 1024),
+            (0, 256, 512),
+            (0, 512, 100),
+        ];
+        for (sector, sector_size, len) in requests {
+            let err = cache
+                .write_sectors(sector, sector_size, &vec![0xAA; len])
+                .unwrap_err();
+            assert_eq!(err.context.operation, Some(Operation::Write));
+            assert_eq!(err.context.layer, Some("CachedDisk"));
+        }
+        let err = cache.write_sectors(16, 512, &[0; 512]).unwrap_err();
+        assert_eq!(
+            err.kind,
+            DiskErrKind::InvalidSectorIndex { found: 16, max: 15 }
+        );
+
+        assert_eq!(cache.dirty_blocks(), 0);
+        assert_eq!(cache.stats(), CacheStats::default());
+        cache.flush().unwrap();
+
+        // Nothing can be cached on a read-only disk
+        let disk = self::disk(Permissions::read_only());
+        let cache = self::cache(&disk, 4, WritePolicy::WriteBack);
+        read(&cache, 0);
+        let err = cache.write_sectors(0, 512, &[0xAA; 512]).unwrap_err();
+        assert_eq!(
+            err.kind,
+            DiskErrKind::InvalidPermission {
+                disk_permissions: Permissions::read_only()
+            }
+        );
+        assert!(cache.discard(0, 512, 1).is_err());
+        assert_eq!(cache.dirty_blocks(), 0);
+        assert_eq!(read(&cache, 0), 0);
+    }
+}
@@ -0,0 +1,48 @@
+/// Lookup table of the reflected CRC32 with the polynomial `poly`, computed at compile time
+const fn crc_table(poly: u32) -> [u32; 256] {
+    let mut table = [0; 256];
+    let mut i = 0;
+
+    while i < 256 {
+        let mut crc = i as u32;
+        let mut bit = 0;
+
+        while bit < 8 {
+            crc = if crc & 1 == 1 {
+                (crc >> 1) ^ poly
+            } else {
+                crc >> 1
+            };
+            bit += 1;
+        }
+
+        table[i] = crc;
+        i += 1;
+    }
+
+    table
+}
+
+const CRC32_TABLE: [u32; 256] = crc_table(0xEDB88320);
+const CRC32C_TABLE: [u32; 256] = crc_table(0x82F63B78);
+
+fn crc_update(table: &[u32; 256], crc: u32, data: &[u8]) -> u32 {
+    !data.iter().fold(!crc, |crc, &b| {
+        (crc >> 8) ^ table[((crc ^ b as u32) & 0xFF) as usize]
+    })
+}
+
+/// Continues the CRC32 `crc` (IEEE 802.3, as used by gzip and zip) over `data`. Starts with 0.
+pub(crate) fn crc32_update(crc: u32, data: &[u8]) -> u32 {
+    crc_update(&CRC32_TABLE, crc, data)
+}
+
+/// CRC32 (IEEE 802.3) of `data`
+pub(crate) fn crc32(data: &[u8]) -> u32 {
+    crc32_update(0, data)
+}
+
+/// CRC32C (Castagnoli, as used by iSCSI, ext4 and btrfs) of `data`
+pub(crate) fn crc32c(data: &[u8]) -> u32 {
+    crc_update(&CRC32C_TABLE, 0, data)
+}
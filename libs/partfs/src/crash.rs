@@ -0,0 +1,325 @@
+use crate::{
+    Operation, Permissions, SectorSize,
+    memdisk::MemDisk,
+    tracing::{Trace, TracingDisk},
+};
+use alloc::{sync::Arc, vec, vec::Vec};
+
+/// Generates the states a disk can be left in if the power is cut during an operation, from the
+/// writes the operation did, and runs a checker on each of them. Everything runs on `MemDisk`s.
+///
+/// A disk persists the sectors one by one, and may persist the sectors written between two
+/// flushes in any order. The simulator splits the writes in sectors and generates every prefix of
+/// them, so the states where a multi-sector write is torn are covered too. With
+/// `with_reorderings`, it also generates every subset of the sectors written since the last flush.
+pub struct CrashSimulator {
+    /// The content of the disk before the operation
+    image: Vec<u8>,
+    sector_size: SectorSize,
+    /// The sector writes, grouped by flush: every write of an epoch is persisted before the
+    /// writes of the next one start
+    epochs: Vec<Vec<SectorWrite>>,
+    /// Maximum count of writes in an epoch for its reorderings to be generated
+    max_reordered_writes: usize,
+}
+
+/// A write of one sector, at `offset` bytes. Discards are writes of zeroes.
+struct SectorWrite {
+    offset: u64,
+    data: Vec<u8>,
+}
+
+/// A crash state: the writes of the first `flushed_epochs` epochs are persisted, and the writes
+/// at the indices `persisted` in the next one.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CrashState {
+    pub flushed_epochs: usize,
+    pub persisted: Vec<usize>,
+}
+
+/// The result of `CrashSimulator::run`
+#[derive(Debug)]
+pub struct CrashReport<E> {
+    /// Count of states checked
+    pub states: usize,
+    /// The states rejected by the checker, with its error
+    pub failures: Vec<(CrashState, E)>,
+}
+
+impl CrashSimulator {
+    /// Builds the simulator from the successful writes, discards and flushes recorded in `trace`
+    /// through the disk labelled `label`. `image` is the content of the disk before the first
+    /// recorded request.
+    pub fn from_trace(image: Vec<u8>, sector_size: SectorSize, trace: &Trace, label: &str) -> Self {
+        let mut epochs = vec![Vec::new()];
+
+        for record in trace.records() {
+            if record.label != label || record.error.is_some() {
+                continue;
+            }
+
+            let data = match (record.operation, record.data) {
+                (Operation::Flush, _) => {
+                    epochs.push(Vec::new());
+                    continue;
+                }
+                (Operation::Write, Some(data)) if record.sector_size != 0 => data,
+                (Operation::Discard, _) if record.sector_size != 0 => vec![0; record.len as usize],
+                _ => continue,
+            };
+
+            let epoch = epochs.last_mut().expect("there is always an epoch");
+            let start = record.sector * record.sector_size as u64;
+
+            for (i, sector) in data.chunks(record.sector_size).enumerate() {
+                epoch.push(SectorWrite {
+                    offset: start + (i * record.sector_size) as u64,
+                    data: sector.to_vec(),
+                });
+            }
+
+            // A FUA write is handled like the default `write_sectors_fua`, as a write followed by
+            // a flush: the writes before it are considered persisted with it
+            if record.fua {
+                epochs.push(Vec::new());
+            }
+        }
+
+        Self {
+            image,
+            sector_size,
+            epochs,
+            max_reordered_writes: 0,
+        }
+    }
+
+    /// Runs `operation` on a `MemDisk` holding `image`, recording its writes, and builds the
+    /// simulator from them. Returns the result of the operation too.
+    pub fn record<R>(
+        image: Vec<u8>,
+        sector_size: SectorSize,
+        operation: impl FnOnce(Arc<TracingDisk<MemDisk>>) -> R,
+    ) -> (Self, R) {
+        let trace = Trace::new();
+        let disk = MemDisk::from_vec(
+            image.clone(),
+            sector_size.clone(),
+            Permissions::read_write(),
+        );
+        let result = operation(Arc::new(TracingDisk::new(disk, trace.clone(), "disk")));
+
+        (Self::from_trace(image, sector_size, &trace, "disk"), result)
+    }
+
+    /// Also generates the states where the writes since the last flush were persisted in another
+    /// order, for the epochs of at most `max_reordered_writes` sector writes. There are 2^n states
+    /// for an epoch of n writes, the longer epochs only get their prefixes checked.
+    pub fn with_reorderings(mut self, max_reordered_writes: usize) -> Self {
+        self.max_reordered_writes = max_reordered_writes.min(usize::BITS as usize - 1);
+        self
+    }
+
+    /// Count of sector writes recorded
+    pub fn writes(&self) -> usize {
+        self.epochs.iter().map(|e| e.len()).sum()
+    }
+
+    /// Count of flushes recorded
+    pub fn flushes(&self) -> usize {
+        self.epochs.len() - 1
+    }
+
+    /// Calls `checker` on a `MemDisk` in each of the crash states, including the state before the
+    /// operation and the state after it. The disks are readable and writable, so that a checker
+    /// can also run a repair or a mount.
+    pub fn run<E>(&self, mut checker: impl FnMut(MemDisk) -> Result<(), E>) -> CrashReport<E> {
+        let mut report = CrashReport {
+            states: 0,
+            failures: Vec::new(),
+        };
+        let mut check = |content: Vec<u8>, state: CrashState| {
+            report.states += 1;
+            let disk =
+                MemDisk::from_vec(content, self.sector_size.clone(), Permissions::read_write());
+            if let Err(e) = checker(disk) {
+                report.failures.push((state, e));
+            }
+        };
+
+        // The content with the epochs before the current one persisted
+        let mut flushed = self.image.clone();
+
+        for (flushed_epochs, epoch) in self.epochs.iter().enumerate() {
+            if epoch.len() <= self.max_reordered_writes {
+                // Every strict subset, the whole epoch is the first state of the next one
+                for mask in 0..((1usize << epoch.len()) - 1) {
+                    let persisted: Vec<usize> =
+                        (0..epoch.len()).filter(|i| mask & (1 << i) != 0).collect();
+                    let content = Self::apply(&flushed, epoch, &persisted);
+                    check(
+                        content,
+                        CrashState {
+                            flushed_epochs,
+                            persisted,
+                        },
+                    );
+                }
+            } else {
+                let mut content = flushed.clone();
+
+                for len in 0..epoch.len() {
+                    if len > 0 {
+                        Self::write(&mut content, &epoch[len - 1]);
+                    }
+                    check(
+                        content.clone(),
+                        CrashState {
+                            flushed_epochs,
+                            persisted: (0..len).collect(),
+                        },
+                    );
+                }
+            }
+
+            for write in epoch {
+                Self::write(&mut flushed, write);
+            }
+        }
+
+        check(
+            flushed,
+            CrashState {
+                flushed_epochs: self.epochs.len(),
+                persisted: Vec::new(),
+            },
+        );
+
+        report
+    }
+
+    /// Returns `content` with the writes of `epoch` at the indices `persisted` applied, in order
+    fn apply(content: &[u8], epoch: &[SectorWrite], persisted: &[usize]) -> Vec<u8> {
+        let mut content = content.to_vec();
+
+        for &i in persisted {
+            Self::write(&mut content, &epoch[i]);
+        }
+
+        content
+    }
+
+    fn write(content: &mut [u8], write: &SectorWrite) {
+        let start = write.offset as usize;
+        content[start..(start + write.data.len())].copy_from_slice(&write.data);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::Disk;
+
+    /// Runs the simulator and returns every state with the disk content
+    fn states(simulator: &CrashSimulator) -> Vec<(CrashState, Vec<u8>)> {
+        let report = simulator.run(|disk| Err(disk.into_vec()));
+        assert_eq!(report.states, report.failures.len());
+        report.failures
+    }
+
+    /// The first byte of each sector
+    fn sectors(content: &[u8]) -> Vec<u8> {
+        content.chunks(512).map(|s| s[0]).collect()
+    }
+
+    #[test]
+    fn unflushed_writes() {
+        let (simulator, ()) = CrashSimulator::record(vec![0; 4 * 512], SectorSize::Any, |disk| {
+            disk.write_sector(0, &[1; 512]).unwrap();
+            disk.flush().unwrap();
+            disk.write_sectors(1, 512, &[2; 1024]).unwrap();
+            disk.write_sector(3, &[3; 512]).unwrap();
+        });
+        assert_eq!(simulator.writes(), 4);
+        assert_eq!(simulator.flushes(), 1);
+
+        let contents: Vec<Vec<u8>> = states(&simulator)
+            .into_iter()
+            .map(|(_, content)| sectors(&content))
+            .collect();
+        assert_eq!(
+            contents,
+            [
+                vec![0, 0, 0, 0],
+                vec![1, 0, 0, 0],
+                vec![1, 2, 0, 0],
+                vec![1, 2, 2, 0],
+                vec![1, 2, 2, 3],
+            ]
+        );
+
+        let simulator = simulator.with_reorderings(3);
+        let states = states(&simulator);
+        assert_eq!(states.len(), 1 + 7 + 1);
+
+        for (state, content) in &states {
+            let content = sectors(content);
+            // The write before the flush is never lost
+            assert_eq!(content[0] == 1, state.flushed_epochs > 0);
+        }
+
+        // The last write persisted without the previous ones
+        assert!(states.contains(&(
+            CrashState {
+                flushed_epochs: 1,
+                persisted: vec![2],
+            },
+            [vec![1; 512], vec![0; 1024], vec![3; 512]].concat()
+        )));
+    }
+
+    #[test]
+    fn fua_writes() {
+        let (simulator, ()) = CrashSimulator::record(vec![0; 4 * 512], SectorSize::Any, |disk| {
+            disk.write_sector(0, &[1; 512]).unwrap();
+            disk.write_sectors_fua(1, 512, &[2; 512]).unwrap();
+            disk.write_sector(2, &[3; 512]).unwrap();
+            disk.discard(1, 512, 1).unwrap();
+        });
+        let simulator = simulator.with_reorderings(8);
+
+        for (state, content) in states(&simulator) {
+            let content = sectors(&content);
+            if state.flushed_epochs == 0 {
+                // The operation is interrupted before the FUA write completes
+                assert_eq!(content[2], 0);
+            } else {
+                // Both the FUA write and the write before it survive, until the discard
+                assert_eq!(content[0], 1);
+                let discarded = state.flushed_epochs == 2 || state.persisted.contains(&1);
+                assert_eq!(content[1], if discarded { 0 } else { 2 });
+            }
+        }
+    }
+
+    #[test]
+    fn deterministic_enumeration() {
+        let operation = |disk: Arc<TracingDisk<MemDisk>>| {
+            for i in 0..6 {
+                disk.write_sector(i % 4, &[i as u8 + 1; 512]).unwrap();
+                if i == 2 {
+                    disk.flush().unwrap();
+                }
+            }
+        };
+
+        let (first, ()) = CrashSimulator::record(vec![0; 4 * 512], SectorSize::Any, operation);
+        let (second, ()) = CrashSimulator::record(vec![0; 4 * 512], SectorSize::Any, operation);
+        let first = first.with_reorderings(3);
+        let second = second.with_reorderings(3);
+
+        let states = states(&first);
+        assert_eq!(states.len(), 2 * 7 + 1);
+        assert_eq!(states, self::states(&second));
+        assert_eq!(states, self::states(&first));
+    }
+}
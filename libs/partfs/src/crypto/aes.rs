@@ -0,0 +1,435 @@
+// ### BITSLICED S-BOX ###
+
+/// Up to 8 blocks as 8 bit planes: the bit `16 * k + i` of `planes[b]` is the bit `b` of the byte
+/// `i` of the block `k`. The S-box is computed on the planes with logical operations only, for
+/// all the bytes at once: no table is indexed by the data, so the timing doesn't depend on it.
+type Planes = [u128; 8];
+
+/// Transposes the 8x8 bit matrix whose rows are the bytes of `x`: the bit `j` of the byte `i`
+/// becomes the bit `i` of the byte `j`
+const fn transpose(mut x: u64) -> u64 {
+    let t = (x ^ (x >> 7)) & 0x00AA00AA00AA00AA;
+    x ^= t ^ (t << 7);
+    let t = (x ^ (x >> 14)) & 0x0000CCCC0000CCCC;
+    x ^= t ^ (t << 14);
+    let t = (x ^ (x >> 28)) & 0x00000000F0F0F0F0;
+    x ^ t ^ (t << 28)
+}
+
+fn bitslice(blocks: &[[u8; 16]]) -> Planes {
+    let mut planes = [0; 8];
+
+    for (k, block) in blocks.iter().enumerate() {
+        let low = transpose(u64::from_le_bytes(block[..8].try_into().unwrap())).to_le_bytes();
+        let high = transpose(u64::from_le_bytes(block[8..].try_into().unwrap())).to_le_bytes();
+
+        for (b, plane) in planes.iter_mut().enumerate() {
+            *plane |= (u16::from_le_bytes([low[b], high[b]]) as u128) << (16 * k);
+        }
+    }
+
+    planes
+}
+
+fn unbitslice(planes: &Planes, blocks: &mut [[u8; 16]]) {
+    for (k, block) in blocks.iter_mut().enumerate() {
+        let low = transpose(u64::from_le_bytes(planes.map(|p| (p >> (16 * k)) as u8)));
+        let high = transpose(u64::from_le_bytes(
+            planes.map(|p| (p >> (16 * k + 8)) as u8),
+        ));
+
+        block[..8].copy_from_slice(&low.to_le_bytes());
+        block[8..].copy_from_slice(&high.to_le_bytes());
+    }
+}
+
+/// Reduces a polynomial of degree up to 14 modulo x^8 + x^4 + x^3 + x + 1
+fn reduce(mut t: [u128; 15]) -> Planes {
+    // x^k = x^(k-4) + x^(k-5) + x^(k-7) + x^(k-8), from the highest degree down
+    for k in (8..15).rev() {
+        t[k - 4] ^= t[k];
+        t[k - 5] ^= t[k];
+        t[k - 7] ^= t[k];
+        t[k - 8] ^= t[k];
+    }
+
+    t[..8].try_into().unwrap()
+}
+
+/// Multiplication in GF(2^8)
+fn mul(a: &Planes, b: &Planes) -> Planes {
+    let mut t = [0; 15];
+
+    for (i, a) in a.iter().enumerate() {
+        for (j, b) in b.iter().enumerate() {
+            t[i + j] ^= a & b;
+        }
+    }
+
+    reduce(t)
+}
+
+/// Squaring in GF(2^8), a linear map
+fn square(a: &Planes) -> Planes {
+    let mut t = [0; 15];
+
+    for (i, a) in a.iter().enumerate() {
+        t[2 * i] = *a;
+    }
+
+    reduce(t)
+}
+
+/// Multiplicative inverse in GF(2^8), x^254, which maps 0 to 0
+fn inverse(x: &Planes) -> Planes {
+    let x2 = square(x);
+    let x3 = mul(&x2, x);
+    let x12 = square(&square(&x3));
+    let x15 = mul(&x12, &x3);
+    let x240 = square(&square(&square(&square(&x15))));
+    let x252 = mul(&x240, &x12);
+
+    mul(&x252, &x2)
+}
+
+/// The affine transformation of SubBytes applied to the inverse
+fn sub_planes(planes: &Planes) -> Planes {
+    let inv = inverse(planes);
+    let mut out = [0; 8];
+
+    for (b, out) in out.iter_mut().enumerate() {
+        *out = inv[b] ^ inv[(b + 4) % 8] ^ inv[(b + 5) % 8] ^ inv[(b + 6) % 8] ^ inv[(b + 7) % 8];
+        if (0x63 >> b) & 1 != 0 {
+            *out = !*out;
+        }
+    }
+
+    out
+}
+
+/// The inverse of the affine transformation, then the inverse
+fn inv_sub_planes(planes: &Planes) -> Planes {
+    let mut t = [0; 8];
+
+    for (b, t) in t.iter_mut().enumerate() {
+        *t = planes[(b + 7) % 8] ^ planes[(b + 5) % 8] ^ planes[(b + 2) % 8];
+        if (0x05 >> b) & 1 != 0 {
+            *t = !*t;
+        }
+    }
+
+    inverse(&t)
+}
+
+/// SubBytes of at most 8 blocks
+fn sub_bytes(blocks: &mut [[u8; 16]]) {
+    unbitslice(&sub_planes(&bitslice(blocks)), blocks);
+}
+
+fn inv_sub_bytes(blocks: &mut [[u8; 16]]) {
+    unbitslice(&inv_sub_planes(&bitslice(blocks)), blocks);
+}
+
+// ### ROUND FUNCTIONS ###
+
+const RCON: [u8; 10] = [0x01, 0x02, 0x04, 0x08, 0x10, 0x20, 0x40, 0x80, 0x1B, 0x36];
+
+/// Multiplication by x in GF(2^8), without branch
+const fn xtime(b: u8) -> u8 {
+    (b << 1) ^ (0x1B & 0u8.wrapping_sub(b >> 7))
+}
+
+/// The state is stored by columns: the byte `4 * col + row`
+fn shift_rows(state: &mut [u8; 16]) {
+    let old = *state;
+    for col in 0..4 {
+        for row in 1..4 {
+            state[4 * col + row] = old[4 * ((col + row) % 4) + row];
+        }
+    }
+}
+
+fn inv_shift_rows(state: &mut [u8; 16]) {
+    let old = *state;
+    for col in 0..4 {
+        for row in 1..4 {
+            state[4 * ((col + row) % 4) + row] = old[4 * col + row];
+        }
+    }
+}
+
+fn mix_columns(state: &mut [u8; 16]) {
+    for col in state.chunks_exact_mut(4) {
+        let a = [col[0], col[1], col[2], col[3]];
+        let all = a[0] ^ a[1] ^ a[2] ^ a[3];
+
+        for row in 0..4 {
+            col[row] = a[row] ^ all ^ xtime(a[row] ^ a[(row + 1) % 4]);
+        }
+    }
+}
+
+/// InvMixColumns is MixColumns after a multiplication by 4x^2 + 5
+fn inv_mix_columns(state: &mut [u8; 16]) {
+    for col in state.chunks_exact_mut(4) {
+        let even = xtime(xtime(col[0] ^ col[2]));
+        let odd = xtime(xtime(col[1] ^ col[3]));
+        col[0] ^= even;
+        col[1] ^= odd;
+        col[2] ^= even;
+        col[3] ^= odd;
+    }
+
+    mix_columns(state);
+}
+
+// ### CIPHER ###
+
+/// The AES block cipher, with a key of 128, 192 or 256 bits. The implementation is bitsliced and
+/// uses no lookup table, so that its timing doesn't depend on the key or the data (it is slower
+/// than a table-based one). The expanded keys are erased on drop.
+pub struct Aes {
+    rounds: usize,
+    /// Round keys, 16 bytes per round
+    round_keys: [u8; 240],
+}
+
+impl Aes {
+    pub const BLOCK_SIZE: usize = 16;
+    /// Count of blocks whose SubBytes are computed together, which costs as much as a single one
+    pub const PARALLEL_BLOCKS: usize = 8;
+
+    /// Expands `key`. Returns `None` if it is not 16, 24 or 32 bytes long.
+    pub fn new(key: &[u8]) -> Option<Self> {
+        let nk = match key.len() {
+            16 | 24 | 32 => key.len() / 4,
+            _ => return None,
+        };
+        let rounds = nk + 6;
+        let words = 4 * (rounds + 1);
+
+        let mut round_keys = [0; 240];
+        round_keys[..key.len()].copy_from_slice(key);
+
+        for i in nk..words {
+            let mut temp: [u8; 4] = round_keys[(4 * i - 4)..(4 * i)].try_into().unwrap();
+
+            if i % nk == 0 {
+                temp.rotate_left(1);
+                temp = sub_word(temp);
+                temp[0] ^= RCON[i / nk - 1];
+            } else if nk > 6 && i % nk == 4 {
+                temp = sub_word(temp);
+            }
+
+            for (j, temp) in temp.iter().enumerate() {
+                round_keys[4 * i + j] = round_keys[4 * (i - nk) + j] ^ temp;
+            }
+        }
+
+        Some(Self { rounds, round_keys })
+    }
+
+    pub fn encrypt_block(&self, block: &mut [u8; 16]) {
+        self.encrypt_blocks(core::slice::from_mut(block));
+    }
+
+    pub fn decrypt_block(&self, block: &mut [u8; 16]) {
+        self.decrypt_blocks(core::slice::from_mut(block));
+    }
+
+    /// Encrypts independent blocks, `PARALLEL_BLOCKS` at a time
+    pub fn encrypt_blocks(&self, blocks: &mut [[u8; 16]]) {
+        for group in blocks.chunks_mut(Self::PARALLEL_BLOCKS) {
+            group
+                .iter_mut()
+                .for_each(|block| self.add_round_key(block, 0));
+
+            for round in 1..self.rounds {
+                sub_bytes(group);
+                for block in group.iter_mut() {
+                    shift_rows(block);
+                    mix_columns(block);
+                    self.add_round_key(block, round);
+                }
+            }
+
+            sub_bytes(group);
+            for block in group.iter_mut() {
+                shift_rows(block);
+                self.add_round_key(block, self.rounds);
+            }
+        }
+    }
+
+    /// Decrypts independent blocks, `PARALLEL_BLOCKS` at a time
+    pub fn decrypt_blocks(&self, blocks: &mut [[u8; 16]]) {
+        for group in blocks.chunks_mut(Self::PARALLEL_BLOCKS) {
+            group
+                .iter_mut()
+                .for_each(|block| self.add_round_key(block, self.rounds));
+
+            for round in (1..self.rounds).rev() {
+                group.iter_mut().for_each(inv_shift_rows);
+                inv_sub_bytes(group);
+                for block in group.iter_mut() {
+                    self.add_round_key(block, round);
+                    inv_mix_columns(block);
+                }
+            }
+
+            group.iter_mut().for_each(inv_shift_rows);
+            inv_sub_bytes(group);
+            group
+                .iter_mut()
+                .for_each(|block| self.add_round_key(block, 0));
+        }
+    }
+
+    fn add_round_key(&self, block: &mut [u8; 16], round: usize) {
+        for (b, k) in block.iter_mut().zip(&self.round_keys[(16 * round)..]) {
+            *b ^= k;
+        }
+    }
+}
+
+impl Drop for Aes {
+    fn drop(&mut self) {
+        for byte in self.round_keys.iter_mut() {
+            // SAFETY: `byte` is a valid reference. The volatile write is not optimized out.
+            unsafe { core::ptr::write_volatile(byte, 0) };
+        }
+    }
+}
+
+fn sub_word(word: [u8; 4]) -> [u8; 4] {
+    let mut block = [[0; 16]];
+    block[0][..4].copy_from_slice(&word);
+    sub_bytes(&mut block);
+    block[0][..4].try_into().unwrap()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::crypto::tests::hex;
+    use alloc::{vec, vec::Vec};
+
+    /// Multiplication in GF(2^8), bit by bit
+    fn gf_mul(mut a: u8, mut b: u8) -> u8 {
+        let mut product = 0;
+        while b != 0 {
+            if b & 1 != 0 {
+                product ^= a;
+            }
+            a = xtime(a);
+            b >>= 1;
+        }
+        product
+    }
+
+    /// The S-box of FIPS 197 §5.1.1, computed byte by byte
+    fn reference_sbox(byte: u8) -> u8 {
+        let inverse = (1..=255u8).find(|&i| gf_mul(i, byte) == 1).unwrap_or(0);
+        inverse
+            ^ inverse.rotate_left(1)
+            ^ inverse.rotate_left(2)
+            ^ inverse.rotate_left(3)
+            ^ inverse.rotate_left(4)
+            ^ 0x63
+    }
+
+    #[test]
+    fn sbox() {
+        let input: [[u8; 16]; 16] =
+            core::array::from_fn(|k| core::array::from_fn(|i| (16 * k + i) as u8));
+        let mut blocks = input;
+
+        for group in blocks.chunks_mut(Aes::PARALLEL_BLOCKS) {
+            sub_bytes(group);
+        }
+        for (input, output) in input.iter().flatten().zip(blocks.iter().flatten()) {
+            assert_eq!(*output, reference_sbox(*input), "S-box of {input:#04x}");
+        }
+        assert_eq!(blocks[5][3], 0xED);
+
+        for group in blocks.chunks_mut(Aes::PARALLEL_BLOCKS) {
+            inv_sub_bytes(group);
+        }
+        assert_eq!(blocks, input);
+    }
+
+    #[test]
+    fn key_expansion() {
+        // FIPS 197 appendix A.1, the last round key
+        let aes = Aes::new(&hex("2b7e151628aed2a6abf7158809cf4f3c")).unwrap();
+        assert_eq!(
+            aes.round_keys[160..176],
+            hex("d014f9a8c9ee2589e13f0cc8b6630ca6")
+        );
+    }
+
+    /// Checks a vector of FIPS 197, both ways
+    fn check(key: &str, plaintext: &str, ciphertext: &str) {
+        let aes = Aes::new(&hex(key)).unwrap();
+        let mut block: [u8; 16] = hex(plaintext).try_into().unwrap();
+
+        aes.encrypt_block(&mut block);
+        assert_eq!(block[..], hex(ciphertext));
+        aes.decrypt_block(&mut block);
+        assert_eq!(block[..], hex(plaintext));
+    }
+
+    #[test]
+    fn fips_197() {
+        // Appendix B
+        check(
+            "2b7e151628aed2a6abf7158809cf4f3c",
+            "3243f6a8885a308d313198a2e0370734",
+            "3925841d02dc09fbdc118597196a0b32",
+        );
+
+        // Appendix C.1, C.2 and C.3
+        let plaintext = "00112233445566778899aabbccddeeff";
+        check(
+            "000102030405060708090a0b0c0d0e0f",
+            plaintext,
+            "69c4e0d86a7b0430d8cdb78070b4c55a",
+        );
+        check(
+            "000102030405060708090a0b0c0d0e0f1011121314151617",
+            plaintext,
+            "dda97ca4864cdfe06eaf70a0ec0d7191",
+        );
+        check(
+            "000102030405060708090a0b0c0d0e0f101112131415161718191a1b1c1d1e1f",
+            plaintext,
+            "8ea2b7ca516745bfeafc49904b496089",
+        );
+    }
+
+    #[test]
+    fn several_blocks() {
+        let aes = Aes::new(&[7; 32]).unwrap();
+        let plaintext: Vec<[u8; 16]> = (0..11u8).map(|i| [i; 16]).collect();
+
+        // Like block by block, including a last group of 3 blocks
+        let mut blocks = plaintext.clone();
+        aes.encrypt_blocks(&mut blocks);
+        for (block, plain) in blocks.iter().zip(&plaintext) {
+            let mut expected = *plain;
+            aes.encrypt_block(&mut expected);
+            assert_eq!(*block, expected);
+        }
+
+        aes.decrypt_blocks(&mut blocks);
+        assert_eq!(blocks, plaintext);
+    }
+
+    #[test]
+    fn invalid_keys() {
+        for len in [0, 8, 15, 17, 20, 31, 33, 64] {
+            assert!(Aes::new(&vec![0; len]).is_none());
+        }
+    }
+}
@@ -0,0 +1,449 @@
+use super::blake2b::Blake2b;
+use alloc::vec::Vec;
+
+/// Argon2 version 1.3, the only one used today
+const VERSION: u32 = 0x13;
+
+/// Number of slices of each lane, the synchronization points between the lanes
+const SYNC_POINTS: usize = 4;
+
+/// A memory block of 1 KiB
+type Block = [u64; 128];
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Variant {
+    /// Data-dependent memory accesses, the fastest but open to side-channel attacks
+    Argon2d = 0,
+    /// Data-independent memory accesses
+    Argon2i = 1,
+    /// Argon2i for the first half of the first pass, then Argon2d. The recommended variant.
+    Argon2id = 2,
+}
+
+impl Variant {
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name {
+            "argon2d" => Some(Self::Argon2d),
+            "argon2i" => Some(Self::Argon2i),
+            "argon2id" => Some(Self::Argon2id),
+            _ => None,
+        }
+    }
+
+    pub const fn name(self) -> &'static str {
+        match self {
+            Self::Argon2d => "argon2d",
+            Self::Argon2i => "argon2i",
+            Self::Argon2id => "argon2id",
+        }
+    }
+}
+
+/// The costs of an Argon2 derivation
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Params {
+    pub variant: Variant,
+    /// Number of passes over the memory
+    pub iterations: u32,
+    /// Memory used, in KiB. Rounded down to a multiple of `4 * lanes`.
+    pub memory: u32,
+    /// Number of independent lanes of the memory. They are computed one after the other here,
+    /// but the result depends on their number.
+    pub lanes: u32,
+}
+
+impl Params {
+    pub fn is_valid(&self) -> bool {
+        self.iterations >= 1
+            && (1..(1 << 24)).contains(&self.lanes)
+            && self.memory as u64 >= 8 * self.lanes as u64
+    }
+
+    /// Fills `out` with the Argon2 hash of `password` and `salt`. Returns `false` if the
+    /// parameters are invalid, if `out` is shorter than 4 bytes, or if the memory can't be
+    /// allocated.
+    pub fn derive(&self, password: &[u8], salt: &[u8], out: &mut [u8]) -> bool {
+        self.derive_keyed(password, salt, &[], &[], out)
+    }
+
+    /// `derive` with the secret key and the associated data of RFC 9106, that LUKS doesn't use
+    fn derive_keyed(
+        &self,
+        password: &[u8],
+        salt: &[u8],
+        secret: &[u8],
+        associated_data: &[u8],
+        out: &mut [u8],
+    ) -> bool {
+        if !self.is_valid() || out.len() < 4 || u32::try_from(out.len()).is_err() {
+            return false;
+        }
+
+        let lanes = self.lanes as usize;
+        let segment_len = self.memory as usize / (SYNC_POINTS * lanes);
+        let lane_len = segment_len * SYNC_POINTS;
+
+        let mut memory: Vec<Block> = Vec::new();
+        if memory.try_reserve_exact(lanes * lane_len).is_err() {
+            return false;
+        }
+        memory.resize(lanes * lane_len, [0; 128]);
+
+        // ### INITIAL BLOCKS ###
+
+        let mut h0 = Blake2b::new(64);
+        for value in [
+            self.lanes,
+            out.len() as u32,
+            self.memory,
+            self.iterations,
+            VERSION,
+            self.variant as u32,
+        ] {
+            h0.update(&value.to_le_bytes());
+        }
+        for data in [password, salt, secret, associated_data] {
+            h0.update(&(data.len() as u32).to_le_bytes());
+            h0.update(data);
+        }
+        let h0 = h0.finalize();
+
+        let mut bytes = [0; 1024];
+        for lane in 0..lanes {
+            for i in 0..2 {
+                hash_long(
+                    &mut bytes,
+                    &[&h0, &(i as u32).to_le_bytes(), &(lane as u32).to_le_bytes()],
+                );
+                memory[lane * lane_len + i] = block_from_bytes(&bytes);
+            }
+        }
+
+        // ### PASSES ###
+
+        let instance = Instance {
+            params: self,
+            lanes,
+            segment_len,
+            lane_len,
+        };
+
+        for pass in 0..self.iterations as usize {
+            for slice in 0..SYNC_POINTS {
+                // The segments of a slice don't reference each other, so computing the lanes one
+                // after the other gives the same result as in parallel
+                for lane in 0..lanes {
+                    instance.fill_segment(&mut memory, pass, slice, lane);
+                }
+            }
+        }
+
+        // ### FINALIZATION ###
+
+        let mut last = memory[lane_len - 1];
+        for lane in 1..lanes {
+            for (w, v) in last.iter_mut().zip(&memory[lane * lane_len + lane_len - 1]) {
+                *w ^= v;
+            }
+        }
+
+        for (chunk, w) in bytes.chunks_mut(8).zip(&last) {
+            chunk.copy_from_slice(&w.to_le_bytes());
+        }
+        hash_long(out, &[&bytes]);
+
+        bytes.fill(0);
+        memory.iter_mut().for_each(|block| block.fill(0));
+
+        true
+    }
+}
+
+struct Instance<'a> {
+    params: &'a Params,
+    lanes: usize,
+    segment_len: usize,
+    lane_len: usize,
+}
+
+impl Instance<'_> {
+    fn fill_segment(&self, memory: &mut [Block], pass: usize, slice: usize, lane: usize) {
+        let data_independent = match self.params.variant {
+            Variant::Argon2d => false,
+            Variant::Argon2i => true,
+            Variant::Argon2id => pass == 0 && slice < SYNC_POINTS / 2,
+        };
+
+        // The pseudo-random indexes of Argon2i come from the compression of a counter
+        let mut input = [0u64; 128];
+        let mut addresses = [0u64; 128];
+        if data_independent {
+            input[..6].copy_from_slice(&[
+                pass as u64,
+                lane as u64,
+                slice as u64,
+                memory.len() as u64,
+                self.params.iterations as u64,
+                self.params.variant as u64,
+            ]);
+        }
+        let mut next_addresses = |addresses: &mut Block| {
+            input[6] += 1;
+            let zero = [0; 128];
+            compress(&zero, &input, addresses, false);
+            let first = *addresses;
+            compress(&zero, &first, addresses, false);
+        };
+
+        // The two first blocks of each lane are already computed
+        let start = if pass == 0 && slice == 0 {
+            if data_independent {
+                next_addresses(&mut addresses);
+            }
+            2
+        } else {
+            0
+        };
+
+        for index in start..self.segment_len {
+            let current = lane * self.lane_len + slice * self.segment_len + index;
+            let previous = if current.is_multiple_of(self.lane_len) {
+                current + self.lane_len - 1
+            } else {
+                current - 1
+            };
+
+            let pseudo_rand = if data_independent {
+                if index % 128 == 0 {
+                    next_addresses(&mut addresses);
+                }
+                addresses[index % 128]
+            } else {
+                memory[previous][0]
+            };
+
+            let ref_lane = if pass == 0 && slice == 0 {
+                lane
+            } else {
+                (pseudo_rand >> 32) as usize % self.lanes
+            };
+            let ref_index = self.reference_index(pass, slice, index, pseudo_rand, ref_lane == lane);
+            let reference = ref_lane * self.lane_len + ref_index;
+
+            let (prev_block, ref_block) = (memory[previous], memory[reference]);
+            // From the version 1.3, the later passes XOR the new block with the old one
+            compress(&prev_block, &ref_block, &mut memory[current], pass != 0);
+        }
+    }
+
+    /// Index, in its lane, of the block referenced by the block `index` of the segment
+    fn reference_index(
+        &self,
+        pass: usize,
+        slice: usize,
+        index: usize,
+        pseudo_rand: u64,
+        same_lane: bool,
+    ) -> usize {
+        // The blocks that can be referenced: the finished segments, and in the same lane the
+        // blocks of the current segment before the previous one
+        let area_size = if pass == 0 {
+            if slice == 0 || same_lane {
+                slice * self.segment_len + index - 1
+            } else {
+                slice * self.segment_len - (index == 0) as usize
+            }
+        } else if same_lane {
+            self.lane_len - self.segment_len + index - 1
+        } else {
+            self.lane_len - self.segment_len - (index == 0) as usize
+        } as u64;
+
+        // Non-uniform mapping, favoring the most recent blocks
+        let j1 = pseudo_rand & 0xFFFFFFFF;
+        let x = (j1 * j1) >> 32;
+        let relative = area_size - 1 - ((area_size * x) >> 32);
+
+        let start = if pass != 0 && slice != SYNC_POINTS - 1 {
+            (slice + 1) * self.segment_len
+        } else {
+            0
+        };
+
+        (start + relative as usize) % self.lane_len
+    }
+}
+
+/// The compression function G: `out = P(x ^ y) ^ x ^ y`, XORed with the old `out` if `xor`
+fn compress(x: &Block, y: &Block, out: &mut Block, xor: bool) {
+    let mut r = [0u64; 128];
+    for i in 0..128 {
+        r[i] = x[i] ^ y[i];
+    }
+
+    let mut q = r;
+    for i in 0..8 {
+        permute(&mut q, core::array::from_fn(|j| 16 * i + j));
+    }
+    for i in 0..8 {
+        permute(
+            &mut q,
+            core::array::from_fn(|j| 2 * i + 16 * (j / 2) + j % 2),
+        );
+    }
+
+    for i in 0..128 {
+        if xor {
+            out[i] ^= r[i] ^ q[i];
+        } else {
+            out[i] = r[i] ^ q[i];
+        }
+    }
+}
+
+/// The BLAKE2b round without message, on the words `indexes` of the block
+fn permute(block: &mut Block, indexes: [usize; 16]) {
+    let mut v: [u64; 16] = core::array::from_fn(|i| block[indexes[i]]);
+
+    for [a, b, c, d] in [
+        [0, 4, 8, 12],
+        [1, 5, 9, 13],
+        [2, 6, 10, 14],
+        [3, 7, 11, 15],
+        [0, 5, 10, 15],
+        [1, 6, 11, 12],
+        [2, 7, 8, 13],
+        [3, 4, 9, 14],
+    ] {
+        v[a] = blamka(v[a], v[b]);
+        v[d] = (v[d] ^ v[a]).rotate_right(32);
+        v[c] = blamka(v[c], v[d]);
+        v[b] = (v[b] ^ v[c]).rotate_right(24);
+        v[a] = blamka(v[a], v[b]);
+        v[d] = (v[d] ^ v[a]).rotate_right(16);
+        v[c] = blamka(v[c], v[d]);
+        v[b] = (v[b] ^ v[c]).rotate_right(63);
+    }
+
+    for (i, &index) in indexes.iter().enumerate() {
+        block[index] = v[i];
+    }
+}
+
+/// The addition of BLAKE2b, with a multiplication of the low halves to add latency
+fn blamka(x: u64, y: u64) -> u64 {
+    let product = (x & 0xFFFFFFFF) * (y & 0xFFFFFFFF);
+    x.wrapping_add(y).wrapping_add(product.wrapping_mul(2))
+}
+
+/// The variable-length hash H' of Argon2, over the concatenation of `parts`
+fn hash_long(out: &mut [u8], parts: &[&[u8]]) {
+    let mut hasher = Blake2b::new(out.len().min(64));
+    hasher.update(&(out.len() as u32).to_le_bytes());
+    for part in parts {
+        hasher.update(part);
+    }
+    let mut v = hasher.finalize();
+
+    // Longer outputs are made of the first half of each hash of a chain, the last one entirely
+    let mut pos = 0;
+    while out.len() - pos > 64 {
+        out[pos..(pos + 32)].copy_from_slice(&v[..32]);
+        pos += 32;
+        v = Blake2b::digest((out.len() - pos).min(64), &v);
+    }
+
+    out[pos..].copy_from_slice(&v);
+}
+
+fn block_from_bytes(bytes: &[u8; 1024]) -> Block {
+    core::array::from_fn(|i| u64::from_le_bytes(bytes[(8 * i)..(8 * i + 8)].try_into().unwrap()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::crypto::tests::hex;
+    use alloc::vec;
+
+    #[test]
+    fn rfc_9106() {
+        // Section 5: 32 KiB, 3 passes, 4 lanes, with a secret and associated data
+        for (variant, tag) in [
+            (
+                Variant::Argon2d,
+                "512b391b6f1162975371d30919734294f868e3be3984f3c1a13a4db9fabe4acb",
+            ),
+            (
+                Variant::Argon2i,
+                "c814d9d1dc7f37aa13f0d77f2494bda1c8de6b016dd388d29952a4c4672b6ce8",
+            ),
+            (
+                Variant::Argon2id,
+                "0d640df58d78766c08c037a34a8b53c9d01ef0452d75b65eb52520e96b01e659",
+            ),
+        ] {
+            let params = Params {
+                variant,
+                iterations: 3,
+                memory: 32,
+                lanes: 4,
+            };
+            let mut out = [0; 32];
+            assert!(params.derive_keyed(&[1; 32], &[2; 16], &[3; 8], &[4; 12], &mut out));
+            assert_eq!(out[..], hex(tag), "{}", variant.name());
+        }
+    }
+
+    #[test]
+    fn outputs() {
+        let params = Params {
+            variant: Variant::Argon2id,
+            iterations: 1,
+            memory: 64,
+            lanes: 2,
+        };
+
+        // The output length is hashed, so a shorter output isn't a prefix of a longer one
+        let mut short = [0; 32];
+        let mut long = vec![0; 100];
+        assert!(params.derive(b"password", b"somesalt", &mut short));
+        assert!(params.derive(b"password", b"somesalt", &mut long));
+        assert_ne!(short[..], long[..32]);
+
+        let mut again = vec![0; 100];
+        assert!(params.derive(b"password", b"somesalt", &mut again));
+        assert_eq!(long, again);
+        assert!(params.derive(b"password", b"othersalt", &mut again));
+        assert_ne!(long, again);
+    }
+
+    #[test]
+    fn invalid_params() {
+        let valid = Params {
+            variant: Variant::Argon2i,
+            iterations: 1,
+            memory: 16,
+            lanes: 2,
+        };
+        assert!(valid.is_valid());
+
+        for params in [
+            Params {
+                iterations: 0,
+                ..valid
+            },
+            Params { lanes: 0, ..valid },
+            Params {
+                memory: 15,
+                ..valid
+            },
+        ] {
+            assert!(!params.is_valid());
+            assert!(!params.derive(b"password", b"salt", &mut [0; 32]));
+        }
+
+        // The output must be 4 bytes at least
+        assert!(!valid.derive(b"password", b"salt", &mut [0; 3]));
+    }
+}
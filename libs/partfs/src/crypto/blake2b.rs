@@ -0,0 +1,207 @@
+use super::sha2::IV512;
+use alloc::vec::Vec;
+
+/// Message word permutations of the rounds
+const SIGMA: [[usize; 16]; 10] = [
+    [0, 1, 2, 3, 4, 5, 6, 7, 8, 9, 10, 11, 12, 13, 14, 15],
+    [14, 10, 4, 8, 9, 15, 13, 6, 1, 12, 0, 2, 11, 7, 5, 3],
+    [11, 8, 12, 0, 5, 2, 15, 13, 10, 14, 3, 6, 7, 1, 9, 4],
+    [7, 9, 3, 1, 13, 12, 11, 14, 2, 6, 5, 10, 4, 0, 15, 8],
+    [9, 0, 5, 7, 2, 4, 10, 15, 14, 1, 11, 12, 6, 8, 3, 13],
+    [2, 12, 6, 10, 0, 11, 8, 3, 4, 13, 7, 5, 15, 14, 1, 9],
+    [12, 5, 1, 15, 14, 13, 4, 10, 0, 7, 6, 3, 9, 2, 8, 11],
+    [13, 11, 7, 14, 12, 1, 3, 9, 5, 0, 15, 4, 8, 6, 2, 10],
+    [6, 15, 14, 9, 11, 3, 0, 8, 12, 2, 13, 7, 1, 4, 10, 5],
+    [10, 2, 8, 4, 7, 6, 1, 5, 15, 11, 9, 14, 3, 12, 13, 0],
+];
+
+/// BLAKE2b without key, with a digest of 1 to 64 bytes
+#[derive(Clone)]
+pub struct Blake2b {
+    state: [u64; 8],
+    buffer: [u8; 128],
+    buffer_len: usize,
+    /// Number of bytes compressed
+    counter: u128,
+    output_size: usize,
+}
+
+impl Blake2b {
+    /// # Panics
+    /// If `output_size` is not in [1, 64].
+    pub fn new(output_size: usize) -> Self {
+        assert!(
+            (1..=64).contains(&output_size),
+            "invalid BLAKE2b output size"
+        );
+
+        let mut state = IV512;
+        state[0] ^= 0x01010000 ^ output_size as u64;
+
+        Self {
+            state,
+            buffer: [0; 128],
+            buffer_len: 0,
+            counter: 0,
+            output_size,
+        }
+    }
+
+    pub fn update(&mut self, mut data: &[u8]) {
+        while !data.is_empty() {
+            // The last block is compressed differently, so a full buffer is only compressed once
+            // more data comes
+            if self.buffer_len == 128 {
+                self.counter += 128;
+                self.compress(false);
+                self.buffer_len = 0;
+            }
+
+            let count = (128 - self.buffer_len).min(data.len());
+            self.buffer[self.buffer_len..(self.buffer_len + count)].copy_from_slice(&data[..count]);
+            self.buffer_len += count;
+            data = &data[count..];
+        }
+    }
+
+    pub fn finalize(mut self) -> Vec<u8> {
+        self.counter += self.buffer_len as u128;
+        self.buffer[self.buffer_len..].fill(0);
+        self.compress(true);
+
+        self.state
+            .iter()
+            .flat_map(|w| w.to_le_bytes())
+            .take(self.output_size)
+            .collect()
+    }
+
+    /// Digest of `data`, of `output_size` bytes
+    pub fn digest(output_size: usize, data: &[u8]) -> Vec<u8> {
+        let mut hasher = Self::new(output_size);
+        hasher.update(data);
+        hasher.finalize()
+    }
+
+    fn compress(&mut self, last: bool) {
+        let mut m = [0u64; 16];
+        for (i, word) in self.buffer.chunks(8).enumerate() {
+            m[i] = u64::from_le_bytes(word.try_into().unwrap());
+        }
+
+        let mut v = [0u64; 16];
+        v[..8].copy_from_slice(&self.state);
+        v[8..].copy_from_slice(&IV512);
+        v[12] ^= self.counter as u64;
+        v[13] ^= (self.counter >> 64) as u64;
+        if last {
+            v[14] = !v[14];
+        }
+
+        for round in 0..12 {
+            let s = &SIGMA[round % 10];
+
+            g(&mut v, [0, 4, 8, 12], m[s[0]], m[s[1]]);
+            g(&mut v, [1, 5, 9, 13], m[s[2]], m[s[3]]);
+            g(&mut v, [2, 6, 10, 14], m[s[4]], m[s[5]]);
+            g(&mut v, [3, 7, 11, 15], m[s[6]], m[s[7]]);
+            g(&mut v, [0, 5, 10, 15], m[s[8]], m[s[9]]);
+            g(&mut v, [1, 6, 11, 12], m[s[10]], m[s[11]]);
+            g(&mut v, [2, 7, 8, 13], m[s[12]], m[s[13]]);
+            g(&mut v, [3, 4, 9, 14], m[s[14]], m[s[15]]);
+        }
+
+        for i in 0..8 {
+            self.state[i] ^= v[i] ^ v[i + 8];
+        }
+    }
+}
+
+/// The mixing function, on the words `v[a]`, `v[b]`, `v[c]` and `v[d]`
+fn g(v: &mut [u64; 16], [a, b, c, d]: [usize; 4], x: u64, y: u64) {
+    v[a] = v[a].wrapping_add(v[b]).wrapping_add(x);
+    v[d] = (v[d] ^ v[a]).rotate_right(32);
+    v[c] = v[c].wrapping_add(v[d]);
+    v[b] = (v[b] ^ v[c]).rotate_right(24);
+    v[a] = v[a].wrapping_add(v[b]).wrapping_add(y);
+    v[d] = (v[d] ^ v[a]).rotate_right(16);
+    v[c] = v[c].wrapping_add(v[d]);
+    v[b] = (v[b] ^ v[c]).rotate_right(63);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::crypto::tests::hex;
+
+    #[test]
+    fn rfc_7693() {
+        // Appendix A
+        assert_eq!(
+            Blake2b::digest(64, b"abc"),
+            hex(concat!(
+                "ba80a53f981c4d0d6a2797b69f12f6e94c212f14685ac4b74b12bb6fdbffa2d1",
+                "7d87c5392aab792dc252d5de4533cc9518d38aa8dbf1925ab92386edd4009923",
+            ))
+        );
+        assert_eq!(
+            Blake2b::digest(64, b""),
+            hex(concat!(
+                "786a02f742015903c6c6fd852552d272912f4740e15847618a86e217f71f5419",
+                "d25e1031afee585313896444934eb04b903a685b1448b755d56f701afe9be2ce",
+            ))
+        );
+    }
+
+    #[test]
+    fn output_sizes() {
+        // The output size is a parameter of the hash, not a truncation
+        assert_eq!(
+            Blake2b::digest(32, b"abc"),
+            hex("bddd813c634239723171ef3fee98579b94964e3bb1cb3e427262c8c068d52319")
+        );
+        assert_eq!(
+            Blake2b::digest(20, b"abc"),
+            hex("384264f676f39536840523f284921cdc68b6846b")
+        );
+    }
+
+    #[test]
+    fn block_boundaries() {
+        // The last block is compressed differently, even when it is full
+        let message: Vec<u8> = (0..1000).map(|i| (i % 251) as u8).collect();
+        let digests = [
+            (
+                128,
+                concat!(
+                    "2319e3789c47e2daa5fe807f61bec2a1a6537fa03f19ff32e87eecbfd64b7e0e",
+                    "8ccff439ac333b040f19b0c4ddd11a61e24ac1fe0f10a039806c5dcc0da3d115",
+                ),
+            ),
+            (
+                256,
+                concat!(
+                    "93463ac058b6163eb43be3f5bb32b28541498f4e3366f1effe253ad44e1e076e",
+                    "41c3616046027c82a7124f8f4746668ad10b12e8e25a95ac8f3151df01cd5a93",
+                ),
+            ),
+            (
+                1000,
+                concat!(
+                    "c11e1c0340bd7e5a1b275f1230c962fad215ecb1391486e74e31b960a2f29963",
+                    "81a5fad092da06841d5f26e38f6ecfeaf441acbcd1c2de61aef121e7927175f5",
+                ),
+            ),
+        ];
+
+        for (len, digest) in digests {
+            for piece in [1, 127, 128, 129, len] {
+                let mut hasher = Blake2b::new(64);
+                for chunk in message[..len].chunks(piece) {
+                    hasher.update(chunk);
+                }
+                assert_eq!(hasher.finalize(), hex(digest), "{len} bytes by {piece}");
+            }
+        }
+    }
+}
@@ -0,0 +1,200 @@
+use alloc::vec::Vec;
+
+/// AES block cipher (FIPS 197), bitsliced to run in constant time
+pub mod aes;
+/// Argon2 memory-hard key derivation (RFC 9106)
+pub mod argon2;
+/// BLAKE2b hash function (RFC 7693), used by Argon2
+pub mod blake2b;
+/// HMAC (RFC 2104) and the PBKDF2 key derivation (RFC 8018)
+pub mod pbkdf2;
+/// SHA-1 hash function (FIPS 180-4), still used by older LUKS headers
+pub mod sha1;
+/// SHA-256 and SHA-512 hash functions (FIPS 180-4)
+pub mod sha2;
+/// XTS mode of operation (IEEE 1619), for sector encryption
+pub mod xts;
+
+/// An incremental hash function
+pub trait Hasher: Clone {
+    /// Size of the blocks the input is processed by, in bytes
+    const BLOCK_SIZE: usize;
+    /// Size of the digest, in bytes
+    const OUTPUT_SIZE: usize;
+
+    fn new() -> Self;
+
+    fn update(&mut self, data: &[u8]);
+
+    fn finalize(self) -> Vec<u8>;
+
+    /// Digest of `data`
+    fn digest(data: &[u8]) -> Vec<u8> {
+        let mut hasher = Self::new();
+        hasher.update(data);
+        hasher.finalize()
+    }
+}
+
+/// The hash functions selectable at runtime, by the names used in the LUKS headers
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum HashAlgorithm {
+    Sha1,
+    Sha256,
+    Sha512,
+}
+
+impl HashAlgorithm {
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name.to_ascii_lowercase().as_str() {
+            "sha1" => Some(Self::Sha1),
+            "sha256" => Some(Self::Sha256),
+            "sha512" => Some(Self::Sha512),
+            _ => None,
+        }
+    }
+
+    pub const fn name(self) -> &'static str {
+        match self {
+            Self::Sha1 => "sha1",
+            Self::Sha256 => "sha256",
+            Self::Sha512 => "sha512",
+        }
+    }
+
+    pub const fn output_size(self) -> usize {
+        match self {
+            Self::Sha1 => sha1::Sha1::OUTPUT_SIZE,
+            Self::Sha256 => sha2::Sha256::OUTPUT_SIZE,
+            Self::Sha512 => sha2::Sha512::OUTPUT_SIZE,
+        }
+    }
+
+    /// Digest of the concatenation of `parts`
+    pub fn digest(self, parts: &[&[u8]]) -> Vec<u8> {
+        fn digest<H: Hasher>(parts: &[&[u8]]) -> Vec<u8> {
+            let mut hasher = H::new();
+            for part in parts {
+                hasher.update(part);
+            }
+            hasher.finalize()
+        }
+
+        match self {
+            Self::Sha1 => digest::<sha1::Sha1>(parts),
+            Self::Sha256 => digest::<sha2::Sha256>(parts),
+            Self::Sha512 => digest::<sha2::Sha512>(parts),
+        }
+    }
+
+    /// Fills `out` with PBKDF2-HMAC of `password` (see `pbkdf2::pbkdf2`)
+    pub fn pbkdf2(self, password: &[u8], salt: &[u8], iterations: u32, out: &mut [u8]) {
+        match self {
+            Self::Sha1 => pbkdf2::pbkdf2::<sha1::Sha1>(password, salt, iterations, out),
+            Self::Sha256 => pbkdf2::pbkdf2::<sha2::Sha256>(password, salt, iterations, out),
+            Self::Sha512 => pbkdf2::pbkdf2::<sha2::Sha512>(password, salt, iterations, out),
+        }
+    }
+}
+
+/// Input buffer of the Merkle–Damgård hashes, accumulating the data until a block is full
+#[derive(Clone)]
+struct BlockBuffer<const N: usize> {
+    data: [u8; N],
+    len: usize,
+}
+
+impl<const N: usize> BlockBuffer<N> {
+    const fn new() -> Self {
+        Self {
+            data: [0; N],
+            len: 0,
+        }
+    }
+
+    /// Appends `data`, calling `compress` on each block filled
+    fn update(&mut self, mut data: &[u8], mut compress: impl FnMut(&[u8; N])) {
+        while !data.is_empty() {
+            if self.len == 0 && data.len() >= N {
+                let (block, rest) = data.split_at(N);
+                compress(block.try_into().unwrap());
+                data = rest;
+                continue;
+            }
+
+            let count = (N - self.len).min(data.len());
+            self.data[self.len..(self.len + count)].copy_from_slice(&data[..count]);
+            self.len += count;
+            data = &data[count..];
+
+            if self.len == N {
+                compress(&self.data);
+                self.len = 0;
+            }
+        }
+    }
+
+    /// Pads the message with a 1 bit, zeroes, and its length in bits on the last `length_size`
+    /// bytes of the final block, big endian
+    fn pad(&mut self, bit_len: u128, length_size: usize, mut compress: impl FnMut(&[u8; N])) {
+        self.data[self.len] = 0x80;
+        self.data[(self.len + 1)..].fill(0);
+
+        if self.len + 1 > N - length_size {
+            compress(&self.data);
+            self.data.fill(0);
+        }
+
+        self.data[(N - length_size)..]
+            .copy_from_slice(&bit_len.to_be_bytes()[(16 - length_size)..]);
+        compress(&self.data);
+        self.len = 0;
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+
+    pub(crate) fn hex(s: &str) -> Vec<u8> {
+        (0..s.len())
+            .step_by(2)
+            .map(|i| u8::from_str_radix(&s[i..(i + 2)], 16).unwrap())
+            .collect()
+    }
+
+    /// Checks the digest of `message`, hashed at once then by pieces of several sizes
+    pub(crate) fn check_hasher<H: Hasher>(message: &[u8], digest: &str) {
+        assert_eq!(H::digest(message), hex(digest));
+
+        for piece in [1, 3, H::BLOCK_SIZE - 1, H::BLOCK_SIZE, H::BLOCK_SIZE + 1] {
+            let mut hasher = H::new();
+            for chunk in message.chunks(piece) {
+                hasher.update(chunk);
+            }
+            assert_eq!(hasher.finalize(), hex(digest), "pieces of {piece} bytes");
+        }
+    }
+
+    #[test]
+    fn hash_algorithms() {
+        for hash in [
+            HashAlgorithm::Sha1,
+            HashAlgorithm::Sha256,
+            HashAlgorithm::Sha512,
+        ] {
+            assert_eq!(HashAlgorithm::from_name(hash.name()), Some(hash));
+            assert_eq!(
+                HashAlgorithm::from_name(&hash.name().to_ascii_uppercase()),
+                Some(hash)
+            );
+
+            // The parts are concatenated
+            let digest = hash.digest(&[b"ab", b"", b"c"]);
+            assert_eq!(digest, hash.digest(&[b"abc"]));
+            assert_eq!(digest.len(), hash.output_size());
+        }
+
+        assert_eq!(HashAlgorithm::from_name("md5"), None);
+    }
+}
@@ -0,0 +1,186 @@
+use super::Hasher;
+use alloc::{vec, vec::Vec};
+
+/// HMAC keyed with a fixed key. The keyed state can be cloned to authenticate several messages
+/// without hashing the key again.
+#[derive(Clone)]
+pub struct Hmac<H: Hasher> {
+    inner: H,
+    outer: H,
+}
+
+impl<H: Hasher> Hmac<H> {
+    pub fn new(key: &[u8]) -> Self {
+        let mut block = vec![0; H::BLOCK_SIZE];
+        if key.len() > H::BLOCK_SIZE {
+            let digest = H::digest(key);
+            block[..digest.len()].copy_from_slice(&digest);
+        } else {
+            block[..key.len()].copy_from_slice(key);
+        }
+
+        let mut inner = H::new();
+        let mut outer = H::new();
+        inner.update(&block.iter().map(|b| b ^ 0x36).collect::<Vec<_>>());
+        outer.update(&block.iter().map(|b| b ^ 0x5C).collect::<Vec<_>>());
+        block.fill(0);
+
+        Self { inner, outer }
+    }
+
+    pub fn update(&mut self, data: &[u8]) {
+        self.inner.update(data);
+    }
+
+    pub fn finalize(self) -> Vec<u8> {
+        let mut outer = self.outer;
+        outer.update(&self.inner.finalize());
+        outer.finalize()
+    }
+}
+
+/// Fills `out` with PBKDF2-HMAC-`H` of `password` and `salt`, with `iterations` iterations (at
+/// least one is done)
+pub fn pbkdf2<H: Hasher>(password: &[u8], salt: &[u8], iterations: u32, out: &mut [u8]) {
+    let prf = Hmac::<H>::new(password);
+
+    for (i, chunk) in out.chunks_mut(H::OUTPUT_SIZE).enumerate() {
+        let mut mac = prf.clone();
+        mac.update(salt);
+        mac.update(&(i as u32 + 1).to_be_bytes());
+
+        let mut u = mac.finalize();
+        let mut t = u.clone();
+
+        for _ in 1..iterations {
+            let mut mac = prf.clone();
+            mac.update(&u);
+            u = mac.finalize();
+
+            for (t, u) in t.iter_mut().zip(&u) {
+                *t ^= u;
+            }
+        }
+
+        chunk.copy_from_slice(&t[..chunk.len()]);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::crypto::{
+        sha1::Sha1,
+        sha2::{Sha256, Sha512},
+        tests::hex,
+    };
+
+    fn mac<H: Hasher>(key: &[u8], data: &[u8]) -> Vec<u8> {
+        let mut mac = Hmac::<H>::new(key);
+        mac.update(data);
+        mac.finalize()
+    }
+
+    #[test]
+    fn hmac() {
+        // RFC 4231, test cases 1, 2 and 6 (a key longer than a block)
+        let cases: [(&[u8], &[u8], &str, &str); 3] = [
+            (
+                &[0x0B; 20],
+                b"Hi There",
+                "b0344c61d8db38535ca8afceaf0bf12b881dc200c9833da726e9376c2e32cff7",
+                concat!(
+                    "87aa7cdea5ef619d4ff0b4241a1d6cb02379f4e2ce4ec2787ad0b30545e17cde",
+                    "daa833b7d6b8a702038b274eaea3f4e4be9d914eeb61f1702e696c203a126854",
+                ),
+            ),
+            (
+                b"Jefe",
+                b"what do ya want for nothing?",
+                "5bdcc146bf60754e6a042426089575c75a003f089d2739839dec58b964ec3843",
+                concat!(
+                    "164b7a7bfcf819e2e395fbe73b56e0a387bd64222e831fd610270cd7ea250554",
+                    "9758bf75c05a994a6d034f65f8f0e6fdcaeab1a34d4a6b4b636e070a38bce737",
+                ),
+            ),
+            (
+                &[0xAA; 131],
+                b"Test Using Larger Than Block-Size Key - Hash Key First",
+                "60e431591ee0b67f0d8a26aacbf5b77f8e0bc6213728c5140546040f0ee37f54",
+                concat!(
+                    "80b24263c7c1a3ebb71493c1dd7be8b49b46d1f41b4aeec1121b013783f8f352",
+                    "6b56d037e05f2598bd0fd2215d6a1e5295e64f73f63f0aec8b915a985d786598",
+                ),
+            ),
+        ];
+
+        for (key, data, sha256, sha512) in cases {
+            assert_eq!(mac::<Sha256>(key, data), hex(sha256));
+            assert_eq!(mac::<Sha512>(key, data), hex(sha512));
+        }
+    }
+
+    fn check<H: Hasher>(password: &[u8], salt: &[u8], iterations: u32, expected: &str) {
+        let mut out = vec![0; expected.len() / 2];
+        pbkdf2::<H>(password, salt, iterations, &mut out);
+        assert_eq!(out, hex(expected));
+    }
+
+    #[test]
+    fn rfc_6070() {
+        // PBKDF2-HMAC-SHA1
+        check::<Sha1>(
+            b"password",
+            b"salt",
+            1,
+            "0c60c80f961f0e71f3a9b524af6012062fe037a6",
+        );
+        check::<Sha1>(
+            b"password",
+            b"salt",
+            2,
+            "ea6c014dc72d6f8ccd1ed92ace1d41f0d8de8957",
+        );
+        check::<Sha1>(
+            b"password",
+            b"salt",
+            4096,
+            "4b007901b765489abead49d926f721d065a429c1",
+        );
+        check::<Sha1>(
+            b"passwordPASSWORDpassword",
+            b"saltSALTsaltSALTsaltSALTsaltSALTsalt",
+            4096,
+            "3d2eec4fe41c849b80c8d83662c0e44a8b291a964cf2f07038",
+        );
+        check::<Sha1>(
+            b"pass\0word",
+            b"sa\0lt",
+            4096,
+            "56fa6aa75548099dcc37d7f03425e0c3",
+        );
+    }
+
+    #[test]
+    fn rfc_7914() {
+        // Section 11, PBKDF2-HMAC-SHA256
+        check::<Sha256>(
+            b"passwd",
+            b"salt",
+            1,
+            concat!(
+                "55ac046e56e3089fec1691c22544b605f94185216dde0465e68b9d57c20dacbc",
+                "49ca9cccf179b645991664b39d77ef317c71b845b1e30bd509112041d3a19783",
+            ),
+        );
+        check::<Sha256>(
+            b"Password",
+            b"NaCl",
+            80000,
+            concat!(
+                "4ddcd8f60b98be21830cee5ef22701f9641a4418d04c0414aeff08876b34ab56",
+                "a1d425a1225833549adb841b51c9b3176a272bdebba1d078478f62b397f33c8d",
+            ),
+        );
+    }
+}
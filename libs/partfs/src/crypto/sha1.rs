@@ -0,0 +1,95 @@
+use super::{BlockBuffer, Hasher};
+use alloc::vec::Vec;
+
+/// SHA-1. It is broken for collisions, but still fine in HMAC and PBKDF2, where the LUKS1
+/// containers created by older tools use it.
+#[derive(Clone)]
+pub struct Sha1 {
+    state: [u32; 5],
+    buffer: BlockBuffer<64>,
+    /// Length of the message, in bytes
+    length: u64,
+}
+
+impl Hasher for Sha1 {
+    const BLOCK_SIZE: usize = 64;
+    const OUTPUT_SIZE: usize = 20;
+
+    fn new() -> Self {
+        Self {
+            state: [0x67452301, 0xEFCDAB89, 0x98BADCFE, 0x10325476, 0xC3D2E1F0],
+            buffer: BlockBuffer::new(),
+            length: 0,
+        }
+    }
+
+    fn update(&mut self, data: &[u8]) {
+        self.length = self.length.wrapping_add(data.len() as u64);
+        let state = &mut self.state;
+        self.buffer.update(data, |block| compress(state, block));
+    }
+
+    fn finalize(mut self) -> Vec<u8> {
+        let state = &mut self.state;
+        self.buffer
+            .pad(self.length as u128 * 8, 8, |block| compress(state, block));
+        self.state.iter().flat_map(|w| w.to_be_bytes()).collect()
+    }
+}
+
+fn compress(state: &mut [u32; 5], block: &[u8; 64]) {
+    let mut w = [0u32; 80];
+    for (i, word) in block.chunks(4).enumerate() {
+        w[i] = u32::from_be_bytes(word.try_into().unwrap());
+    }
+    for i in 16..80 {
+        w[i] = (w[i - 3] ^ w[i - 8] ^ w[i - 14] ^ w[i - 16]).rotate_left(1);
+    }
+
+    let [mut a, mut b, mut c, mut d, mut e] = *state;
+
+    for (i, &w) in w.iter().enumerate() {
+        let (f, k) = match i {
+            0..20 => ((b & c) | (!b & d), 0x5A827999),
+            20..40 => (b ^ c ^ d, 0x6ED9EBA1),
+            40..60 => ((b & c) | (b & d) | (c & d), 0x8F1BBCDC),
+            _ => (b ^ c ^ d, 0xCA62C1D6),
+        };
+
+        let temp = a
+            .rotate_left(5)
+            .wrapping_add(f)
+            .wrapping_add(e)
+            .wrapping_add(k)
+            .wrapping_add(w);
+        e = d;
+        d = c;
+        c = b.rotate_left(30);
+        b = a;
+        a = temp;
+    }
+
+    for (s, v) in state.iter_mut().zip([a, b, c, d, e]) {
+        *s = s.wrapping_add(v);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::crypto::tests::{check_hasher, hex};
+
+    #[test]
+    fn fips_180_4() {
+        check_hasher::<Sha1>(b"abc", "a9993e364706816aba3e25717850c26c9cd0d89d");
+        check_hasher::<Sha1>(
+            b"abcdbcdecdefdefgefghfghighijhijkijkljklmklmnlmnomnopnopq",
+            "84983e441c3bd26ebaae4aa1f95129e5e54670f1",
+        );
+        check_hasher::<Sha1>(b"", "da39a3ee5e6b4b0d3255bfef95601890afd80709");
+        assert_eq!(
+            Sha1::digest(&[b'a'; 1_000_000]),
+            hex("34aa973cd4c4daa4f61eeb2bdbad27316534016f")
+        );
+    }
+}
@@ -0,0 +1,252 @@
+use super::aes::Aes;
+
+/// AES in XTS mode: each data unit (a sector) is encrypted with a tweak derived from its number,
+/// so that identical sectors at different places don't give identical ciphertexts. The key is
+/// made of the data key followed by the tweak key. Neither `Aes` nor the tweak computation
+/// branch on or index memory by secret data.
+pub struct Xts {
+    data: Aes,
+    tweak: Aes,
+}
+
+impl Xts {
+    /// Returns `None` if `key` is not 32 (AES-128) or 64 (AES-256) bytes long
+    pub fn new(key: &[u8]) -> Option<Self> {
+        if key.len() != 32 && key.len() != 64 {
+            return None;
+        }
+
+        let (data, tweak) = key.split_at(key.len() / 2);
+        Some(Self {
+            data: Aes::new(data)?,
+            tweak: Aes::new(tweak)?,
+        })
+    }
+
+    /// Encrypts the data unit number `unit` in place. It must be at least 16 bytes long, the last
+    /// block is encrypted with ciphertext stealing if it is partial.
+    pub fn encrypt_unit(&self, unit: u128, data: &mut [u8]) {
+        assert!(data.len() >= Aes::BLOCK_SIZE, "XTS data unit too short");
+
+        let mut tweak = unit.to_le_bytes();
+        self.tweak.encrypt_block(&mut tweak);
+
+        let full_blocks = data.len() / 16;
+        let partial = data.len() % 16;
+
+        xor_encrypt_blocks(
+            &self.data,
+            &mut data.as_chunks_mut().0[..full_blocks],
+            &mut tweak,
+            true,
+        );
+
+        if partial != 0 {
+            // The partial block takes the head of the last ciphertext, and the last full block
+            // is the encryption of the partial block padded with its tail
+            let last = 16 * full_blocks;
+            let mut block = [0; 16];
+            block[..partial].copy_from_slice(&data[last..]);
+            block[partial..].copy_from_slice(&data[(last - 16 + partial)..last]);
+
+            let (head, tail) = data.split_at_mut(last);
+            tail.copy_from_slice(&head[(last - 16)..(last - 16 + partial)]);
+
+            xor_encrypt(&self.data, &mut block, &tweak, true);
+            data[(last - 16)..last].copy_from_slice(&block);
+        }
+    }
+
+    /// Decrypts the data unit number `unit` in place. It must be at least 16 bytes long.
+    pub fn decrypt_unit(&self, unit: u128, data: &mut [u8]) {
+        assert!(data.len() >= Aes::BLOCK_SIZE, "XTS data unit too short");
+
+        let mut tweak = unit.to_le_bytes();
+        self.tweak.encrypt_block(&mut tweak);
+
+        let full_blocks = data.len() / 16;
+        let partial = data.len() % 16;
+
+        // With ciphertext stealing, the last full block is decrypted with the next tweak
+        let normal_blocks = if partial != 0 {
+            full_blocks - 1
+        } else {
+            full_blocks
+        };
+
+        xor_encrypt_blocks(
+            &self.data,
+            &mut data.as_chunks_mut().0[..normal_blocks],
+            &mut tweak,
+            false,
+        );
+
+        if partial != 0 {
+            let last = 16 * full_blocks;
+            let mut next_tweak = tweak;
+            mul_alpha(&mut next_tweak);
+
+            let mut block: [u8; 16] = data[(last - 16)..last].try_into().unwrap();
+            xor_encrypt(&self.data, &mut block, &next_tweak, false);
+
+            let mut stolen = [0; 16];
+            stolen[..partial].copy_from_slice(&data[last..]);
+            stolen[partial..].copy_from_slice(&block[partial..]);
+            data[last..].copy_from_slice(&block[..partial]);
+
+            xor_encrypt(&self.data, &mut stolen, &tweak, false);
+            data[(last - 16)..last].copy_from_slice(&stolen);
+        }
+    }
+}
+
+/// `block = E(block ^ tweak) ^ tweak`, or with the decryption
+fn xor_encrypt(aes: &Aes, block: &mut [u8; 16], tweak: &[u8; 16], encrypt: bool) {
+    for (b, t) in block.iter_mut().zip(tweak) {
+        *b ^= t;
+    }
+
+    if encrypt {
+        aes.encrypt_block(block);
+    } else {
+        aes.decrypt_block(block);
+    }
+
+    for (b, t) in block.iter_mut().zip(tweak) {
+        *b ^= t;
+    }
+}
+
+/// `xor_encrypt` of consecutive blocks, the tweak being multiplied by alpha after each one. The
+/// blocks are encrypted by groups, which costs as much as a single block with `Aes`.
+fn xor_encrypt_blocks(aes: &Aes, blocks: &mut [[u8; 16]], tweak: &mut [u8; 16], encrypt: bool) {
+    for group in blocks.chunks_mut(Aes::PARALLEL_BLOCKS) {
+        let mut tweaks = [[0; 16]; Aes::PARALLEL_BLOCKS];
+        for (block, t) in group.iter_mut().zip(&mut tweaks) {
+            *t = *tweak;
+            block.iter_mut().zip(t.iter()).for_each(|(b, t)| *b ^= t);
+            mul_alpha(tweak);
+        }
+
+        if encrypt {
+            aes.encrypt_blocks(group);
+        } else {
+            aes.decrypt_blocks(group);
+        }
+
+        for (block, t) in group.iter_mut().zip(&tweaks) {
+            block.iter_mut().zip(t).for_each(|(b, t)| *b ^= t);
+        }
+    }
+}
+
+/// Multiplies the tweak by the primitive element of GF(2^128), in little endian
+fn mul_alpha(tweak: &mut [u8; 16]) {
+    let value = u128::from_le_bytes(*tweak);
+    // Without branch, the tweak is secret
+    let carry = value >> 127;
+    let value = (value << 1) ^ (0x87 & 0u128.wrapping_sub(carry));
+
+    *tweak = value.to_le_bytes();
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::crypto::tests::hex;
+    use alloc::vec::Vec;
+
+    /// Checks a vector of IEEE 1619, both ways
+    fn check(key: &str, unit: u128, plaintext: &[u8], ciphertext: &str) {
+        let xts = Xts::new(&hex(key)).unwrap();
+        let mut data = plaintext.to_vec();
+
+        xts.encrypt_unit(unit, &mut data);
+        assert_eq!(data, hex(ciphertext));
+        xts.decrypt_unit(unit, &mut data);
+        assert_eq!(data, plaintext);
+    }
+
+    #[test]
+    fn xts_aes_128() {
+        // Vectors 1 and 2
+        check(
+            concat!(
+                "00000000000000000000000000000000",
+                "00000000000000000000000000000000",
+            ),
+            0,
+            &[0; 32],
+            "917cf69ebd68b2ec9b9fe9a3eadda692cd43d2f59598ed858c02c2652fbf922e",
+        );
+        check(
+            concat!(
+                "11111111111111111111111111111111",
+                "22222222222222222222222222222222",
+            ),
+            0x3333333333,
+            &[0x44; 32],
+            "c454185e6a16936e39334038acef838bfb186fff7480adc4289382ecd6d394f0",
+        );
+    }
+
+    #[test]
+    fn xts_aes_256() {
+        // Vector 10
+        let plaintext: Vec<u8> = (0..512).map(|i| i as u8).collect();
+        check(
+            concat!(
+                "2718281828459045235360287471352662497757247093699959574966967627",
+                "3141592653589793238462643383279502884197169399375105820974944592",
+            ),
+            0xff,
+            &plaintext,
+            concat!(
+                "1c3b3a102f770386e4836c99e370cf9bea00803f5e482357a4ae12d414a3e63b",
+                "5d31e276f8fe4a8d66b317f9ac683f44680a86ac35adfc3345befecb4bb188fd",
+                "5776926c49a3095eb108fd1098baec70aaa66999a72a82f27d848b21d4a741b0",
+                "c5cd4d5fff9dac89aeba122961d03a757123e9870f8acf1000020887891429ca",
+                "2a3e7a7d7df7b10355165c8b9a6d0a7de8b062c4500dc4cd120c0f7418dae3d0",
+                "b5781c34803fa75421c790dfe1de1834f280d7667b327f6c8cd7557e12ac3a0f",
+                "93ec05c52e0493ef31a12d3d9260f79a289d6a379bc70c50841473d1a8cc81ec",
+                "583e9645e07b8d9670655ba5bbcfecc6dc3966380ad8fecb17b6ba02469a020a",
+                "84e18e8f84252070c13e9f1f289be54fbc481457778f616015e1327a02b140f1",
+                "505eb309326d68378f8374595c849d84f4c333ec4423885143cb47bd71c5edae",
+                "9be69a2ffeceb1bec9de244fbe15992b11b77c040f12bd8f6a975a44a0f90c29",
+                "a9abc3d4d893927284c58754cce294529f8614dcd2aba991925fedc4ae74ffac",
+                "6e333b93eb4aff0479da9a410e4450e0dd7ae4c6e2910900575da401fc07059f",
+                "645e8b7e9bfdef33943054ff84011493c27b3429eaedb4ed5376441a77ed4385",
+                "1ad77f16f541dfd269d50d6a5f14fb0aab1cbb4c1550be97f7ab4066193c4caa",
+                "773dad38014bd2092fa755c824bb5e54c4f36ffda9fcea70b9c6e693e148c151",
+            ),
+        );
+    }
+
+    #[test]
+    fn ciphertext_stealing() {
+        // Vectors 15 and 18
+        let key = concat!(
+            "fffefdfcfbfaf9f8f7f6f5f4f3f2f1f0",
+            "bfbebdbcbbbab9b8b7b6b5b4b3b2b1b0",
+        );
+        let plaintext: Vec<u8> = (0..20).collect();
+        check(
+            key,
+            0x123456789a,
+            &plaintext[..17],
+            "6c1625db4671522d3d7599601de7ca09ed",
+        );
+        check(
+            key,
+            0x123456789a,
+            &plaintext,
+            "9d84c813f719aa2c7be3f66171c7c5c2edbf9dac",
+        );
+    }
+
+    #[test]
+    fn invalid_keys() {
+        assert!(Xts::new(&[0; 16]).is_none());
+        assert!(Xts::new(&[0; 48]).is_none());
+    }
+}
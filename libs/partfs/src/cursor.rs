@@ -0,0 +1,259 @@
+use crate::{Disk, DiskErr, DiskErrKind, bytedisk::ByteDisk};
+use std::io::{self, Read, Seek, SeekFrom, Write};
+
+/// Implements `Read`, `Write` and `Seek` over any `Disk`, so that disks and partitions can be used
+/// with the standard library I/O tools (`io::copy`, hashers, parsers, ...). The cursor behaves
+/// like a block device: reads stop at the end of the disk, writes crossing the end are cut, and
+/// writes starting at or past the end fail with `io::ErrorKind::StorageFull`.
+pub struct DiskCursor<D: Disk> {
+    disk: ByteDisk<D>,
+    /// Current position, in bytes
+    position: u64,
+}
+
+impl<D: Disk> DiskCursor<D> {
+    /// Creates a cursor at the start of the disk. The sector size is chosen like in
+    /// `ByteDisk::new`.
+    pub fn new(disk: D) -> Result<Self, DiskErr> {
+        Ok(Self {
+            disk: ByteDisk::new(disk)?,
+            position: 0,
+        })
+    }
+
+    /// Creates a cursor at the start of the disk, with an explicit sector size
+    pub fn with_sector_size(disk: D, sector_size: usize) -> Result<Self, DiskErr> {
+        Ok(Self {
+            disk: ByteDisk::with_sector_size(disk, sector_size)?,
+            position: 0,
+        })
+    }
+
+    pub const fn position(&self) -> u64 {
+        self.position
+    }
+
+    pub fn set_position(&mut self, position: u64) {
+        self.position = position
+    }
+
+    pub const fn get_ref(&self) -> &D {
+        self.disk.inner()
+    }
+
+    pub fn into_inner(self) -> D {
+        self.disk.into_inner()
+    }
+
+    /// Returns the count of bytes that can be accessed from the current position, limited to
+    /// `max`.
+    fn available(&self, max: usize) -> io::Result<usize> {
+        let size = self.disk.size()?;
+        Ok(size.saturating_sub(self.position).min(max as u64) as usize)
+    }
+}
+
+impl<D: Disk> Read for DiskCursor<D> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let len = self.available(buf.len())?;
+
+        // The position may be past the end, where `read_at` fails even for 0 bytes
+        if len == 0 {
+            return Ok(0);
+        }
+
+        self.disk.read_at(self.position, &mut buf[..len])?;
+        self.position += len as u64;
+
+        Ok(len)
+    }
+}
+
+impl<D: Disk> Write for DiskCursor<D> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let len = self.available(buf.len())?;
+
+        if len == 0 && buf.is_empty() {
+            return Ok(0);
+        }
+
+        // `Ok(0)` would only tell `write_all` that nothing was written
+        if len == 0 {
+            return Err(io::Error::new(
+                io::ErrorKind::StorageFull,
+                "write past the end of the disk",
+            ));
+        }
+
+        self.disk.write_at(self.position, &buf[..len])?;
+        self.position += len as u64;
+
+        Ok(len)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(self.disk.inner().flush()?)
+    }
+}
+
+impl<D: Disk> Seek for DiskCursor<D> {
+    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+        let (base, offset) = match pos {
+            SeekFrom::Start(v) => {
+                self.position = v;
+                return Ok(v);
+            }
+            SeekFrom::End(v) => (self.disk.size()?, v),
+            SeekFrom::Current(v) => (self.position, v),
+        };
+
+        match base.checked_add_signed(offset) {
+            Some(v) => {
+                self.position = v;
+                Ok(v)
+            }
+            None => Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "invalid seek to a negative or overflowing position",
+            )),
+        }
+    }
+}
+
+impl From<DiskErr> for io::Error {
+    fn from(value: DiskErr) -> Self {
+        // The kind of the original I/O error is kept when there is one
+        let kind = match value.context.io_kind {
+            Some(kind) => kind,
+            None => match value.kind {
+                DiskErrKind::InvalidSectorSize { .. }
+                | DiskErrKind::InvalidSectorIndex { .. }
+                | DiskErrKind::InvalidDiskSize
+                | DiskErrKind::IndexOutOfRange => io::ErrorKind::InvalidInput,
+                DiskErrKind::InvalidPermission { .. } => io::ErrorKind::PermissionDenied,
+                DiskErrKind::UnreachableDisk => io::ErrorKind::NotConnected,
+                DiskErrKind::Busy => io::ErrorKind::ResourceBusy,
+                DiskErrKind::UnsupportedDiskSectorSize => io::ErrorKind::Unsupported,
+                DiskErrKind::IOErr => io::ErrorKind::Other,
+                DiskErrKind::VerificationFailed { .. }
+                | DiskErrKind::ChecksumMismatch { .. }
+                | DiskErrKind::InvalidImage { .. } => io::ErrorKind::InvalidData,
+            },
+        };
+
+        io::Error::new(kind, value)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{Permissions, SectorSize, memdisk::MemDisk, wrappers::DiskWrapper};
+
+    fn disk() -> MemDisk {
+        MemDisk::from_vec(
+            (0..4096).map(|i| (i % 251) as u8).collect(),
+            SectorSize::AllOf(vec![512]),
+            Permissions::read_write(),
+        )
+    }
+
+    #[test]
+    fn seeks() {
+        let mut cursor = DiskCursor::new(disk()).unwrap();
+
+        assert_eq!(cursor.seek(SeekFrom::Start(100)).unwrap(), 100);
+        assert_eq!(cursor.seek(SeekFrom::Current(-50)).unwrap(), 50);
+        assert_eq!(cursor.seek(SeekFrom::Current(1000)).unwrap(), 1050);
+        assert_eq!(cursor.seek(SeekFrom::End(-96)).unwrap(), 4000);
+        assert_eq!(cursor.stream_position().unwrap(), 4000);
+
+        let mut buf = [0; 4];
+        cursor.read_exact(&mut buf).unwrap();
+        assert_eq!(
+            buf,
+            [4000 % 251, 4001 % 251, 4002 % 251, 4003 % 251].map(|v| v as u8)
+        );
+
+        // Past the end is allowed, before the start is not
+        assert_eq!(cursor.seek(SeekFrom::End(10)).unwrap(), 4106);
+        assert_eq!(cursor.seek(SeekFrom::Start(u64::MAX)).unwrap(), u64::MAX);
+        for pos in [SeekFrom::Current(1), SeekFrom::End(-4097)] {
+            let err = cursor.seek(pos).unwrap_err();
+            assert_eq!(err.kind(), io::ErrorKind::InvalidInput);
+        }
+        assert_eq!(cursor.position(), u64::MAX);
+
+        cursor.set_position(3);
+        cursor.read_exact(&mut buf).unwrap();
+        assert_eq!(buf, [3, 4, 5, 6]);
+    }
+
+    #[test]
+    fn reads_stop_at_the_end() {
+        let mut cursor = DiskCursor::new(disk()).unwrap();
+        cursor.seek(SeekFrom::End(-10)).unwrap();
+
+        let mut buf = [0; 100];
+        assert_eq!(cursor.read(&mut buf).unwrap(), 10);
+        assert_eq!(cursor.read(&mut buf).unwrap(), 0);
+
+        cursor.seek(SeekFrom::End(10)).unwrap();
+        assert_eq!(cursor.read(&mut buf).unwrap(), 0);
+
+        let mut content = Vec::new();
+        cursor.rewind().unwrap();
+        assert_eq!(cursor.read_to_end(&mut content).unwrap(), 4096);
+        assert_eq!(content, disk().into_vec());
+
+        cursor.seek(SeekFrom::End(-3)).unwrap();
+        let err = cursor.read_exact(&mut buf).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::UnexpectedEof);
+    }
+
+    #[test]
+    fn writes() {
+        let mut cursor = DiskCursor::with_sector_size(disk(), 512).unwrap();
+        cursor.seek(SeekFrom::Start(1000)).unwrap();
+        cursor.write_all(&[0xAA; 100]).unwrap();
+        assert_eq!(cursor.position(), 1100);
+        cursor.flush().unwrap();
+
+        // Cut at the end, then failing
+        cursor.seek(SeekFrom::End(-4)).unwrap();
+        assert_eq!(cursor.write(&[0xBB; 10]).unwrap(), 4);
+        assert_eq!(cursor.write(&[]).unwrap(), 0);
+        let err = cursor.write(&[0xBB; 10]).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::StorageFull);
+
+        cursor.seek(SeekFrom::End(-4)).unwrap();
+        let err = cursor.write_all(&[0xCC; 10]).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::StorageFull);
+
+        cursor.seek(SeekFrom::End(100)).unwrap();
+        assert!(cursor.write(&[0xDD]).is_err());
+
+        let mut expected = disk().into_vec();
+        expected[1000..1100].fill(0xAA);
+        expected[4092..].fill(0xCC);
+        assert_eq!(cursor.into_inner().into_vec(), expected);
+    }
+
+    #[test]
+    fn partitions_and_errors() {
+        let wrapper = DiskWrapper::new(disk());
+        let partition = wrapper
+            .subdisk(1024, 2048, Permissions::read_only())
+            .unwrap();
+
+        let mut cursor = DiskCursor::new(partition).unwrap();
+        let mut content = Vec::new();
+        io::copy(&mut cursor, &mut content).unwrap();
+        assert_eq!(content, disk().into_vec()[1024..2048]);
+
+        cursor.rewind().unwrap();
+        let err = cursor.write(&[0; 10]).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::PermissionDenied);
+        assert!(err.get_ref().unwrap().is::<DiskErr>());
+    }
+}
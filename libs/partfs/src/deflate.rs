@@ -0,0 +1,1089 @@
+use crate::{DiskErr, DiskErrKind};
+use alloc::{collections::BinaryHeap, vec, vec::Vec};
+use core::cmp::Reverse;
+
+/// Size of the history the back-references can reach
+pub const WINDOW_SIZE: usize = 32768;
+
+const MAX_BITS: usize = 15;
+/// Codes up to this length are decoded with a single table lookup
+const FAST_BITS: u32 = 10;
+
+const MIN_MATCH: usize = 3;
+const MAX_MATCH: usize = 258;
+
+/// Input of each block of the `Deflater`, the largest stored block
+const BLOCK_SIZE: usize = 65535;
+const HASH_SIZE: usize = 1 << 15;
+/// Candidates tried for each match
+const MAX_CHAIN: usize = 64;
+
+const INPUT_BUFFER_SIZE: usize = 65536;
+
+const LENGTH_BASE: [u16; 29] = [
+    3, 4, 5, 6, 7, 8, 9, 10, 11, 13, 15, 17, 19, 23, 27, 31, 35, 43, 51, 59, 67, 83, 99, 115, 131,
+    163, 195, 227, 258,
+];
+const LENGTH_EXTRA: [u8; 29] = [
+    0, 0, 0, 0, 0, 0, 0, 0, 1, 1, 1, 1, 2, 2, 2, 2, 3, 3, 3, 3, 4, 4, 4, 4, 5, 5, 5, 5, 0,
+];
+const DIST_BASE: [u16; 30] = [
+    1, 2, 3, 4, 5, 7, 9, 13, 17, 25, 33, 49, 65, 97, 129, 193, 257, 385, 513, 769, 1025, 1537,
+    2049, 3073, 4097, 6145, 8193, 12289, 16385, 24577,
+];
+const DIST_EXTRA: [u8; 30] = [
+    0, 0, 0, 0, 1, 1, 2, 2, 3, 3, 4, 4, 5, 5, 6, 6, 7, 7, 8, 8, 9, 9, 10, 10, 11, 11, 12, 12, 13,
+    13,
+];
+/// Order of the lengths of the code length code in the dynamic block headers
+const CODE_LENGTH_ORDER: [usize; 19] = [
+    16, 17, 18, 0, 8, 7, 9, 6, 10, 5, 11, 4, 12, 3, 13, 2, 14, 1, 15,
+];
+
+fn invalid(reason: &'static str) -> DiskErr {
+    DiskErr::new(DiskErrKind::InvalidImage { reason })
+}
+
+/// The code lengths of the fixed literal/length code
+fn fixed_lengths() -> [u8; 288] {
+    let mut lengths = [8; 288];
+    lengths[144..256].fill(9);
+    lengths[256..280].fill(7);
+    lengths
+}
+
+fn reverse(code: u32, len: usize) -> u32 {
+    code.reverse_bits() >> (32 - len)
+}
+
+/// The canonical codes of `lengths`, bit-reversed as they are written LSB first
+fn canonical_codes(lengths: &[u8]) -> Vec<u32> {
+    let mut counts = [0u32; MAX_BITS + 1];
+    for &len in lengths {
+        counts[len as usize] += 1;
+    }
+    counts[0] = 0;
+
+    let mut next = [0u32; MAX_BITS + 1];
+    let mut code = 0;
+    for len in 1..=MAX_BITS {
+        code = (code + counts[len - 1]) << 1;
+        next[len] = code;
+    }
+
+    lengths
+        .iter()
+        .map(|&len| match len as usize {
+            0 => 0,
+            len => {
+                next[len] += 1;
+                reverse(next[len] - 1, len)
+            }
+        })
+        .collect()
+}
+
+/// Index of the length or distance code of `value` in `base`
+fn code_index(base: &[u16], value: usize) -> usize {
+    base.partition_point(|&b| b as usize <= value) - 1
+}
+
+// ### INFLATE ###
+
+/// The compressed data read by an `Inflater`
+pub trait Source {
+    /// Reads at most `buf.len()` bytes and returns the count, 0 at the end of the data
+    fn read(&mut self, buf: &mut [u8]) -> Result<usize, DiskErr>;
+}
+
+impl Source for &[u8] {
+    fn read(&mut self, buf: &mut [u8]) -> Result<usize, DiskErr> {
+        let len = buf.len().min(self.len());
+        buf[..len].copy_from_slice(&self[..len]);
+        *self = &self[len..];
+        Ok(len)
+    }
+}
+
+/// A canonical Huffman code, as decoded from the code lengths
+struct Huffman {
+    /// `symbol << 4 | length` of the codes up to `FAST_BITS` bits, indexed by the next bits of
+    /// the stream. 0 for the longer codes.
+    fast: Vec<u16>,
+    /// Number of codes of each length
+    counts: [u16; MAX_BITS + 1],
+    /// The symbols, ordered by code
+    symbols: Vec<u16>,
+}
+
+impl Huffman {
+    fn new(lengths: &[u8]) -> Result<Self, DiskErr> {
+        let mut counts = [0u16; MAX_BITS + 1];
+        for &len in lengths {
+            counts[len as usize] += 1;
+        }
+        counts[0] = 0;
+
+        // Incomplete codes are accepted, their unused codes are rejected when decoded
+        let mut left = 1i32;
+        for &count in &counts[1..] {
+            left = (left << 1) - count as i32;
+            if left < 0 {
+                return Err(invalid("invalid Huffman code"));
+            }
+        }
+
+        let mut offsets = [0u16; MAX_BITS + 1];
+        for len in 1..MAX_BITS {
+            offsets[len + 1] = offsets[len] + counts[len];
+        }
+
+        let codes = canonical_codes(lengths);
+        let mut symbols = vec![0; lengths.iter().filter(|&&len| len != 0).count()];
+        let mut fast = vec![0u16; 1 << FAST_BITS];
+
+        for (symbol, &len) in lengths.iter().enumerate() {
+            let len = len as usize;
+            if len == 0 {
+                continue;
+            }
+
+            symbols[offsets[len] as usize] = symbol as u16;
+            offsets[len] += 1;
+
+            if len as u32 <= FAST_BITS {
+                for i in ((codes[symbol] as usize)..fast.len()).step_by(1 << len) {
+                    fast[i] = ((symbol as u16) << 4) | len as u16;
+                }
+            }
+        }
+
+        Ok(Self {
+            fast,
+            counts,
+            symbols,
+        })
+    }
+}
+
+/// Bit-level reader over a `Source`, the bits of each byte are read LSB first
+struct BitReader<S> {
+    source: S,
+    input: Vec<u8>,
+    input_pos: usize,
+    input_len: usize,
+    /// Bytes taken from the source
+    consumed: u64,
+    bits: u64,
+    bit_count: u32,
+}
+
+impl<S: Source> BitReader<S> {
+    /// Loads as many bytes as fit in the bit buffer, less at the end of the source
+    fn refill(&mut self) -> Result<(), DiskErr> {
+        while self.bit_count <= 56 {
+            if self.input_pos == self.input_len {
+                self.input_len = self.source.read(&mut self.input)?;
+                self.input_pos = 0;
+
+                if self.input_len == 0 {
+                    break;
+                }
+            }
+
+            self.bits |= (self.input[self.input_pos] as u64) << self.bit_count;
+            self.input_pos += 1;
+            self.consumed += 1;
+            self.bit_count += 8;
+        }
+
+        Ok(())
+    }
+
+    fn take(&mut self, count: u32) -> Result<u32, DiskErr> {
+        if self.bit_count < count {
+            self.refill()?;
+
+            if self.bit_count < count {
+                return Err(invalid("truncated deflate stream"));
+            }
+        }
+
+        let value = (self.bits & ((1 << count) - 1)) as u32;
+        self.bits >>= count;
+        self.bit_count -= count;
+        Ok(value)
+    }
+
+    fn decode(&mut self, huffman: &Huffman) -> Result<u16, DiskErr> {
+        if self.bit_count < MAX_BITS as u32 {
+            self.refill()?;
+        }
+
+        let entry = huffman.fast[(self.bits & ((1 << FAST_BITS) - 1)) as usize];
+        let len = (entry & 15) as u32;
+        if entry != 0 && len <= self.bit_count {
+            self.bits >>= len;
+            self.bit_count -= len;
+            return Ok(entry >> 4);
+        }
+
+        // Longer codes, one bit at a time
+        let (mut code, mut first, mut index) = (0i32, 0i32, 0i32);
+        for &count in &huffman.counts[1..] {
+            code |= self.take(1)? as i32;
+            let count = count as i32;
+
+            if code - first < count {
+                return Ok(huffman.symbols[(index + code - first) as usize]);
+            }
+
+            index += count;
+            first = (first + count) << 1;
+            code <<= 1;
+        }
+
+        Err(invalid("invalid Huffman code"))
+    }
+
+    /// Drops the bits up to the next byte boundary
+    fn align(&mut self) {
+        let extra = self.bit_count % 8;
+        self.bits >>= extra;
+        self.bit_count -= extra;
+    }
+
+    /// Reads whole bytes, the reader must be aligned. Returns less than `buf.len()` at the end of
+    /// the source only.
+    fn read_bytes(&mut self, buf: &mut [u8]) -> Result<usize, DiskErr> {
+        let mut n = 0;
+
+        while n < buf.len() && self.bit_count >= 8 {
+            buf[n] = self.bits as u8;
+            self.bits >>= 8;
+            self.bit_count -= 8;
+            n += 1;
+        }
+
+        while n < buf.len() {
+            if self.input_pos == self.input_len {
+                self.input_len = self.source.read(&mut self.input)?;
+                self.input_pos = 0;
+
+                if self.input_len == 0 {
+                    break;
+                }
+            }
+
+            let len = (buf.len() - n).min(self.input_len - self.input_pos);
+            buf[n..(n + len)].copy_from_slice(&self.input[self.input_pos..(self.input_pos + len)]);
+            self.input_pos += len;
+            self.consumed += len as u64;
+            n += len;
+        }
+
+        Ok(n)
+    }
+}
+
+#[derive(Clone, Copy)]
+enum Block {
+    Header,
+    Stored { remaining: usize },
+    Codes,
+    Done,
+}
+
+/// Streaming DEFLATE (RFC 1951) decompressor, pulling the compressed data from a `Source`.
+///
+/// The decompression can be resumed at any block boundary from the position of the stream and the
+/// last `WINDOW_SIZE` bytes of output (see `position`, `window` and `resume`), which allows random
+/// access through an index of such checkpoints.
+pub struct Inflater<S> {
+    reader: BitReader<S>,
+    block: Block,
+    last: bool,
+    lit: Huffman,
+    dist: Huffman,
+    window: Vec<u8>,
+    /// Bytes written since the start, the position in `window`
+    total: u64,
+    /// Bytes of `window` the back-references can reach
+    history: usize,
+    /// Length and distance of the back-reference left to copy
+    copy: (usize, usize),
+}
+
+impl<S: Source> Inflater<S> {
+    /// Starts the decompression at the current position of `source`
+    pub fn new(source: S) -> Self {
+        Self {
+            reader: BitReader {
+                source,
+                input: vec![0; INPUT_BUFFER_SIZE],
+                input_pos: 0,
+                input_len: 0,
+                consumed: 0,
+                bits: 0,
+                bit_count: 0,
+            },
+            block: Block::Header,
+            last: false,
+            lit: Huffman {
+                fast: Vec::new(),
+                counts: [0; MAX_BITS + 1],
+                symbols: Vec::new(),
+            },
+            dist: Huffman {
+                fast: Vec::new(),
+                counts: [0; MAX_BITS + 1],
+                symbols: Vec::new(),
+            },
+            window: vec![0; WINDOW_SIZE],
+            total: 0,
+            history: 0,
+            copy: (0, 0),
+        }
+    }
+
+    /// Resumes the decompression at a block boundary: `source` is at the byte of the boundary,
+    /// which starts after `skip_bits` bits, and `window` is the output preceding it.
+    pub fn resume(source: S, skip_bits: u32, window: &[u8]) -> Result<Self, DiskErr> {
+        let mut inflater = Self::new(source);
+
+        let window = &window[window.len().saturating_sub(WINDOW_SIZE)..];
+        inflater.window[..window.len()].copy_from_slice(window);
+        inflater.total = window.len() as u64;
+        inflater.history = window.len();
+
+        inflater.reader.take(skip_bits)?;
+        Ok(inflater)
+    }
+
+    pub fn into_source(self) -> S {
+        self.reader.source
+    }
+
+    /// Position in the compressed stream, in bits from the position of the source when the
+    /// inflater was created
+    pub fn position(&self) -> u64 {
+        self.reader.consumed * 8 - self.reader.bit_count as u64
+    }
+
+    /// True between two blocks, where the decompression can be resumed
+    pub fn at_block_start(&self) -> bool {
+        matches!(self.block, Block::Header)
+    }
+
+    /// True once the final block has been read
+    pub fn is_finished(&self) -> bool {
+        matches!(self.block, Block::Done)
+    }
+
+    /// The last `WINDOW_SIZE` bytes of output, or less at the start of the stream
+    pub fn window(&self) -> Vec<u8> {
+        (0..self.history)
+            .map(|i| self.window[(self.total - (self.history - i) as u64) as usize % WINDOW_SIZE])
+            .collect()
+    }
+
+    /// Starts a new stream after the end of the current one, like the next member of a gzip file
+    pub fn restart(&mut self) {
+        self.block = Block::Header;
+        self.last = false;
+        self.history = 0;
+        self.copy = (0, 0);
+    }
+
+    /// Reads the bytes following the end of the stream, like the trailer of a gzip member. Returns
+    /// less than `buf.len()` at the end of the source only.
+    pub fn read_aligned(&mut self, buf: &mut [u8]) -> Result<usize, DiskErr> {
+        self.reader.align();
+        self.reader.read_bytes(buf)
+    }
+
+    /// Decompresses into `buf`, and returns the number of bytes written. Stops at the end of each
+    /// block, so it can return less than `buf.len()`. 0 means the end of the stream.
+    pub fn read(&mut self, buf: &mut [u8]) -> Result<usize, DiskErr> {
+        let mut n = 0;
+
+        while n < buf.len() {
+            if self.copy.0 > 0 {
+                let (len, distance) = self.copy;
+                let count = len.min(buf.len() - n);
+
+                for b in &mut buf[n..(n + count)] {
+                    *b = self.window[(self.total - distance as u64) as usize % WINDOW_SIZE];
+                    self.push(*b);
+                }
+
+                n += count;
+                self.copy.0 -= count;
+                continue;
+            }
+
+            match self.block {
+                Block::Done => break,
+                Block::Header if n > 0 => break,
+                Block::Header => self.block_header()?,
+                Block::Stored { remaining: 0 } => self.end_block(),
+                Block::Stored { remaining } => {
+                    let count = remaining.min(buf.len() - n);
+                    let part = &mut buf[n..(n + count)];
+
+                    if self.reader.read_bytes(part)? < count {
+                        return Err(invalid("truncated deflate stream"));
+                    }
+                    for &b in part.iter() {
+                        self.push(b);
+                    }
+
+                    n += count;
+                    self.block = Block::Stored {
+                        remaining: remaining - count,
+                    };
+                }
+                Block::Codes => match self.reader.decode(&self.lit)? {
+                    symbol @ 0..=255 => {
+                        buf[n] = symbol as u8;
+                        self.push(symbol as u8);
+                        n += 1;
+                    }
+                    256 => self.end_block(),
+                    symbol => {
+                        let index = symbol as usize - 257;
+                        if index >= LENGTH_BASE.len() {
+                            return Err(invalid("invalid length code"));
+                        }
+                        let len = LENGTH_BASE[index] as usize
+                            + self.reader.take(LENGTH_EXTRA[index] as u32)? as usize;
+
+                        let index = self.reader.decode(&self.dist)? as usize;
+                        if index >= DIST_BASE.len() {
+                            return Err(invalid("invalid distance code"));
+                        }
+                        let distance = DIST_BASE[index] as usize
+                            + self.reader.take(DIST_EXTRA[index] as u32)? as usize;
+
+                        if distance > self.history {
+                            return Err(invalid("distance too far back"));
+                        }
+                        self.copy = (len, distance);
+                    }
+                },
+            }
+        }
+
+        Ok(n)
+    }
+
+    fn push(&mut self, b: u8) {
+        self.window[self.total as usize % WINDOW_SIZE] = b;
+        self.total += 1;
+        self.history = (self.history + 1).min(WINDOW_SIZE);
+    }
+
+    fn end_block(&mut self) {
+        self.block = match self.last {
+            true => Block::Done,
+            false => Block::Header,
+        };
+    }
+
+    fn block_header(&mut self) -> Result<(), DiskErr> {
+        self.last = self.reader.take(1)? == 1;
+
+        self.block = match self.reader.take(2)? {
+            0 => {
+                self.reader.align();
+                let len = self.reader.take(16)?;
+                if self.reader.take(16)? != !len & 0xFFFF {
+                    return Err(invalid("invalid stored block length"));
+                }
+
+                Block::Stored {
+                    remaining: len as usize,
+                }
+            }
+            1 => {
+                self.lit = Huffman::new(&fixed_lengths())?;
+                self.dist = Huffman::new(&[5; 30])?;
+                Block::Codes
+            }
+            2 => {
+                self.dynamic_codes()?;
+                Block::Codes
+            }
+            _ => return Err(invalid("invalid block type")),
+        };
+
+        Ok(())
+    }
+
+    fn dynamic_codes(&mut self) -> Result<(), DiskErr> {
+        let lit_count = self.reader.take(5)? as usize + 257;
+        let dist_count = self.reader.take(5)? as usize + 1;
+        let code_length_count = self.reader.take(4)? as usize + 4;
+
+        if lit_count > 286 || dist_count > 30 {
+            return Err(invalid("invalid code lengths"));
+        }
+
+        let mut code_lengths = [0; 19];
+        for &i in &CODE_LENGTH_ORDER[..code_length_count] {
+            code_lengths[i] = self.reader.take(3)? as u8;
+        }
+        let code_length_code = Huffman::new(&code_lengths)?;
+
+        let total = lit_count + dist_count;
+        let mut lengths = [0; 286 + 30];
+        let mut i = 0;
+
+        while i < total {
+            let (value, repeat) = match self.reader.decode(&code_length_code)? {
+                symbol @ 0..=15 => (symbol as u8, 1),
+                16 if i > 0 => (lengths[i - 1], 3 + self.reader.take(2)? as usize),
+                17 => (0, 3 + self.reader.take(3)? as usize),
+                18 => (0, 11 + self.reader.take(7)? as usize),
+                _ => return Err(invalid("invalid code lengths")),
+            };
+
+            if i + repeat > total {
+                return Err(invalid("invalid code lengths"));
+            }
+            lengths[i..(i + repeat)].fill(value);
+            i += repeat;
+        }
+
+        if lengths[256] == 0 {
+            return Err(invalid("missing end of block code"));
+        }
+
+        self.lit = Huffman::new(&lengths[..lit_count])?;
+        self.dist = Huffman::new(&lengths[lit_count..total])?;
+        Ok(())
+    }
+}
+
+/// Decompresses a whole DEFLATE stream
+pub fn decompress(data: &[u8]) -> Result<Vec<u8>, DiskErr> {
+    let mut inflater = Inflater::new(data);
+    let mut buf = vec![0; WINDOW_SIZE];
+    let mut out = Vec::new();
+
+    loop {
+        match inflater.read(&mut buf)? {
+            0 => return Ok(out),
+            n => out.extend_from_slice(&buf[..n]),
+        }
+    }
+}
+
+// ### DEFLATE ###
+
+/// Code lengths of a Huffman code for `freqs`, limited to `max_bits`
+fn huffman_lengths(freqs: &[u32], max_bits: u8) -> Vec<u8> {
+    let mut freqs = freqs.to_vec();
+
+    // The decoders expect complete codes, which takes two symbols
+    for i in 0..freqs.len() {
+        if freqs.iter().filter(|&&f| f > 0).count() >= 2 {
+            break;
+        }
+        if freqs[i] == 0 {
+            freqs[i] = 1;
+        }
+    }
+
+    loop {
+        let mut heap: BinaryHeap<Reverse<(u64, usize)>> = freqs
+            .iter()
+            .enumerate()
+            .filter(|&(_, &f)| f > 0)
+            .map(|(i, &f)| Reverse((f as u64, i)))
+            .collect();
+        let mut parents = vec![usize::MAX; freqs.len()];
+
+        while let (Some(Reverse((f1, a))), Some(Reverse((f2, b)))) = (heap.pop(), heap.pop()) {
+            let node = parents.len();
+            parents.push(usize::MAX);
+            parents[a] = node;
+            parents[b] = node;
+            heap.push(Reverse((f1 + f2, node)));
+        }
+
+        let lengths: Vec<u8> = (0..freqs.len())
+            .map(|i| {
+                let mut node = i;
+                let mut depth = 0u8;
+                while parents[node] != usize::MAX {
+                    node = parents[node];
+                    depth = depth.saturating_add(1);
+                }
+                depth
+            })
+            .collect();
+
+        if lengths.iter().all(|&len| len <= max_bits) {
+            return lengths;
+        }
+
+        // Flattens the frequencies until the longest code fits
+        for f in freqs.iter_mut().filter(|f| **f > 0) {
+            *f = (*f / 2).max(1);
+        }
+    }
+}
+
+/// Code length symbols (with their extra bits) encoding `lengths` in a dynamic block header
+fn code_length_symbols(lengths: &[u8]) -> Vec<(u8, u8)> {
+    let mut symbols = Vec::new();
+    let mut i = 0;
+
+    while i < lengths.len() {
+        let value = lengths[i];
+        let run = lengths[i..].iter().take_while(|&&len| len == value).count();
+
+        if value == 0 && run >= 11 {
+            let run = run.min(138);
+            symbols.push((18, (run - 11) as u8));
+            i += run;
+        } else if value == 0 && run >= 3 {
+            let run = run.min(10);
+            symbols.push((17, (run - 3) as u8));
+            i += run;
+        } else if run >= 4 {
+            let run = run.min(7);
+            symbols.push((value, 0));
+            symbols.push((16, (run - 4) as u8));
+            i += run;
+        } else {
+            symbols.push((value, 0));
+            i += 1;
+        }
+    }
+
+    symbols
+}
+
+fn code_length_extra(symbol: u8) -> u32 {
+    match symbol {
+        16 => 2,
+        17 => 3,
+        18 => 7,
+        _ => 0,
+    }
+}
+
+/// Streaming DEFLATE (RFC 1951) compressor: LZ77 with hash chains, then for each block the
+/// smallest of the stored, fixed and dynamic encodings.
+pub struct Deflater {
+    /// The last `WINDOW_SIZE` bytes compressed at least, then the ones waiting to be
+    data: Vec<u8>,
+    /// Position in `data` of the first byte waiting to be compressed
+    pending: usize,
+    /// Position + 1 in `data` of the last occurrence of each hash of 3 bytes, 0 if none
+    head: Vec<u32>,
+    /// Position + 1 of the previous occurrence of the hash of each position, modulo `WINDOW_SIZE`
+    prev: Vec<u32>,
+    bits: u64,
+    bit_count: u32,
+}
+
+impl Default for Deflater {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Deflater {
+    pub fn new() -> Self {
+        Self {
+            data: Vec::new(),
+            pending: 0,
+            head: vec![0; HASH_SIZE],
+            prev: vec![0; WINDOW_SIZE],
+            bits: 0,
+            bit_count: 0,
+        }
+    }
+
+    /// Compresses `data`, appending the completed blocks to `out`
+    pub fn write(&mut self, data: &[u8], out: &mut Vec<u8>) {
+        for chunk in data.chunks(BLOCK_SIZE) {
+            self.data.extend_from_slice(chunk);
+
+            while self.data.len() - self.pending >= BLOCK_SIZE {
+                self.compress_block(self.pending + BLOCK_SIZE, false, out);
+            }
+        }
+    }
+
+    /// Compresses the remaining data as the final block, and appends it to `out`
+    pub fn finish(mut self, out: &mut Vec<u8>) {
+        self.compress_block(self.data.len(), true, out);
+
+        if self.bit_count > 0 {
+            out.push(self.bits as u8);
+        }
+    }
+
+    fn put(&mut self, out: &mut Vec<u8>, value: u32, count: u32) {
+        self.bits |= (value as u64) << self.bit_count;
+        self.bit_count += count;
+
+        while self.bit_count >= 8 {
+            out.push(self.bits as u8);
+            self.bits >>= 8;
+            self.bit_count -= 8;
+        }
+    }
+
+    fn hash(&self, pos: usize) -> usize {
+        (((self.data[pos] as usize) << 10)
+            ^ ((self.data[pos + 1] as usize) << 5)
+            ^ self.data[pos + 2] as usize)
+            % HASH_SIZE
+    }
+
+    fn insert(&mut self, pos: usize) {
+        if pos + MIN_MATCH <= self.data.len() {
+            let hash = self.hash(pos);
+            self.prev[pos % WINDOW_SIZE] = self.head[hash];
+            self.head[hash] = pos as u32 + 1;
+        }
+    }
+
+    /// Longest match for `pos` in the history, up to `max_len` bytes: length and distance
+    fn find_match(&self, pos: usize, max_len: usize) -> (usize, usize) {
+        if max_len < MIN_MATCH || pos + MIN_MATCH > self.data.len() {
+            return (0, 0);
+        }
+
+        let mut candidate = self.head[self.hash(pos)] as usize;
+        let (mut best, mut best_distance) = (0, 0);
+
+        for _ in 0..MAX_CHAIN {
+            if candidate == 0 || pos - (candidate - 1) > WINDOW_SIZE {
+                break;
+            }
+            let start = candidate - 1;
+
+            if self.data[start + best] == self.data[pos + best] {
+                let len = self.data[start..]
+                    .iter()
+                    .zip(&self.data[pos..(pos + max_len)])
+                    .take_while(|(a, b)| a == b)
+                    .count();
+
+                if len > best {
+                    best = len;
+                    best_distance = pos - start;
+
+                    if len == max_len {
+                        break;
+                    }
+                }
+            }
+
+            // The entries of the positions overwritten in `prev` are newer
+            let next = self.prev[start % WINDOW_SIZE] as usize;
+            if next >= candidate {
+                break;
+            }
+            candidate = next;
+        }
+
+        (best, best_distance)
+    }
+
+    /// Compresses `data[pending..end]` as a block
+    fn compress_block(&mut self, end: usize, last: bool, out: &mut Vec<u8>) {
+        let start = self.pending;
+
+        // (literal, 0) or (length, distance)
+        let mut symbols: Vec<(u16, u16)> = Vec::new();
+        let mut lit_freqs = [0u32; 286];
+        let mut dist_freqs = [0u32; 30];
+
+        let mut i = start;
+        while i < end {
+            let (len, distance) = self.find_match(i, (end - i).min(MAX_MATCH));
+
+            if len >= MIN_MATCH {
+                lit_freqs[257 + code_index(&LENGTH_BASE, len)] += 1;
+                dist_freqs[code_index(&DIST_BASE, distance)] += 1;
+                symbols.push((len as u16, distance as u16));
+
+                for pos in i..(i + len) {
+                    self.insert(pos);
+                }
+                i += len;
+            } else {
+                lit_freqs[self.data[i] as usize] += 1;
+                symbols.push((self.data[i] as u16, 0));
+                self.insert(i);
+                i += 1;
+            }
+        }
+        lit_freqs[256] = 1;
+
+        // ### ENCODING CHOICE ###
+
+        let data_cost = |lit_lengths: &[u8], dist_lengths: &[u8]| -> u64 {
+            let lit: u64 = (0..286)
+                .map(|s| {
+                    let extra = if s > 256 { LENGTH_EXTRA[s - 257] } else { 0 };
+                    lit_freqs[s] as u64 * (lit_lengths[s] + extra) as u64
+                })
+                .sum();
+            let dist: u64 = (0..30)
+                .map(|d| dist_freqs[d] as u64 * (dist_lengths[d] + DIST_EXTRA[d]) as u64)
+                .sum();
+            lit + dist
+        };
+
+        let lit_lengths = huffman_lengths(&lit_freqs, MAX_BITS as u8);
+        let dist_lengths = huffman_lengths(&dist_freqs, MAX_BITS as u8);
+
+        let lit_count = lit_lengths.iter().rposition(|&len| len != 0).unwrap() + 1;
+        let dist_count = dist_lengths.iter().rposition(|&len| len != 0).unwrap() + 1;
+        let mut lengths = lit_lengths[..lit_count].to_vec();
+        lengths.extend_from_slice(&dist_lengths[..dist_count]);
+
+        let length_symbols = code_length_symbols(&lengths);
+        let mut code_length_freqs = [0u32; 19];
+        for &(symbol, _) in &length_symbols {
+            code_length_freqs[symbol as usize] += 1;
+        }
+        let code_lengths = huffman_lengths(&code_length_freqs, 7);
+        let code_length_count = CODE_LENGTH_ORDER
+            .iter()
+            .rposition(|&i| code_lengths[i] != 0)
+            .map_or(4, |i| (i + 1).max(4));
+
+        let dynamic_cost = 14
+            + 3 * code_length_count as u64
+            + length_symbols
+                .iter()
+                .map(|&(s, _)| (code_lengths[s as usize] as u32 + code_length_extra(s)) as u64)
+                .sum::<u64>()
+            + data_cost(&lit_lengths, &dist_lengths);
+        let fixed_cost = data_cost(&fixed_lengths(), &[5; 30]);
+        let stored_cost =
+            ((8 - (self.bit_count + 3) % 8) % 8 + 32) as u64 + 8 * (end - start) as u64;
+
+        // ### OUTPUT ###
+
+        if stored_cost <= fixed_cost.min(dynamic_cost) {
+            self.put(out, last as u32, 3);
+            if self.bit_count > 0 {
+                self.put(out, 0, 8 - self.bit_count);
+            }
+
+            let len = (end - start) as u16;
+            out.extend_from_slice(&len.to_le_bytes());
+            out.extend_from_slice(&(!len).to_le_bytes());
+            out.extend_from_slice(&self.data[start..end]);
+        } else if fixed_cost <= dynamic_cost {
+            self.put(out, last as u32 | (1 << 1), 3);
+            self.put_symbols(out, &symbols, &fixed_lengths(), &[5; 30]);
+        } else {
+            self.put(out, last as u32 | (2 << 1), 3);
+            self.put(out, (lit_count - 257) as u32, 5);
+            self.put(out, (dist_count - 1) as u32, 5);
+            self.put(out, (code_length_count - 4) as u32, 4);
+
+            for &i in &CODE_LENGTH_ORDER[..code_length_count] {
+                self.put(out, code_lengths[i] as u32, 3);
+            }
+
+            let codes = canonical_codes(&code_lengths);
+            for &(symbol, extra) in &length_symbols {
+                let symbol = symbol as usize;
+                self.put(out, codes[symbol], code_lengths[symbol] as u32);
+                self.put(out, extra as u32, code_length_extra(symbol as u8));
+            }
+
+            self.put_symbols(out, &symbols, &lit_lengths, &dist_lengths);
+        }
+
+        self.pending = end;
+
+        // Drops the data out of reach, by multiples of the window to keep the `prev` indices
+        if self.pending >= 2 * WINDOW_SIZE + BLOCK_SIZE {
+            let shift = (self.pending - WINDOW_SIZE) / WINDOW_SIZE * WINDOW_SIZE;
+            self.data.drain(..shift);
+            self.pending -= shift;
+
+            for pos in self.head.iter_mut().chain(self.prev.iter_mut()) {
+                *pos = pos.saturating_sub(shift as u32);
+            }
+        }
+    }
+
+    fn put_symbols(
+        &mut self,
+        out: &mut Vec<u8>,
+        symbols: &[(u16, u16)],
+        lit_lengths: &[u8],
+        dist_lengths: &[u8],
+    ) {
+        let lit_codes = canonical_codes(lit_lengths);
+        let dist_codes = canonical_codes(dist_lengths);
+
+        for &(value, distance) in symbols {
+            if distance == 0 {
+                let value = value as usize;
+                self.put(out, lit_codes[value], lit_lengths[value] as u32);
+                continue;
+            }
+
+            let index = code_index(&LENGTH_BASE, value as usize);
+            let symbol = 257 + index;
+            self.put(out, lit_codes[symbol], lit_lengths[symbol] as u32);
+            self.put(
+                out,
+                (value - LENGTH_BASE[index]) as u32,
+                LENGTH_EXTRA[index] as u32,
+            );
+
+            let index = code_index(&DIST_BASE, distance as usize);
+            self.put(out, dist_codes[index], dist_lengths[index] as u32);
+            self.put(
+                out,
+                (distance - DIST_BASE[index]) as u32,
+                DIST_EXTRA[index] as u32,
+            );
+        }
+
+        self.put(out, lit_codes[256], lit_lengths[256] as u32);
+    }
+}
+
+/// Compresses `data` as a single DEFLATE stream
+pub fn compress(data: &[u8]) -> Vec<u8> {
+    let mut deflater = Deflater::new();
+    let mut out = Vec::new();
+
+    deflater.write(data, &mut out);
+    deflater.finish(&mut out);
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use alloc::format;
+
+    fn hex(s: &str) -> Vec<u8> {
+        (0..s.len())
+            .step_by(2)
+            .map(|i| u8::from_str_radix(&s[i..(i + 2)], 16).unwrap())
+            .collect()
+    }
+
+    /// The type of the first block of `stream`
+    fn block_type(stream: &[u8]) -> u8 {
+        (stream[0] >> 1) & 3
+    }
+
+    /// Bytes without repetitions, from a linear congruential generator
+    fn noise(len: usize) -> Vec<u8> {
+        let mut state = 12345u32;
+        (0..len)
+            .map(|_| {
+                state = state.wrapping_mul(1103515245).wrapping_add(12345);
+                (state >> 16) as u8
+            })
+            .collect()
+    }
+
+    #[test]
+    fn known_streams() {
+        // A stored block, then the outputs of zlib with a fixed and with a dynamic block
+        assert_eq!(decompress(&hex("010500faff68656c6c6f")).unwrap(), b"hello");
+        assert_eq!(
+            decompress(&hex("cb48cdc9c9d751c840a21401")).unwrap(),
+            b"hello, hello, hello!"
+        );
+
+        let text = [
+            b"The quick brown fox jumps over the lazy dog. ".repeat(3),
+            b"Pack my box with five dozen liquor jugs.".to_vec(),
+        ]
+        .concat();
+        let stream = hex(concat!(
+            "b5cbc70180201005d1567e05d4e2c10640490656b250bddb84e779b33a8d58fd",
+            "764225ea01865e1cf57e32a8e984c2f9927360272bb0fe8617c9ee1e508cba2f",
+            "0ec637cd69ea80cbc74a895f9bc507",
+        ));
+        assert_eq!(block_type(&stream), 2);
+        assert_eq!(decompress(&stream).unwrap(), text);
+
+        // An empty fixed block
+        assert_eq!(decompress(&[0x03, 0x00]).unwrap(), b"");
+    }
+
+    #[test]
+    fn round_trips() {
+        let text: Vec<u8> = (0..2000)
+            .flat_map(|i| format!("line {} of {}\n", i % 37, i % 11).into_bytes())
+            .collect();
+
+        for (data, expected_type) in [
+            (noise(1000), Some(0)),
+            (b"abcabcabcabc".to_vec(), Some(1)),
+            (text, Some(2)),
+            (Vec::new(), None),
+            // Several blocks
+            ([noise(100_000), vec![7; 100_000]].concat(), None),
+        ] {
+            let stream = compress(&data);
+            if let Some(expected_type) = expected_type {
+                assert_eq!(block_type(&stream), expected_type);
+            }
+            assert_eq!(decompress(&stream).unwrap(), data);
+        }
+    }
+
+    #[test]
+    fn incremental_compression() {
+        let data = [noise(50_000), vec![0; 200_000], noise(50_000)].concat();
+        let mut deflater = Deflater::new();
+        let mut stream = Vec::new();
+
+        for part in data.chunks(7000) {
+            deflater.write(part, &mut stream);
+        }
+        deflater.finish(&mut stream);
+
+        assert!(stream.len() < 110_000);
+        assert_eq!(decompress(&stream).unwrap(), data);
+    }
+
+    #[test]
+    fn malformed_streams() {
+        let invalid = |reason| DiskErrKind::InvalidImage { reason };
+
+        assert_eq!(
+            decompress(&[0x07]).unwrap_err().kind,
+            invalid("invalid block type")
+        );
+        assert_eq!(
+            decompress(&hex("010500fbff68656c6c6f")).unwrap_err().kind,
+            invalid("invalid stored block length")
+        );
+
+        let stream = compress(b"hello, hello, hello!");
+        assert_eq!(
+            decompress(&stream[..(stream.len() - 1)]).unwrap_err().kind,
+            invalid("truncated deflate stream")
+        );
+        // A distance of 1 before any output
+        assert_eq!(
+            decompress(&hex("030200")).unwrap_err().kind,
+            invalid("distance too far back")
+        );
+    }
+}
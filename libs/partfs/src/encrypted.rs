@@ -0,0 +1,248 @@
+use crate::{Disk, DiskErr, DiskErrKind, DiskInfos, Operation, SectorSize, crypto::xts::Xts};
+use alloc::{vec, vec::Vec};
+
+/// A wrapper encrypting each sector of a `Disk` with AES-XTS, the LBA being the tweak (the
+/// `plain64` IV of dm-crypt). The disk exposes a single sector size, the encryption unit.
+///
+/// The discards write encrypted zeroes, so that the discarded sectors read as zeroes without
+/// revealing which sectors are in use.
+pub struct EncryptedDisk<D: Disk> {
+    disk: D,
+    cipher: Xts,
+    /// Size of the encryption unit, in bytes
+    sector_size: usize,
+    /// Added to the LBA to get the tweak
+    tweak_offset: u64,
+}
+
+impl<D: Disk> EncryptedDisk<D> {
+    /// Encrypts `disk` by sectors of `sector_size` bytes (512 by default, like dm-crypt), which
+    /// must be supported by the disk and be a multiple of 16
+    pub fn new(disk: D, cipher: Xts, sector_size: Option<usize>) -> Result<Self, DiskErr> {
+        let infos = disk.disk_infos()?;
+        let sector_size = sector_size.unwrap_or(512);
+
+        if sector_size == 0
+            || !sector_size.is_multiple_of(16)
+            || !infos.sector_size.is_supported(sector_size, infos.disk_size)
+        {
+            return Err(DiskErr::new(DiskErrKind::InvalidSectorSize {
+                found: sector_size,
+                supported: infos.sector_size,
+                start: 0,
+            })
+            .during(Operation::DiskInfos)
+            .in_layer("EncryptedDisk"));
+        }
+
+        Ok(Self {
+            disk,
+            cipher,
+            sector_size,
+            tweak_offset: 0,
+        })
+    }
+
+    /// Adds `offset` to the LBA to get the tweak (the `iv_offset` of dm-crypt)
+    pub fn with_tweak_offset(mut self, offset: u64) -> Self {
+        self.tweak_offset = offset;
+        self
+    }
+
+    pub const fn sector_size(&self) -> usize {
+        self.sector_size
+    }
+
+    pub const fn inner(&self) -> &D {
+        &self.disk
+    }
+
+    pub fn into_inner(self) -> D {
+        self.disk
+    }
+
+    fn check(&self, sector_size: usize, len: usize) -> Result<(), DiskErr> {
+        if sector_size != self.sector_size || !len.is_multiple_of(sector_size) {
+            return Err(DiskErrKind::InvalidSectorSize {
+                found: sector_size,
+                supported: SectorSize::AllOf(vec![self.sector_size]),
+                start: 0,
+            }
+            .into());
+        }
+
+        Ok(())
+    }
+
+    fn read_inner(&self, sector: u64, sector_size: usize, buf: &mut [u8]) -> Result<(), DiskErr> {
+        self.check(sector_size, buf.len())?;
+        self.disk.read_sectors(sector, sector_size, buf)?;
+
+        for (i, data) in buf.chunks_mut(sector_size).enumerate() {
+            let unit = sector
+                .wrapping_add(self.tweak_offset)
+                .wrapping_add(i as u64);
+            self.cipher.decrypt_unit(unit as u128, data);
+        }
+
+        Ok(())
+    }
+
+    /// Returns `buf` encrypted
+    fn encrypt(&self, sector: u64, buf: &[u8]) -> Vec<u8> {
+        let mut encrypted = buf.to_vec();
+
+        for (i, data) in encrypted.chunks_mut(self.sector_size).enumerate() {
+            let unit = sector
+                .wrapping_add(self.tweak_offset)
+                .wrapping_add(i as u64);
+            self.cipher.encrypt_unit(unit as u128, data);
+        }
+
+        encrypted
+    }
+}
+
+impl<D: Disk> Disk for EncryptedDisk<D> {
+    fn read_sector(&self, sector: u64, buf: &mut [u8]) -> Result<(), DiskErr> {
+        self.read_sectors(sector, buf.len(), buf)
+    }
+
+    fn write_sector(&self, sector: u64, buf: &[u8]) -> Result<(), DiskErr> {
+        self.write_sectors(sector, buf.len(), buf)
+    }
+
+    fn disk_infos(&self) -> Result<DiskInfos, DiskErr> {
+        let infos = self.disk.disk_infos()?;
+
+        let mut geometry = infos.geometry;
+        geometry.logical_sector_size = self.sector_size;
+        geometry.physical_sector_size = geometry.physical_sector_size.max(self.sector_size);
+        geometry.min_io_size = geometry.min_io_size.max(self.sector_size);
+
+        Ok(DiskInfos {
+            sector_size: SectorSize::AllOf(vec![self.sector_size]),
+            disk_size: infos.disk_size / self.sector_size as u64 * self.sector_size as u64,
+            geometry,
+            ..infos
+        })
+    }
+
+    fn read_sectors(&self, sector: u64, sector_size: usize, buf: &mut [u8]) -> Result<(), DiskErr> {
+        self.read_inner(sector, sector_size, buf)
+            .map_err(|e| e.at(Operation::Read, sector).in_layer("EncryptedDisk"))
+    }
+
+    fn write_sectors(&self, sector: u64, sector_size: usize, buf: &[u8]) -> Result<(), DiskErr> {
+        self.check(sector_size, buf.len())
+            .and_then(|_| {
+                self.disk
+                    .write_sectors(sector, sector_size, &self.encrypt(sector, buf))
+            })
+            .map_err(|e| e.at(Operation::Write, sector).in_layer("EncryptedDisk"))
+    }
+
+    fn write_sectors_fua(
+        &self,
+        sector: u64,
+        sector_size: usize,
+        buf: &[u8],
+    ) -> Result<(), DiskErr> {
+        self.check(sector_size, buf.len())
+            .and_then(|_| {
+                self.disk
+                    .write_sectors_fua(sector, sector_size, &self.encrypt(sector, buf))
+            })
+            .map_err(|e| e.at(Operation::Write, sector).in_layer("EncryptedDisk"))
+    }
+
+    fn flush(&self) -> Result<(), DiskErr> {
+        self.disk.flush()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{Permissions, memdisk::MemDisk};
+
+    const KEY: [u8; 64] = [7; 64];
+
+    fn disk(sector_size: Option<usize>) -> EncryptedDisk<MemDisk> {
+        let disk = MemDisk::new(
+            64 * 512,
+            SectorSize::AllOf(vec![512, 4096]),
+            Permissions::read_write(),
+        );
+        EncryptedDisk::new(disk, Xts::new(&KEY).unwrap(), sector_size).unwrap()
+    }
+
+    #[test]
+    fn round_trip() {
+        for sector_size in [512, 4096] {
+            let disk = disk(Some(sector_size));
+            let data: Vec<u8> = (0..(2 * sector_size)).map(|i| (i % 251) as u8).collect();
+
+            disk.write_sectors(3, sector_size, &data).unwrap();
+            disk.write_sectors_fua(5, sector_size, &data[..sector_size])
+                .unwrap();
+
+            let mut buf = vec![0; 2 * sector_size];
+            disk.read_sectors(3, sector_size, &mut buf).unwrap();
+            assert_eq!(buf, data);
+            disk.read_sectors(5, sector_size, &mut buf[..sector_size])
+                .unwrap();
+            assert_eq!(buf[..sector_size], data[..sector_size]);
+
+            // The same data at another LBA gives another ciphertext
+            let mut raw = vec![0; 3 * sector_size];
+            disk.inner().read_sectors(3, sector_size, &mut raw).unwrap();
+            assert_ne!(raw[..sector_size], data[..sector_size]);
+            assert_ne!(raw[..sector_size], raw[(2 * sector_size)..]);
+        }
+    }
+
+    #[test]
+    fn plain64_tweak() {
+        let disk = disk(None).with_tweak_offset(100);
+        disk.write_sector(2, &[0xAA; 512]).unwrap();
+
+        let mut raw = [0; 512];
+        disk.inner().read_sector(2, &mut raw).unwrap();
+        Xts::new(&KEY).unwrap().decrypt_unit(102, &mut raw);
+        assert_eq!(raw, [0xAA; 512]);
+
+        // Another offset can't decrypt it
+        let disk = EncryptedDisk::new(disk.into_inner(), Xts::new(&KEY).unwrap(), None).unwrap();
+        let mut buf = [0; 512];
+        disk.read_sector(2, &mut buf).unwrap();
+        assert_ne!(buf, [0xAA; 512]);
+    }
+
+    #[test]
+    fn discards_and_sector_sizes() {
+        let disk = disk(None);
+        disk.write_sectors(0, 512, &[1; 1024]).unwrap();
+        disk.discard(1, 512, 1).unwrap();
+
+        let mut buf = [0; 1024];
+        disk.read_sectors(0, 512, &mut buf).unwrap();
+        assert_eq!(buf[..512], [1; 512]);
+        assert_eq!(buf[512..], [0; 512]);
+
+        let mut raw = [0; 512];
+        disk.inner().read_sector(1, &mut raw).unwrap();
+        assert_ne!(raw, [0; 512]);
+
+        let err = disk.read_sectors(0, 4096, &mut [0; 4096]).unwrap_err();
+        assert!(matches!(err.kind, DiskErrKind::InvalidSectorSize { .. }));
+        assert_eq!(err.context.layer, Some("EncryptedDisk"));
+        assert_eq!(
+            disk.disk_infos().unwrap().sector_size,
+            SectorSize::AllOf(vec![512])
+        );
+
+        let inner = MemDisk::new(4096, SectorSize::Any, Permissions::read_write());
+        assert!(EncryptedDisk::new(inner, Xts::new(&KEY).unwrap(), Some(520)).is_err());
+    }
+}
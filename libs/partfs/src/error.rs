@@ -0,0 +1,504 @@
+use crate::{Permissions, SectorSize};
+use alloc::string::String;
+use core::fmt;
+
+/// Error of the device layer: any `Disk` implementation, wrapper or adapter. It is made of what
+/// went wrong (`kind`) and of where it happened (`context`).
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DiskErr {
+    pub kind: DiskErrKind,
+    pub context: ErrContext,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[non_exhaustive]
+pub enum DiskErrKind {
+    /// Will trigger if the size of the buffer isn't supported.
+    ///
+    /// `found` is the size of the provided buffer (`buf.len()`)
+    ///
+    /// `supported` is the supported sector size(s)
+    ///
+    /// `start % sector_size` should be zero (used with subdisks)
+    InvalidSectorSize {
+        found: usize,
+        supported: SectorSize,
+        start: u64,
+    },
+
+    /// Will trigger if the sector index is out of the range of the disk.
+    ///
+    /// `found` is the provided sector index (lba)
+    ///
+    /// `max` is the last existing sector index **with the size of the given buffer** (0 if the disk
+    /// holds no sector of this size)
+    InvalidSectorIndex { found: u64, max: u64 },
+
+    /// Will trigger if a write is performed on a read-only disk or if the program tries to read a
+    /// write-only disk
+    InvalidPermission { disk_permissions: Permissions },
+
+    /// Will trigger if, for any reason, the disk is not found anymore.
+    UnreachableDisk,
+
+    /// Will trigger when attempting to create a subdisk out of the range of the original disk
+    /// size
+    InvalidDiskSize,
+
+    /// Will trigger if a read/write/subdisk creation is requested when the disk is already in
+    /// use/on a space already borrowed
+    Busy,
+
+    /// Will trigger for all the errors coming from IO processes. Under `std`, the original
+    /// `io::ErrorKind` is kept in the context.
+    IOErr,
+
+    /// Will trigger if the disk supports none of the sector sizes usable by the caller
+    UnsupportedDiskSectorSize,
+
+    /// Will trigger if a byte range is out of the disk (see `ByteDisk`)
+    IndexOutOfRange,
+
+    /// Will trigger if a sector read back doesn't hold the data written to it (see
+    /// `VerifyingDisk`)
+    ///
+    /// `lba` is the first sector that differs, in the sector size of the request
+    VerificationFailed { lba: u64 },
+
+    /// Will trigger if a sector doesn't match its checksum (see `IntegrityDisk`)
+    ///
+    /// `lba` is the first sector that doesn't match
+    ChecksumMismatch { lba: u64 },
+
+    /// Will trigger if the metadata of a disk image is damaged, or uses a feature this library
+    /// doesn't implement (see `Qcow2Disk`)
+    InvalidImage { reason: &'static str },
+}
+
+/// The operation that triggered an error
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Operation {
+    Read,
+    Write,
+    Flush,
+    Discard,
+    DiskInfos,
+    CreateSubdisk,
+}
+
+/// Where an error happened. Every field is optional: each layer fills the ones it knows and
+/// that are still empty, so the context describes the lowest layer that could tell.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct ErrContext {
+    pub operation: Option<Operation>,
+    /// The LBA of the request, in the sectors of `layer`
+    pub lba: Option<u64>,
+    /// The name of the `Disk` implementation which raised the error (`"SubDisk"`, `"DiskFile"`,
+    /// ...)
+    pub layer: Option<&'static str>,
+    /// The kind of the underlying `io::Error`, if any
+    #[cfg(feature = "std")]
+    pub io_kind: Option<std::io::ErrorKind>,
+}
+
+impl DiskErr {
+    pub fn new(kind: DiskErrKind) -> Self {
+        Self {
+            kind,
+            context: ErrContext::default(),
+        }
+    }
+
+    /// Creates an `IOErr` keeping the kind of the `io::Error`
+    #[cfg(feature = "std")]
+    pub fn from_io(err: &std::io::Error) -> Self {
+        let mut slf = Self::new(DiskErrKind::IOErr);
+        slf.context.io_kind = Some(err.kind());
+        slf
+    }
+
+    /// Records the operation, unless already recorded by a lower layer
+    pub fn during(mut self, operation: Operation) -> Self {
+        self.context.operation.get_or_insert(operation);
+        self
+    }
+
+    /// Records the operation and the LBA, unless already recorded by a lower layer
+    pub fn at(mut self, operation: Operation, lba: u64) -> Self {
+        self.context.operation.get_or_insert(operation);
+        self.context.lba.get_or_insert(lba);
+        self
+    }
+
+    /// Records the layer which raised the error, unless already recorded by a lower layer
+    pub fn in_layer(mut self, layer: &'static str) -> Self {
+        self.context.layer.get_or_insert(layer);
+        self
+    }
+}
+
+impl DiskErrKind {
+    /// The name of the variant, without its fields. Useful to group the errors by kind.
+    pub const fn name(&self) -> &'static str {
+        match self {
+            Self::InvalidSectorSize { .. } => "InvalidSectorSize",
+            Self::InvalidSectorIndex { .. } => "InvalidSectorIndex",
+            Self::InvalidPermission { .. } => "InvalidPermission",
+            Self::UnreachableDisk => "UnreachableDisk",
+            Self::InvalidDiskSize => "InvalidDiskSize",
+            Self::Busy => "Busy",
+            Self::IOErr => "IOErr",
+            Self::UnsupportedDiskSectorSize => "UnsupportedDiskSectorSize",
+            Self::IndexOutOfRange => "IndexOutOfRange",
+            Self::VerificationFailed { .. } => "VerificationFailed",
+            Self::ChecksumMismatch { .. } => "ChecksumMismatch",
+            Self::InvalidImage { .. } => "InvalidImage",
+        }
+    }
+}
+
+impl From<DiskErrKind> for DiskErr {
+    fn from(value: DiskErrKind) -> Self {
+        Self::new(value)
+    }
+}
+
+impl fmt::Display for DiskErrKind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::InvalidSectorSize {
+                found,
+                supported,
+                start,
+            } => write!(
+                f,
+                "invalid sector size {found} (supported: {supported:?}, start offset: {start})"
+            ),
+            Self::InvalidSectorIndex { found, max } => {
+                write!(f, "sector {found} is out of the disk (last sector {max})")
+            }
+            Self::InvalidPermission { disk_permissions } => write!(
+                f,
+                "operation not allowed by the disk permissions (read: {}, write: {})",
+                disk_permissions.read, disk_permissions.write
+            ),
+            Self::UnreachableDisk => write!(f, "the disk is unreachable"),
+            Self::InvalidDiskSize => write!(f, "the range is out of the disk"),
+            Self::Busy => write!(f, "the disk space is already borrowed"),
+            Self::IOErr => write!(f, "I/O error"),
+            Self::UnsupportedDiskSectorSize => {
+                write!(f, "the disk supports no usable sector size")
+            }
+            Self::IndexOutOfRange => write!(f, "the byte range is out of the disk"),
+            Self::VerificationFailed { lba } => {
+                write!(f, "sector {lba} doesn't hold the data written to it")
+            }
+            Self::ChecksumMismatch { lba } => write!(f, "sector {lba} doesn't match its checksum"),
+            Self::InvalidImage { reason } => write!(f, "invalid disk image: {reason}"),
+        }
+    }
+}
+
+impl fmt::Display for Operation {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Self::Read => "read",
+            Self::Write => "write",
+            Self::Flush => "flush",
+            Self::Discard => "discard",
+            Self::DiskInfos => "disk infos",
+            Self::CreateSubdisk => "subdisk creation",
+        })
+    }
+}
+
+impl fmt::Display for DiskErr {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.kind)?;
+
+        #[cfg(feature = "std")]
+        if let Some(io_kind) = self.context.io_kind {
+            write!(f, ": {io_kind}")?;
+        }
+
+        match (self.context.operation, self.context.lba) {
+            (Some(op), Some(lba)) => write!(f, " ({op} of sector {lba}")?,
+            (Some(op), None) => write!(f, " ({op}")?,
+            (None, Some(lba)) => write!(f, " (sector {lba}")?,
+            (None, None) => match self.context.layer {
+                Some(layer) => return write!(f, " (in {layer})"),
+                None => return Ok(()),
+            },
+        }
+
+        match self.context.layer {
+            Some(layer) => write!(f, ", in {layer})"),
+            None => write!(f, ")"),
+        }
+    }
+}
+
+impl core::error::Error for DiskErr {}
+
+/// Error of the partition table layer
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[non_exhaustive]
+pub enum PartitionErr {
+    /// The disk holding the partition table failed
+    Disk(DiskErr),
+
+    /// The disk supports no sector size usable by the partition table
+    UnsupportedSectorSize,
+
+    /// The partition doesn't exist in the table
+    InvalidPartitionIndex { index: usize },
+
+    /// The requested partition overlaps an existing one, or the partition table itself.
+    /// `start` and `end` are the requested range [start, end[, in sectors.
+    SpaceAlreadyInUse { start: u64, end: u64 },
+
+    /// The requested partition ends after the end of the disk, or can't be represented in the
+    /// partition table. `found` is the faulty value, in sectors.
+    OutOfRange { found: u64, max: u64 },
+}
+
+impl From<DiskErr> for PartitionErr {
+    fn from(value: DiskErr) -> Self {
+        Self::Disk(value)
+    }
+}
+
+impl fmt::Display for PartitionErr {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Disk(e) => write!(f, "partition table disk error: {e}"),
+            Self::UnsupportedSectorSize => {
+                write!(
+                    f,
+                    "the disk supports no sector size usable by the partition table"
+                )
+            }
+            Self::InvalidPartitionIndex { index } => {
+                write!(f, "the partition {index} doesn't exist")
+            }
+            Self::SpaceAlreadyInUse { start, end } => {
+                write!(f, "the sectors [{start}, {end}[ are already in use")
+            }
+            Self::OutOfRange { found, max } => {
+                write!(f, "sector {found} is out of range (max: {max})")
+            }
+        }
+    }
+}
+
+impl core::error::Error for PartitionErr {
+    fn source(&self) -> Option<&(dyn core::error::Error + 'static)> {
+        match self {
+            Self::Disk(e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+/// Error of the LUKS layer
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[non_exhaustive]
+pub enum LuksErr {
+    /// The disk holding the container failed
+    Disk(DiskErr),
+
+    /// The header is malformed, or doesn't match its checksum
+    InvalidHeader { reason: &'static str },
+
+    /// The container uses a cipher, a hash or a key derivation this library doesn't implement
+    Unsupported { feature: String },
+
+    /// The passphrase or the volume key doesn't open the container
+    WrongKey,
+
+    /// The key slot doesn't exist or is not active
+    InvalidKeySlot { index: usize },
+
+    /// The key derivation couldn't run: invalid costs, or not enough memory
+    KdfFailed,
+
+    /// The disk can't hold the header, the key slots and a payload. Sizes in bytes.
+    DiskTooSmall { size: u64, min: u64 },
+}
+
+impl From<DiskErr> for LuksErr {
+    fn from(value: DiskErr) -> Self {
+        Self::Disk(value)
+    }
+}
+
+impl fmt::Display for LuksErr {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Disk(e) => write!(f, "LUKS disk error: {e}"),
+            Self::InvalidHeader { reason } => write!(f, "invalid LUKS header: {reason}"),
+            Self::Unsupported { feature } => write!(f, "unsupported LUKS feature: {feature}"),
+            Self::WrongKey => write!(f, "no key slot can be opened with this key"),
+            Self::InvalidKeySlot { index } => write!(f, "the key slot {index} is not active"),
+            Self::KdfFailed => write!(f, "the key derivation failed"),
+            Self::DiskTooSmall { size, min } => {
+                write!(f, "the disk is too small ({size} bytes, min: {min})")
+            }
+        }
+    }
+}
+
+impl core::error::Error for LuksErr {
+    fn source(&self) -> Option<&(dyn core::error::Error + 'static)> {
+        match self {
+            Self::Disk(e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+/// Error of the disk image formats, when an image is opened or created
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[non_exhaustive]
+pub enum ImageErr {
+    /// The file holding the image failed
+    Disk(DiskErr),
+
+    /// The file is not an image of the expected format, or its header is malformed
+    InvalidHeader { reason: &'static str },
+
+    /// The image uses a feature this library doesn't implement
+    Unsupported { feature: String },
+}
+
+impl From<DiskErr> for ImageErr {
+    fn from(value: DiskErr) -> Self {
+        Self::Disk(value)
+    }
+}
+
+impl fmt::Display for ImageErr {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Disk(e) => write!(f, "disk image error: {e}"),
+            Self::InvalidHeader { reason } => write!(f, "invalid image header: {reason}"),
+            Self::Unsupported { feature } => write!(f, "unsupported image feature: {feature}"),
+        }
+    }
+}
+
+impl core::error::Error for ImageErr {
+    fn source(&self) -> Option<&(dyn core::error::Error + 'static)> {
+        match self {
+            Self::Disk(e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+/// Error of the filesystem layer
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[non_exhaustive]
+pub enum FsErr {
+    /// The disk holding the filesystem failed
+    Disk(DiskErr),
+
+    /// The partition holding the filesystem couldn't be accessed
+    Partition(PartitionErr),
+
+    /// The sector size is not supported by the filesystem, or by the disk
+    UnsupportedSectorSize {
+        found: usize,
+    },
+
+    /// The cluster doesn't exist on the volume. Valid clusters are in [2, max[.
+    InvalidCluster {
+        found: usize,
+        max: usize,
+    },
+
+    /// A cluster chain is corrupted: `cluster` is linked to `next`, which is free, reserved, bad,
+    /// out of the volume, or already in the chain
+    CorruptedChain {
+        cluster: usize,
+        next: usize,
+    },
+
+    InvalidPathFormat,
+}
+
+impl From<DiskErr> for FsErr {
+    fn from(value: DiskErr) -> Self {
+        Self::Disk(value)
+    }
+}
+
+impl From<PartitionErr> for FsErr {
+    fn from(value: PartitionErr) -> Self {
+        match value {
+            PartitionErr::Disk(e) => Self::Disk(e),
+            e => Self::Partition(e),
+        }
+    }
+}
+
+impl fmt::Display for FsErr {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Disk(e) => write!(f, "filesystem disk error: {e}"),
+            Self::Partition(e) => write!(f, "filesystem partition error: {e}"),
+            Self::UnsupportedSectorSize { found } => {
+                write!(f, "sector size {found} is not supported by the filesystem")
+            }
+            Self::InvalidCluster { found, max } => {
+                write!(
+                    f,
+                    "cluster {found} doesn't exist (valid clusters: [2, {max}[)"
+                )
+            }
+            Self::CorruptedChain { cluster, next } => {
+                write!(
+                    f,
+                    "corrupted cluster chain: cluster {cluster} is linked to {next}"
+                )
+            }
+            Self::InvalidPathFormat => write!(f, "invalid path format"),
+        }
+    }
+}
+
+impl core::error::Error for FsErr {
+    fn source(&self) -> Option<&(dyn core::error::Error + 'static)> {
+        match self {
+            Self::Disk(e) => Some(e),
+            Self::Partition(e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{Disk, memdisk::MemDisk};
+    use alloc::{string::ToString, vec};
+
+    #[test]
+    fn out_of_range_message() {
+        let disk = MemDisk::new(
+            4 * 512,
+            SectorSize::AllOf(vec![512]),
+            Permissions::read_write(),
+        );
+        let err = disk.read_sectors(4, 512, &mut [0; 512]).unwrap_err();
+
+        assert_eq!(
+            err.kind,
+            DiskErrKind::InvalidSectorIndex { found: 4, max: 3 }
+        );
+        assert_eq!(
+            err.to_string(),
+            "sector 4 is out of the disk (last sector 3) (read of sector 4, in MemDisk)"
+        );
+    }
+}
@@ -0,0 +1,396 @@
+use crate::{Disk, DiskErr, DiskErrKind, DiskInfos, Operation, Permissions};
+use alloc::{vec, vec::Vec};
+use mutex::Mutex;
+
+/// A wrapper injecting faults in the requests to a `Disk`, to test the error paths of the code
+/// using it. The faults are programmed while the disk is in use, and each failed request returns
+/// the chosen error (typically `IOErr`, `Busy` or `UnreachableDisk`).
+///
+/// Only the reads, writes, discards and flushes are counted as operations, as `disk_infos` is
+/// called by most of the wrappers and filesystems at unpredictable times.
+pub struct FaultyDisk<D: Disk> {
+    disk: D,
+    faults: Mutex<Faults>,
+}
+
+#[derive(Default)]
+struct Faults {
+    /// Count of operations seen since the creation of the disk
+    operations: u64,
+    /// Failing ranges of bytes `(start, end, operation, error)`, for any operation if `None`
+    ranges: Vec<(u64, u64, Option<Operation>, DiskErrKind)>,
+    /// Failing operations `(remaining, operation, error)`. The operation fails when `remaining`
+    /// matching operations have been done.
+    countdowns: Vec<(u64, Option<Operation>, DiskErrKind)>,
+    /// Torn writes `(remaining, persisted bytes, error)`
+    torn_writes: Vec<(u64, usize, DiskErrKind)>,
+    /// Bits flipped on read `(offset, mask)`
+    bit_flips: Vec<(u64, u8)>,
+    /// Count of writes before the disk becomes read-only
+    writes_left: Option<u64>,
+}
+
+impl<D: Disk> FaultyDisk<D> {
+    /// Wraps `disk` without any fault: the requests are forwarded until faults are programmed
+    pub fn new(disk: D) -> Self {
+        Self {
+            disk,
+            faults: Mutex::new(Faults::default()),
+        }
+    }
+
+    /// Fails every `operation` (every operation if `None`) touching one of the `count` sectors of
+    /// `sector_size` bytes starting at the LBA `sector`, until `clear` is called. The range is
+    /// kept in bytes, so it also applies to requests using other sector sizes.
+    pub fn fail_sectors(
+        &self,
+        operation: Option<Operation>,
+        sector: u64,
+        sector_size: usize,
+        count: u64,
+        error: DiskErrKind,
+    ) {
+        let start = sector.saturating_mul(sector_size as u64);
+        let end = start.saturating_add(count.saturating_mul(sector_size as u64));
+
+        self.faults
+            .lock()
+            .ranges
+            .push((start, end, operation, error));
+    }
+
+    /// Fails the `n`th next `operation` (of any kind if `None`), 0 being the next one. The
+    /// following operations succeed again.
+    pub fn fail_nth(&self, operation: Option<Operation>, n: u64, error: DiskErrKind) {
+        self.faults.lock().countdowns.push((n, operation, error));
+    }
+
+    /// Tears the `n`th next write or discard, 0 being the next one: only the first `persisted`
+    /// bytes of a write reach the disk, and it returns `error`. A torn discard leaves its sectors
+    /// untouched.
+    pub fn tear_write(&self, n: u64, persisted: usize, error: DiskErrKind) {
+        self.faults.lock().torn_writes.push((n, persisted, error));
+    }
+
+    /// Flips the bits of `mask` in the byte at `offset` in every read covering it. The data of the
+    /// disk is not modified.
+    pub fn flip_bits(&self, offset: u64, mask: u8) {
+        self.faults.lock().bit_flips.push((offset, mask));
+    }
+
+    /// Makes the disk read-only after `writes` more successful writes or discards, like a flash
+    /// device at the end of its life. Its permissions are updated accordingly.
+    pub fn read_only_after(&self, writes: u64) {
+        self.faults.lock().writes_left = Some(writes);
+    }
+
+    /// Removes all the programmed faults. A disk gone read-only becomes writable again.
+    pub fn clear(&self) {
+        let mut faults = self.faults.lock();
+        *faults = Faults {
+            operations: faults.operations,
+            ..Faults::default()
+        };
+    }
+
+    /// The count of operations seen since the creation of the disk, failed or not
+    pub fn operations(&self) -> u64 {
+        self.faults.lock().operations
+    }
+
+    pub const fn inner(&self) -> &D {
+        &self.disk
+    }
+
+    pub fn into_inner(self) -> D {
+        self.disk
+    }
+
+    /// Counts an `operation` on the bytes `range`, and returns the error it must fail with if any.
+    /// For writes, the torn write to do is returned too.
+    fn check(
+        faults: &mut Faults,
+        operation: Operation,
+        range: Option<(u64, u64)>,
+    ) -> Result<Option<(usize, DiskErrKind)>, DiskErr> {
+        faults.operations += 1;
+
+        let mut error = None;
+
+        // Every countdown is updated, even when the operation fails because of another one
+        faults.countdowns.retain_mut(|(remaining, op, kind)| {
+            if op.is_some_and(|op| op != operation) {
+                return true;
+            }
+
+            if *remaining == 0 {
+                error.get_or_insert(kind.clone());
+                return false;
+            }
+
+            *remaining -= 1;
+            true
+        });
+
+        let mut torn = None;
+        if operation == Operation::Write || operation == Operation::Discard {
+            faults
+                .torn_writes
+                .retain_mut(|(remaining, persisted, kind)| {
+                    if *remaining == 0 {
+                        torn.get_or_insert((*persisted, kind.clone()));
+                        return false;
+                    }
+
+                    *remaining -= 1;
+                    true
+                });
+        }
+
+        if let Some((start, end)) = range {
+            for (fault_start, fault_end, op, kind) in &faults.ranges {
+                if op.is_none_or(|op| op == operation) && start < *fault_end && *fault_start < end {
+                    error.get_or_insert(kind.clone());
+                }
+            }
+        }
+
+        if (operation == Operation::Write || operation == Operation::Discard)
+            && faults.writes_left == Some(0)
+        {
+            error.get_or_insert(DiskErrKind::InvalidPermission {
+                disk_permissions: Permissions::read_only(),
+            });
+        }
+
+        match error {
+            Some(kind) => Err(kind.into()),
+            None => Ok(torn),
+        }
+    }
+
+    /// Returns the range of bytes of a request, if it doesn't overflow
+    fn range(sector: u64, sector_size: usize, len: u64) -> Option<(u64, u64)> {
+        let start = sector.checked_mul(sector_size as u64)?;
+        Some((start, start.checked_add(len)?))
+    }
+
+    /// Updates the count of writes before the disk becomes read-only
+    fn count_write(&self) {
+        if let Some(writes_left) = &mut self.faults.lock().writes_left {
+            *writes_left = writes_left.saturating_sub(1);
+        }
+    }
+
+    fn read_inner(&self, sector: u64, sector_size: usize, buf: &mut [u8]) -> Result<(), DiskErr> {
+        let range = Self::range(sector, sector_size, buf.len() as u64);
+        let bit_flips = {
+            let mut faults = self.faults.lock();
+            Self::check(&mut faults, Operation::Read, range)?;
+            faults.bit_flips.clone()
+        };
+
+        self.disk.read_sectors(sector, sector_size, buf)?;
+
+        if let Some((start, end)) = range {
+            for (offset, mask) in bit_flips {
+                if (start..end).contains(&offset) {
+                    buf[(offset - start) as usize] ^= mask;
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    fn write_inner(&self, sector: u64, sector_size: usize, buf: &[u8]) -> Result<(), DiskErr> {
+        let range = Self::range(sector, sector_size, buf.len() as u64);
+        let torn = Self::check(&mut self.faults.lock(), Operation::Write, range)?;
+
+        let Some((persisted, kind)) = torn else {
+            self.disk.write_sectors(sector, sector_size, buf)?;
+            self.count_write();
+            return Ok(());
+        };
+
+        // The disk is written with whole sectors: the part of the buffer that isn't persisted is
+        // replaced with the current data
+        if persisted > 0 {
+            let mut torn_buf = vec![0; buf.len()];
+            self.disk.read_sectors(sector, sector_size, &mut torn_buf)?;
+
+            let persisted = persisted.min(buf.len());
+            torn_buf[..persisted].copy_from_slice(&buf[..persisted]);
+            self.disk.write_sectors(sector, sector_size, &torn_buf)?;
+        }
+
+        Err(kind.into())
+    }
+
+    fn discard_inner(&self, sector: u64, sector_size: usize, count: u64) -> Result<(), DiskErr> {
+        let range = Self::range(
+            sector,
+            sector_size,
+            count.saturating_mul(sector_size as u64),
+        );
+
+        if let Some((_, kind)) = Self::check(&mut self.faults.lock(), Operation::Discard, range)? {
+            return Err(kind.into());
+        }
+
+        self.disk.discard(sector, sector_size, count)?;
+        self.count_write();
+        Ok(())
+    }
+}
+
+impl<D: Disk> Disk for FaultyDisk<D> {
+    fn read_sector(&self, sector: u64, buf: &mut [u8]) -> Result<(), DiskErr> {
+        self.read_sectors(sector, buf.len(), buf)
+    }
+
+    fn write_sector(&self, sector: u64, buf: &[u8]) -> Result<(), DiskErr> {
+        self.write_sectors(sector, buf.len(), buf)
+    }
+
+    fn disk_infos(&self) -> Result<DiskInfos, DiskErr> {
+        let mut infos = self.disk.disk_infos()?;
+
+        if self.faults.lock().writes_left == Some(0) {
+            infos.permissions.write = false;
+        }
+
+        Ok(infos)
+    }
+
+    fn read_sectors(&self, sector: u64, sector_size: usize, buf: &mut [u8]) -> Result<(), DiskErr> {
+        self.read_inner(sector, sector_size, buf)
+            .map_err(|e| e.at(Operation::Read, sector).in_layer("FaultyDisk"))
+    }
+
+    fn write_sectors(&self, sector: u64, sector_size: usize, buf: &[u8]) -> Result<(), DiskErr> {
+        self.write_inner(sector, sector_size, buf)
+            .map_err(|e| e.at(Operation::Write, sector).in_layer("FaultyDisk"))
+    }
+
+    fn discard(&self, sector: u64, sector_size: usize, count: u64) -> Result<(), DiskErr> {
+        self.discard_inner(sector, sector_size, count)
+            .map_err(|e| e.at(Operation::Discard, sector).in_layer("FaultyDisk"))
+    }
+
+    fn flush(&self) -> Result<(), DiskErr> {
+        Self::check(&mut self.faults.lock(), Operation::Flush, None)
+            .map_err(|e| e.during(Operation::Flush).in_layer("FaultyDisk"))?;
+
+        self.disk.flush()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{SectorSize, memdisk::MemDisk, wrappers::DiskWrapper};
+
+    fn disk() -> FaultyDisk<MemDisk> {
+        FaultyDisk::new(MemDisk::new(
+            64 * 512,
+            SectorSize::AllOf(vec![512, 4096]),
+            Permissions::read_write(),
+        ))
+    }
+
+    #[test]
+    fn failing_sectors() {
+        let disk = disk();
+        disk.fail_sectors(Some(Operation::Write), 10, 512, 2, DiskErrKind::IOErr);
+
+        disk.write_sectors(8, 512, &[1; 1024]).unwrap();
+        disk.write_sectors(12, 512, &[1; 512]).unwrap();
+        disk.read_sectors(10, 512, &mut [0; 1024]).unwrap();
+
+        let err = disk.write_sectors(9, 512, &[1; 1024]).unwrap_err();
+        assert_eq!(err.kind, DiskErrKind::IOErr);
+        assert_eq!(err.context.operation, Some(Operation::Write));
+        assert_eq!(err.context.lba, Some(9));
+        assert_eq!(err.context.layer, Some("FaultyDisk"));
+
+        // The range is in bytes: sector 1 of 4096 bytes covers the sectors 8 to 15 of 512 bytes
+        assert!(disk.write_sectors(1, 4096, &[1; 4096]).is_err());
+
+        disk.clear();
+        disk.write_sectors(10, 512, &[1; 512]).unwrap();
+    }
+
+    #[test]
+    fn failing_nth_operation() {
+        let disk = disk();
+        disk.fail_nth(Some(Operation::Read), 2, DiskErrKind::Busy);
+
+        disk.read_sector(0, &mut [0; 512]).unwrap();
+        disk.write_sector(0, &[0; 512]).unwrap();
+        disk.read_sector(1, &mut [0; 512]).unwrap();
+        let err = disk.read_sector(2, &mut [0; 512]).unwrap_err();
+        assert_eq!(err.kind, DiskErrKind::Busy);
+        assert_eq!(err.context.lba, Some(2));
+        disk.read_sector(3, &mut [0; 512]).unwrap();
+        assert_eq!(disk.operations(), 5);
+
+        disk.fail_nth(None, 0, DiskErrKind::UnreachableDisk);
+        let err = disk.flush().unwrap_err();
+        assert_eq!(err.kind, DiskErrKind::UnreachableDisk);
+        assert_eq!(err.context.operation, Some(Operation::Flush));
+        disk.flush().unwrap();
+    }
+
+    #[test]
+    fn torn_writes_and_bit_flips() {
+        let disk = disk();
+        disk.tear_write(1, 700, DiskErrKind::IOErr);
+
+        disk.write_sectors(0, 512, &[1; 1024]).unwrap();
+        assert!(disk.write_sectors(0, 512, &[2; 1024]).is_err());
+
+        let mut buf = [0; 1024];
+        disk.read_sectors(0, 512, &mut buf).unwrap();
+        assert!(buf[..700].iter().all(|&b| b == 2));
+        assert!(buf[700..].iter().all(|&b| b == 1));
+
+        disk.flip_bits(1000, 0x81);
+        disk.read_sectors(0, 512, &mut buf).unwrap();
+        assert_eq!(buf[1000], 1 ^ 0x81);
+        disk.inner().read_sectors(0, 512, &mut buf).unwrap();
+        assert_eq!(buf[1000], 1);
+    }
+
+    #[test]
+    fn read_only_after_writes() {
+        let disk = disk();
+        disk.read_only_after(2);
+
+        disk.write_sector(0, &[0; 512]).unwrap();
+        disk.discard(1, 512, 1).unwrap();
+        assert!(disk.disk_infos().unwrap().permissions == Permissions::read_only());
+
+        let err = disk.write_sector(2, &[0; 512]).unwrap_err();
+        assert!(matches!(err.kind, DiskErrKind::InvalidPermission { .. }));
+
+        disk.clear();
+        disk.write_sector(2, &[0; 512]).unwrap();
+    }
+
+    #[test]
+    fn context_of_the_lowest_layer() {
+        let faulty = disk();
+        faulty.fail_sectors(None, 10, 512, 1, DiskErrKind::IOErr);
+        let wrapper = DiskWrapper::new(faulty);
+        let sub = wrapper
+            .subdisk(4096, 16384, Permissions::read_write())
+            .unwrap();
+
+        sub.read_sectors(1, 512, &mut [0; 512]).unwrap();
+        let err = sub.read_sectors(2, 512, &mut [0; 512]).unwrap_err();
+        assert_eq!(err.kind, DiskErrKind::IOErr);
+        assert_eq!(err.context.layer, Some("FaultyDisk"));
+        assert_eq!(err.context.lba, Some(10));
+    }
+}
@@ -0,0 +1,184 @@
+use crate::{
+    FsErr,
+    async_disk::AsyncDisk,
+    filesystems::fat12::{
+        bpb::BiosParameterBlock, bytes_per_sector, decode_fat_entry, fat_entry_offset,
+        parse_boot_sector, probe_sector_size, root_dir_sector,
+    },
+};
+use alloc::{vec, vec::Vec};
+
+/// The asynchronous version of `Fat12`, reading a volume on an `AsyncDisk`. Every access goes
+/// through the futures of the disk. Read only: the volumes are formatted and modified with `Fat12`.
+pub struct AsyncFat12<D: AsyncDisk> {
+    bpb: BiosParameterBlock,
+    disk: D,
+    sector_size: usize,
+}
+
+impl<D: AsyncDisk> AsyncFat12<D> {
+    /// Reads a FAT12 volume from the given disk. Returns `None` if the boot sector doesn't hold a
+    /// valid BPB with this sector size.
+    pub async fn read_from_disk(
+        disk: D,
+        sector_size: Option<usize>,
+    ) -> Result<Option<Self>, FsErr> {
+        let sector_size = match sector_size {
+            Some(v) => v,
+            None => {
+                let probe = probe_sector_size(&disk.disk_infos().await?)?;
+                let mut first_sector = vec![0; probe];
+                disk.read_sectors(0, probe, &mut first_sector).await?;
+                bytes_per_sector(&first_sector)
+            }
+        };
+
+        if sector_size < 512 || sector_size.count_ones() != 1 {
+            return Err(FsErr::UnsupportedSectorSize { found: sector_size });
+        }
+
+        let mut first_sector = vec![0; sector_size];
+        disk.read_sectors(0, sector_size, &mut first_sector).await?;
+
+        Ok(
+            parse_boot_sector(&first_sector, sector_size).map(|bpb| Self {
+                bpb,
+                disk,
+                sector_size,
+            }),
+        )
+    }
+
+    pub const fn bios_parameter_block(&self) -> BiosParameterBlock {
+        self.bpb
+    }
+
+    pub const fn sector_size(&self) -> usize {
+        self.sector_size
+    }
+
+    pub const fn inner(&self) -> &D {
+        &self.disk
+    }
+
+    pub fn into_inner(self) -> D {
+        self.disk
+    }
+
+    pub async fn get_fat_entry(
+        &self,
+        index: usize,
+        fat_index: usize,
+    ) -> Result<Option<u16>, FsErr> {
+        // The data clusters are numbered from 2
+        if index >= self.bpb.count_of_clusters() + 2 || fat_index >= self.bpb.number_of_fats() {
+            return Ok(None);
+        }
+
+        let offset = fat_entry_offset(&self.bpb, index + index / 2, fat_index);
+        let sector_size = self.sector_size as u64;
+        let within = (offset % sector_size) as usize;
+
+        // The entry may straddle two sectors, the FAT is followed by at least the root directory
+        // or the data region
+        let count = if within + 2 > self.sector_size { 2 } else { 1 };
+        let mut buf = vec![0; count * self.sector_size];
+        self.disk
+            .read_sectors(offset / sector_size, self.sector_size, &mut buf)
+            .await?;
+
+        Ok(Some(decode_fat_entry(
+            index,
+            [buf[within], buf[within + 1]],
+        )))
+    }
+
+    /// Reads the root directory: the raw 32-byte entries, including the free ones
+    pub async fn read_root_dir(&self) -> Result<Vec<u8>, FsErr> {
+        let mut buf = vec![0; self.bpb.root_dir_sectors() * self.sector_size];
+        self.disk
+            .read_sectors(root_dir_sector(&self.bpb), self.sector_size, &mut buf)
+            .await?;
+
+        Ok(buf)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        Disk, Permissions, SectorSize,
+        async_disk::tests::{YieldingDisk, run_local},
+        filesystems::fat12::Fat12,
+        memdisk::MemDisk,
+    };
+    use alloc::sync::Arc;
+
+    fn mem_disk() -> Arc<MemDisk> {
+        Arc::new(MemDisk::new(
+            1 << 20,
+            SectorSize::AllOf(vec![512]),
+            Permissions::read_write(),
+        ))
+    }
+
+    #[test]
+    fn same_as_fat12() {
+        let disk = mem_disk();
+        let fat = Fat12::new(disk.clone(), 224, 2, 0, Some(512), Some(1))
+            .unwrap()
+            .unwrap();
+
+        // The entry of the cluster 341 straddles the first two sectors of the FATs
+        for (index, value) in [(2, 3), (3, 0xFFF), (340, 341), (341, 342), (342, 0xFF7)] {
+            fat.set_fat_entry(index, value).unwrap();
+        }
+
+        let root_dir = (0..224 * 32).map(|i| i as u8).collect::<Vec<_>>();
+        disk.write_sectors(root_dir_sector(&fat.bios_parameter_block()), 512, &root_dir)
+            .unwrap();
+
+        let async_fat = run_local(AsyncFat12::read_from_disk(
+            YieldingDisk::new(disk.clone()),
+            None,
+        ))
+        .unwrap()
+        .unwrap();
+        assert_eq!(async_fat.sector_size(), 512);
+
+        let max = fat.bios_parameter_block().count_of_clusters() + 2;
+        run_local(async {
+            for fat_index in 0..3 {
+                for index in [0, 1, 2, 3, 4, 340, 341, 342, max - 1, max] {
+                    assert_eq!(
+                        async_fat.get_fat_entry(index, fat_index).await.unwrap(),
+                        fat.get_fat_entry(index, fat_index).unwrap()
+                    );
+                }
+            }
+
+            assert_eq!(async_fat.get_fat_entry(341, 1).await.unwrap(), Some(342));
+            assert_eq!(async_fat.read_root_dir().await.unwrap(), root_dir);
+        });
+        assert_eq!(fat.read_root_dir().unwrap(), root_dir);
+    }
+
+    #[test]
+    fn invalid_boot_sector() {
+        let disk = YieldingDisk::new(mem_disk());
+
+        run_local(async {
+            assert!(
+                AsyncFat12::read_from_disk(&disk, Some(512))
+                    .await
+                    .unwrap()
+                    .is_none()
+            );
+            assert!(matches!(
+                AsyncFat12::read_from_disk(&disk, Some(768)).await,
+                Err(FsErr::UnsupportedSectorSize { found: 768 })
+            ));
+        });
+    }
+}
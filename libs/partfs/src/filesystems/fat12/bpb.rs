@@ -195,4 +195,20 @@ impl BiosParameterBlock {
     pub const fn fat_size(&self) -> usize {
         self.fat_size as usize
     }
+
+    pub const fn sectors_per_cluster(&self) -> usize {
+        self.sectors_per_cluster as usize
+    }
+
+    /// Count of sectors occupied by the root directory
+    pub const fn root_dir_sectors(&self) -> usize {
+        ((self.root_entries_count as usize) * 32).div_ceil(self.bytes_per_sector as usize)
+    }
+
+    /// First sector of the data region, where the cluster 2 starts
+    pub const fn first_data_sector(&self) -> usize {
+        self.reserved_sectors_count()
+            + self.number_of_fats() * self.fat_size()
+            + self.root_dir_sectors()
+    }
 }
@@ -1,54 +1,147 @@
-use crate::{Disk, DiskErr, filesystems::fat12::bpb::BiosParameterBlock, wrappers::DiskWrapper};
-use alloc::{sync::Arc, vec};
+use crate::{
+    Disk, DiskInfos, FsErr, bytedisk::ByteDisk, filesystems::fat12::bpb::BiosParameterBlock,
+    wrappers::DiskWrapper,
+};
+use alloc::{sync::Arc, vec, vec::Vec};
 
+pub mod async_fat12;
 pub mod bpb;
 
+/// FAT entry of a free cluster
+pub const FREE_CLUSTER: u16 = 0x000;
+/// FAT entry of a bad cluster
+pub const BAD_CLUSTER: u16 = 0xFF7;
+/// FAT entries greater or equal to this value mark the end of a cluster chain
+pub const END_OF_CHAIN: u16 = 0xFF8;
+
 pub struct Fat12 {
     bpb: BiosParameterBlock,
     disk: Arc<DiskWrapper>,
     sector_size: usize,
 }
 
+/// Returns true if FAT12 can use this sector size on this disk
+fn is_valid_sector_size(sector_size: usize, infos: &DiskInfos) -> bool {
+    (512..=4096).contains(&sector_size)
+        && sector_size.is_power_of_two()
+        && infos.sector_size.is_supported(sector_size, infos.disk_size)
+}
+
+/// Returns the sector size to use to format the disk, or 0 if there is none. The physical sector
+/// size is preferred when the disk starts on a physical sector, so that the clusters never share
+/// a physical sector. Otherwise, the logical sector size is used.
+fn default_sector_size(infos: &DiskInfos) -> usize {
+    let geometry = &infos.geometry;
+    let physical = geometry.physical_sector_size;
+
+    if physical != 0
+        && geometry.alignment_offset.is_multiple_of(physical as u64)
+        && is_valid_sector_size(physical, infos)
+    {
+        return physical;
+    }
+
+    if is_valid_sector_size(geometry.logical_sector_size, infos) {
+        return geometry.logical_sector_size;
+    }
+
+    match infos.sector_size.powers_of_two().minimal_ge(512) {
+        Some(v) if is_valid_sector_size(v, infos) => v,
+        _ => 0,
+    }
+}
+
+/// Returns the sector size to read the boot sector with. The BPB is in its first 512 bytes, and
+/// gives the actual sector size of the volume.
+fn probe_sector_size(infos: &DiskInfos) -> Result<usize, FsErr> {
+    match infos.sector_size.minimal_ge(512) {
+        Some(v) if infos.sector_size.is_supported(v, infos.disk_size) => Ok(v),
+        _ => Err(FsErr::UnsupportedSectorSize { found: 512 }),
+    }
+}
+
+/// Reads the sector size of the volume in its boot sector
+fn bytes_per_sector(first_sector: &[u8]) -> usize {
+    let mut bs = [0; 512];
+    bs.copy_from_slice(&first_sector[..512]);
+    BiosParameterBlock::from_bytes(bs).bytes_per_sector()
+}
+
+/// Parses the BPB from the first sector of the volume, and checks it
+fn parse_boot_sector(first_sector: &[u8], sector_size: usize) -> Option<BiosParameterBlock> {
+    let mut bs = [0; 512];
+    bs.copy_from_slice(&first_sector[..512]);
+
+    let bpb = BiosParameterBlock::from_bytes(bs);
+
+    if !bpb.is_valid() || bpb.bytes_per_sector() != sector_size {
+        return None;
+    }
+
+    Some(bpb)
+}
+
+/// Returns the offset in bytes on the disk of the byte `fat_offset` of the FAT `fat_index`
+fn fat_entry_offset(bpb: &BiosParameterBlock, fat_offset: usize, fat_index: usize) -> u64 {
+    (bpb.reserved_sectors_count() as u64 + (fat_index * bpb.fat_size()) as u64)
+        * bpb.bytes_per_sector() as u64
+        + fat_offset as u64
+}
+
+/// Extracts the FAT entry of the cluster `index` from the two bytes holding it
+fn decode_fat_entry(index: usize, bytes: [u8; 2]) -> u16 {
+    let entry = u16::from_le_bytes(bytes);
+
+    if (index & 1) == 1 {
+        entry >> 4
+    } else {
+        entry & 0xFFF
+    }
+}
+
+/// Returns the LBA of the first sector of the root directory
+fn root_dir_sector(bpb: &BiosParameterBlock) -> u64 {
+    (bpb.first_data_sector() - bpb.root_dir_sectors()) as u64
+}
+
 impl Fat12 {
     pub fn read_from_disk<T: Disk + 'static>(
         disk: T,
         sector_size: Option<usize>,
-    ) -> Result<Option<Self>, DiskErr> {
+    ) -> Result<Option<Self>, FsErr> {
         let disk = DiskWrapper::new(disk);
-        let sector_size = sector_size.unwrap_or_else(|| {
-            let infos = match disk.disk_infos() {
-                Ok(v) => v,
-                Err(_) => return 0,
-            };
-            for i in [512, 1024, 2048, 4096] {
-                if infos.sector_size.is_supported(i, infos.disk_size) {
-                    return i;
-                }
+
+        let sector_size = match sector_size {
+            Some(v) => v,
+            None => {
+                let probe = probe_sector_size(&disk.disk_infos()?)?;
+                let mut first_sector = vec![0; probe];
+                disk.read_sector(0, &mut first_sector)?;
+                bytes_per_sector(&first_sector)
             }
-            0
-        });
+        };
 
         if sector_size < 512 || sector_size.count_ones() != 1 {
-            return Err(DiskErr::UnsupportedDiskSectorSize);
+            return Err(FsErr::UnsupportedSectorSize { found: sector_size });
         }
 
         let mut first_sector = vec![0; sector_size];
         disk.read_sector(0, &mut first_sector)?;
 
-        let mut bs = [0; 512];
-        bs.copy_from_slice(&first_sector[..512]);
-
-        let bpb = BiosParameterBlock::from_bytes(bs);
-
-        if !bpb.is_valid() || bpb.bytes_per_sector() != sector_size {
-            return Ok(None);
-        }
+        Ok(Self::from_first_sector(&first_sector, disk, sector_size))
+    }
 
-        Ok(Some(Self {
+    /// Parses the BPB from the first sector of the volume, and checks it
+    fn from_first_sector(
+        first_sector: &[u8],
+        disk: Arc<DiskWrapper>,
+        sector_size: usize,
+    ) -> Option<Self> {
+        parse_boot_sector(first_sector, sector_size).map(|bpb| Self {
             bpb,
             disk,
             sector_size,
-        }))
+        })
     }
 
     pub fn new<T: Disk + 'static>(
@@ -58,23 +151,16 @@ impl Fat12 {
         hidden_sectors: usize,
         sector_size: Option<usize>,
         sectors_per_cluster: Option<usize>,
-    ) -> Result<Option<Self>, DiskErr> {
+    ) -> Result<Option<Self>, FsErr> {
         let disk = DiskWrapper::new(disk);
         let disk_infos = disk.disk_infos()?;
 
-        let sector_size = sector_size.unwrap_or_else(|| {
-            for i in [512, 1024, 2048, 4096] {
-                if disk_infos.sector_size.is_supported(i, disk_infos.disk_size) {
-                    return i;
-                }
-            }
-            0
-        });
+        let sector_size = sector_size.unwrap_or_else(|| default_sector_size(&disk_infos));
 
         if sector_size < 512
             || sector_size.count_ones() != 1
             || sector_size > 0xFFFF
-            || (root_dir_entries * 32).is_multiple_of(sector_size)
+            || !(root_dir_entries * 32).is_multiple_of(sector_size)
             || number_of_fats > 0xFF
             || root_dir_entries > 0xFFFF
         {
@@ -82,21 +168,25 @@ impl Fat12 {
         }
 
         let root_dir_sectors = (root_dir_entries * 32) / sector_size;
-        let total_sectors = disk_infos.disk_size / sector_size;
+        // FAT12 counts the sectors on 32 bits
+        let total_sectors = match usize::try_from(disk_infos.disk_size / sector_size as u64) {
+            Ok(v) if v <= 0xFFFFFFFF => v,
+            _ => return Ok(None),
+        };
 
         let sectors_per_cluster = match sectors_per_cluster {
             Some(v) => v,
             None => {
                 // TODO: optimize the `sectors_per_cluster` choice to get the most possible sectors
                 // with fewer reserved sectors
-                ((total_sectors - root_dir_sectors - 1).div_ceil(4085)).next_power_of_two()
+                // A cluster is never smaller than a physical sector of the disk
+                ((total_sectors - root_dir_sectors - 1).div_ceil(4085))
+                    .max(disk_infos.geometry.physical_sector_size / sector_size)
+                    .next_power_of_two()
             }
         };
 
-        if sectors_per_cluster.count_ones() != 1
-            || sectors_per_cluster > 0xFF
-            || total_sectors > 0xFFFFFFFF
-        {
+        if sectors_per_cluster.count_ones() != 1 || sectors_per_cluster > 0xFF {
             return Ok(None);
         }
 
@@ -145,14 +235,18 @@ impl Fat12 {
         }
 
         let bytes = bpb.to_bytes();
-        let mut sector = vec![0; sector_size];
+        let system_sectors = reserved_sectors + number_of_fats * fat_size + root_dir_sectors;
 
-        for i in 0..(reserved_sectors + number_of_fats * fat_size + root_dir_sectors) {
-            disk.write_sector(i, &sector)?;
-        }
+        // Discarded sectors read as zeroes, and disk images stay sparse
+        disk.discard(0, sector_size, system_sectors as u64)?;
+
+        // The BPB is what makes the volume valid, so it must only reach the disk once the rest of
+        // the system area is cleared.
+        disk.flush()?;
 
+        let mut sector = vec![0; sector_size];
         sector[..512].copy_from_slice(&bytes);
-        disk.write_sector(0, &sector)?;
+        disk.write_sectors_fua(0, sector_size, &sector)?;
 
         Ok(Some(Self {
             bpb,
@@ -161,6 +255,12 @@ impl Fat12 {
         }))
     }
 
+    /// Returns the LBA of the first sector of the cluster `cluster` (numbered from 2)
+    fn cluster_sector(&self, cluster: usize) -> u64 {
+        self.bpb.first_data_sector() as u64
+            + ((cluster - 2) * self.bpb.sectors_per_cluster()) as u64
+    }
+
     pub const fn bios_parameter_block(&self) -> BiosParameterBlock {
         self.bpb
     }
@@ -169,35 +269,345 @@ impl Fat12 {
         self.sector_size
     }
 
-    pub fn get_fat_entry(&self, index: usize, fat_index: usize) -> Result<Option<u16>, DiskErr> {
-        if index >= self.bpb.count_of_clusters() || fat_index >= self.bpb.number_of_fats() {
+    /// Flushes the disk, so that every change made to the volume is durable. Needed when the disk
+    /// caches the writes (see `CachedDisk`).
+    pub fn flush(&self) -> Result<(), FsErr> {
+        Ok(self.disk.flush()?)
+    }
+
+    pub fn get_fat_entry(&self, index: usize, fat_index: usize) -> Result<Option<u16>, FsErr> {
+        // The data clusters are numbered from 2
+        if index >= self.bpb.count_of_clusters() + 2 || fat_index >= self.bpb.number_of_fats() {
             return Ok(None);
         }
 
         let fat_offset = index + index / 2;
-        let sector_number = self.bpb.reserved_sectors_count()
-            + (fat_offset / self.sector_size)
-            + fat_index * self.bpb.fat_size();
-        let fat_entry_offset = fat_offset % self.sector_size;
+        let offset = fat_entry_offset(&self.bpb, fat_offset, fat_index);
+
+        // The entry may straddle two sectors, `ByteDisk` takes care of it
+        let mut bytes = [0; 2];
+        ByteDisk::with_sector_size(&*self.disk, self.sector_size)?.read_at(offset, &mut bytes)?;
 
-        let mut sectors = vec![0; 2 * self.sector_size];
+        Ok(Some(decode_fat_entry(index, bytes)))
+    }
 
+    /// Reads the root directory: the raw 32-byte entries, including the free ones
+    pub fn read_root_dir(&self) -> Result<Vec<u8>, FsErr> {
+        let mut buf = vec![0; self.bpb.root_dir_sectors() * self.sector_size];
         self.disk
-            .read_sector(sector_number, &mut sectors[..self.sector_size])?;
-        if fat_offset == self.sector_size - 1 {
-            self.disk
-                .read_sector(sector_number + 1, &mut sectors[self.sector_size..])?;
+            .read_sectors(root_dir_sector(&self.bpb), self.sector_size, &mut buf)?;
+
+        Ok(buf)
+    }
+
+    /// Sets the FAT entry of the cluster `index` in all the FATs. Only the 12 lower bits of `value`
+    /// are used.
+    pub fn set_fat_entry(&self, index: usize, value: u16) -> Result<(), FsErr> {
+        let max = self.bpb.count_of_clusters() + 2;
+        if !(2..max).contains(&index) {
+            return Err(FsErr::InvalidCluster { found: index, max });
         }
 
-        let mut entry =
-            u16::from_le_bytes([sectors[fat_entry_offset], sectors[fat_entry_offset + 1]]);
+        let disk = ByteDisk::with_sector_size(&*self.disk, self.sector_size)?;
+        let fat_offset = index + index / 2;
 
-        if (index & 1) == 1 {
-            entry >>= 4
-        } else {
-            entry &= 0xFFF
+        for fat_index in 0..self.bpb.number_of_fats() {
+            let offset = fat_entry_offset(&self.bpb, fat_offset, fat_index);
+
+            // Two entries share the byte in the middle, the other one must be kept
+            let mut bytes = [0; 2];
+            disk.read_at(offset, &mut bytes)?;
+
+            let mut entry = u16::from_le_bytes(bytes);
+            if (index & 1) == 1 {
+                entry = (entry & 0x000F) | (value << 4);
+            } else {
+                entry = (entry & 0xF000) | (value & 0x0FFF);
+            }
+
+            disk.write_at(offset, &entry.to_le_bytes())?;
+        }
+
+        Ok(())
+    }
+
+    /// Returns the clusters of the chain starting at `first_cluster`, in order. Fails with
+    /// `FsErr::CorruptedChain` if the chain loops, or if it links to a cluster which can't be part
+    /// of a chain.
+    pub fn cluster_chain(&self, first_cluster: usize) -> Result<Vec<usize>, FsErr> {
+        let max = self.bpb.count_of_clusters() + 2;
+        if !(2..max).contains(&first_cluster) {
+            return Err(FsErr::InvalidCluster {
+                found: first_cluster,
+                max,
+            });
+        }
+
+        let mut chain = vec![first_cluster];
+        let mut in_chain = vec![false; max];
+        in_chain[first_cluster] = true;
+
+        loop {
+            let cluster = chain[chain.len() - 1];
+            let next = match self.get_fat_entry(cluster, 0)? {
+                Some(v) if v >= END_OF_CHAIN => return Ok(chain),
+                Some(v) => v as usize,
+                None => {
+                    return Err(FsErr::InvalidCluster {
+                        found: cluster,
+                        max,
+                    });
+                }
+            };
+
+            // Also rejects the free and bad clusters, outside of [2, max[
+            if !(2..max).contains(&next) || in_chain[next] {
+                return Err(FsErr::CorruptedChain { cluster, next });
+            }
+
+            in_chain[next] = true;
+            chain.push(next);
+        }
+    }
+
+    /// Frees all the clusters of the chain starting at `first_cluster`, and returns the count of
+    /// freed clusters. If `discard` is set, the data of the freed clusters is discarded, which
+    /// releases the space on disks supporting it. The chain is checked first (see
+    /// `cluster_chain`), nothing is freed if it is corrupted.
+    pub fn free_cluster_chain(&self, first_cluster: usize, discard: bool) -> Result<usize, FsErr> {
+        let sectors_per_cluster = self.bpb.sectors_per_cluster();
+        let chain = self.cluster_chain(first_cluster)?;
+
+        // Contiguous clusters are discarded with a single request: (first cluster, count)
+        let mut run: Option<(usize, usize)> = None;
+
+        for &cluster in &chain {
+            self.set_fat_entry(cluster, FREE_CLUSTER)?;
+
+            if discard {
+                run = match run {
+                    Some((start, count)) if start + count == cluster => Some((start, count + 1)),
+                    Some((start, count)) => {
+                        self.disk.discard(
+                            self.cluster_sector(start),
+                            self.sector_size,
+                            (count * sectors_per_cluster) as u64,
+                        )?;
+                        Some((cluster, 1))
+                    }
+                    None => Some((cluster, 1)),
+                };
+            }
+        }
+
+        if let Some((start, count)) = run {
+            self.disk.discard(
+                self.cluster_sector(start),
+                self.sector_size,
+                (count * sectors_per_cluster) as u64,
+            )?;
+        }
+
+        Ok(chain.len())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        Operation, Permissions, SectorSize,
+        memdisk::MemDisk,
+        tracing::{Trace, TracingDisk},
+    };
+
+    /// A 1 MiB disk filled with 0xFF
+    fn mem_disk() -> Arc<MemDisk> {
+        Arc::new(MemDisk::from_vec(
+            vec![0xFF; 1 << 20],
+            SectorSize::AllOf(vec![512]),
+            Permissions::read_write(),
+        ))
+    }
+
+    fn format(disk: Arc<MemDisk>) -> Fat12 {
+        Fat12::new(disk, 224, 2, 0, Some(512), Some(1))
+            .unwrap()
+            .unwrap()
+    }
+
+    #[test]
+    fn formatting() {
+        let disk = mem_disk();
+        let trace = Trace::new();
+        let traced = TracingDisk::new(disk.clone(), trace.clone(), "disk");
+        let fat = Fat12::new(traced, 224, 2, 0, Some(512), Some(1))
+            .unwrap()
+            .unwrap();
+        let bpb = fat.bios_parameter_block();
+        let system_sectors = bpb.first_data_sector() as u64;
+
+        // The system area is discarded, then the BPB written once the discard is durable
+        let requests = trace
+            .records()
+            .into_iter()
+            .filter(|r| r.operation != Operation::DiskInfos)
+            .map(|r| (r.operation, r.fua, r.sector, r.len))
+            .collect::<Vec<_>>();
+        assert_eq!(
+            requests,
+            [
+                (Operation::Discard, false, 0, system_sectors * 512),
+                (Operation::Flush, false, 0, 0),
+                (Operation::Write, true, 0, 512),
+            ]
+        );
+
+        let mut content = vec![0; 1 << 20];
+        disk.read_sectors(0, 512, &mut content).unwrap();
+        assert_eq!(content[..512], bpb.to_bytes());
+        let data_start = system_sectors as usize * 512;
+        assert!(content[512..data_start].iter().all(|&b| b == 0));
+        assert!(content[data_start..].iter().all(|&b| b == 0xFF));
+
+        let read = Fat12::read_from_disk(disk, None).unwrap().unwrap();
+        assert_eq!(read.get_fat_entry(2, 1).unwrap(), Some(FREE_CLUSTER));
+        assert_eq!(read.read_root_dir().unwrap(), vec![0; 224 * 32]);
+
+        // The root directory must fill whole sectors
+        assert!(
+            Fat12::new(mem_disk(), 200, 2, 0, Some(512), Some(1))
+                .unwrap()
+                .is_none()
+        );
+    }
+
+    #[test]
+    fn free_chains() {
+        let disk = mem_disk();
+        let fat = format(disk.clone());
+        let cluster_data = |cluster| {
+            let mut buf = [0; 512];
+            disk.read_sectors(fat.cluster_sector(cluster), 512, &mut buf)
+                .unwrap();
+            buf
+        };
+
+        // 5 -> 6 -> 7 -> 9 and 20 -> 21, 8 isn't part of a chain
+        for (cluster, next) in [(5, 6), (6, 7), (7, 9), (9, 0xFFF), (20, 21), (21, 0xFF8)] {
+            fat.set_fat_entry(cluster, next).unwrap();
         }
+        assert_eq!(fat.cluster_chain(5).unwrap(), [5, 6, 7, 9]);
+
+        let trace = Trace::new();
+        let traced = Arc::new(TracingDisk::new(disk.clone(), trace.clone(), "disk"));
+        let fat = Fat12::read_from_disk(traced, None).unwrap().unwrap();
+        assert_eq!(fat.free_cluster_chain(5, true).unwrap(), 4);
 
-        Ok(Some(entry))
+        for cluster in [5, 6, 7, 9] {
+            for fat_index in 0..2 {
+                assert_eq!(fat.get_fat_entry(cluster, fat_index).unwrap(), Some(0));
+            }
+            assert_eq!(cluster_data(cluster), [0; 512]);
+        }
+        assert_eq!(cluster_data(8), [0xFF; 512]);
+
+        // One discard by run of contiguous clusters
+        let discards = trace
+            .records()
+            .into_iter()
+            .filter(|r| r.operation == Operation::Discard)
+            .map(|r| (r.sector, r.len))
+            .collect::<Vec<_>>();
+        assert_eq!(
+            discards,
+            [
+                (fat.cluster_sector(5), 3 * 512),
+                (fat.cluster_sector(9), 512)
+            ]
+        );
+
+        // Without discard, the data is kept
+        assert_eq!(fat.free_cluster_chain(20, false).unwrap(), 2);
+        assert_eq!(fat.get_fat_entry(20, 0).unwrap(), Some(0));
+        assert_eq!(cluster_data(20), [0xFF; 512]);
+        assert_eq!(cluster_data(21), [0xFF; 512]);
+    }
+
+    #[test]
+    fn corrupted_chains() {
+        let fat = format(mem_disk());
+        let max = fat.bios_parameter_block().count_of_clusters() + 2;
+        let links = [
+            // Loops
+            (10, 11),
+            (11, 12),
+            (12, 11),
+            (15, 15),
+            // Out of the volume, free, reserved and bad
+            (20, max as u16),
+            (30, 31),
+            (40, 1),
+            (50, BAD_CLUSTER),
+        ];
+        for (cluster, next) in links {
+            fat.set_fat_entry(cluster, next).unwrap();
+        }
+
+        let table = [
+            (
+                10,
+                FsErr::CorruptedChain {
+                    cluster: 12,
+                    next: 11,
+                },
+            ),
+            (
+                15,
+                FsErr::CorruptedChain {
+                    cluster: 15,
+                    next: 15,
+                },
+            ),
+            (
+                20,
+                FsErr::CorruptedChain {
+                    cluster: 20,
+                    next: max,
+                },
+            ),
+            (
+                30,
+                FsErr::CorruptedChain {
+                    cluster: 31,
+                    next: 0,
+                },
+            ),
+            (
+                40,
+                FsErr::CorruptedChain {
+                    cluster: 40,
+                    next: 1,
+                },
+            ),
+            (
+                50,
+                FsErr::CorruptedChain {
+                    cluster: 50,
+                    next: 0xFF7,
+                },
+            ),
+            (1, FsErr::InvalidCluster { found: 1, max }),
+            (max, FsErr::InvalidCluster { found: max, max }),
+        ];
+
+        for (first_cluster, err) in table {
+            assert_eq!(fat.free_cluster_chain(first_cluster, true), Err(err));
+        }
+
+        // Nothing was freed
+        for (cluster, next) in links {
+            assert_eq!(fat.get_fat_entry(cluster, 0).unwrap(), Some(next));
+        }
     }
 }
@@ -0,0 +1,473 @@
+use crate::{
+    DeviceId, Disk, DiskErr, DiskErrKind, DiskInfos, Geometry, ImageErr, Operation, Permissions,
+    SectorSize,
+    bytedisk::ByteDisk,
+    checksum::crc32_update,
+    deflate::{self, Deflater, Inflater, Source},
+    std_helpers::path_hash,
+};
+use mutex::Mutex;
+use std::{
+    fs::{self, File},
+    io::{self, Read, Seek, SeekFrom, Write},
+    path::PathBuf,
+};
+
+const MAGIC: [u8; 2] = [0x1F, 0x8B];
+const METHOD_DEFLATE: u8 = 8;
+
+const FLAG_HCRC: u8 = 1 << 1;
+const FLAG_EXTRA: u8 = 1 << 2;
+const FLAG_NAME: u8 = 1 << 3;
+const FLAG_COMMENT: u8 = 1 << 4;
+
+/// Uncompressed bytes between two checkpoints of the index when none is given
+pub const DEFAULT_SPAN: u64 = 1 << 20;
+
+/// Size of the reads on the exported disk, and of the buffer used to skip data
+const IO_SIZE: usize = 1 << 20;
+
+fn invalid(reason: &'static str) -> DiskErr {
+    DiskErr::new(DiskErrKind::InvalidImage { reason })
+}
+
+impl Source for File {
+    fn read(&mut self, buf: &mut [u8]) -> Result<usize, DiskErr> {
+        Read::read(self, buf).map_err(|e| DiskErr::from_io(&e))
+    }
+}
+
+/// A position where the decompression can be resumed, at a block boundary
+struct Checkpoint {
+    /// Position in the uncompressed data
+    offset: u64,
+    /// Position in the file, in bits
+    input: u64,
+    /// The uncompressed data preceding the checkpoint, compressed to keep the index small
+    window: Vec<u8>,
+}
+
+struct State {
+    /// The decompression in progress, `None` after an error
+    inflater: Option<Inflater<File>>,
+    /// Position in the uncompressed data of the next byte of `inflater`
+    offset: u64,
+}
+
+/// A raw disk image compressed with gzip, like the `.img.gz` files. Read only.
+///
+/// The whole image is decompressed once when opened to build an index of checkpoints (like zlib's
+/// `zran`), from which the reads resume the decompression. The reads continuing the previous one
+/// resume where it stopped.
+pub struct GzipDisk {
+    size: u64,
+    checkpoints: Vec<Checkpoint>,
+    device_id: Option<DeviceId>,
+    file: File,
+    state: Mutex<State>,
+}
+
+impl Disk for GzipDisk {
+    fn read_sector(&self, sector: u64, buf: &mut [u8]) -> Result<(), DiskErr> {
+        self.read_sectors(sector, buf.len(), buf)
+    }
+
+    fn write_sector(&self, sector: u64, buf: &[u8]) -> Result<(), DiskErr> {
+        self.write_sectors(sector, buf.len(), buf)
+    }
+
+    fn disk_infos(&self) -> Result<DiskInfos, DiskErr> {
+        Ok(DiskInfos {
+            sector_size: SectorSize::Any,
+            disk_size: self.size,
+            permissions: Permissions::read_only(),
+            geometry: Geometry::from_sector_size(&SectorSize::Any, self.size),
+            device_id: self.device_id.clone(),
+        })
+    }
+
+    fn read_sectors(&self, sector: u64, sector_size: usize, buf: &mut [u8]) -> Result<(), DiskErr> {
+        self.offset(sector, sector_size, buf.len() as u64)
+            .and_then(|offset| self.read_at(offset, buf))
+            .map_err(|e| e.at(Operation::Read, sector).in_layer("GzipDisk"))
+    }
+
+    fn write_sectors(&self, sector: u64, _: usize, _: &[u8]) -> Result<(), DiskErr> {
+        Err(DiskErr::new(DiskErrKind::InvalidPermission {
+            disk_permissions: Permissions::read_only(),
+        })
+        .at(Operation::Write, sector)
+        .in_layer("GzipDisk"))
+    }
+}
+
+impl GzipDisk {
+    /// Opens an existing image, and builds its index with a checkpoint every `span` bytes of
+    /// uncompressed data ([`DEFAULT_SPAN`] if `None`). Each checkpoint costs a compressed copy of
+    /// the 32 KiB preceding it. The checksums of all the gzip members are verified.
+    pub fn open(path: PathBuf, span: Option<u64>) -> Result<Self, ImageErr> {
+        let io = |e: io::Error| ImageErr::Disk(DiskErr::from_io(&e).in_layer("GzipDisk"));
+        let invalid_header = |reason| ImageErr::InvalidHeader { reason };
+        let span = span.unwrap_or(DEFAULT_SPAN);
+
+        let file = File::open(&path).map_err(io)?;
+        let mut inflater = Inflater::new(file);
+
+        match read_member_header(&mut inflater) {
+            Ok(true) => {}
+            Ok(false) | Err(_) => return Err(invalid_header("not a gzip image")),
+        }
+
+        // ### INDEX ###
+
+        let mut checkpoints: Vec<Checkpoint> = Vec::new();
+        let mut buf = vec![0; IO_SIZE];
+        let mut offset = 0;
+        let mut member_start = 0;
+        let mut crc = 0;
+
+        loop {
+            if inflater.at_block_start()
+                && checkpoints.last().is_none_or(|c| offset - c.offset >= span)
+            {
+                checkpoints.push(Checkpoint {
+                    offset,
+                    input: inflater.position(),
+                    window: deflate::compress(&inflater.window()),
+                });
+            }
+
+            let n = inflater
+                .read(&mut buf)
+                .map_err(|e| ImageErr::Disk(e.in_layer("GzipDisk")))?;
+
+            if n > 0 {
+                crc = crc32_update(crc, &buf[..n]);
+                offset += n as u64;
+                continue;
+            }
+
+            let mut trailer = [0; 8];
+            read_exact(&mut inflater, &mut trailer)
+                .map_err(|e| ImageErr::Disk(e.in_layer("GzipDisk")))?;
+
+            if u32::from_le_bytes(trailer[0..4].try_into().unwrap()) != crc
+                || u32::from_le_bytes(trailer[4..8].try_into().unwrap())
+                    != (offset - member_start) as u32
+            {
+                return Err(ImageErr::Disk(
+                    DiskErr::new(DiskErrKind::ChecksumMismatch {
+                        lba: member_start / 512,
+                    })
+                    .in_layer("GzipDisk"),
+                ));
+            }
+
+            if !read_member_header(&mut inflater)
+                .map_err(|e| ImageErr::Disk(e.in_layer("GzipDisk")))?
+            {
+                break;
+            }
+
+            inflater.restart();
+            member_start = offset;
+            crc = 0;
+        }
+
+        let device_id = fs::canonicalize(&path)
+            .ok()
+            .map(|p| DeviceId::Image(path_hash(&p)));
+
+        Ok(Self {
+            size: offset,
+            checkpoints,
+            device_id,
+            file: inflater.into_source(),
+            state: Mutex::new(State {
+                inflater: None,
+                offset: 0,
+            }),
+        })
+    }
+
+    fn read_at(&self, offset: u64, buf: &mut [u8]) -> Result<(), DiskErr> {
+        let mut state = self.state.lock();
+
+        let checkpoint = &self.checkpoints[self
+            .checkpoints
+            .partition_point(|c| c.offset <= offset)
+            .saturating_sub(1)];
+
+        // Resumes from the checkpoint, unless the current decompression is closer
+        let mut inflater = match state.inflater.take() {
+            Some(inflater) if (checkpoint.offset..=offset).contains(&state.offset) => inflater,
+            _ => {
+                let mut file = self.file.try_clone().map_err(|e| DiskErr::from_io(&e))?;
+                file.seek(SeekFrom::Start(checkpoint.input / 8))
+                    .map_err(|e| DiskErr::from_io(&e))?;
+                let window = deflate::decompress(&checkpoint.window)?;
+                state.offset = checkpoint.offset;
+                Inflater::resume(file, (checkpoint.input % 8) as u32, &window)?
+            }
+        };
+
+        let mut skip = vec![0; ((offset - state.offset) as usize).min(IO_SIZE)];
+        while state.offset < offset {
+            let len = ((offset - state.offset) as usize).min(IO_SIZE);
+            state.offset += read_data(&mut inflater, &mut skip[..len])? as u64;
+        }
+
+        let mut n = 0;
+        while n < buf.len() {
+            n += read_data(&mut inflater, &mut buf[n..])?;
+        }
+
+        state.offset += n as u64;
+        state.inflater = Some(inflater);
+        Ok(())
+    }
+
+    /// Checks a request of `len` bytes starting at the LBA `sector` and returns its offset in the
+    /// virtual disk.
+    fn offset(&self, sector: u64, sector_size: usize, len: u64) -> Result<u64, DiskErr> {
+        if sector_size == 0
+            || sector_size as u64 > self.size
+            || !len.is_multiple_of(sector_size as u64)
+        {
+            return Err(DiskErrKind::InvalidSectorSize {
+                found: sector_size,
+                supported: SectorSize::Any,
+                start: 0,
+            }
+            .into());
+        }
+
+        match sector.checked_mul(sector_size as u64) {
+            Some(offset) if offset.checked_add(len).is_some_and(|end| end <= self.size) => {
+                Ok(offset)
+            }
+            _ => Err(DiskErrKind::InvalidSectorIndex {
+                found: sector,
+                max: (self.size / sector_size as u64).saturating_sub(1),
+            }
+            .into()),
+        }
+    }
+}
+
+fn read_exact(inflater: &mut Inflater<File>, buf: &mut [u8]) -> Result<(), DiskErr> {
+    match inflater.read_aligned(buf)? == buf.len() {
+        true => Ok(()),
+        false => Err(invalid("truncated gzip stream")),
+    }
+}
+
+/// Reads the header of a gzip member, returns false at the end of the file
+fn read_member_header(inflater: &mut Inflater<File>) -> Result<bool, DiskErr> {
+    let mut header = [0; 10];
+    match inflater.read_aligned(&mut header)? {
+        0 => return Ok(false),
+        n if n < 2 || header[0..2] != MAGIC => {
+            return Err(invalid("trailing data after the gzip members"));
+        }
+        n if n < 10 => return Err(invalid("truncated gzip stream")),
+        _ => {}
+    }
+
+    if header[2] != METHOD_DEFLATE || header[3] & 0xE0 != 0 {
+        return Err(invalid("unsupported gzip header"));
+    }
+
+    let flags = header[3];
+    if flags & FLAG_EXTRA != 0 {
+        let mut len = [0; 2];
+        read_exact(inflater, &mut len)?;
+        read_exact(inflater, &mut vec![0; u16::from_le_bytes(len) as usize])?;
+    }
+
+    for flag in [FLAG_NAME, FLAG_COMMENT] {
+        if flags & flag != 0 {
+            let mut b = [1];
+            while b[0] != 0 {
+                read_exact(inflater, &mut b)?;
+            }
+        }
+    }
+
+    if flags & FLAG_HCRC != 0 {
+        read_exact(inflater, &mut [0; 2])?;
+    }
+
+    Ok(true)
+}
+
+/// Decompresses into `buf`, going through the boundaries of the gzip members. Returns a non-zero
+/// count.
+fn read_data(inflater: &mut Inflater<File>, buf: &mut [u8]) -> Result<usize, DiskErr> {
+    loop {
+        match inflater.read(buf)? {
+            0 => {
+                read_exact(inflater, &mut [0; 8])?;
+                if !read_member_header(inflater)? {
+                    return Err(invalid("truncated gzip stream"));
+                }
+                inflater.restart();
+            }
+            n => return Ok(n),
+        }
+    }
+}
+
+// ### EXPORT ###
+
+/// Writes the content of `disk` to `out` as a gzip stream
+pub fn export<D: Disk, W: Write>(disk: D, mut out: W) -> Result<(), ImageErr> {
+    let io = |e: io::Error| ImageErr::Disk(DiskErr::from_io(&e).in_layer("GzipDisk"));
+
+    let disk = ByteDisk::new(disk)?;
+    let size = disk.size()?;
+
+    // No name nor modification time, unknown OS
+    out.write_all(&[MAGIC[0], MAGIC[1], METHOD_DEFLATE, 0, 0, 0, 0, 0, 0, 255])
+        .map_err(io)?;
+
+    let mut deflater = Deflater::new();
+    let mut buf = vec![0; IO_SIZE];
+    let mut compressed = Vec::new();
+    let mut crc = 0;
+    let mut offset = 0;
+
+    while offset < size {
+        let len = ((size - offset) as usize).min(IO_SIZE);
+        disk.read_at(offset, &mut buf[..len])?;
+
+        crc = crc32_update(crc, &buf[..len]);
+        deflater.write(&buf[..len], &mut compressed);
+        out.write_all(&compressed).map_err(io)?;
+        compressed.clear();
+
+        offset += len as u64;
+    }
+
+    deflater.finish(&mut compressed);
+    compressed.extend_from_slice(&crc.to_le_bytes());
+    compressed.extend_from_slice(&(size as u32).to_le_bytes());
+    out.write_all(&compressed).map_err(io)?;
+
+    out.flush().map_err(io)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{memdisk::MemDisk, std_helpers::TempPath};
+
+    fn content(len: usize) -> Vec<u8> {
+        let mut state = 1u32;
+        (0..len)
+            .map(|i| match (i >> 12) % 3 {
+                0 => 0,
+                1 => (i % 251) as u8,
+                _ => {
+                    state = state.wrapping_mul(1103515245).wrapping_add(12345);
+                    (state >> 16) as u8
+                }
+            })
+            .collect()
+    }
+
+    fn export_to_vec(content: &[u8]) -> Vec<u8> {
+        let disk = MemDisk::from_vec(content.to_vec(), SectorSize::Any, Permissions::read_only());
+        let mut image = Vec::new();
+        export(disk, &mut image).unwrap();
+        image
+    }
+
+    #[test]
+    fn export_and_open() {
+        let temp = TempPath::new("export.img.gz");
+        let content = content(256 << 10);
+        fs::write(&temp.path, export_to_vec(&content)).unwrap();
+
+        for span in [None, Some(16 << 10)] {
+            let disk = GzipDisk::open(temp.path.clone(), span).unwrap();
+            assert_eq!(disk.disk_infos().unwrap().disk_size, content.len() as u64);
+
+            let mut buf = vec![0; content.len()];
+            disk.read_sectors(0, 512, &mut buf).unwrap();
+            assert_eq!(buf, content);
+
+            // Backwards, from the checkpoints
+            for sector in [60, 3, 40, 41, 0] {
+                disk.read_sectors(sector, 4096, &mut buf[..8192]).unwrap();
+                let start = sector as usize * 4096;
+                assert_eq!(buf[..8192], content[start..(start + 8192)]);
+            }
+
+            assert!(disk.write_sectors(0, 512, &[0; 512]).is_err());
+            assert!(disk.read_sectors(64, 4096, &mut buf[..4096]).is_err());
+        }
+    }
+
+    #[test]
+    fn members_and_header_fields() {
+        let temp = TempPath::new("members.img.gz");
+        let content = content(64 << 10);
+
+        // A second member with a name, a comment and a header checksum
+        let mut member = vec![0x1F, 0x8B, 8, FLAG_NAME | FLAG_COMMENT | FLAG_HCRC];
+        member.extend_from_slice(&[0, 0, 0, 0, 0, 3]);
+        member.extend_from_slice(b"disk.img\0a comment\0\0\0");
+        member.extend_from_slice(&deflate::compress(&content[..4096]));
+        member.extend_from_slice(&crc32_update(0, &content[..4096]).to_le_bytes());
+        member.extend_from_slice(&4096u32.to_le_bytes());
+
+        fs::write(&temp.path, [export_to_vec(&content), member].concat()).unwrap();
+        let disk = GzipDisk::open(temp.path.clone(), Some(4096)).unwrap();
+
+        let mut buf = vec![0; content.len() + 4096];
+        disk.read_sectors(0, 4096, &mut buf).unwrap();
+        assert_eq!(buf[..content.len()], content);
+        assert_eq!(buf[content.len()..], content[..4096]);
+    }
+
+    #[test]
+    fn malformed_headers() {
+        let temp = TempPath::new("malformed.img.gz");
+        let open = |image: &[u8]| {
+            fs::write(&temp.path, image).unwrap();
+            GzipDisk::open(temp.path.clone(), None).err()
+        };
+        let invalid_header = |reason| Some(ImageErr::InvalidHeader { reason });
+        let invalid_image = |reason| Some(ImageErr::Disk(invalid(reason).in_layer("GzipDisk")));
+
+        let image = export_to_vec(&content(16 << 10));
+        assert_eq!(open(&image), None);
+
+        let mut bad = image.clone();
+        bad[1] = 0;
+        assert_eq!(open(&bad), invalid_header("not a gzip image"));
+        let mut bad = image.clone();
+        bad[2] = 7;
+        assert_eq!(open(&bad), invalid_header("not a gzip image"));
+
+        assert_eq!(
+            open(&image[..(image.len() - 4)]),
+            invalid_image("truncated gzip stream")
+        );
+        assert_eq!(
+            open(&[image.as_slice(), b"garbage"].concat()),
+            invalid_image("trailing data after the gzip members")
+        );
+
+        let mut bad = image.clone();
+        let crc = bad.len() - 8;
+        bad[crc] ^= 1;
+        assert_eq!(
+            open(&bad),
+            Some(ImageErr::Disk(
+                DiskErr::new(DiskErrKind::ChecksumMismatch { lba: 0 }).in_layer("GzipDisk")
+            ))
+        );
+    }
+}
@@ -0,0 +1,590 @@
+use crate::{
+    Disk, DiskErr, DiskErrKind, DiskInfos, Geometry, Operation, SectorSize, checksum::crc32c,
+};
+use alloc::{boxed::Box, vec, vec::Vec};
+use mutex::Mutex;
+
+/// Magic number at the start of the superblock
+const INTEGRITY_MAGIC: &[u8; 8] = b"PFSINTEG";
+const INTEGRITY_VERSION: u32 = 1;
+
+/// Maximum count of blocks read with a single request by `format` and `scrub`
+const INTEGRITY_BATCH: u64 = 64;
+
+/// A wrapper storing a CRC32C of each block of a `Disk`, and checking it on every read. A block
+/// not matching its checksum fails the read with `ChecksumMismatch`, so that the corruptions of
+/// the device are detected even by filesystems without checksums, like FAT12.
+///
+/// The checksums and a superblock describing the layout are stored either at the start of the
+/// disk, the data following them, or on a side disk, the whole disk holding data then. The disk
+/// exposes a single sector size, the block size chosen by `format`.
+///
+/// A block and its checksum are not written atomically: after a power cut during a write, the
+/// block may not match its checksum. `scrub` finds such blocks.
+pub struct IntegrityDisk<D: Disk> {
+    disk: D,
+    /// Holds the superblock and the checksums if set, else they are on `disk`
+    side: Option<Box<dyn Disk + Send + Sync>>,
+    /// Size of the protected blocks, in bytes
+    block_size: usize,
+    /// Count of blocks exposed
+    data_blocks: u64,
+    /// LBA of the first data block on `disk`, in blocks
+    data_start: u64,
+    /// Serializes the accesses to the data and its checksums, so a read never sees a block and a
+    /// checksum from different writes, and a checksum block shared by many blocks is never
+    /// updated by two writes at once
+    meta_lock: Mutex<()>,
+}
+
+/// The layout stored in the superblock
+struct Layout {
+    block_size: usize,
+    data_blocks: u64,
+    data_start: u64,
+}
+
+impl Layout {
+    /// Count of checksums in a block of the metadata area
+    const fn checksums_per_block(&self) -> u64 {
+        (self.block_size / 4) as u64
+    }
+
+    /// Count of blocks of the metadata area, including the superblock
+    const fn meta_blocks(&self) -> u64 {
+        1 + self.data_blocks.div_ceil(self.checksums_per_block())
+    }
+
+    fn to_bytes(&self) -> Vec<u8> {
+        let mut block = vec![0; self.block_size];
+        block[0..8].copy_from_slice(INTEGRITY_MAGIC);
+        block[8..12].copy_from_slice(&INTEGRITY_VERSION.to_le_bytes());
+        block[12..16].copy_from_slice(&(self.block_size as u32).to_le_bytes());
+        block[16..24].copy_from_slice(&self.data_blocks.to_le_bytes());
+        block[24..32].copy_from_slice(&self.data_start.to_le_bytes());
+        let crc = crc32c(&block[0..32]);
+        block[32..36].copy_from_slice(&crc.to_le_bytes());
+        block
+    }
+
+    /// Returns `None` if `bytes` is not a valid superblock
+    fn from_bytes(bytes: &[u8]) -> Option<Self> {
+        let u32_at = |i: usize| u32::from_le_bytes(bytes[i..(i + 4)].try_into().unwrap());
+        let u64_at = |i: usize| u64::from_le_bytes(bytes[i..(i + 8)].try_into().unwrap());
+
+        if bytes.len() < 36
+            || &bytes[0..8] != INTEGRITY_MAGIC
+            || u32_at(8) != INTEGRITY_VERSION
+            || u32_at(32) != crc32c(&bytes[0..32])
+        {
+            return None;
+        }
+
+        let block_size = u32_at(12) as usize;
+        if block_size < 36 || !block_size.is_multiple_of(4) {
+            return None;
+        }
+
+        Some(Self {
+            block_size,
+            data_blocks: u64_at(16),
+            data_start: u64_at(24),
+        })
+    }
+}
+
+impl<D: Disk> IntegrityDisk<D> {
+    /// Initializes the integrity metadata at the start of `disk`, with blocks of `block_size`
+    /// bytes (the preferred sector size of the disk by default). The checksums are computed from
+    /// the current content of the data area, which is not erased.
+    pub fn format(disk: D, block_size: Option<usize>) -> Result<Self, DiskErr> {
+        Self::format_inner(disk, None, block_size)
+            .map_err(|e| e.during(Operation::Write).in_layer("IntegrityDisk"))
+    }
+
+    /// Initializes the integrity metadata on `side`, to protect the whole `disk`. The block size
+    /// must be supported by both disks. The checksums are computed from the current content of
+    /// `disk`, which is not modified: an existing image can be protected.
+    pub fn format_with_side<S: Disk + Send + Sync + 'static>(
+        disk: D,
+        side: S,
+        block_size: Option<usize>,
+    ) -> Result<Self, DiskErr> {
+        Self::format_inner(disk, Some(Box::new(side)), block_size)
+            .map_err(|e| e.during(Operation::Write).in_layer("IntegrityDisk"))
+    }
+
+    /// Opens a disk formatted by `format`. Returns `None` if there is no valid superblock.
+    pub fn open(disk: D) -> Result<Option<Self>, DiskErr> {
+        Self::open_inner(disk, None)
+            .map_err(|e| e.during(Operation::Read).in_layer("IntegrityDisk"))
+    }
+
+    /// Opens a disk formatted by `format_with_side`. Returns `None` if there is no valid
+    /// superblock on `side`.
+    pub fn open_with_side<S: Disk + Send + Sync + 'static>(
+        disk: D,
+        side: S,
+    ) -> Result<Option<Self>, DiskErr> {
+        Self::open_inner(disk, Some(Box::new(side)))
+            .map_err(|e| e.during(Operation::Read).in_layer("IntegrityDisk"))
+    }
+
+    pub const fn block_size(&self) -> usize {
+        self.block_size
+    }
+
+    pub const fn inner(&self) -> &D {
+        &self.disk
+    }
+
+    pub fn into_inner(self) -> D {
+        self.disk
+    }
+
+    /// Reads the whole disk and returns the LBA of the blocks not matching their checksum
+    pub fn scrub(&self) -> Result<Vec<u64>, DiskErr> {
+        let mut bad_blocks = Vec::new();
+        let mut block = 0;
+
+        while block < self.data_blocks {
+            let count = (self.data_blocks - block).min(INTEGRITY_BATCH);
+            let mut buf = vec![0; count as usize * self.block_size];
+            let _lock = self.meta_lock.lock();
+
+            self.disk
+                .read_sectors(self.data_start + block, self.block_size, &mut buf)
+                .and_then(|_| {
+                    let checksums = self.checksums(block, count)?;
+
+                    for (i, data) in buf.chunks(self.block_size).enumerate() {
+                        if crc32c(data) != checksums[i] {
+                            bad_blocks.push(block + i as u64);
+                        }
+                    }
+
+                    Ok(())
+                })
+                .map_err(|e| e.at(Operation::Read, block).in_layer("IntegrityDisk"))?;
+
+            block += count;
+        }
+
+        Ok(bad_blocks)
+    }
+
+    fn format_inner(
+        disk: D,
+        side: Option<Box<dyn Disk + Send + Sync>>,
+        block_size: Option<usize>,
+    ) -> Result<Self, DiskErr> {
+        let infos = disk.disk_infos()?;
+
+        let block_size = match block_size.or(infos.sector_size.preferred(infos.disk_size)) {
+            Some(v) => v,
+            None => return Err(DiskErrKind::UnsupportedDiskSectorSize.into()),
+        };
+
+        let mut supported = infos.sector_size.is_supported(block_size, infos.disk_size);
+        if let Some(side) = &side {
+            let side_infos = side.disk_infos()?;
+            supported &= side_infos
+                .sector_size
+                .is_supported(block_size, side_infos.disk_size);
+        }
+
+        if !supported || block_size < 36 || !block_size.is_multiple_of(4) {
+            return Err(DiskErrKind::InvalidSectorSize {
+                found: block_size,
+                supported: infos.sector_size,
+                start: 0,
+            }
+            .into());
+        }
+
+        let blocks = infos.disk_size / block_size as u64;
+        let per_block = (block_size / 4) as u64;
+
+        let layout = match &side {
+            Some(side) => {
+                let layout = Layout {
+                    block_size,
+                    data_blocks: blocks,
+                    data_start: 0,
+                };
+
+                if layout.meta_blocks() > side.disk_infos()?.disk_size / block_size as u64 {
+                    return Err(DiskErrKind::InvalidDiskSize.into());
+                }
+
+                layout
+            }
+            None => {
+                // Each checksum block covers `per_block` data blocks
+                let data_blocks = blocks.saturating_sub(1) * per_block / (per_block + 1);
+                let layout = Layout {
+                    block_size,
+                    data_blocks,
+                    data_start: 1 + data_blocks.div_ceil(per_block),
+                };
+
+                if data_blocks == 0 {
+                    return Err(DiskErrKind::InvalidDiskSize.into());
+                }
+
+                layout
+            }
+        };
+
+        let slf = Self::from_layout(disk, side, &layout);
+
+        // The checksums are written before the superblock, so that an interrupted format leaves
+        // an unformatted disk
+        let mut block = 0;
+        while block < layout.data_blocks {
+            let count = (layout.data_blocks - block).min(INTEGRITY_BATCH);
+            let mut buf = vec![0; count as usize * block_size];
+            slf.disk
+                .read_sectors(slf.data_start + block, block_size, &mut buf)?;
+
+            let checksums: Vec<u32> = buf.chunks(block_size).map(crc32c).collect();
+            slf.write_checksums(block, &checksums)?;
+            block += count;
+        }
+
+        slf.meta_disk().flush()?;
+        slf.meta_disk()
+            .write_sectors_fua(0, block_size, &layout.to_bytes())?;
+
+        Ok(slf)
+    }
+
+    fn open_inner(
+        disk: D,
+        side: Option<Box<dyn Disk + Send + Sync>>,
+    ) -> Result<Option<Self>, DiskErr> {
+        let meta_infos = match &side {
+            Some(side) => side.disk_infos()?,
+            None => disk.disk_infos()?,
+        };
+
+        // The superblock fits in any sector size of at least 36 bytes
+        let Some(sector_size) = meta_infos.sector_size.minimal_ge(36) else {
+            return Ok(None);
+        };
+
+        let mut sector = vec![0; sector_size];
+        match &side {
+            Some(side) => side.read_sectors(0, sector_size, &mut sector)?,
+            None => disk.read_sectors(0, sector_size, &mut sector)?,
+        }
+
+        let Some(layout) = Layout::from_bytes(&sector) else {
+            return Ok(None);
+        };
+
+        // The layout must fit in the disks
+        let blocks = disk.disk_infos()?.disk_size / layout.block_size as u64;
+        let meta_blocks = meta_infos.disk_size / layout.block_size as u64;
+        let fits = match &side {
+            Some(_) => layout.data_blocks <= blocks && layout.meta_blocks() <= meta_blocks,
+            None => {
+                layout.data_start >= layout.meta_blocks()
+                    && layout
+                        .data_start
+                        .checked_add(layout.data_blocks)
+                        .is_some_and(|end| end <= blocks)
+            }
+        };
+
+        if !fits {
+            return Ok(None);
+        }
+
+        Ok(Some(Self::from_layout(disk, side, &layout)))
+    }
+
+    fn from_layout(disk: D, side: Option<Box<dyn Disk + Send + Sync>>, layout: &Layout) -> Self {
+        Self {
+            disk,
+            side,
+            block_size: layout.block_size,
+            data_blocks: layout.data_blocks,
+            data_start: layout.data_start,
+            meta_lock: Mutex::new(()),
+        }
+    }
+
+    /// The disk holding the superblock and the checksums
+    fn meta_disk(&self) -> &dyn Disk {
+        match &self.side {
+            Some(side) => side.as_ref(),
+            None => &self.disk,
+        }
+    }
+
+    /// Reads the checksums of the `count` blocks starting at `first`
+    fn checksums(&self, first: u64, count: u64) -> Result<Vec<u32>, DiskErr> {
+        let per_block = (self.block_size / 4) as u64;
+        let first_meta = first / per_block;
+        let last_meta = (first + count - 1) / per_block;
+
+        let mut meta = vec![0; (last_meta - first_meta + 1) as usize * self.block_size];
+        self.meta_disk()
+            .read_sectors(1 + first_meta, self.block_size, &mut meta)?;
+
+        let skip = ((first % per_block) * 4) as usize;
+        Ok(meta[skip..(skip + count as usize * 4)]
+            .chunks(4)
+            .map(|c| u32::from_le_bytes(c.try_into().unwrap()))
+            .collect())
+    }
+
+    /// Writes the checksums of the blocks starting at `first`. The checksum blocks are read first
+    /// if they are only partially updated. The caller holds `meta_lock`.
+    fn write_checksums(&self, first: u64, checksums: &[u32]) -> Result<(), DiskErr> {
+        let per_block = (self.block_size / 4) as u64;
+        let count = checksums.len() as u64;
+        let first_meta = first / per_block;
+        let last_meta = (first + count - 1) / per_block;
+
+        let mut meta = vec![0; (last_meta - first_meta + 1) as usize * self.block_size];
+        let skip = ((first % per_block) * 4) as usize;
+
+        if skip != 0 || !(first + count).is_multiple_of(per_block) {
+            self.meta_disk()
+                .read_sectors(1 + first_meta, self.block_size, &mut meta)?;
+        }
+
+        for (i, checksum) in checksums.iter().enumerate() {
+            meta[(skip + i * 4)..(skip + i * 4 + 4)].copy_from_slice(&checksum.to_le_bytes());
+        }
+
+        self.meta_disk()
+            .write_sectors(1 + first_meta, self.block_size, &meta)
+    }
+
+    /// Checks a request of `len` bytes starting at the LBA `sector` and returns the count of
+    /// blocks it covers.
+    fn check(&self, sector: u64, sector_size: usize, len: u64) -> Result<u64, DiskErr> {
+        if sector_size != self.block_size || !len.is_multiple_of(sector_size as u64) {
+            return Err(DiskErrKind::InvalidSectorSize {
+                found: sector_size,
+                supported: SectorSize::AllOf(vec![self.block_size]),
+                start: 0,
+            }
+            .into());
+        }
+
+        let count = len / sector_size as u64;
+        match sector.checked_add(count) {
+            Some(end) if end <= self.data_blocks => Ok(count),
+            _ => Err(DiskErrKind::InvalidSectorIndex {
+                found: sector,
+                max: self.data_blocks.saturating_sub(1),
+            }
+            .into()),
+        }
+    }
+
+    fn read_inner(&self, sector: u64, sector_size: usize, buf: &mut [u8]) -> Result<(), DiskErr> {
+        let count = self.check(sector, sector_size, buf.len() as u64)?;
+        if count == 0 {
+            return Ok(());
+        }
+
+        let checksums = {
+            let _lock = self.meta_lock.lock();
+            self.disk
+                .read_sectors(self.data_start + sector, sector_size, buf)?;
+            self.checksums(sector, count)?
+        };
+
+        match buf
+            .chunks(sector_size)
+            .zip(checksums)
+            .position(|(data, checksum)| crc32c(data) != checksum)
+        {
+            Some(i) => Err(DiskErrKind::ChecksumMismatch {
+                lba: sector + i as u64,
+            }
+            .into()),
+            None => Ok(()),
+        }
+    }
+
+    fn write_inner(
+        &self,
+        sector: u64,
+        sector_size: usize,
+        buf: &[u8],
+        fua: bool,
+    ) -> Result<(), DiskErr> {
+        if self.check(sector, sector_size, buf.len() as u64)? == 0 {
+            return Ok(());
+        }
+
+        let _lock = self.meta_lock.lock();
+        if fua {
+            self.disk
+                .write_sectors_fua(self.data_start + sector, sector_size, buf)?;
+        } else {
+            self.disk
+                .write_sectors(self.data_start + sector, sector_size, buf)?;
+        }
+
+        let checksums: Vec<u32> = buf.chunks(sector_size).map(crc32c).collect();
+        self.write_checksums(sector, &checksums)?;
+
+        if fua {
+            self.meta_disk().flush()?;
+        }
+
+        Ok(())
+    }
+
+    fn discard_inner(&self, sector: u64, sector_size: usize, count: u64) -> Result<(), DiskErr> {
+        self.check(
+            sector,
+            sector_size,
+            count.saturating_mul(sector_size as u64),
+        )?;
+        if count == 0 {
+            return Ok(());
+        }
+
+        let _lock = self.meta_lock.lock();
+        self.disk
+            .discard(self.data_start + sector, sector_size, count)?;
+
+        // The discarded blocks read as zeroes
+        let zero_checksum = crc32c(&vec![0; sector_size]);
+        let mut done = 0;
+        while done < count {
+            let batch = (count - done).min(INTEGRITY_BATCH * (sector_size / 4) as u64);
+            self.write_checksums(sector + done, &vec![zero_checksum; batch as usize])?;
+            done += batch;
+        }
+
+        Ok(())
+    }
+}
+
+impl<D: Disk> Disk for IntegrityDisk<D> {
+    fn read_sector(&self, sector: u64, buf: &mut [u8]) -> Result<(), DiskErr> {
+        self.read_sectors(sector, buf.len(), buf)
+    }
+
+    fn write_sector(&self, sector: u64, buf: &[u8]) -> Result<(), DiskErr> {
+        self.write_sectors(sector, buf.len(), buf)
+    }
+
+    fn disk_infos(&self) -> Result<DiskInfos, DiskErr> {
+        let infos = self.disk.disk_infos()?;
+        let sector_size = SectorSize::AllOf(vec![self.block_size]);
+        let disk_size = self.data_blocks * self.block_size as u64;
+        let start = self.data_start * self.block_size as u64;
+
+        let mut geometry = Geometry::from_sector_size(&sector_size, disk_size);
+        geometry.rotational = infos.geometry.rotational;
+
+        Ok(DiskInfos {
+            sector_size,
+            disk_size,
+            permissions: infos.permissions,
+            geometry,
+            device_id: infos
+                .device_id
+                .map(|id| id.slice(vec![(start, start + disk_size)])),
+        })
+    }
+
+    fn read_sectors(&self, sector: u64, sector_size: usize, buf: &mut [u8]) -> Result<(), DiskErr> {
+        self.read_inner(sector, sector_size, buf)
+            .map_err(|e| e.at(Operation::Read, sector).in_layer("IntegrityDisk"))
+    }
+
+    fn write_sectors(&self, sector: u64, sector_size: usize, buf: &[u8]) -> Result<(), DiskErr> {
+        self.write_inner(sector, sector_size, buf, false)
+            .map_err(|e| e.at(Operation::Write, sector).in_layer("IntegrityDisk"))
+    }
+
+    fn write_sectors_fua(
+        &self,
+        sector: u64,
+        sector_size: usize,
+        buf: &[u8],
+    ) -> Result<(), DiskErr> {
+        self.write_inner(sector, sector_size, buf, true)
+            .map_err(|e| e.at(Operation::Write, sector).in_layer("IntegrityDisk"))
+    }
+
+    fn discard(&self, sector: u64, sector_size: usize, count: u64) -> Result<(), DiskErr> {
+        self.discard_inner(sector, sector_size, count)
+            .map_err(|e| e.at(Operation::Discard, sector).in_layer("IntegrityDisk"))
+    }
+
+    fn flush(&self) -> Result<(), DiskErr> {
+        self.disk
+            .flush()
+            .and_then(|_| match &self.side {
+                Some(side) => side.flush(),
+                None => Ok(()),
+            })
+            .map_err(|e| e.during(Operation::Flush).in_layer("IntegrityDisk"))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    extern crate std;
+
+    use super::*;
+    use crate::{Permissions, memdisk::MemDisk};
+    use std::thread;
+
+    fn disk() -> IntegrityDisk<MemDisk> {
+        let disk = MemDisk::new(
+            256 * 512,
+            SectorSize::AllOf(vec![512]),
+            Permissions::read_write(),
+        );
+        IntegrityDisk::format(disk, Some(512)).unwrap()
+    }
+
+    #[test]
+    fn detects_corruption() {
+        let disk = disk();
+        disk.write_sectors(3, 512, &[0xA5; 1024]).unwrap();
+        assert!(disk.scrub().unwrap().is_empty());
+
+        disk.inner()
+            .write_sector(disk.data_start + 4, &[0x5A; 512])
+            .unwrap();
+
+        let err = disk.read_sectors(3, 512, &mut [0; 1024]).unwrap_err();
+        assert_eq!(err.kind, DiskErrKind::ChecksumMismatch { lba: 4 });
+        assert_eq!(disk.scrub().unwrap(), vec![4]);
+    }
+
+    #[test]
+    fn concurrent_reads_and_writes() {
+        let disk = disk();
+
+        thread::scope(|s| {
+            s.spawn(|| {
+                for i in 0..2000 {
+                    disk.write_sectors(7, 512, &[i as u8; 2048]).unwrap();
+                }
+            });
+            s.spawn(|| {
+                for _ in 0..2000 {
+                    let mut buf = [0; 2048];
+                    disk.read_sectors(7, 512, &mut buf).unwrap();
+                }
+            });
+        });
+
+        assert!(disk.scrub().unwrap().is_empty());
+    }
+}
@@ -2,7 +2,7 @@
 
 extern crate alloc;
 
-use alloc::vec::Vec;
+use alloc::{boxed::Box, string::String, sync::Arc, vec, vec::Vec};
 
 /// Procides an implementation of the `Disk` trait for `std::fs`
 #[cfg(feature = "std")]
@@ -11,9 +11,65 @@ pub mod std_helpers;
 #[cfg(feature = "std")]
 pub use std_helpers::*;
 
+pub use error::*;
+
+/// Provides a `std::io` cursor over any `Disk`
+#[cfg(feature = "std")]
+pub mod cursor;
+
+/// Provides a read-only `Disk` implementation for gzip-compressed raw images
+#[cfg(feature = "std")]
+pub mod gzip;
+/// Provides a `Disk` implementation for qcow2 disk images
+#[cfg(feature = "std")]
+pub mod qcow2;
+/// Provides a `Disk` implementation for Android sparse images
+#[cfg(feature = "std")]
+pub mod simg;
+/// Provides a `Disk` implementation for VHD disk images
+#[cfg(feature = "std")]
+pub mod vhd;
+/// Provides a `Disk` implementation for VHDX disk images
+#[cfg(feature = "std")]
+pub mod vhdx;
+/// Provides a `Disk` implementation for VMDK disk images
+#[cfg(feature = "std")]
+pub mod vmdk;
+
+/// Provides an asynchronous version of the `Disk` trait, and adapters between both
+pub mod async_disk;
+/// Provides byte-addressed access over any `Disk`
+pub mod bytedisk;
+/// Provides a block cache over any `Disk`
+pub mod cache;
+mod checksum;
+/// Provides a simulator of the states a power cut can leave a disk in
+pub mod crash;
+/// Provides the ciphers used by the encrypted disks
+pub mod crypto;
+/// Provides a DEFLATE compressor and decompressor, as used by gzip
+pub mod deflate;
+/// Provides a `Disk` wrapper encrypting the sectors with AES-XTS
+pub mod encrypted;
+/// Provides the error types of the device, partition table, LUKS and filesystem layers
+pub mod error;
+/// Provides a `Disk` wrapper injecting faults, to test the error paths
+pub mod faulty;
 pub mod filesystems;
+/// Provides a `Disk` wrapper storing and checking a checksum of each sector
+pub mod integrity;
+/// Provides LUKS1 and LUKS2 encrypted containers, opened as `Disk`s
+pub mod luks;
 pub mod memdisk;
+/// Provides a copy-on-write overlay over any `Disk`, to try changes without writing them
+pub mod overlay;
 pub mod partition_tables;
+/// Provides `Disk` wrappers counting the requests and simulating the latency of slower devices
+pub mod stats;
+/// Provides a `Disk` wrapper recording the requests, and the replay of the recorded writes
+pub mod tracing;
+/// Provides a `Disk` wrapper reading back and verifying the written sectors
+pub mod verify;
 /// Procides disk wrappers to allow subdisk creation. `SubDisk`s are useful when working with
 /// partitions or filesystems for example.
 pub mod wrappers;
@@ -27,80 +83,337 @@ pub trait Disk {
     /// The size of the buffer is implicitly the sector size (in bytes). `sector` is the LBA of the
     /// sector. It's the implementation responsibility to check the sector and the disk sizes, the
     /// caller may produce invalid requests.
-    fn read_sector(&self, sector: usize, buf: &mut [u8]) -> Result<(), DiskErr>;
+    fn read_sector(&self, sector: u64, buf: &mut [u8]) -> Result<(), DiskErr>;
 
     /// The size of the buffer is implicitly the sector size (in bytes). `sector` is the LBA of the
     /// sector. It's the implementation responsibility to check the sector and the disk sizes, the
     /// caller may produce invalid requests.
-    fn write_sector(&self, sector: usize, buf: &[u8]) -> Result<(), DiskErr>;
+    fn write_sector(&self, sector: u64, buf: &[u8]) -> Result<(), DiskErr>;
 
     fn disk_infos(&self) -> Result<DiskInfos, DiskErr>;
+
+    /// Reads `buf.len() / sector_size` contiguous sectors, starting at the LBA `sector`. The size
+    /// of the buffer must be a multiple of `sector_size`. The default implementation falls back to
+    /// one `read_sector` call per sector, implementations should override it when they can serve
+    /// the whole run in a single request.
+    fn read_sectors(&self, sector: u64, sector_size: usize, buf: &mut [u8]) -> Result<(), DiskErr> {
+        if sector_size == 0 || !buf.len().is_multiple_of(sector_size) {
+            return Err(DiskErr::new(DiskErrKind::InvalidSectorSize {
+                found: buf.len(),
+                supported: self.disk_infos()?.sector_size,
+                start: 0,
+            })
+            .at(Operation::Read, sector));
+        }
+
+        for (i, chunk) in buf.chunks_exact_mut(sector_size).enumerate() {
+            self.read_sector(sector + i as u64, chunk)?;
+        }
+
+        Ok(())
+    }
+
+    /// Writes `buf.len() / sector_size` contiguous sectors, starting at the LBA `sector`. The size
+    /// of the buffer must be a multiple of `sector_size`. The default implementation falls back to
+    /// one `write_sector` call per sector, implementations should override it when they can serve
+    /// the whole run in a single request.
+    fn write_sectors(&self, sector: u64, sector_size: usize, buf: &[u8]) -> Result<(), DiskErr> {
+        if sector_size == 0 || !buf.len().is_multiple_of(sector_size) {
+            return Err(DiskErr::new(DiskErrKind::InvalidSectorSize {
+                found: buf.len(),
+                supported: self.disk_infos()?.sector_size,
+                start: 0,
+            })
+            .at(Operation::Write, sector));
+        }
+
+        for (i, chunk) in buf.chunks_exact(sector_size).enumerate() {
+            self.write_sector(sector + i as u64, chunk)?;
+        }
+
+        Ok(())
+    }
+
+    /// Write barrier: once it returns, every write completed before the call is durable. Disks
+    /// without a volatile cache (like `MemDisk`) can keep the default implementation, which does
+    /// nothing. Wrappers must propagate it to the disk they wrap.
+    fn flush(&self) -> Result<(), DiskErr> {
+        Ok(())
+    }
+
+    /// Same as `write_sectors`, but the data is durable once it returns (like a FUA write). It
+    /// only guarantees the durability of this write, use `flush` to order the previous ones. The
+    /// default implementation writes the sectors and then flushes the disk.
+    fn write_sectors_fua(
+        &self,
+        sector: u64,
+        sector_size: usize,
+        buf: &[u8],
+    ) -> Result<(), DiskErr> {
+        self.write_sectors(sector, sector_size, buf)?;
+        self.flush()
+    }
+
+    /// Discards `count` sectors of `sector_size` bytes, starting at the LBA `sector`. The disk may
+    /// release the underlying space, and the discarded sectors read as zeroes afterwards. The
+    /// default implementation writes zeroed sectors.
+    fn discard(&self, sector: u64, sector_size: usize, count: u64) -> Result<(), DiskErr> {
+        write_zeroes(self, sector, sector_size, count)
+    }
+
+    /// Scatter read: each request is a run of contiguous sectors `(lba, buffer)`, all using the
+    /// same `sector_size`. The requests are served in order and the first error is returned.
+    fn read_sectors_vectored(
+        &self,
+        sector_size: usize,
+        requests: &mut [(u64, &mut [u8])],
+    ) -> Result<(), DiskErr> {
+        for (sector, buf) in requests.iter_mut() {
+            self.read_sectors(*sector, sector_size, buf)?;
+        }
+
+        Ok(())
+    }
+
+    /// Gather write: each request is a run of contiguous sectors `(lba, buffer)`, all using the
+    /// same `sector_size`. The requests are served in order and the first error is returned.
+    fn write_sectors_vectored(
+        &self,
+        sector_size: usize,
+        requests: &[(u64, &[u8])],
+    ) -> Result<(), DiskErr> {
+        for (sector, buf) in requests {
+            self.write_sectors(*sector, sector_size, buf)?;
+        }
+
+        Ok(())
+    }
 }
 
-#[derive(Debug, Clone, PartialEq, Eq)]
-#[non_exhaustive]
-pub enum DiskErr {
-    /// Will trigger if the size of the buffer isn't supported.
-    ///
-    /// `found` is the size of the provided buffer (`buf.len()`)
-    ///
-    /// `supported` is the supported sector size(s)
-    ///
-    /// `start % sector_size` should be zero (used with subdisks)
-    InvalidSectorSize {
-        found: usize,
-        supported: SectorSize,
-        start: usize,
-    },
+/// Forwards every method, so that the overridden ones are still used
+macro_rules! forward_disk_impl {
+    ($($ty:ty),*) => {$(
+        impl<T: Disk + ?Sized> Disk for $ty {
+            fn read_sector(&self, sector: u64, buf: &mut [u8]) -> Result<(), DiskErr> {
+                (**self).read_sector(sector, buf)
+            }
 
-    /// Will trigger if the sector index is out of the range of the disk.
-    ///
-    /// `found` is the provided sector index (lba)
-    ///
-    /// `max` is the last existing sector index **with the size of the given buffer**
-    InvalidSectorIndex {
-        found: usize,
-        max: usize,
-    },
+            fn write_sector(&self, sector: u64, buf: &[u8]) -> Result<(), DiskErr> {
+                (**self).write_sector(sector, buf)
+            }
 
-    /// Will trigger if a write is performed on a read-only disk or if the program tries to read a
-    /// write-only disk
-    InvalidPermission {
-        disk_permissions: Permissions,
-    },
+            fn disk_infos(&self) -> Result<DiskInfos, DiskErr> {
+                (**self).disk_infos()
+            }
 
-    /// Will trigger if, for any reason, the disk is not found anymore.
-    UnreachableDisk,
+            fn read_sectors(
+                &self,
+                sector: u64,
+                sector_size: usize,
+                buf: &mut [u8],
+            ) -> Result<(), DiskErr> {
+                (**self).read_sectors(sector, sector_size, buf)
+            }
+
+            fn write_sectors(
+                &self,
+                sector: u64,
+                sector_size: usize,
+                buf: &[u8],
+            ) -> Result<(), DiskErr> {
+                (**self).write_sectors(sector, sector_size, buf)
+            }
 
-    /// Will trigger when attempting to create a subdisk out of the range of the original disk
-    /// size
-    InvalidDiskSize,
+            fn flush(&self) -> Result<(), DiskErr> {
+                (**self).flush()
+            }
 
-    /// Will trigger if a read/write/subdisk creation is requested when the disk is already in
-    /// use/on a space already borrowed
-    Busy,
+            fn write_sectors_fua(
+                &self,
+                sector: u64,
+                sector_size: usize,
+                buf: &[u8],
+            ) -> Result<(), DiskErr> {
+                (**self).write_sectors_fua(sector, sector_size, buf)
+            }
 
-    /// Will trigger for all the errors coming from IO processes
-    IOErr,
+            fn discard(
+                &self,
+                sector: u64,
+                sector_size: usize,
+                count: u64,
+            ) -> Result<(), DiskErr> {
+                (**self).discard(sector, sector_size, count)
+            }
 
-    UnsupportedDiskSectorSize,
-    InvalidPartitionIndex,
-    SpaceAlreadyInUse,
-    IndexOutOfRange,
-    InvalidPathFormat,
+            fn read_sectors_vectored(
+                &self,
+                sector_size: usize,
+                requests: &mut [(u64, &mut [u8])],
+            ) -> Result<(), DiskErr> {
+                (**self).read_sectors_vectored(sector_size, requests)
+            }
+
+            fn write_sectors_vectored(
+                &self,
+                sector_size: usize,
+                requests: &[(u64, &[u8])],
+            ) -> Result<(), DiskErr> {
+                (**self).write_sectors_vectored(sector_size, requests)
+            }
+        }
+    )*};
+}
+
+forward_disk_impl!(&T, Box<T>, Arc<T>);
+
+/// Maximum count of sectors written with a single request by `write_zeroes`
+const ZEROING_BATCH: u64 = 64;
+
+/// Writes `count` zeroed sectors of `sector_size` bytes starting at the LBA `sector`, by batches of
+/// `ZEROING_BATCH` sectors. Used to implement `Disk::discard` on disks that can't release space.
+pub(crate) fn write_zeroes<T: Disk + ?Sized>(
+    disk: &T,
+    sector: u64,
+    sector_size: usize,
+    count: u64,
+) -> Result<(), DiskErr> {
+    if sector_size == 0 {
+        return Err(DiskErr::new(DiskErrKind::InvalidSectorSize {
+            found: sector_size,
+            supported: disk.disk_infos()?.sector_size,
+            start: 0,
+        })
+        .at(Operation::Discard, sector));
+    }
+
+    let zeroes = vec![0; sector_size * (ZEROING_BATCH.min(count) as usize)];
+
+    let mut done = 0;
+    while done < count {
+        let batch = ZEROING_BATCH.min(count - done);
+        disk.write_sectors(
+            sector + done,
+            sector_size,
+            &zeroes[..(batch as usize * sector_size)],
+        )?;
+        done += batch;
+    }
+
+    Ok(())
 }
 
 #[derive(Debug, Clone, PartialEq, Eq)]
 pub struct DiskInfos {
     pub sector_size: SectorSize,
     /// The disk size in bytes
-    pub disk_size: usize,
+    pub disk_size: u64,
     /// Specially useful when working with disk images, or without `sudo` privileges
     pub permissions: Permissions,
+    /// How the device is organized physically. Only used to choose sector sizes and alignments,
+    /// the supported sector sizes are always given by `sector_size`.
+    pub geometry: Geometry,
+    /// Identifies the device across runs, if it can be identified
+    pub device_id: Option<DeviceId>,
+}
+
+/// Physical layout of a device, as reported by the device itself or guessed from its sector
+/// sizes. All the sizes are in bytes.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Geometry {
+    /// The smallest unit the device can address (often 512B)
+    pub logical_sector_size: usize,
+    /// The unit the device writes internally. Writing less than a physical sector implies a
+    /// read-modify-write cycle on the device (4KiB on "512e" drives).
+    pub physical_sector_size: usize,
+    /// The smallest request size without a performance penalty
+    pub min_io_size: usize,
+    /// The preferred request size, 0 if the device doesn't report any
+    pub optimal_io_size: usize,
+    /// Offset of the first physically aligned logical sector from the start of the disk. It is
+    /// not zero for partitions or old drives that shift their sectors.
+    pub alignment_offset: u64,
+    /// Rotational media (hard drives, ...) favor sequential accesses
+    pub rotational: bool,
+}
+
+/// A stable identifier of a device
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub enum DeviceId {
+    /// A physical drive, identified by its model and its serial number
+    Hardware { model: String, serial: String },
+    /// A disk image, identified by a hash of its canonical path
+    Image(u64),
+    /// Ranges [start, end[ (in bytes) of another device, like a partition
+    Slice {
+        parent: Box<DeviceId>,
+        parts: Vec<(u64, u64)>,
+    },
+}
+
+impl Geometry {
+    /// Guesses the geometry of a device that reports nothing but its supported sector sizes: it
+    /// is a non-rotational device whose logical and physical sector sizes are the preferred
+    /// sector size (see `SectorSize::preferred`).
+    pub fn from_sector_size(sector_size: &SectorSize, disk_size: u64) -> Self {
+        let sector_size = sector_size.preferred(disk_size).unwrap_or(512);
+
+        Self {
+            logical_sector_size: sector_size,
+            physical_sector_size: sector_size,
+            min_io_size: sector_size,
+            optimal_io_size: 0,
+            alignment_offset: 0,
+            rotational: false,
+        }
+    }
+
+    /// The geometry of the range of bytes starting at `start` of this device
+    pub fn slice(&self, start: u64) -> Self {
+        let physical = self.physical_sector_size.max(1) as u64;
+
+        Self {
+            alignment_offset: (self.alignment_offset % physical + physical - start % physical)
+                % physical,
+            ..*self
+        }
+    }
+
+    /// The granularity of the accesses without any performance penalty: the largest of the
+    /// physical sector size, the minimum I/O size and the optimal I/O size
+    pub fn io_granularity(&self) -> usize {
+        self.physical_sector_size
+            .max(self.min_io_size)
+            .max(self.optimal_io_size)
+            .max(1)
+    }
+
+    /// Returns the first byte offset >= `offset` aligned on `granularity` bytes on the device,
+    /// taking the alignment offset into account
+    pub fn align_up(&self, offset: u64, granularity: u64) -> Option<u64> {
+        let granularity = granularity.max(1);
+        let shift = self.alignment_offset % granularity;
+
+        let aligned = offset
+            .checked_sub(shift)
+            .map_or(Some(0), |v| v.checked_next_multiple_of(granularity))?;
+
+        aligned.checked_add(shift)
+    }
+}
+
+impl DeviceId {
+    /// The identifier of the ranges `parts` of this device
+    pub fn slice(&self, parts: Vec<(u64, u64)>) -> Self {
+        Self::Slice {
+            parent: Box::new(self.clone()),
+            parts,
+        }
+    }
 }
 
 /// Informs the supported sector sizes. A sector size superior to the disk size is always invalid
-/// and should trigger an error `DiskErr::InvalidSectorSize`.
+/// and should trigger an error `DiskErrKind::InvalidSectorSize`.
 #[derive(Debug, Clone, PartialEq, Eq)]
 pub enum SectorSize {
     /// All sector sizes are supported
@@ -153,7 +466,7 @@ impl Permissions {
 
 impl SectorSize {
     /// Checks if a given sector size is supported.
-    pub fn is_supported(&self, sector_size: usize, disk_size: usize) -> bool {
+    pub fn is_supported(&self, sector_size: usize, disk_size: u64) -> bool {
         (match self {
             Self::Any => true,
             Self::AllOf(l) => l.contains(&sector_size),
@@ -162,7 +475,16 @@ impl SectorSize {
             Self::AnyExceptRanges(rs) => {
                 !rs.iter().any(|r| r.0 <= sector_size && sector_size < r.1)
             }
-        }) && (sector_size <= disk_size)
+        }) && (sector_size as u64 <= disk_size)
+    }
+
+    /// Returns the sector size to use when the caller has no requirement: the smallest supported
+    /// one >= 512, or the smallest supported one if the disk only supports smaller ones.
+    pub fn preferred(&self, disk_size: u64) -> Option<usize> {
+        [512, 1]
+            .into_iter()
+            .filter_map(|min| self.minimal_ge(min))
+            .find(|&v| self.is_supported(v, disk_size))
     }
 
     /// Returns the minimal supported sector size greater than or equal to `sector_size` if any
@@ -203,7 +525,7 @@ impl SectorSize {
                 let mut min = None;
 
                 for &(start, end) in ranges {
-                    if end <= sector_size {
+                    if end <= sector_size || end <= start {
                         continue;
                     }
 
@@ -243,4 +565,388 @@ impl SectorSize {
             }
         }
     }
+
+    /// Returns the maximal supported sector size lower than or equal to `sector_size` if any
+    /// exists.
+    pub fn maximal_le(&self, sector_size: usize) -> Option<usize> {
+        self.to_ranges()
+            .into_iter()
+            .rev()
+            .find(|&(start, _)| start <= sector_size)
+            .map(|(_, end)| (end - 1).min(sector_size))
+    }
+
+    /// Iterates over the supported sector sizes in [min, max], in increasing order
+    pub fn iter(&self, min: usize, max: usize) -> impl Iterator<Item = usize> {
+        self.to_ranges()
+            .into_iter()
+            .flat_map(move |(start, end)| start.max(min)..end.min(max.saturating_add(1)))
+    }
+
+    /// Returns the supported sector sizes which are powers of two
+    pub fn powers_of_two(&self) -> Self {
+        Self::AllOf(
+            (0..usize::BITS)
+                .map(|i| 1 << i)
+                .filter(|&v| self.is_supported(v, u64::MAX))
+                .collect(),
+        )
+    }
+
+    /// Returns the sector sizes supported by both `self` and `other`
+    pub fn intersection(&self, other: &Self) -> Self {
+        let (a, b) = (self.to_ranges(), other.to_ranges());
+        let mut ranges = Vec::new();
+        let (mut i, mut j) = (0, 0);
+
+        while i < a.len() && j < b.len() {
+            let start = a[i].0.max(b[j].0);
+            let end = a[i].1.min(b[j].1);
+
+            if start < end {
+                ranges.push((start, end));
+            }
+
+            if a[i].1 < b[j].1 { i += 1 } else { j += 1 }
+        }
+
+        Self::from_ranges(ranges)
+    }
+
+    /// Returns the sector sizes supported by `self` or `other`
+    pub fn union(&self, other: &Self) -> Self {
+        let mut ranges = self.to_ranges();
+        ranges.extend(other.to_ranges());
+        Self::from_ranges(merge_ranges(ranges))
+    }
+
+    /// Returns the same set of sector sizes with the most compact representation: lists are
+    /// sorted and deduplicated, overlapping and adjacent ranges are merged, and the empty ones are
+    /// removed.
+    pub fn normalized(&self) -> Self {
+        Self::from_ranges(self.to_ranges())
+    }
+
+    /// Returns the supported sector sizes which divide `offset`, that is the sector sizes usable
+    /// by a subdisk starting at the byte `offset` of a disk supporting `self`. The odd factors of
+    /// the offset are only searched up to the greatest supported sector size: for a set without
+    /// one (like `Any`), only the powers of two are kept.
+    pub fn aligned_to(&self, offset: u64) -> Self {
+        if offset == 0 {
+            return self.normalized();
+        }
+
+        // No divisor is greater than the offset, nor than the greatest supported sector size
+        let ranges = self.to_ranges();
+        let (bound, bounded) = match ranges.last() {
+            Some(&(_, end)) => (offset.min(end as u64 - 1), end != usize::MAX),
+            None => return Self::AllOf(Vec::new()),
+        };
+
+        // ### FACTORIZES THE OFFSET, IGNORING THE FACTORS GREATER THAN THE BOUND ###
+
+        // The powers of two are given by the trailing zeros. Searching the odd factors of a large
+        // offset could take very long, this is only done when the set is bounded.
+        let twos = offset.trailing_zeros();
+        let mut factors: Vec<(u64, u32)> = vec![(2, twos)];
+        let odd_bound = if bounded { bound } else { 1 };
+        let mut n = offset >> twos;
+        let mut p = 3;
+
+        while p <= odd_bound && p.saturating_mul(p) <= n {
+            let mut count = 0;
+            while n.is_multiple_of(p) {
+                n /= p;
+                count += 1;
+            }
+
+            if count != 0 {
+                factors.push((p, count));
+            }
+
+            p += 2;
+        }
+
+        // What remains is a prime factor
+        if n > 1 && n <= odd_bound {
+            factors.push((n, 1));
+        }
+
+        // ### BUILDS THE DIVISORS ###
+
+        let mut divisors = vec![1u64];
+        for (factor, count) in factors {
+            for i in 0..divisors.len() {
+                let mut v = divisors[i];
+                for _ in 0..count {
+                    v = match v.checked_mul(factor) {
+                        Some(v) if v <= bound => v,
+                        _ => break,
+                    };
+                    divisors.push(v);
+                }
+            }
+        }
+
+        let mut supported: Vec<usize> = divisors
+            .into_iter()
+            .map(|v| v as usize)
+            .filter(|&v| self.is_supported(v, u64::MAX))
+            .collect();
+        supported.sort_unstable();
+
+        Self::AllOf(supported)
+    }
+
+    /// Converts the set to sorted, disjoint and non-empty ranges [min, max[
+    fn to_ranges(&self) -> Vec<(usize, usize)> {
+        let singletons =
+            |l: &[usize]| merge_ranges(l.iter().map(|&v| (v, v.saturating_add(1))).collect());
+
+        match self {
+            Self::Any => vec![(0, usize::MAX)],
+            Self::AllOf(l) => singletons(l),
+            Self::AnyExcept(l) => complement_ranges(&singletons(l)),
+            Self::InRanges(rs) => merge_ranges(rs.clone()),
+            Self::AnyExceptRanges(rs) => complement_ranges(&merge_ranges(rs.clone())),
+        }
+    }
+
+    /// Builds the most compact representation of sorted, disjoint and non-empty ranges
+    fn from_ranges(ranges: Vec<(usize, usize)>) -> Self {
+        let is_singleton = |&(start, end): &(usize, usize)| end - start == 1;
+        let complement = complement_ranges(&ranges);
+
+        if complement.is_empty() {
+            Self::Any
+        } else if ranges.iter().all(is_singleton) {
+            Self::AllOf(ranges.into_iter().map(|(v, _)| v).collect())
+        } else if complement.iter().all(is_singleton) {
+            Self::AnyExcept(complement.into_iter().map(|(v, _)| v).collect())
+        } else if complement.len() < ranges.len() {
+            Self::AnyExceptRanges(complement)
+        } else {
+            Self::InRanges(ranges)
+        }
+    }
+}
+
+/// Sorts and merges the overlapping and adjacent ranges, and removes the empty ones
+fn merge_ranges(mut ranges: Vec<(usize, usize)>) -> Vec<(usize, usize)> {
+    ranges.retain(|&(start, end)| start < end);
+    ranges.sort_unstable();
+
+    let mut merged: Vec<(usize, usize)> = Vec::with_capacity(ranges.len());
+    for (start, end) in ranges {
+        match merged.last_mut() {
+            Some(last) if start <= last.1 => last.1 = last.1.max(end),
+            _ => merged.push((start, end)),
+        }
+    }
+
+    merged
+}
+
+/// Returns the ranges of [0, usize::MAX[ not covered by sorted, disjoint and non-empty ranges
+fn complement_ranges(ranges: &[(usize, usize)]) -> Vec<(usize, usize)> {
+    let mut complement = Vec::new();
+    let mut pos = 0;
+
+    for &(start, end) in ranges {
+        if pos < start {
+            complement.push((pos, start));
+        }
+        pos = end;
+    }
+
+    if pos < usize::MAX {
+        complement.push((pos, usize::MAX));
+    }
+
+    complement
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use SectorSize::*;
+
+    const MAX: usize = usize::MAX;
+
+    #[test]
+    fn intersection() {
+        let table = [
+            (Any, Any, Any),
+            (Any, AllOf(vec![4096, 512, 512]), AllOf(vec![512, 4096])),
+            (AllOf(vec![512, 4096]), Any, AllOf(vec![512, 4096])),
+            (AllOf(vec![512]), AllOf(vec![4096]), AllOf(vec![])),
+            (AllOf(vec![]), Any, AllOf(vec![])),
+            (
+                InRanges(vec![(512, 1024)]),
+                InRanges(vec![(768, 2048)]),
+                InRanges(vec![(768, 1024)]),
+            ),
+            (
+                InRanges(vec![(0, 512)]),
+                InRanges(vec![(512, 1024)]),
+                AllOf(vec![]),
+            ),
+            (
+                InRanges(vec![(1, 4), (10, 20)]),
+                InRanges(vec![(2, 12), (15, 30)]),
+                InRanges(vec![(2, 4), (10, 12), (15, 20)]),
+            ),
+            (
+                AnyExcept(vec![512]),
+                AllOf(vec![512, 1024]),
+                AllOf(vec![1024]),
+            ),
+            (
+                AnyExceptRanges(vec![(0, 512)]),
+                AnyExceptRanges(vec![(4096, 8192)]),
+                InRanges(vec![(512, 4096), (8192, MAX)]),
+            ),
+        ];
+
+        for (a, b, expected) in table {
+            assert_eq!(a.intersection(&b), expected, "{a:?} ∩ {b:?}");
+        }
+    }
+
+    #[test]
+    fn union() {
+        let table = [
+            (Any, AllOf(vec![]), Any),
+            (AllOf(vec![]), Any, Any),
+            (AllOf(vec![]), AllOf(vec![]), AllOf(vec![])),
+            (
+                AllOf(vec![4096]),
+                AllOf(vec![512, 4096]),
+                AllOf(vec![512, 4096]),
+            ),
+            (
+                InRanges(vec![(512, 1024)]),
+                InRanges(vec![(1024, 2048)]),
+                InRanges(vec![(512, 2048)]),
+            ),
+            (
+                InRanges(vec![(0, 512)]),
+                AnyExceptRanges(vec![(0, 1024)]),
+                AnyExceptRanges(vec![(512, 1024)]),
+            ),
+            (AnyExcept(vec![512]), AllOf(vec![512]), Any),
+            (AllOf(vec![1]), AnyExcept(vec![0, 1]), AnyExcept(vec![0])),
+        ];
+
+        for (a, b, expected) in table {
+            assert_eq!(a.union(&b), expected, "{a:?} ∪ {b:?}");
+        }
+    }
+
+    #[test]
+    fn normalized() {
+        let table = [
+            (AllOf(vec![4096, 512, 4096]), AllOf(vec![512, 4096])),
+            (
+                InRanges(vec![(1024, 2048), (512, 1536), (4, 4)]),
+                InRanges(vec![(512, 2048)]),
+            ),
+            (
+                InRanges(vec![(512, 513), (1024, 1025)]),
+                AllOf(vec![512, 1024]),
+            ),
+            (InRanges(vec![(0, MAX)]), Any),
+            (InRanges(vec![]), AllOf(vec![])),
+            (AnyExcept(vec![]), Any),
+            (AnyExceptRanges(vec![(512, 513)]), AnyExcept(vec![512])),
+            (AnyExceptRanges(vec![(0, MAX)]), AllOf(vec![])),
+        ];
+
+        for (set, expected) in table {
+            assert_eq!(set.normalized(), expected, "{set:?}");
+        }
+    }
+
+    #[test]
+    fn maximal_le() {
+        let table = [
+            (Any, 4096, Some(4096)),
+            (AllOf(vec![512, 4096]), 4095, Some(512)),
+            (AllOf(vec![512, 4096]), 4096, Some(4096)),
+            (AllOf(vec![512, 4096]), 511, None),
+            (InRanges(vec![(512, 1024)]), 2000, Some(1023)),
+            (InRanges(vec![(512, 1024)]), 700, Some(700)),
+            (AnyExcept(vec![512]), 512, Some(511)),
+            (AnyExceptRanges(vec![(0, 512)]), 100, None),
+            (AllOf(vec![]), 512, None),
+        ];
+
+        for (set, size, expected) in table {
+            assert_eq!(set.maximal_le(size), expected, "{set:?} <= {size}");
+        }
+    }
+
+    #[test]
+    fn iter() {
+        let table = [
+            (AllOf(vec![4096, 512, 1024]), 0, 2048, vec![512, 1024]),
+            (InRanges(vec![(10, 13), (20, 22)]), 11, 20, vec![11, 12, 20]),
+            (Any, 5, 8, vec![5, 6, 7, 8]),
+            (AnyExcept(vec![6]), 5, 8, vec![5, 7, 8]),
+            (Any, 8, 5, vec![]),
+            (AllOf(vec![]), 0, 100, vec![]),
+            (AllOf(vec![MAX - 1]), 0, MAX, vec![MAX - 1]),
+        ];
+
+        for (set, min, max, expected) in table {
+            assert_eq!(
+                set.iter(min, max).collect::<Vec<_>>(),
+                expected,
+                "{set:?} in [{min}, {max}]"
+            );
+        }
+
+        assert_eq!(
+            InRanges(vec![(500, 5000)]).powers_of_two(),
+            AllOf(vec![512, 1024, 2048, 4096])
+        );
+    }
+
+    #[test]
+    fn aligned_to() {
+        let table = [
+            (AllOf(vec![4096, 512]), 0, AllOf(vec![512, 4096])),
+            (AllOf(vec![512, 4096]), 3072, AllOf(vec![512])),
+            (AllOf(vec![512, 4096]), 8192, AllOf(vec![512, 4096])),
+            (AllOf(vec![512, 4096]), 100, AllOf(vec![])),
+            (AllOf(vec![]), 512, AllOf(vec![])),
+            // Bounded sets keep the sizes with odd factors
+            (AllOf(vec![520, 4096]), 1560, AllOf(vec![520])),
+            (
+                InRanges(vec![(20, 200)]),
+                1560,
+                AllOf(vec![
+                    20, 24, 26, 30, 39, 40, 52, 60, 65, 78, 104, 120, 130, 156, 195,
+                ]),
+            ),
+            // Unbounded ones only keep the powers of two
+            (
+                Any,
+                3072,
+                AllOf(vec![1, 2, 4, 8, 16, 32, 64, 128, 256, 512, 1024]),
+            ),
+            (AnyExcept(vec![1, 2]), 12, AllOf(vec![4])),
+            // The greatest prime below 2^64, which would take long to factorize
+            (Any, 18446744073709551557, AllOf(vec![1])),
+            (
+                AnyExceptRanges(vec![(0, 512)]),
+                4096 * 2251799813685119,
+                AllOf(vec![512, 1024, 2048, 4096]),
+            ),
+        ];
+
+        for (set, offset, expected) in table {
+            assert_eq!(set.aligned_to(offset), expected, "{set:?} at {offset}");
+        }
+    }
 }
@@ -0,0 +1,415 @@
+use alloc::{string::String, vec::Vec};
+use core::fmt;
+
+/// Maximal nesting of arrays and objects, to bound the recursion on malformed headers
+const MAX_DEPTH: usize = 32;
+
+/// A JSON value, as found in the LUKS2 metadata. Only integer numbers are supported, the metadata
+/// has no other. The members of the objects keep their order.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) enum Json {
+    Null,
+    Bool(bool),
+    Number(i64),
+    String(String),
+    Array(Vec<Json>),
+    Object(Vec<(String, Json)>),
+}
+
+impl Json {
+    /// Parses `text`, which must hold a single value, surrounded by whitespace only
+    pub fn parse(text: &str) -> Option<Self> {
+        let mut parser = Parser {
+            bytes: text.as_bytes(),
+            pos: 0,
+        };
+
+        let value = parser.value(0)?;
+        parser.skip_whitespace();
+
+        (parser.pos == parser.bytes.len()).then_some(value)
+    }
+
+    /// Member `key` of an object
+    pub fn get(&self, key: &str) -> Option<&Json> {
+        self.as_object()?
+            .iter()
+            .find(|(k, _)| k == key)
+            .map(|(_, v)| v)
+    }
+
+    pub fn as_object(&self) -> Option<&[(String, Json)]> {
+        match self {
+            Self::Object(members) => Some(members),
+            _ => None,
+        }
+    }
+
+    pub fn as_array(&self) -> Option<&[Json]> {
+        match self {
+            Self::Array(values) => Some(values),
+            _ => None,
+        }
+    }
+
+    pub fn as_str(&self) -> Option<&str> {
+        match self {
+            Self::String(s) => Some(s),
+            _ => None,
+        }
+    }
+
+    pub fn as_u64(&self) -> Option<u64> {
+        match self {
+            Self::Number(n) => u64::try_from(*n).ok(),
+            _ => None,
+        }
+    }
+
+    /// A number stored in a string, as LUKS2 does for the 64-bit values
+    pub fn as_u64_str(&self) -> Option<u64> {
+        let s = self.as_str()?;
+
+        if s.is_empty() || !s.bytes().all(|b| b.is_ascii_digit()) {
+            return None;
+        }
+        s.parse().ok()
+    }
+}
+
+impl fmt::Display for Json {
+    /// Compact serialization, without whitespace
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Null => f.write_str("null"),
+            Self::Bool(b) => write!(f, "{b}"),
+            Self::Number(n) => write!(f, "{n}"),
+            Self::String(s) => write_string(f, s),
+            Self::Array(values) => {
+                f.write_str("[")?;
+                for (i, value) in values.iter().enumerate() {
+                    if i != 0 {
+                        f.write_str(",")?;
+                    }
+                    write!(f, "{value}")?;
+                }
+                f.write_str("]")
+            }
+            Self::Object(members) => {
+                f.write_str("{")?;
+                for (i, (key, value)) in members.iter().enumerate() {
+                    if i != 0 {
+                        f.write_str(",")?;
+                    }
+                    write_string(f, key)?;
+                    write!(f, ":{value}")?;
+                }
+                f.write_str("}")
+            }
+        }
+    }
+}
+
+fn write_string(f: &mut fmt::Formatter<'_>, s: &str) -> fmt::Result {
+    f.write_str("\"")?;
+    for c in s.chars() {
+        match c {
+            '"' => f.write_str("\\\"")?,
+            '\\' => f.write_str("\\\\")?,
+            '\n' => f.write_str("\\n")?,
+            '\r' => f.write_str("\\r")?,
+            '\t' => f.write_str("\\t")?,
+            c if (c as u32) < 0x20 => write!(f, "\\u{:04x}", c as u32)?,
+            c => write!(f, "{c}")?,
+        }
+    }
+    f.write_str("\"")
+}
+
+struct Parser<'a> {
+    bytes: &'a [u8],
+    pos: usize,
+}
+
+impl Parser<'_> {
+    fn skip_whitespace(&mut self) {
+        while let Some(b' ' | b'\t' | b'\n' | b'\r') = self.bytes.get(self.pos) {
+            self.pos += 1;
+        }
+    }
+
+    fn peek(&self) -> Option<u8> {
+        self.bytes.get(self.pos).copied()
+    }
+
+    fn expect(&mut self, token: &[u8]) -> Option<()> {
+        if self.bytes[self.pos..].starts_with(token) {
+            self.pos += token.len();
+            Some(())
+        } else {
+            None
+        }
+    }
+
+    fn value(&mut self, depth: usize) -> Option<Json> {
+        if depth > MAX_DEPTH {
+            return None;
+        }
+
+        self.skip_whitespace();
+
+        match self.peek()? {
+            b'n' => self.expect(b"null").map(|_| Json::Null),
+            b't' => self.expect(b"true").map(|_| Json::Bool(true)),
+            b'f' => self.expect(b"false").map(|_| Json::Bool(false)),
+            b'"' => self.string().map(Json::String),
+            b'[' => {
+                self.pos += 1;
+                let mut values = Vec::new();
+
+                self.skip_whitespace();
+                if self.peek()? == b']' {
+                    self.pos += 1;
+                    return Some(Json::Array(values));
+                }
+
+                loop {
+                    values.push(self.value(depth + 1)?);
+                    self.skip_whitespace();
+                    match self.peek()? {
+                        b',' => self.pos += 1,
+                        b']' => {
+                            self.pos += 1;
+                            return Some(Json::Array(values));
+                        }
+                        _ => return None,
+                    }
+                }
+            }
+            b'{' => {
+                self.pos += 1;
+                let mut members = Vec::new();
+
+                self.skip_whitespace();
+                if self.peek()? == b'}' {
+                    self.pos += 1;
+                    return Some(Json::Object(members));
+                }
+
+                loop {
+                    self.skip_whitespace();
+                    let key = self.string()?;
+                    self.skip_whitespace();
+                    self.expect(b":")?;
+                    members.push((key, self.value(depth + 1)?));
+                    self.skip_whitespace();
+                    match self.peek()? {
+                        b',' => self.pos += 1,
+                        b'}' => {
+                            self.pos += 1;
+                            return Some(Json::Object(members));
+                        }
+                        _ => return None,
+                    }
+                }
+            }
+            b'-' | b'0'..=b'9' => self.number(),
+            _ => None,
+        }
+    }
+
+    fn number(&mut self) -> Option<Json> {
+        let start = self.pos;
+        if self.peek() == Some(b'-') {
+            self.pos += 1;
+        }
+        while let Some(b'0'..=b'9') = self.peek() {
+            self.pos += 1;
+        }
+
+        // Fractions and exponents are not supported
+        if let Some(b'.' | b'e' | b'E') = self.peek() {
+            return None;
+        }
+
+        core::str::from_utf8(&self.bytes[start..self.pos])
+            .ok()?
+            .parse()
+            .ok()
+            .map(Json::Number)
+    }
+
+    fn string(&mut self) -> Option<String> {
+        self.expect(b"\"")?;
+        let mut s = String::new();
+
+        loop {
+            let start = self.pos;
+            while let Some(b) = self.peek() {
+                if b == b'"' || b == b'\\' || b < 0x20 {
+                    break;
+                }
+                self.pos += 1;
+            }
+            s.push_str(core::str::from_utf8(&self.bytes[start..self.pos]).ok()?);
+
+            match self.peek()? {
+                b'"' => {
+                    self.pos += 1;
+                    return Some(s);
+                }
+                b'\\' => {
+                    self.pos += 1;
+                    let escape = self.peek()?;
+                    self.pos += 1;
+
+                    s.push(match escape {
+                        b'"' => '"',
+                        b'\\' => '\\',
+                        b'/' => '/',
+                        b'b' => '\u{8}',
+                        b'f' => '\u{c}',
+                        b'n' => '\n',
+                        b'r' => '\r',
+                        b't' => '\t',
+                        b'u' => {
+                            let high = self.hex4()?;
+                            // Characters out of the BMP are escaped as surrogate pairs
+                            if (0xD800..0xDC00).contains(&high) {
+                                self.expect(b"\\u")?;
+                                let low = self.hex4()?;
+                                if !(0xDC00..0xE000).contains(&low) {
+                                    return None;
+                                }
+                                char::from_u32(0x10000 + ((high - 0xD800) << 10) + (low - 0xDC00))?
+                            } else {
+                                char::from_u32(high)?
+                            }
+                        }
+                        _ => return None,
+                    });
+                }
+                // Unescaped control character
+                _ => return None,
+            }
+        }
+    }
+
+    fn hex4(&mut self) -> Option<u32> {
+        let digits = self.bytes.get(self.pos..(self.pos + 4))?;
+        if !digits.iter().all(u8::is_ascii_hexdigit) {
+            return None;
+        }
+        let value = u32::from_str_radix(core::str::from_utf8(digits).ok()?, 16).ok()?;
+        self.pos += 4;
+        Some(value)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use alloc::{string::ToString, vec};
+
+    #[test]
+    fn parse() {
+        let json = Json::parse(
+            r#" {"keyslots": {"0": {"type": "luks2", "key_size": 64}}, "segments": [],
+                "config": {"json_size": "12288", "flags": [true, false, null, -1]}} "#,
+        )
+        .unwrap();
+
+        let slot = json.get("keyslots").and_then(|k| k.get("0")).unwrap();
+        assert_eq!(slot.get("type").and_then(Json::as_str), Some("luks2"));
+        assert_eq!(slot.get("key_size").and_then(Json::as_u64), Some(64));
+        assert_eq!(json.get("segments").and_then(Json::as_array), Some(&[][..]));
+
+        let config = json.get("config").unwrap();
+        assert_eq!(
+            config.get("json_size").and_then(Json::as_u64_str),
+            Some(12288)
+        );
+        assert_eq!(
+            config.get("flags"),
+            Some(&Json::Array(vec![
+                Json::Bool(true),
+                Json::Bool(false),
+                Json::Null,
+                Json::Number(-1)
+            ]))
+        );
+        assert_eq!(json.get("tokens"), None);
+
+        // The members keep their order
+        let keys: Vec<&str> = json
+            .as_object()
+            .unwrap()
+            .iter()
+            .map(|(k, _)| k.as_str())
+            .collect();
+        assert_eq!(keys, ["keyslots", "segments", "config"]);
+    }
+
+    #[test]
+    fn strings() {
+        let json = Json::parse(r#""a\"b\\c\/d\n\t\u00e9\ud83d\ude00""#).unwrap();
+        assert_eq!(json.as_str(), Some("a\"b\\c/d\n\t\u{e9}\u{1F600}"));
+
+        // The serialization parses back to the same value
+        let value = Json::Object(vec![(
+            "key \"1\"".into(),
+            Json::String("\u{1}\r\n\\ é".into()),
+        )]);
+        let text = value.to_string();
+        assert_eq!(text, r#"{"key \"1\"":"\u0001\r\n\\ é"}"#);
+        assert_eq!(Json::parse(&text), Some(value));
+    }
+
+    #[test]
+    fn numbers() {
+        assert_eq!(Json::parse("0"), Some(Json::Number(0)));
+        assert_eq!(
+            Json::parse("-9223372036854775808"),
+            Some(Json::Number(i64::MIN))
+        );
+        assert_eq!(Json::Number(-1).as_u64(), None);
+
+        for s in ["", "-", "1a", "-1", " 1", "18446744073709551616"] {
+            assert_eq!(Json::String(s.into()).as_u64_str(), None, "{s:?}");
+        }
+        assert_eq!(
+            Json::String("18446744073709551615".into()).as_u64_str(),
+            Some(u64::MAX)
+        );
+    }
+
+    #[test]
+    fn invalid() {
+        let texts = [
+            "",
+            "{",
+            "[1,]",
+            "{\"a\" 1}",
+            "{\"a\":1,}",
+            "{a:1}",
+            "[1] [2]",
+            "nul",
+            "1.5",
+            "1e3",
+            "9223372036854775808",
+            "\"unterminated",
+            "\"control \n character\"",
+            "\"\\x\"",
+            "\"\\u12\"",
+            "\"\\ud83d alone\"",
+        ];
+        for text in texts {
+            assert_eq!(Json::parse(text), None, "{text:?}");
+        }
+
+        // The nesting is bounded
+        let nested = |depth| "[".repeat(depth) + &"]".repeat(depth);
+        assert!(Json::parse(&nested(MAX_DEPTH)).is_some());
+        assert!(Json::parse(&nested(1000)).is_none());
+    }
+}
@@ -0,0 +1,263 @@
+use super::{
+    AF_STRIPES, AREA_SECTOR_SIZE, Kdf, KeyDigest, KeySlot, LuksHeader, LuksVersion, MAGIC, c_string,
+};
+use crate::{LuksErr, crypto::HashAlgorithm};
+use alloc::{format, vec, vec::Vec};
+
+/// Size of the header, key slots included
+pub(super) const HEADER_SIZE: usize = 592;
+
+/// Size of the digest of the volume key, whatever the hash
+pub(super) const DIGEST_SIZE: usize = 20;
+
+const KEYSLOTS: usize = 8;
+const KEYSLOT_ACTIVE: u32 = 0x00AC71F3;
+const KEYSLOT_DISABLED: u32 = 0x0000DEAD;
+
+/// Alignment of the key material areas (4 KiB), and of the payload (1 MiB), in bytes, as
+/// cryptsetup does
+const AREA_ALIGNMENT: u64 = 4096;
+const PAYLOAD_ALIGNMENT: u64 = 1 << 20;
+
+fn be32(raw: &[u8], offset: usize) -> u32 {
+    u32::from_be_bytes(raw[offset..(offset + 4)].try_into().unwrap())
+}
+
+/// The positions of the key material areas in bytes, and the offset of the payload, for keys of
+/// `key_size` bytes
+pub(super) fn layout(key_size: usize) -> ([(u64, u64); KEYSLOTS], u64) {
+    let area_size = (key_size as u64 * AF_STRIPES as u64).next_multiple_of(AREA_SECTOR_SIZE as u64);
+    let stride = area_size.next_multiple_of(AREA_ALIGNMENT);
+
+    let areas = core::array::from_fn(|i| (AREA_ALIGNMENT + i as u64 * stride, area_size));
+    let payload_offset =
+        (AREA_ALIGNMENT + KEYSLOTS as u64 * stride).next_multiple_of(PAYLOAD_ALIGNMENT);
+
+    (areas, payload_offset)
+}
+
+pub(super) fn parse(raw: &[u8; HEADER_SIZE]) -> Result<LuksHeader, LuksErr> {
+    let cipher = format!("{}-{}", c_string(&raw[8..40])?, c_string(&raw[40..72])?);
+    let hash_spec = c_string(&raw[72..104])?;
+    let hash =
+        HashAlgorithm::from_name(&hash_spec).ok_or(LuksErr::Unsupported { feature: hash_spec })?;
+
+    let payload_offset = be32(raw, 104) as u64 * 512;
+    let key_size = be32(raw, 108) as usize;
+
+    let mut keyslots = Vec::new();
+    for index in 0..KEYSLOTS {
+        let slot = &raw[(208 + 48 * index)..(208 + 48 * (index + 1))];
+
+        match be32(slot, 0) {
+            KEYSLOT_ACTIVE => {}
+            KEYSLOT_DISABLED => continue,
+            _ => {
+                return Err(LuksErr::InvalidHeader {
+                    reason: "invalid key slot state",
+                });
+            }
+        }
+
+        let af_stripes = be32(slot, 44);
+        let keyslot = KeySlot {
+            index,
+            key_size,
+            kdf: Kdf::Pbkdf2 {
+                hash,
+                iterations: be32(slot, 4),
+            },
+            salt: slot[8..40].to_vec(),
+            af_hash: hash,
+            af_stripes,
+            area_offset: be32(slot, 40) as u64 * 512,
+            area_size: (key_size as u64 * af_stripes as u64)
+                .next_multiple_of(AREA_SECTOR_SIZE as u64),
+            area_cipher: cipher.clone(),
+            area_key_size: key_size,
+        };
+        keyslot.check()?;
+
+        keyslots.push(keyslot);
+    }
+
+    Ok(LuksHeader {
+        version: LuksVersion::Luks1,
+        uuid: c_string(&raw[168..208])?,
+        label: Default::default(),
+        cipher,
+        key_size,
+        payload_offset,
+        payload_size: None,
+        sector_size: 512,
+        iv_tweak: 0,
+        digests: vec![KeyDigest {
+            hash,
+            iterations: be32(raw, 164),
+            salt: raw[132..164].to_vec(),
+            digest: raw[112..132].to_vec(),
+            keyslots: keyslots.iter().map(|s| s.index).collect(),
+        }],
+        keyslots,
+    })
+}
+
+/// The on-disk header of `header`, created by `Luks::format`: the key slots have the areas of
+/// `layout`, and the hashes are the one of the digest
+pub(super) fn to_bytes(header: &LuksHeader) -> [u8; HEADER_SIZE] {
+    let mut raw = [0; HEADER_SIZE];
+    let digest = &header.digests[0];
+    let (areas, _) = layout(header.key_size);
+
+    let (cipher_name, cipher_mode) = header
+        .cipher
+        .split_once('-')
+        .unwrap_or((&header.cipher, ""));
+    let strings: [(usize, &str); 4] = [
+        (8, cipher_name),
+        (40, cipher_mode),
+        (72, digest.hash.name()),
+        (168, &header.uuid),
+    ];
+
+    raw[..6].copy_from_slice(&MAGIC);
+    raw[6..8].copy_from_slice(&1u16.to_be_bytes());
+    for (offset, s) in strings {
+        raw[offset..(offset + s.len())].copy_from_slice(s.as_bytes());
+    }
+    raw[104..108].copy_from_slice(&((header.payload_offset / 512) as u32).to_be_bytes());
+    raw[108..112].copy_from_slice(&(header.key_size as u32).to_be_bytes());
+    raw[112..132].copy_from_slice(&digest.digest);
+    raw[132..164].copy_from_slice(&digest.salt);
+    raw[164..168].copy_from_slice(&digest.iterations.to_be_bytes());
+
+    for (index, (area_offset, _)) in areas.iter().enumerate() {
+        let slot = &mut raw[(208 + 48 * index)..(208 + 48 * (index + 1))];
+
+        match header.keyslots.iter().find(|s| s.index == index) {
+            Some(keyslot) => {
+                let iterations = match keyslot.kdf {
+                    Kdf::Pbkdf2 { iterations, .. } => iterations,
+                    Kdf::Argon2(_) => 0,
+                };
+
+                slot[..4].copy_from_slice(&KEYSLOT_ACTIVE.to_be_bytes());
+                slot[4..8].copy_from_slice(&iterations.to_be_bytes());
+                slot[8..40].copy_from_slice(&keyslot.salt);
+                slot[40..44].copy_from_slice(&((keyslot.area_offset / 512) as u32).to_be_bytes());
+                slot[44..48].copy_from_slice(&keyslot.af_stripes.to_be_bytes());
+            }
+            None => {
+                slot[..4].copy_from_slice(&KEYSLOT_DISABLED.to_be_bytes());
+                slot[40..44].copy_from_slice(&((area_offset / 512) as u32).to_be_bytes());
+                slot[44..48].copy_from_slice(&AF_STRIPES.to_be_bytes());
+            }
+        }
+    }
+
+    raw
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{Disk, luks::tests::formatted};
+
+    fn read_header(disk: &impl Disk) -> [u8; HEADER_SIZE] {
+        let mut sectors = [0; 1024];
+        disk.read_sectors(0, 512, &mut sectors).unwrap();
+        sectors[..HEADER_SIZE].try_into().unwrap()
+    }
+
+    #[test]
+    fn cryptsetup_layout() {
+        // The offsets of cryptsetup, in sectors
+        let cases = [
+            (32, [8, 264, 520, 776, 1032, 1288, 1544, 1800]),
+            (64, [8, 512, 1016, 1520, 2024, 2528, 3032, 3536]),
+        ];
+
+        for (key_size, offsets) in cases {
+            let (areas, payload_offset) = layout(key_size);
+            for ((offset, size), expected) in areas.iter().zip(offsets) {
+                assert_eq!(*offset, expected * 512);
+                assert_eq!(*size, key_size as u64 * 4000);
+            }
+            assert_eq!(payload_offset, 4096 * 512);
+        }
+    }
+
+    #[test]
+    fn header() {
+        let (disk, luks) = formatted(LuksVersion::Luks1);
+        let raw = read_header(&disk);
+
+        assert_eq!(raw[..8], *b"LUKS\xBA\xBE\x00\x01");
+        assert_eq!(c_string(&raw[8..40]).unwrap(), "aes");
+        assert_eq!(c_string(&raw[40..72]).unwrap(), "xts-plain64");
+        assert_eq!(c_string(&raw[72..104]).unwrap(), "sha1");
+        assert_eq!(be32(&raw, 104), 4096);
+        assert_eq!(be32(&raw, 108), 32);
+        assert_eq!(be32(&raw, 164), 1000);
+        assert_eq!(c_string(&raw[168..208]).unwrap(), luks.header().uuid);
+
+        // The slot 0 is active, the other ones are disabled but keep their areas
+        for (index, offset) in [8, 264, 520, 776, 1032, 1288, 1544, 1800]
+            .iter()
+            .enumerate()
+        {
+            let slot = &raw[(208 + 48 * index)..(208 + 48 * (index + 1))];
+            let state = if index == 0 {
+                KEYSLOT_ACTIVE
+            } else {
+                KEYSLOT_DISABLED
+            };
+            assert_eq!(be32(slot, 0), state);
+            assert_eq!(be32(slot, 40), *offset);
+            assert_eq!(be32(slot, 44), 4000);
+        }
+
+        let header = parse(&raw).unwrap();
+        assert_eq!(&header, luks.header());
+        assert_eq!(to_bytes(&header), raw);
+    }
+
+    #[test]
+    fn invalid_headers() {
+        let (disk, _) = formatted(LuksVersion::Luks1);
+        let raw = read_header(&disk);
+
+        let mut state = raw;
+        state[(208 + 48)..(208 + 52)].fill(0xFF);
+        assert_eq!(
+            parse(&state),
+            Err(LuksErr::InvalidHeader {
+                reason: "invalid key slot state"
+            })
+        );
+
+        // Too large a key, or too many stripes for the area of the key slot
+        let mut key_size = raw;
+        key_size[108..112].copy_from_slice(&0x10000u32.to_be_bytes());
+        let mut stripes = raw;
+        stripes[(208 + 44)..(208 + 48)].copy_from_slice(&u32::MAX.to_be_bytes());
+        for raw in [key_size, stripes] {
+            assert_eq!(
+                parse(&raw),
+                Err(LuksErr::InvalidHeader {
+                    reason: "invalid key slot sizes"
+                })
+            );
+        }
+
+        let mut hash = raw;
+        hash[72..104].fill(0);
+        hash[72..75].copy_from_slice(b"md5");
+        assert_eq!(
+            parse(&hash),
+            Err(LuksErr::Unsupported {
+                feature: "md5".into()
+            })
+        );
+    }
+}
@@ -0,0 +1,629 @@
+use super::{
+    AF_STRIPES, Kdf, KeyDigest, KeySlot, LuksHeader, LuksVersion, MAGIC, c_string, json::Json,
+};
+use crate::{
+    LuksErr,
+    bytedisk::ByteDisk,
+    crypto::{HashAlgorithm, Hasher, argon2, sha2::Sha256},
+    wrappers::DiskWrapper,
+};
+use alloc::{
+    format,
+    string::{String, ToString},
+    sync::Arc,
+    vec,
+    vec::Vec,
+};
+
+/// Magic of the secondary header
+const SECONDARY_MAGIC: [u8; 6] = *b"SKUL\xBA\xBE";
+
+/// Size of the binary header, followed by the JSON area
+const BINARY_HEADER_SIZE: usize = 4096;
+
+/// The possible sizes of the metadata (binary header and JSON area). The secondary header follows
+/// the primary one, at one of these offsets.
+const METADATA_SIZES: [u64; 9] = [
+    0x4000, 0x8000, 0x10000, 0x20000, 0x40000, 0x80000, 0x100000, 0x200000, 0x400000,
+];
+
+/// Layout of the created containers, the default of cryptsetup: 16 KiB of metadata, twice, then
+/// the key slots area up to the payload at 16 MiB
+const METADATA_SIZE: u64 = 0x4000;
+const KEYSLOTS_OFFSET: u64 = 2 * METADATA_SIZE;
+pub(super) const PAYLOAD_OFFSET: u64 = 16 << 20;
+
+/// Alignment of the key slot areas
+const AREA_ALIGNMENT: u64 = 4096;
+
+type Bytes = ByteDisk<Arc<DiskWrapper>>;
+
+fn be64(raw: &[u8], offset: usize) -> u64 {
+    u64::from_be_bytes(raw[offset..(offset + 8)].try_into().unwrap())
+}
+
+/// The area of the key slot of the created containers, for keys of `key_size` bytes
+pub(super) fn area(key_size: usize) -> (u64, u64) {
+    let size = (key_size as u64 * AF_STRIPES as u64).next_multiple_of(AREA_ALIGNMENT);
+    (KEYSLOTS_OFFSET, size)
+}
+
+// ### READING ###
+
+/// Reads the valid header with the highest sequence number. Returns `None` if there is no LUKS2
+/// header at all.
+pub(super) fn read(disk: &Bytes) -> Result<Option<LuksHeader>, LuksErr> {
+    let disk_size = disk.size()?;
+    let locations = core::iter::once((0, MAGIC)).chain(
+        METADATA_SIZES
+            .iter()
+            .map(|&offset| (offset, SECONDARY_MAGIC)),
+    );
+
+    let mut found = false;
+    let mut best: Option<(u64, Vec<u8>)> = None;
+
+    for (offset, magic) in locations {
+        if offset + BINARY_HEADER_SIZE as u64 > disk_size {
+            continue;
+        }
+
+        let mut binary = vec![0; BINARY_HEADER_SIZE];
+        disk.read_at(offset, &mut binary)?;
+
+        if binary[..6] != magic || binary[6..8] != [0, 2] {
+            continue;
+        }
+        found = true;
+
+        let metadata_size = be64(&binary, 8);
+        if !METADATA_SIZES.contains(&metadata_size)
+            || be64(&binary, 256) != offset
+            || offset + metadata_size > disk_size
+            || c_string(&binary[72..104])? != "sha256"
+        {
+            continue;
+        }
+
+        let mut metadata = vec![0; metadata_size as usize];
+        disk.read_at(offset, &mut metadata)?;
+
+        // The checksum covers the whole metadata, with the checksum field zeroed
+        let checksum = metadata[448..480].to_vec();
+        metadata[448..512].fill(0);
+        if Sha256::digest(&metadata) != checksum {
+            continue;
+        }
+
+        let seqid = be64(&metadata, 16);
+        if best.as_ref().is_none_or(|(best, _)| seqid > *best) {
+            best = Some((seqid, metadata));
+        }
+    }
+
+    match best {
+        Some((_, metadata)) => parse(&metadata).map(Some),
+        None if found => Err(LuksErr::InvalidHeader {
+            reason: "no LUKS2 header matches its checksum",
+        }),
+        None => Ok(None),
+    }
+}
+
+fn parse(metadata: &[u8]) -> Result<LuksHeader, LuksErr> {
+    let json_area = &metadata[BINARY_HEADER_SIZE..];
+    let len = json_area
+        .iter()
+        .position(|&b| b == 0)
+        .unwrap_or(json_area.len());
+    let json = core::str::from_utf8(&json_area[..len])
+        .ok()
+        .and_then(Json::parse)
+        .ok_or(INVALID_JSON)?;
+
+    // Set during a reencryption for example, that this library can't handle
+    if let Some(requirement) = json
+        .get("config")
+        .and_then(|config| config.get("requirements"))
+        .and_then(|requirements| requirements.get("mandatory"))
+        .and_then(Json::as_array)
+        .and_then(|mandatory| mandatory.first())
+    {
+        return Err(LuksErr::Unsupported {
+            feature: format!("requirement {}", requirement.as_str().unwrap_or("?")),
+        });
+    }
+
+    // ### SEGMENT ###
+
+    let segments = field(&json, "segments")?.as_object().ok_or(INVALID_JSON)?;
+    let segment = match segments {
+        [(_, segment)] if str_field(segment, "type")? == "crypt" => segment,
+        _ => {
+            return Err(LuksErr::Unsupported {
+                feature: "payloads without a single crypt segment".into(),
+            });
+        }
+    };
+
+    let payload_size = match str_field(segment, "size")? {
+        "dynamic" => None,
+        _ => Some(u64_str_field(segment, "size")?),
+    };
+
+    let sector_size = u32_field(segment, "sector_size")? as usize;
+    if !sector_size.is_power_of_two() || !(512..=4096).contains(&sector_size) {
+        return Err(LuksErr::InvalidHeader {
+            reason: "invalid sector size",
+        });
+    }
+
+    // ### KEY SLOTS ###
+
+    let mut keyslots = Vec::new();
+    for (id, slot) in field(&json, "keyslots")?.as_object().ok_or(INVALID_JSON)? {
+        // Other types hold the state of a reencryption, not a key
+        if str_field(slot, "type")? != "luks2" {
+            continue;
+        }
+
+        let af = field(slot, "af")?;
+        let area = field(slot, "area")?;
+        let kdf = field(slot, "kdf")?;
+
+        if str_field(af, "type")? != "luks1" || str_field(area, "type")? != "raw" {
+            return Err(LuksErr::Unsupported {
+                feature: "key slots without raw area or LUKS1 splitter".into(),
+            });
+        }
+
+        let kdf_type = str_field(kdf, "type")?;
+        let keyslot = KeySlot {
+            index: id.parse().map_err(|_| INVALID_JSON)?,
+            key_size: u32_field(slot, "key_size")? as usize,
+            kdf: match (kdf_type, argon2::Variant::from_name(kdf_type)) {
+                ("pbkdf2", _) => Kdf::Pbkdf2 {
+                    hash: hash_field(kdf, "hash")?,
+                    iterations: u32_field(kdf, "iterations")?,
+                },
+                (_, Some(variant)) => Kdf::Argon2(argon2::Params {
+                    variant,
+                    iterations: u32_field(kdf, "time")?,
+                    memory: u32_field(kdf, "memory")?,
+                    lanes: u32_field(kdf, "cpus")?,
+                }),
+                (_, None) => {
+                    return Err(LuksErr::Unsupported {
+                        feature: kdf_type.into(),
+                    });
+                }
+            },
+            salt: base64_field(kdf, "salt")?,
+            af_hash: hash_field(af, "hash")?,
+            af_stripes: u32_field(af, "stripes")?,
+            area_offset: u64_str_field(area, "offset")?,
+            area_size: u64_str_field(area, "size")?,
+            area_cipher: str_field(area, "encryption")?.into(),
+            area_key_size: u32_field(area, "key_size")? as usize,
+        };
+        keyslot.check()?;
+
+        keyslots.push(keyslot);
+    }
+
+    // ### DIGESTS ###
+
+    let mut digests = Vec::new();
+    for (_, digest) in field(&json, "digests")?.as_object().ok_or(INVALID_JSON)? {
+        if str_field(digest, "type")? != "pbkdf2" {
+            continue;
+        }
+
+        digests.push(KeyDigest {
+            hash: hash_field(digest, "hash")?,
+            iterations: u32_field(digest, "iterations")?,
+            salt: base64_field(digest, "salt")?,
+            digest: base64_field(digest, "digest")?,
+            keyslots: field(digest, "keyslots")?
+                .as_array()
+                .ok_or(INVALID_JSON)?
+                .iter()
+                .map(|id| id.as_str().and_then(|id| id.parse().ok()))
+                .collect::<Option<_>>()
+                .ok_or(INVALID_JSON)?,
+        });
+    }
+
+    Ok(LuksHeader {
+        version: LuksVersion::Luks2,
+        uuid: c_string(&metadata[168..208])?,
+        label: c_string(&metadata[24..72])?,
+        cipher: str_field(segment, "encryption")?.into(),
+        key_size: keyslots.first().map_or(0, |slot| slot.key_size),
+        payload_offset: u64_str_field(segment, "offset")?,
+        payload_size,
+        sector_size,
+        iv_tweak: u64_str_field(segment, "iv_tweak")?,
+        keyslots,
+        digests,
+    })
+}
+
+const INVALID_JSON: LuksErr = LuksErr::InvalidHeader {
+    reason: "invalid JSON metadata",
+};
+
+fn field<'a>(value: &'a Json, key: &str) -> Result<&'a Json, LuksErr> {
+    value.get(key).ok_or(INVALID_JSON)
+}
+
+fn str_field<'a>(value: &'a Json, key: &str) -> Result<&'a str, LuksErr> {
+    field(value, key)?.as_str().ok_or(INVALID_JSON)
+}
+
+/// The 64-bit values are stored in strings
+fn u64_str_field(value: &Json, key: &str) -> Result<u64, LuksErr> {
+    field(value, key)?.as_u64_str().ok_or(INVALID_JSON)
+}
+
+fn u32_field(value: &Json, key: &str) -> Result<u32, LuksErr> {
+    field(value, key)?
+        .as_u64()
+        .and_then(|v| u32::try_from(v).ok())
+        .ok_or(INVALID_JSON)
+}
+
+fn hash_field(value: &Json, key: &str) -> Result<HashAlgorithm, LuksErr> {
+    let name = str_field(value, key)?;
+    HashAlgorithm::from_name(name).ok_or_else(|| LuksErr::Unsupported {
+        feature: name.into(),
+    })
+}
+
+fn base64_field(value: &Json, key: &str) -> Result<Vec<u8>, LuksErr> {
+    base64_decode(str_field(value, key)?).ok_or(INVALID_JSON)
+}
+
+// ### WRITING ###
+
+/// Writes the primary and the secondary headers of `header`, created by `Luks::format`
+pub(super) fn write(
+    disk: &Bytes,
+    header: &LuksHeader,
+    random: &mut impl FnMut(&mut [u8]),
+) -> Result<(), LuksErr> {
+    let json = to_json(header).to_string();
+
+    if json.len() >= METADATA_SIZE as usize - BINARY_HEADER_SIZE {
+        return Err(LuksErr::InvalidHeader {
+            reason: "the JSON metadata doesn't fit in its area",
+        });
+    }
+
+    for (offset, magic) in [(0, MAGIC), (METADATA_SIZE, SECONDARY_MAGIC)] {
+        let mut metadata = vec![0; METADATA_SIZE as usize];
+
+        metadata[..6].copy_from_slice(&magic);
+        metadata[6..8].copy_from_slice(&2u16.to_be_bytes());
+        metadata[8..16].copy_from_slice(&METADATA_SIZE.to_be_bytes());
+        metadata[16..24].copy_from_slice(&1u64.to_be_bytes());
+        metadata[24..(24 + header.label.len())].copy_from_slice(header.label.as_bytes());
+        metadata[72..78].copy_from_slice(b"sha256");
+        random(&mut metadata[104..168]);
+        metadata[168..(168 + header.uuid.len())].copy_from_slice(header.uuid.as_bytes());
+        metadata[256..264].copy_from_slice(&offset.to_be_bytes());
+        metadata[BINARY_HEADER_SIZE..(BINARY_HEADER_SIZE + json.len())]
+            .copy_from_slice(json.as_bytes());
+
+        let checksum = Sha256::digest(&metadata);
+        metadata[448..480].copy_from_slice(&checksum);
+
+        disk.write_at(offset, &metadata)?;
+    }
+
+    Ok(())
+}
+
+fn object<const N: usize>(members: [(&str, Json); N]) -> Json {
+    Json::Object(
+        members
+            .into_iter()
+            .map(|(key, value)| (key.into(), value))
+            .collect(),
+    )
+}
+
+fn string(value: impl ToString) -> Json {
+    Json::String(value.to_string())
+}
+
+fn number(value: impl Into<i64>) -> Json {
+    Json::Number(value.into())
+}
+
+fn to_json(header: &LuksHeader) -> Json {
+    let keyslots = header
+        .keyslots
+        .iter()
+        .map(|slot| {
+            let kdf = match slot.kdf {
+                Kdf::Pbkdf2 { hash, iterations } => object([
+                    ("type", string("pbkdf2")),
+                    ("hash", string(hash.name())),
+                    ("iterations", number(iterations)),
+                    ("salt", string(base64_encode(&slot.salt))),
+                ]),
+                Kdf::Argon2(params) => object([
+                    ("type", string(params.variant.name())),
+                    ("time", number(params.iterations)),
+                    ("memory", number(params.memory)),
+                    ("cpus", number(params.lanes)),
+                    ("salt", string(base64_encode(&slot.salt))),
+                ]),
+            };
+
+            let keyslot = object([
+                ("type", string("luks2")),
+                ("key_size", number(slot.key_size as u32)),
+                (
+                    "af",
+                    object([
+                        ("type", string("luks1")),
+                        ("stripes", number(slot.af_stripes)),
+                        ("hash", string(slot.af_hash.name())),
+                    ]),
+                ),
+                (
+                    "area",
+                    object([
+                        ("type", string("raw")),
+                        ("offset", string(slot.area_offset)),
+                        ("size", string(slot.area_size)),
+                        ("encryption", string(&slot.area_cipher)),
+                        ("key_size", number(slot.area_key_size as u32)),
+                    ]),
+                ),
+                ("kdf", kdf),
+            ]);
+
+            (slot.index.to_string(), keyslot)
+        })
+        .collect();
+
+    let segment = object([
+        ("type", string("crypt")),
+        ("offset", string(header.payload_offset)),
+        (
+            "size",
+            match header.payload_size {
+                Some(size) => string(size),
+                None => string("dynamic"),
+            },
+        ),
+        ("iv_tweak", string(header.iv_tweak)),
+        ("encryption", string(&header.cipher)),
+        ("sector_size", number(header.sector_size as u32)),
+    ]);
+
+    let digests = header
+        .digests
+        .iter()
+        .enumerate()
+        .map(|(i, digest)| {
+            let digest = object([
+                ("type", string("pbkdf2")),
+                (
+                    "keyslots",
+                    Json::Array(digest.keyslots.iter().map(string).collect()),
+                ),
+                ("segments", Json::Array(vec![string(0)])),
+                ("hash", string(digest.hash.name())),
+                ("iterations", number(digest.iterations)),
+                ("salt", string(base64_encode(&digest.salt))),
+                ("digest", string(base64_encode(&digest.digest))),
+            ]);
+
+            (i.to_string(), digest)
+        })
+        .collect();
+
+    object([
+        ("keyslots", Json::Object(keyslots)),
+        ("tokens", Json::Object(Vec::new())),
+        ("segments", object([("0", segment)])),
+        ("digests", Json::Object(digests)),
+        (
+            "config",
+            object([
+                (
+                    "json_size",
+                    string(METADATA_SIZE - BINARY_HEADER_SIZE as u64),
+                ),
+                (
+                    "keyslots_size",
+                    string(header.payload_offset - KEYSLOTS_OFFSET),
+                ),
+            ]),
+        ),
+    ])
+}
+
+// ### BASE64 ###
+
+const BASE64_ALPHABET: &[u8; 64] =
+    b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
+
+fn base64_encode(data: &[u8]) -> String {
+    let mut encoded = String::with_capacity(data.len().div_ceil(3) * 4);
+
+    for chunk in data.chunks(3) {
+        let bits = chunk
+            .iter()
+            .enumerate()
+            .fold(0u32, |acc, (i, &b)| acc | (b as u32) << (16 - 8 * i));
+
+        for i in 0..4 {
+            if i <= chunk.len() {
+                encoded.push(BASE64_ALPHABET[(bits >> (18 - 6 * i)) as usize & 0x3F] as char);
+            } else {
+                encoded.push('=');
+            }
+        }
+    }
+
+    encoded
+}
+
+fn base64_decode(text: &str) -> Option<Vec<u8>> {
+    let text = text.trim_end_matches('=').as_bytes();
+    let mut decoded = Vec::with_capacity(text.len() * 3 / 4);
+
+    for chunk in text.chunks(4) {
+        if chunk.len() == 1 {
+            return None;
+        }
+
+        let mut bits = 0u32;
+        for (i, &c) in chunk.iter().enumerate() {
+            let value = BASE64_ALPHABET.iter().position(|&a| a == c)? as u32;
+            bits |= value << (18 - 6 * i);
+        }
+
+        decoded.extend_from_slice(&bits.to_be_bytes()[1..chunk.len()]);
+    }
+
+    Some(decoded)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        Permissions, SectorSize,
+        luks::{Luks, tests::formatted},
+        memdisk::MemDisk,
+    };
+
+    #[test]
+    fn base64() {
+        // RFC 4648, section 10
+        let vectors = [
+            ("", ""),
+            ("f", "Zg=="),
+            ("fo", "Zm8="),
+            ("foo", "Zm9v"),
+            ("foob", "Zm9vYg=="),
+            ("fooba", "Zm9vYmE="),
+            ("foobar", "Zm9vYmFy"),
+        ];
+        for (data, encoded) in vectors {
+            assert_eq!(base64_encode(data.as_bytes()), encoded);
+            assert_eq!(base64_decode(encoded).unwrap(), data.as_bytes());
+        }
+
+        let data: Vec<u8> = (0..=255).collect();
+        assert_eq!(base64_decode(&base64_encode(&data)).unwrap(), data);
+
+        for invalid in ["Z", "Zm9vY", "Zm9v!A==", "Zm 9v"] {
+            assert_eq!(base64_decode(invalid), None, "{invalid:?}");
+        }
+    }
+
+    /// The image of a LUKS2 container, and its header
+    fn image() -> (Vec<u8>, LuksHeader) {
+        let (disk, luks) = formatted(LuksVersion::Luks2);
+        let header = luks.header().clone();
+        drop(luks);
+        (Arc::into_inner(disk).unwrap().into_vec(), header)
+    }
+
+    fn read_image(image: &[u8]) -> Result<Option<Luks>, LuksErr> {
+        Luks::read_from_disk(MemDisk::from_vec(
+            image.to_vec(),
+            SectorSize::AllOf(vec![512, 4096]),
+            Permissions::read_write(),
+        ))
+    }
+
+    /// Modifies the metadata at `offset` with `modify`, then updates its checksum
+    fn rewrite(image: &mut [u8], offset: u64, modify: impl FnOnce(&mut [u8])) {
+        let metadata = &mut image[(offset as usize)..((offset + METADATA_SIZE) as usize)];
+        modify(metadata);
+
+        metadata[448..512].fill(0);
+        let checksum = Sha256::digest(metadata);
+        metadata[448..480].copy_from_slice(&checksum);
+    }
+
+    #[test]
+    fn headers() {
+        let (image, header) = image();
+
+        for (offset, magic) in [(0, MAGIC), (METADATA_SIZE, SECONDARY_MAGIC)] {
+            let metadata = &image[(offset as usize)..];
+            assert_eq!(metadata[..6], magic);
+            assert_eq!(be64(metadata, 8), METADATA_SIZE);
+            assert_eq!(be64(metadata, 16), 1);
+            assert_eq!(be64(metadata, 256), offset);
+            assert_eq!(c_string(&metadata[24..72]).unwrap(), "test volume");
+        }
+
+        // The key slot area ends where the payload starts, as with cryptsetup
+        assert_eq!(header.payload_offset, 16 << 20);
+        assert_eq!(header.keyslots[0].area_offset, 0x8000);
+        assert!(header.keyslots[0].area_size <= header.payload_offset - 0x8000);
+
+        // A damaged primary header: the secondary one is used
+        let mut damaged = image.clone();
+        damaged[..BINARY_HEADER_SIZE].fill(0);
+        let luks = read_image(&damaged).unwrap().unwrap();
+        assert_eq!(luks.header(), &header);
+        assert!(luks.unlock(b"passphrase").is_ok());
+
+        let mut damaged = image.clone();
+        damaged[BINARY_HEADER_SIZE + 10] ^= 1;
+        assert_eq!(read_image(&damaged).unwrap().unwrap().header(), &header);
+
+        // Both damaged
+        damaged[METADATA_SIZE as usize + 448] ^= 1;
+        assert!(matches!(
+            read_image(&damaged),
+            Err(LuksErr::InvalidHeader {
+                reason: "no LUKS2 header matches its checksum"
+            })
+        ));
+
+        // The header with the highest sequence number wins
+        let mut newer = image.clone();
+        rewrite(&mut newer, METADATA_SIZE, |metadata| {
+            metadata[16..24].copy_from_slice(&2u64.to_be_bytes());
+            metadata[24..72].fill(0);
+            metadata[24..29].copy_from_slice(b"newer");
+        });
+        assert_eq!(read_image(&newer).unwrap().unwrap().header().label, "newer");
+    }
+
+    #[test]
+    fn requirements() {
+        let (mut image, _) = image();
+
+        for offset in [0, METADATA_SIZE] {
+            rewrite(&mut image, offset, |metadata| {
+                let area = &mut metadata[BINARY_HEADER_SIZE..];
+                let len = area.iter().position(|&b| b == 0).unwrap();
+                let json = core::str::from_utf8(&area[..len]).unwrap().replace(
+                    "\"config\":{",
+                    "\"config\":{\"requirements\":{\"mandatory\":[\"online-reencrypt\"]},",
+                );
+                area[..json.len()].copy_from_slice(json.as_bytes());
+            });
+        }
+
+        assert_eq!(
+            read_image(&image).err(),
+            Some(LuksErr::Unsupported {
+                feature: "requirement online-reencrypt".into()
+            })
+        );
+    }
+}
@@ -0,0 +1,864 @@
+use crate::{
+    Disk, LuksErr, Permissions,
+    bytedisk::ByteDisk,
+    crypto::{HashAlgorithm, argon2, xts::Xts},
+    encrypted::EncryptedDisk,
+    wrappers::{DiskWrapper, SubDisk},
+};
+use alloc::{format, string::String, sync::Arc, vec, vec::Vec};
+
+mod json;
+mod luks1;
+mod luks2;
+
+/// Magic of the LUKS1 and primary LUKS2 headers
+const MAGIC: [u8; 6] = *b"LUKS\xBA\xBE";
+
+/// The only cipher supported, the default of cryptsetup
+const CIPHER: &str = "aes-xts-plain64";
+
+/// The key material is always encrypted by sectors of 512 bytes, numbered from the start of its
+/// area
+const AREA_SECTOR_SIZE: usize = 512;
+
+/// Number of stripes of the anti-forensic splitter, the only value used in practice
+const AF_STRIPES: u32 = 4000;
+
+/// Iterations of the PBKDF2 digest of the volume key. The digest only recognizes a random key,
+/// so the minimum of cryptsetup is enough.
+const DIGEST_ITERATIONS: u32 = 1000;
+
+/// Size of the salts generated
+const SALT_SIZE: usize = 32;
+
+/// Largest volume key accepted in a header, to bound the allocations on damaged headers
+const MAX_KEY_SIZE: usize = 512;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LuksVersion {
+    Luks1,
+    Luks2,
+}
+
+/// A key derivation function, with its costs
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Kdf {
+    Pbkdf2 {
+        hash: HashAlgorithm,
+        iterations: u32,
+    },
+    /// LUKS2 only
+    Argon2(argon2::Params),
+}
+
+impl Kdf {
+    fn derive(&self, passphrase: &[u8], salt: &[u8], out: &mut [u8]) -> Result<(), LuksErr> {
+        match self {
+            Self::Pbkdf2 { hash, iterations } => {
+                hash.pbkdf2(passphrase, salt, *iterations, out);
+                Ok(())
+            }
+            Self::Argon2(params) => match params.derive(passphrase, salt, out) {
+                true => Ok(()),
+                false => Err(LuksErr::KdfFailed),
+            },
+        }
+    }
+}
+
+/// An active key slot: a copy of the volume key, encrypted with a key derived from a passphrase
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct KeySlot {
+    pub index: usize,
+    /// Size of the volume key stored, in bytes
+    pub key_size: usize,
+    pub kdf: Kdf,
+    pub salt: Vec<u8>,
+    /// Hash of the anti-forensic splitter, spreading the key over `af_stripes` times its size
+    pub af_hash: HashAlgorithm,
+    pub af_stripes: u32,
+    /// Position of the encrypted key material on the disk, in bytes
+    pub area_offset: u64,
+    pub area_size: u64,
+    /// Cipher of the key material, with the size of its key in bytes
+    pub area_cipher: String,
+    pub area_key_size: usize,
+}
+
+impl KeySlot {
+    /// Checks the sizes read from a header, before they are used for allocations. The key
+    /// material is bounded by its area, but LUKS1 derives the area from the stripes, so the split
+    /// key is also bounded by the one of the largest key with the usual stripes.
+    fn check(&self) -> Result<(), LuksErr> {
+        let split_size = self.key_size as u64 * self.af_stripes as u64;
+
+        if !(1..=MAX_KEY_SIZE).contains(&self.key_size)
+            || !(1..=MAX_KEY_SIZE).contains(&self.area_key_size)
+            || self.af_stripes == 0
+            || split_size > MAX_KEY_SIZE as u64 * AF_STRIPES as u64
+            || split_size.next_multiple_of(AREA_SECTOR_SIZE as u64) > self.area_size
+        {
+            return Err(LuksErr::InvalidHeader {
+                reason: "invalid key slot sizes",
+            });
+        }
+
+        Ok(())
+    }
+}
+
+/// A PBKDF2 digest of the volume key, telling if a decrypted key is the right one
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct KeyDigest {
+    pub hash: HashAlgorithm,
+    pub iterations: u32,
+    pub salt: Vec<u8>,
+    pub digest: Vec<u8>,
+    /// The key slots holding the key
+    pub keyslots: Vec<usize>,
+}
+
+/// The header of a container, the same for both versions
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct LuksHeader {
+    pub version: LuksVersion,
+    pub uuid: String,
+    /// Always empty with LUKS1
+    pub label: String,
+    /// Cipher of the payload, as `aes-xts-plain64`
+    pub cipher: String,
+    /// Size of the volume key, in bytes
+    pub key_size: usize,
+    /// Position of the payload on the disk, in bytes. The payload extends to the end of the disk
+    /// when its size is `None`.
+    pub payload_offset: u64,
+    pub payload_size: Option<u64>,
+    /// Size of the encryption sectors of the payload
+    pub sector_size: usize,
+    /// Tweak of the first sector of the payload
+    pub iv_tweak: u64,
+    pub keyslots: Vec<KeySlot>,
+    pub digests: Vec<KeyDigest>,
+}
+
+/// The options of a new container
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct LuksOptions {
+    pub version: LuksVersion,
+    /// Size of the volume key: 32 (AES-128-XTS) or 64 (AES-256-XTS) bytes
+    pub key_size: usize,
+    /// Hash of the digest and of the anti-forensic splitter
+    pub hash: HashAlgorithm,
+    /// Key derivation of the passphrase. LUKS1 only supports PBKDF2 with `hash`.
+    pub kdf: Kdf,
+    /// Size of the encryption sectors of the payload. LUKS1 only supports 512.
+    pub sector_size: usize,
+    /// LUKS2 only, at most 47 bytes
+    pub label: String,
+}
+
+impl Default for LuksOptions {
+    /// LUKS2 with AES-256-XTS and Argon2id, with moderate costs (4 passes over 256 MiB)
+    fn default() -> Self {
+        Self {
+            version: LuksVersion::Luks2,
+            key_size: 64,
+            hash: HashAlgorithm::Sha256,
+            kdf: Kdf::Argon2(argon2::Params {
+                variant: argon2::Variant::Argon2id,
+                iterations: 4,
+                memory: 256 * 1024,
+                lanes: 4,
+            }),
+            sector_size: 512,
+            label: String::new(),
+        }
+    }
+}
+
+/// The key of the payload, erased on drop
+pub struct VolumeKey(Vec<u8>);
+
+impl VolumeKey {
+    /// A key known by other means, such as a key file
+    pub fn new(key: &[u8]) -> Self {
+        Self(key.to_vec())
+    }
+
+    pub fn as_bytes(&self) -> &[u8] {
+        &self.0
+    }
+}
+
+impl Drop for VolumeKey {
+    fn drop(&mut self) {
+        for byte in self.0.iter_mut() {
+            // SAFETY: `byte` is a valid reference. The volatile write is not optimized out.
+            unsafe { core::ptr::write_volatile(byte, 0) };
+        }
+    }
+}
+
+/// A LUKS container. It is opened with a passphrase of one of its key slots, and its payload is a
+/// `SubDisk` of the container, decrypted by an `EncryptedDisk`.
+///
+/// Only AES-XTS with 64-bit sector numbers (`aes-xts-plain64`, the default of cryptsetup) is
+/// supported, with SHA-1, SHA-256 or SHA-512 for the hashes.
+pub struct Luks {
+    header: LuksHeader,
+    disk: Arc<DiskWrapper>,
+}
+
+impl Luks {
+    /// Reads the header of a container. Returns `None` if the disk holds no LUKS header.
+    pub fn read_from_disk<T: Disk + 'static>(disk: T) -> Result<Option<Self>, LuksErr> {
+        let disk = DiskWrapper::new(disk);
+        let bytes = ByteDisk::new(disk.clone())?;
+
+        if bytes.size()? < 4096 {
+            return Ok(None);
+        }
+
+        let mut start = [0; 8];
+        bytes.read_at(0, &mut start)?;
+
+        let header = if start[..6] == MAGIC && start[6..8] == [0, 1] {
+            let mut raw = [0; luks1::HEADER_SIZE];
+            bytes.read_at(0, &mut raw)?;
+            Some(luks1::parse(&raw)?)
+        } else {
+            // A LUKS2 container can still be found from its secondary header when the primary
+            // one is damaged
+            luks2::read(&bytes)?
+        };
+
+        Ok(header.map(|header| Self { header, disk }))
+    }
+
+    /// Creates a container on `disk`, with the key slot 0 opened by `passphrase`. `random` fills
+    /// its argument with cryptographically secure random bytes: it makes the volume key, the
+    /// salts and the UUID.
+    pub fn format<T: Disk + 'static>(
+        disk: T,
+        passphrase: &[u8],
+        options: &LuksOptions,
+        random: &mut impl FnMut(&mut [u8]),
+    ) -> Result<Self, LuksErr> {
+        let key_size = options.key_size;
+
+        if key_size != 32 && key_size != 64 {
+            return Err(LuksErr::Unsupported {
+                feature: format!("{}-bit keys", key_size * 8),
+            });
+        }
+
+        if !options.sector_size.is_power_of_two() || !(512..=4096).contains(&options.sector_size) {
+            return Err(LuksErr::Unsupported {
+                feature: format!("{}-byte sectors", options.sector_size),
+            });
+        }
+
+        if let Kdf::Argon2(params) = &options.kdf
+            && !params.is_valid()
+        {
+            return Err(LuksErr::KdfFailed);
+        }
+
+        let disk = DiskWrapper::new(disk);
+        let bytes = ByteDisk::new(disk.clone())?;
+        let disk_size = bytes.size()?;
+
+        // ### LAYOUT ###
+
+        let (area_offset, area_size, payload_offset) = match options.version {
+            LuksVersion::Luks1 => {
+                if !matches!(options.kdf, Kdf::Pbkdf2 { hash, .. } if hash == options.hash) {
+                    return Err(LuksErr::Unsupported {
+                        feature: "LUKS1 with another KDF than PBKDF2 with the header hash".into(),
+                    });
+                }
+
+                if options.sector_size != 512 || !options.label.is_empty() {
+                    return Err(LuksErr::Unsupported {
+                        feature: "LUKS1 with labels or large sectors".into(),
+                    });
+                }
+
+                let (areas, payload_offset) = luks1::layout(key_size);
+                (areas[0].0, areas[0].1, payload_offset)
+            }
+            LuksVersion::Luks2 => {
+                if options.label.len() > 47 {
+                    return Err(LuksErr::Unsupported {
+                        feature: "labels longer than 47 bytes".into(),
+                    });
+                }
+
+                let (area_offset, area_size) = luks2::area(key_size);
+                (area_offset, area_size, luks2::PAYLOAD_OFFSET)
+            }
+        };
+
+        let min = payload_offset + options.sector_size as u64;
+        if disk_size < min {
+            return Err(LuksErr::DiskTooSmall {
+                size: disk_size,
+                min,
+            });
+        }
+
+        // ### KEYS ###
+
+        let mut volume_key = VolumeKey(vec![0; key_size]);
+        random(&mut volume_key.0);
+
+        let mut salt = vec![0; SALT_SIZE];
+        random(&mut salt);
+
+        let mut digest_salt = vec![0; SALT_SIZE];
+        random(&mut digest_salt);
+
+        let digest_size = match options.version {
+            LuksVersion::Luks1 => luks1::DIGEST_SIZE,
+            LuksVersion::Luks2 => options.hash.output_size(),
+        };
+        let mut digest = vec![0; digest_size];
+        options
+            .hash
+            .pbkdf2(&volume_key.0, &digest_salt, DIGEST_ITERATIONS, &mut digest);
+
+        let header = LuksHeader {
+            version: options.version,
+            uuid: uuid(random),
+            label: options.label.clone(),
+            cipher: CIPHER.into(),
+            key_size,
+            payload_offset,
+            payload_size: None,
+            sector_size: options.sector_size,
+            iv_tweak: 0,
+            keyslots: vec![KeySlot {
+                index: 0,
+                key_size,
+                kdf: options.kdf,
+                salt,
+                af_hash: options.hash,
+                af_stripes: AF_STRIPES,
+                area_offset,
+                area_size,
+                area_cipher: CIPHER.into(),
+                area_key_size: key_size,
+            }],
+            digests: vec![KeyDigest {
+                hash: options.hash,
+                iterations: DIGEST_ITERATIONS,
+                salt: digest_salt,
+                digest,
+                keyslots: vec![0],
+            }],
+        };
+
+        // ### WRITES ###
+
+        let slot = &header.keyslots[0];
+        let mut area_key = VolumeKey(vec![0; slot.area_key_size]);
+        slot.kdf.derive(passphrase, &slot.salt, &mut area_key.0)?;
+        let cipher = xts(&slot.area_cipher, &area_key.0)?;
+
+        let mut material = af_split(&volume_key.0, slot.af_stripes, slot.af_hash, random);
+        material.resize(slot.area_size as usize, 0);
+        crypt_area(&cipher, &mut material, true);
+        bytes.write_at(slot.area_offset, &material)?;
+
+        match header.version {
+            LuksVersion::Luks1 => bytes.write_at(0, &luks1::to_bytes(&header))?,
+            LuksVersion::Luks2 => luks2::write(&bytes, &header, random)?,
+        }
+
+        disk.flush()?;
+
+        Ok(Self { header, disk })
+    }
+
+    pub fn header(&self) -> &LuksHeader {
+        &self.header
+    }
+
+    /// Finds the volume key with `passphrase`, trying each key slot
+    pub fn unlock(&self, passphrase: &[u8]) -> Result<VolumeKey, LuksErr> {
+        let mut error = LuksErr::WrongKey;
+
+        for slot in &self.header.keyslots {
+            match self.unlock_slot(slot.index, passphrase) {
+                Ok(key) => return Ok(key),
+                Err(LuksErr::Disk(e)) => return Err(LuksErr::Disk(e)),
+                // A slot that can't be opened here doesn't prevent opening the others
+                Err(LuksErr::WrongKey) => {}
+                Err(e) => error = e,
+            }
+        }
+
+        Err(error)
+    }
+
+    /// Finds the volume key with `passphrase`, in the key slot `index` only
+    pub fn unlock_slot(&self, index: usize, passphrase: &[u8]) -> Result<VolumeKey, LuksErr> {
+        let slot = match self.header.keyslots.iter().find(|s| s.index == index) {
+            Some(v) => v,
+            None => return Err(LuksErr::InvalidKeySlot { index }),
+        };
+
+        let mut area_key = VolumeKey(vec![0; slot.area_key_size]);
+        slot.kdf.derive(passphrase, &slot.salt, &mut area_key.0)?;
+        let cipher = xts(&slot.area_cipher, &area_key.0)?;
+
+        let split_size = slot.key_size * slot.af_stripes as usize;
+        let mut material = VolumeKey(vec![0; split_size.next_multiple_of(AREA_SECTOR_SIZE)]);
+        ByteDisk::new(self.disk.clone())?.read_at(slot.area_offset, &mut material.0)?;
+        crypt_area(&cipher, &mut material.0, false);
+
+        let key = VolumeKey(af_merge(
+            &material.0[..split_size],
+            slot.key_size,
+            slot.af_hash,
+        ));
+
+        match self.is_volume_key(&key, Some(index)) {
+            true => Ok(key),
+            false => Err(LuksErr::WrongKey),
+        }
+    }
+
+    /// The decrypted payload, borrowing its part of the disk with `permissions`
+    pub fn payload(
+        &self,
+        key: &VolumeKey,
+        permissions: Permissions,
+    ) -> Result<EncryptedDisk<SubDisk>, LuksErr> {
+        if !self.is_volume_key(key, None) {
+            return Err(LuksErr::WrongKey);
+        }
+
+        let cipher = xts(&self.header.cipher, &key.0)?;
+
+        let start = self.header.payload_offset;
+        let disk_size = self.disk.disk_infos()?.disk_size;
+        let end = match self.header.payload_size {
+            Some(size) => start.saturating_add(size),
+            None => disk_size,
+        };
+
+        let min = start + self.header.sector_size as u64;
+        if end < min || end > disk_size {
+            return Err(LuksErr::DiskTooSmall {
+                size: disk_size,
+                min: min.max(end),
+            });
+        }
+
+        let payload = self.disk.subdisk(start, end, permissions)?;
+        Ok(
+            EncryptedDisk::new(payload, cipher, Some(self.header.sector_size))?
+                .with_tweak_offset(self.header.iv_tweak),
+        )
+    }
+
+    /// Unlocks the container with `passphrase` and returns its payload
+    pub fn open(
+        &self,
+        passphrase: &[u8],
+        permissions: Permissions,
+    ) -> Result<EncryptedDisk<SubDisk>, LuksErr> {
+        let key = self.unlock(passphrase)?;
+        self.payload(&key, permissions)
+    }
+
+    /// Checks `key` against the digests, only the ones of the key slot `slot` if given
+    fn is_volume_key(&self, key: &VolumeKey, slot: Option<usize>) -> bool {
+        self.header
+            .digests
+            .iter()
+            .filter(|d| slot.is_none_or(|slot| d.keyslots.contains(&slot)))
+            .any(|d| {
+                let mut digest = vec![0; d.digest.len()];
+                d.hash.pbkdf2(&key.0, &d.salt, d.iterations, &mut digest);
+                digest
+                    .iter()
+                    .zip(&d.digest)
+                    .fold(0, |acc, (a, b)| acc | (a ^ b))
+                    == 0
+            })
+    }
+}
+
+/// The XTS cipher of a cipher specification
+fn xts(cipher: &str, key: &[u8]) -> Result<Xts, LuksErr> {
+    if cipher != CIPHER {
+        return Err(LuksErr::Unsupported {
+            feature: cipher.into(),
+        });
+    }
+
+    Xts::new(key).ok_or_else(|| LuksErr::Unsupported {
+        feature: format!("{cipher} with {}-bit keys", key.len() * 8),
+    })
+}
+
+fn crypt_area(cipher: &Xts, data: &mut [u8], encrypt: bool) {
+    for (i, sector) in data.chunks_mut(AREA_SECTOR_SIZE).enumerate() {
+        if encrypt {
+            cipher.encrypt_unit(i as u128, sector);
+        } else {
+            cipher.decrypt_unit(i as u128, sector);
+        }
+    }
+}
+
+// ### ANTI-FORENSIC SPLITTER ###
+
+/// Mixes `block`, hashing each piece of the digest size with its index
+fn diffuse(hash: HashAlgorithm, block: &mut [u8]) {
+    for (i, chunk) in block.chunks_mut(hash.output_size()).enumerate() {
+        let digest = hash.digest(&[&(i as u32).to_be_bytes(), chunk]);
+        let len = chunk.len();
+        chunk.copy_from_slice(&digest[..len]);
+    }
+}
+
+/// Spreads `key` over `stripes` stripes: all of them are needed to get the key back, so erasing a
+/// small part of the key material is enough to destroy it
+fn af_split(
+    key: &[u8],
+    stripes: u32,
+    hash: HashAlgorithm,
+    random: &mut impl FnMut(&mut [u8]),
+) -> Vec<u8> {
+    let mut split = vec![0; key.len() * stripes as usize];
+    let (head, last) = split.split_at_mut(key.len() * (stripes as usize - 1));
+    random(head);
+
+    let mut mixed = vec![0; key.len()];
+    for stripe in head.chunks(key.len()) {
+        mixed.iter_mut().zip(stripe).for_each(|(m, s)| *m ^= s);
+        diffuse(hash, &mut mixed);
+    }
+
+    for ((l, m), k) in last.iter_mut().zip(&mixed).zip(key) {
+        *l = m ^ k;
+    }
+    mixed.fill(0);
+
+    split
+}
+
+fn af_merge(split: &[u8], key_size: usize, hash: HashAlgorithm) -> Vec<u8> {
+    let (head, last) = split.split_at(split.len() - key_size);
+
+    let mut key = vec![0; key_size];
+    for stripe in head.chunks(key_size) {
+        key.iter_mut().zip(stripe).for_each(|(k, s)| *k ^= s);
+        diffuse(hash, &mut key);
+    }
+
+    key.iter_mut().zip(last).for_each(|(k, l)| *k ^= l);
+    key
+}
+
+// ### HELPERS ###
+
+/// A random UUID (version 4), in its textual form
+fn uuid(random: &mut impl FnMut(&mut [u8])) -> String {
+    let mut bytes = [0; 16];
+    random(&mut bytes);
+    bytes[6] = (bytes[6] & 0x0F) | 0x40;
+    bytes[8] = (bytes[8] & 0x3F) | 0x80;
+
+    let hex: String = bytes.iter().map(|b| format!("{b:02x}")).collect();
+    format!(
+        "{}-{}-{}-{}-{}",
+        &hex[..8],
+        &hex[8..12],
+        &hex[12..16],
+        &hex[16..20],
+        &hex[20..]
+    )
+}
+
+/// The string in `bytes`, ending at the first NUL
+fn c_string(bytes: &[u8]) -> Result<String, LuksErr> {
+    let len = bytes.iter().position(|&b| b == 0).unwrap_or(bytes.len());
+    core::str::from_utf8(&bytes[..len])
+        .map(String::from)
+        .map_err(|_| LuksErr::InvalidHeader {
+            reason: "invalid string",
+        })
+}
+
+#[cfg(test)]
+pub(super) mod tests {
+    use super::*;
+    use crate::{SectorSize, memdisk::MemDisk};
+
+    /// A reproducible source of "random" bytes
+    pub(super) fn random() -> impl FnMut(&mut [u8]) {
+        let mut state = 0x2545F4914F6CDD1Du64;
+        move |buf| {
+            for byte in buf {
+                state ^= state << 13;
+                state ^= state >> 7;
+                state ^= state << 17;
+                *byte = state as u8;
+            }
+        }
+    }
+
+    /// Cheap costs, AES-128-XTS with PBKDF2 for LUKS1, AES-256-XTS with Argon2id and 4 KiB
+    /// sectors for LUKS2
+    pub(super) fn options(version: LuksVersion) -> LuksOptions {
+        match version {
+            LuksVersion::Luks1 => LuksOptions {
+                version,
+                key_size: 32,
+                hash: HashAlgorithm::Sha1,
+                kdf: Kdf::Pbkdf2 {
+                    hash: HashAlgorithm::Sha1,
+                    iterations: 1000,
+                },
+                sector_size: 512,
+                label: String::new(),
+            },
+            LuksVersion::Luks2 => LuksOptions {
+                version,
+                key_size: 64,
+                hash: HashAlgorithm::Sha256,
+                kdf: Kdf::Argon2(argon2::Params {
+                    variant: argon2::Variant::Argon2id,
+                    iterations: 1,
+                    memory: 64,
+                    lanes: 1,
+                }),
+                sector_size: 4096,
+                label: "test volume".into(),
+            },
+        }
+    }
+
+    /// A container of 17 MiB, opened by `passphrase`
+    pub(super) fn formatted(version: LuksVersion) -> (Arc<MemDisk>, Luks) {
+        let disk = Arc::new(MemDisk::new(
+            17 << 20,
+            SectorSize::AllOf(vec![512, 4096]),
+            Permissions::read_write(),
+        ));
+        let luks = Luks::format(
+            disk.clone(),
+            b"passphrase",
+            &options(version),
+            &mut random(),
+        )
+        .unwrap();
+        (disk, luks)
+    }
+
+    fn pattern(len: usize) -> Vec<u8> {
+        (0..len).map(|i| (i % 251) as u8).collect()
+    }
+
+    #[test]
+    fn af_split_merge() {
+        let key = pattern(64);
+
+        for hash in [
+            HashAlgorithm::Sha1,
+            HashAlgorithm::Sha256,
+            HashAlgorithm::Sha512,
+        ] {
+            for stripes in [1, 2, 3, 100] {
+                let mut split = af_split(&key, stripes, hash, &mut random());
+                assert_eq!(split.len(), key.len() * stripes as usize);
+                assert_eq!(af_merge(&split, key.len(), hash), key);
+
+                // Every stripe is needed
+                split[0] ^= 1;
+                assert_ne!(af_merge(&split, key.len(), hash), key);
+            }
+        }
+
+        // The stripes are random: two splits of the same key differ
+        let mut random = random();
+        let first = af_split(&key, 4, HashAlgorithm::Sha256, &mut random);
+        let second = af_split(&key, 4, HashAlgorithm::Sha256, &mut random);
+        assert_ne!(first, second);
+    }
+
+    fn round_trip(version: LuksVersion) {
+        let (disk, luks) = formatted(version);
+        let header = luks.header();
+        let data = pattern(3 * 4096);
+
+        luks.open(b"passphrase", Permissions::read_write())
+            .unwrap()
+            .write_sectors(0, header.sector_size, &data)
+            .unwrap();
+
+        // The payload is encrypted on the disk
+        let mut raw = vec![0; data.len()];
+        disk.read_sectors(header.payload_offset / 512, 512, &mut raw)
+            .unwrap();
+        assert_ne!(raw, data);
+
+        let read = Luks::read_from_disk(disk.clone()).unwrap().unwrap();
+        assert_eq!(read.header(), header);
+
+        let key = read.unlock_slot(0, b"passphrase").unwrap();
+        assert_eq!(key.as_bytes().len(), header.key_size);
+        let payload = read.payload(&key, Permissions::read_only()).unwrap();
+        let mut buf = vec![0; data.len()];
+        payload
+            .read_sectors(0, header.sector_size, &mut buf)
+            .unwrap();
+        assert_eq!(buf, data);
+
+        // The payload covers the rest of the disk
+        let infos = payload.disk_infos().unwrap();
+        assert_eq!(infos.disk_size, (17 << 20) - header.payload_offset);
+        assert!(!infos.permissions.write);
+
+        assert!(matches!(
+            read.open(b"wrong passphrase", Permissions::read_only()),
+            Err(LuksErr::WrongKey)
+        ));
+        assert!(matches!(
+            read.unlock_slot(1, b"passphrase"),
+            Err(LuksErr::InvalidKeySlot { index: 1 })
+        ));
+        let wrong = VolumeKey::new(&vec![0; header.key_size]);
+        assert!(matches!(
+            read.payload(&wrong, Permissions::read_only()),
+            Err(LuksErr::WrongKey)
+        ));
+    }
+
+    #[test]
+    fn luks1_round_trip() {
+        round_trip(LuksVersion::Luks1);
+    }
+
+    #[test]
+    fn luks2_round_trip() {
+        round_trip(LuksVersion::Luks2);
+    }
+
+    #[test]
+    fn format_errors() {
+        let disk = || MemDisk::new(17 << 20, SectorSize::Any, Permissions::read_write());
+        let format = |disk, options: &LuksOptions| {
+            Luks::format(disk, b"passphrase", options, &mut random()).err()
+        };
+
+        let options = LuksOptions {
+            key_size: 48,
+            ..self::options(LuksVersion::Luks2)
+        };
+        assert!(matches!(
+            format(disk(), &options),
+            Some(LuksErr::Unsupported { .. })
+        ));
+
+        let options = LuksOptions {
+            kdf: self::options(LuksVersion::Luks2).kdf,
+            ..self::options(LuksVersion::Luks1)
+        };
+        assert!(matches!(
+            format(disk(), &options),
+            Some(LuksErr::Unsupported { .. })
+        ));
+
+        let options = LuksOptions {
+            kdf: Kdf::Argon2(argon2::Params {
+                variant: argon2::Variant::Argon2id,
+                iterations: 0,
+                memory: 64,
+                lanes: 1,
+            }),
+            ..self::options(LuksVersion::Luks2)
+        };
+        assert_eq!(format(disk(), &options), Some(LuksErr::KdfFailed));
+
+        let small = MemDisk::new(16 << 20, SectorSize::Any, Permissions::read_write());
+        assert_eq!(
+            format(small, &self::options(LuksVersion::Luks2)),
+            Some(LuksErr::DiskTooSmall {
+                size: 16 << 20,
+                min: (16 << 20) + 4096
+            })
+        );
+    }
+
+    #[test]
+    fn not_luks() {
+        let disk = MemDisk::new(1 << 20, SectorSize::Any, Permissions::read_write());
+        assert!(Luks::read_from_disk(disk).unwrap().is_none());
+
+        let disk = MemDisk::new(1024, SectorSize::Any, Permissions::read_write());
+        assert!(Luks::read_from_disk(disk).unwrap().is_none());
+    }
+
+    #[test]
+    fn keyslot_check() {
+        let slot = KeySlot {
+            index: 0,
+            key_size: 64,
+            kdf: options(LuksVersion::Luks2).kdf,
+            salt: vec![0; SALT_SIZE],
+            af_hash: HashAlgorithm::Sha256,
+            af_stripes: AF_STRIPES,
+            area_offset: 0x8000,
+            area_size: 258048,
+            area_cipher: CIPHER.into(),
+            area_key_size: 64,
+        };
+        assert_eq!(slot.check(), Ok(()));
+
+        let invalid = [
+            KeySlot {
+                key_size: 0,
+                ..slot.clone()
+            },
+            KeySlot {
+                key_size: MAX_KEY_SIZE + 1,
+                ..slot.clone()
+            },
+            KeySlot {
+                area_key_size: usize::MAX,
+                ..slot.clone()
+            },
+            KeySlot {
+                af_stripes: 0,
+                ..slot.clone()
+            },
+            // The key material doesn't fit in the area
+            KeySlot {
+                af_stripes: u32::MAX,
+                ..slot.clone()
+            },
+            KeySlot {
+                af_stripes: u32::MAX,
+                area_size: u64::MAX,
+                ..slot.clone()
+            },
+            KeySlot {
+                area_size: 4096,
+                ..slot.clone()
+            },
+        ];
+        for slot in invalid {
+            assert_eq!(
+                slot.check(),
+                Err(LuksErr::InvalidHeader {
+                    reason: "invalid key slot sizes"
+                })
+            );
+        }
+    }
+}
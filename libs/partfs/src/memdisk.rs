@@ -1,63 +1,158 @@
-use crate::{Disk, DiskErr, DiskInfos, Permissions, SectorSize};
-use alloc::vec::Vec;
+use crate::{Disk, DiskErr, DiskErrKind, DiskInfos, Geometry, Operation, Permissions, SectorSize};
+use alloc::{vec, vec::Vec};
 use mutex::Mutex;
 
+/// A disk entirely stored in memory. Mostly useful to build images or for testing.
 pub struct MemDisk {
     sector_size: SectorSize,
     permissions: Permissions,
+    /// The geometry reported by `disk_infos`, guessed from the sector sizes if not set
+    geometry: Option<Geometry>,
     content: Mutex<Vec<u8>>,
 }
 
+impl MemDisk {
+    /// Creates a new zero-filled disk of `size` bytes
+    pub fn new(size: usize, sector_size: SectorSize, permissions: Permissions) -> Self {
+        Self::from_vec(vec![0; size], sector_size, permissions)
+    }
+
+    /// Creates a disk from an existing content. The disk size is the length of the vector.
+    pub fn from_vec(content: Vec<u8>, sector_size: SectorSize, permissions: Permissions) -> Self {
+        Self {
+            sector_size,
+            permissions,
+            geometry: None,
+            content: Mutex::new(content),
+        }
+    }
+
+    /// Sets the geometry reported by the disk, to emulate a given device
+    pub fn with_geometry(mut self, geometry: Geometry) -> Self {
+        self.geometry = Some(geometry);
+        self
+    }
+
+    /// Returns the content of the disk
+    pub fn into_vec(self) -> Vec<u8> {
+        core::mem::take(&mut *self.content.lock())
+    }
+
+    /// Checks a request of `len` bytes starting at the LBA `sector` and returns the range of bytes
+    /// it covers.
+    fn range(&self, sector: u64, sector_size: usize, len: u64) -> Result<(usize, usize), DiskErr> {
+        let disk_size = self.content.lock().len() as u64;
+
+        if sector_size == 0
+            || !self.sector_size.is_supported(sector_size, disk_size)
+            || !len.is_multiple_of(sector_size as u64)
+        {
+            return Err(DiskErrKind::InvalidSectorSize {
+                found: sector_size,
+                supported: self.sector_size.clone(),
+                start: 0,
+            }
+            .into());
+        }
+
+        // Both fit in a `usize` once checked against the size of the content
+        match sector
+            .checked_mul(sector_size as u64)
+            .and_then(|start| Some((start, start.checked_add(len)?)))
+        {
+            Some((start, end)) if end <= disk_size => Ok((start as usize, end as usize)),
+            _ => Err(DiskErrKind::InvalidSectorIndex {
+                found: sector,
+                max: (disk_size / sector_size as u64).saturating_sub(1),
+            }
+            .into()),
+        }
+    }
+}
+
 impl Disk for MemDisk {
     fn disk_infos(&self) -> Result<DiskInfos, DiskErr> {
+        let disk_size = self.content.lock().len() as u64;
+
         Ok(DiskInfos {
             sector_size: self.sector_size.clone(),
-            disk_size: self.content.lock().len(),
+            disk_size,
             permissions: self.permissions,
+            geometry: self
+                .geometry
+                .unwrap_or_else(|| Geometry::from_sector_size(&self.sector_size, disk_size)),
+            device_id: None,
         })
     }
 
-    fn read_sector(&self, sector: usize, buf: &mut [u8]) -> Result<(), DiskErr> {
+    fn read_sector(&self, sector: u64, buf: &mut [u8]) -> Result<(), DiskErr> {
+        self.read_sectors(sector, buf.len(), buf)
+    }
+
+    fn write_sector(&self, sector: u64, buf: &[u8]) -> Result<(), DiskErr> {
+        self.write_sectors(sector, buf.len(), buf)
+    }
+
+    fn read_sectors(&self, sector: u64, sector_size: usize, buf: &mut [u8]) -> Result<(), DiskErr> {
+        self.read_inner(sector, sector_size, buf)
+            .map_err(|e| e.at(Operation::Read, sector).in_layer("MemDisk"))
+    }
+
+    fn write_sectors(&self, sector: u64, sector_size: usize, buf: &[u8]) -> Result<(), DiskErr> {
+        self.write_inner(sector, sector_size, buf)
+            .map_err(|e| e.at(Operation::Write, sector).in_layer("MemDisk"))
+    }
+
+    fn discard(&self, sector: u64, sector_size: usize, count: u64) -> Result<(), DiskErr> {
+        self.discard_inner(sector, sector_size, count)
+            .map_err(|e| e.at(Operation::Discard, sector).in_layer("MemDisk"))
+    }
+}
+
+impl MemDisk {
+    fn read_inner(&self, sector: u64, sector_size: usize, buf: &mut [u8]) -> Result<(), DiskErr> {
         if !self.permissions.read {
-            return Err(DiskErr::InvalidPermission {
+            return Err(DiskErrKind::InvalidPermission {
                 disk_permissions: self.permissions,
-            });
+            }
+            .into());
         }
 
-        if !self
-            .sector_size
-            .is_supported(buf.len(), self.content.lock().len())
-        {
-            return Err(DiskErr::InvalidSectorSize {
-                found: buf.len(),
-                supported: self.sector_size.clone(),
-                start: 0,
-            });
-        }
+        let (start, end) = self.range(sector, sector_size, buf.len() as u64)?;
 
-        buf.copy_from_slice(&self.content.lock()[(sector * buf.len())..((sector + 1) * buf.len())]);
+        buf.copy_from_slice(&self.content.lock()[start..end]);
         Ok(())
     }
 
-    fn write_sector(&self, sector: usize, buf: &[u8]) -> Result<(), DiskErr> {
+    fn write_inner(&self, sector: u64, sector_size: usize, buf: &[u8]) -> Result<(), DiskErr> {
         if !self.permissions.write {
-            return Err(DiskErr::InvalidPermission {
+            return Err(DiskErrKind::InvalidPermission {
                 disk_permissions: self.permissions,
-            });
+            }
+            .into());
         }
 
-        if !self
-            .sector_size
-            .is_supported(buf.len(), self.content.lock().len())
-        {
-            return Err(DiskErr::InvalidSectorSize {
-                found: buf.len(),
-                supported: self.sector_size.clone(),
-                start: 0,
-            });
+        let (start, end) = self.range(sector, sector_size, buf.len() as u64)?;
+
+        self.content.lock()[start..end].copy_from_slice(buf);
+        Ok(())
+    }
+
+    fn discard_inner(&self, sector: u64, sector_size: usize, count: u64) -> Result<(), DiskErr> {
+        if !self.permissions.write {
+            return Err(DiskErrKind::InvalidPermission {
+                disk_permissions: self.permissions,
+            }
+            .into());
         }
 
-        self.content.lock()[(sector * buf.len())..((sector + 1) * buf.len())].copy_from_slice(buf);
+        let (start, end) = self.range(
+            sector,
+            sector_size,
+            count.saturating_mul(sector_size as u64),
+        )?;
+
+        self.content.lock()[start..end].fill(0);
         Ok(())
     }
 }
@@ -0,0 +1,571 @@
+use crate::{Disk, DiskErr, DiskErrKind, DiskInfos, Operation, Permissions};
+use alloc::{boxed::Box, collections::BTreeMap, vec, vec::Vec};
+use mutex::Mutex;
+
+/// A copy-on-write layer over a base `Disk`. The base is never written until `commit`: all the
+/// writes go to a sparse delta, stored in memory or on another `Disk` (a `DiskFile` for example),
+/// and the reads see the base with the delta applied. `rollback` throws the delta away.
+///
+/// The delta is made of blocks of the preferred sector size of the base (see
+/// `SectorSize::preferred`). Smaller or unaligned writes read the rest of the block first. The
+/// size of the overlay is the base size rounded down to whole blocks.
+pub struct OverlayDisk<B: Disk> {
+    base: B,
+    /// Size of a delta block, in bytes
+    block_size: usize,
+    /// Size of the overlay, in bytes
+    disk_size: u64,
+    /// Where the data of the delta blocks is stored, in memory if `None`
+    store: Option<Box<dyn Disk + Send + Sync>>,
+    delta: Mutex<Delta>,
+}
+
+#[derive(Default)]
+struct Delta {
+    /// The modified blocks, by LBA (in blocks)
+    blocks: BTreeMap<u64, Block>,
+    /// The next never used block of the store
+    next_slot: u64,
+    /// The blocks of the store released by discards
+    free_slots: Vec<u64>,
+}
+
+enum Block {
+    /// Data kept in memory
+    Data(Vec<u8>),
+    /// Data kept in the block of the store at this LBA
+    Stored(u64),
+    /// Discarded block, reads as zeroes
+    Zero,
+}
+
+impl<B: Disk> OverlayDisk<B> {
+    /// Creates an overlay keeping the delta in memory
+    pub fn new(base: B) -> Result<Self, DiskErr> {
+        let infos = base.disk_infos()?;
+
+        match infos.sector_size.preferred(infos.disk_size) {
+            Some(block_size) => Ok(Self::build(base, block_size, infos.disk_size)),
+            None => Err(DiskErr::new(DiskErrKind::UnsupportedDiskSectorSize)
+                .during(Operation::DiskInfos)
+                .in_layer("OverlayDisk")),
+        }
+    }
+
+    /// Creates an overlay keeping the delta in memory, by blocks of `block_size` bytes, which must
+    /// be a sector size supported by the base.
+    pub fn with_block_size(base: B, block_size: usize) -> Result<Self, DiskErr> {
+        let infos = base.disk_infos()?;
+
+        if block_size == 0 || !infos.sector_size.is_supported(block_size, infos.disk_size) {
+            return Err(DiskErr::new(DiskErrKind::InvalidSectorSize {
+                found: block_size,
+                supported: infos.sector_size,
+                start: 0,
+            })
+            .during(Operation::DiskInfos)
+            .in_layer("OverlayDisk"));
+        }
+
+        Ok(Self::build(base, block_size, infos.disk_size))
+    }
+
+    fn build(base: B, block_size: usize, disk_size: u64) -> Self {
+        Self {
+            base,
+            block_size,
+            disk_size: disk_size / block_size as u64 * block_size as u64,
+            store: None,
+            delta: Mutex::new(Delta::default()),
+        }
+    }
+
+    /// Keeps the delta on `store` instead of in memory. The store must support the block size of
+    /// the overlay, and the writes fail once it is full.
+    pub fn with_store<S: Disk + Send + Sync + 'static>(
+        mut self,
+        store: S,
+    ) -> Result<Self, DiskErr> {
+        let infos = store.disk_infos()?;
+
+        if !infos
+            .sector_size
+            .is_supported(self.block_size, infos.disk_size)
+        {
+            return Err(DiskErr::new(DiskErrKind::InvalidSectorSize {
+                found: self.block_size,
+                supported: infos.sector_size,
+                start: 0,
+            })
+            .during(Operation::DiskInfos)
+            .in_layer("OverlayDisk"));
+        }
+
+        self.store = Some(Box::new(store));
+        Ok(self)
+    }
+
+    pub const fn block_size(&self) -> usize {
+        self.block_size
+    }
+
+    /// The count of blocks in the delta
+    pub fn modified_blocks(&self) -> usize {
+        self.delta.lock().blocks.len()
+    }
+
+    pub const fn base(&self) -> &B {
+        &self.base
+    }
+
+    /// Returns the base, dropping the delta
+    pub fn into_base(self) -> B {
+        self.base
+    }
+
+    /// Writes the delta to the base and flushes it, then empties the delta. The base must be
+    /// writable. If it fails, the delta is kept: the blocks already written are written again by
+    /// the next `commit`.
+    pub fn commit(&self) -> Result<(), DiskErr> {
+        let mut delta = self.delta.lock();
+        let block_size = self.block_size;
+        let blocks: Vec<u64> = delta.blocks.keys().copied().collect();
+
+        let mut i = 0;
+        while i < blocks.len() {
+            let first = blocks[i];
+
+            if let Some(Block::Zero) = delta.blocks.get(&first) {
+                // Contiguous discarded blocks are discarded with a single request
+                let mut run = 1;
+                while i + run < blocks.len()
+                    && blocks[i + run] == first + run as u64
+                    && matches!(delta.blocks.get(&blocks[i + run]), Some(Block::Zero))
+                {
+                    run += 1;
+                }
+
+                self.base
+                    .discard(first, block_size, run as u64)
+                    .map_err(|e| e.during(Operation::Write).in_layer("OverlayDisk"))?;
+                i += run;
+                continue;
+            }
+
+            // Contiguous modified blocks are written with a single request
+            let mut buf = Vec::new();
+            let mut block = vec![0; block_size];
+            let mut run = 0;
+            while i + run < blocks.len()
+                && blocks[i + run] == first + run as u64
+                && !matches!(delta.blocks.get(&blocks[i + run]), Some(Block::Zero))
+            {
+                self.read_block(&delta, blocks[i + run], &mut block)?;
+                buf.extend_from_slice(&block);
+                run += 1;
+            }
+
+            self.base
+                .write_sectors(first, block_size, &buf)
+                .map_err(|e| e.in_layer("OverlayDisk"))?;
+            i += run;
+        }
+
+        self.base
+            .flush()
+            .map_err(|e| e.during(Operation::Flush).in_layer("OverlayDisk"))?;
+
+        *delta = Delta::default();
+        Ok(())
+    }
+
+    /// Throws the delta away: the overlay reads as the base again
+    pub fn rollback(&self) {
+        *self.delta.lock() = Delta::default();
+    }
+
+    /// Reads the whole block `block` of the delta, or of the base if it isn't modified
+    fn read_block(&self, delta: &Delta, block: u64, buf: &mut [u8]) -> Result<(), DiskErr> {
+        match delta.blocks.get(&block) {
+            Some(Block::Data(data)) => buf.copy_from_slice(data),
+            Some(Block::Stored(slot)) => match &self.store {
+                Some(store) => store.read_sectors(*slot, self.block_size, buf)?,
+                None => unreachable!("stored block without a store"),
+            },
+            Some(Block::Zero) => buf.fill(0),
+            None => self.base.read_sectors(block, self.block_size, buf)?,
+        }
+
+        Ok(())
+    }
+
+    /// Stores the new content of the block `block` in the delta
+    fn store_block(&self, delta: &mut Delta, block: u64, data: &[u8]) -> Result<(), DiskErr> {
+        let Some(store) = &self.store else {
+            delta.blocks.insert(block, Block::Data(data.to_vec()));
+            return Ok(());
+        };
+
+        let slot = match delta.blocks.get(&block) {
+            Some(Block::Stored(slot)) => *slot,
+            _ => match delta.free_slots.pop() {
+                Some(slot) => slot,
+                None => {
+                    delta.next_slot += 1;
+                    delta.next_slot - 1
+                }
+            },
+        };
+
+        if let Err(e) = store.write_sectors(slot, self.block_size, data) {
+            // The slot is still free
+            if !matches!(delta.blocks.get(&block), Some(Block::Stored(_))) {
+                delta.free_slots.push(slot);
+            }
+            return Err(e);
+        }
+
+        delta.blocks.insert(block, Block::Stored(slot));
+        Ok(())
+    }
+
+    /// Marks the block `block` as discarded, releasing its slot in the store
+    fn discard_block(&self, delta: &mut Delta, block: u64) {
+        if let Some(Block::Stored(slot)) = delta.blocks.insert(block, Block::Zero) {
+            delta.free_slots.push(slot);
+        }
+    }
+
+    /// Checks a request of `len` bytes starting at the LBA `sector` and returns the range of bytes
+    /// it covers.
+    fn range(&self, sector: u64, sector_size: usize, len: u64) -> Result<(u64, u64), DiskErr> {
+        let infos = self.base.disk_infos()?;
+
+        if sector_size == 0
+            || !infos.sector_size.is_supported(sector_size, self.disk_size)
+            || !len.is_multiple_of(sector_size as u64)
+        {
+            return Err(DiskErrKind::InvalidSectorSize {
+                found: sector_size,
+                supported: infos.sector_size,
+                start: 0,
+            }
+            .into());
+        }
+
+        match sector
+            .checked_mul(sector_size as u64)
+            .and_then(|start| Some((start, start.checked_add(len)?)))
+        {
+            Some((start, end)) if end <= self.disk_size => Ok((start, end)),
+            _ => Err(DiskErrKind::InvalidSectorIndex {
+                found: sector,
+                max: (self.disk_size / sector_size as u64).saturating_sub(1),
+            }
+            .into()),
+        }
+    }
+
+    fn read_inner(&self, sector: u64, sector_size: usize, buf: &mut [u8]) -> Result<(), DiskErr> {
+        if !self.base.disk_infos()?.permissions.read {
+            return Err(DiskErrKind::InvalidPermission {
+                disk_permissions: self.permissions()?,
+            }
+            .into());
+        }
+
+        let (start, end) = self.range(sector, sector_size, buf.len() as u64)?;
+        let block_size = self.block_size as u64;
+        let delta = self.delta.lock();
+
+        let mut pos = start;
+        while pos < end {
+            let block = pos / block_size;
+            let offset = (pos - start) as usize;
+
+            // The unmodified contiguous blocks are read from the base with a single request
+            let mut run_end = block + 1;
+            if !delta.blocks.contains_key(&block) {
+                while run_end * block_size < end && !delta.blocks.contains_key(&run_end) {
+                    run_end += 1;
+                }
+            }
+
+            let mut run = vec![0; ((run_end - block) * block_size) as usize];
+            if delta.blocks.contains_key(&block) {
+                self.read_block(&delta, block, &mut run)?;
+            } else {
+                self.base.read_sectors(block, self.block_size, &mut run)?;
+            }
+
+            let head = (pos - block * block_size) as usize;
+            let len = ((run_end * block_size).min(end) - pos) as usize;
+            buf[offset..(offset + len)].copy_from_slice(&run[head..(head + len)]);
+            pos += len as u64;
+        }
+
+        Ok(())
+    }
+
+    fn write_inner(&self, sector: u64, sector_size: usize, buf: &[u8]) -> Result<(), DiskErr> {
+        let (start, end) = self.range(sector, sector_size, buf.len() as u64)?;
+        let block_size = self.block_size as u64;
+        let mut delta = self.delta.lock();
+        let mut block_buf = vec![0; self.block_size];
+
+        let mut pos = start;
+        while pos < end {
+            let block = pos / block_size;
+            let offset = (pos - start) as usize;
+            let head = (pos - block * block_size) as usize;
+            let len = ((block + 1) * block_size).min(end) - pos;
+            let len = len as usize;
+
+            if len == self.block_size {
+                self.store_block(&mut delta, block, &buf[offset..(offset + len)])?;
+            } else {
+                // Partial block: the rest of the block is kept
+                self.read_block(&delta, block, &mut block_buf)?;
+                block_buf[head..(head + len)].copy_from_slice(&buf[offset..(offset + len)]);
+                self.store_block(&mut delta, block, &block_buf)?;
+            }
+
+            pos += len as u64;
+        }
+
+        Ok(())
+    }
+
+    fn discard_inner(&self, sector: u64, sector_size: usize, count: u64) -> Result<(), DiskErr> {
+        let len = count.saturating_mul(sector_size as u64);
+        let (start, end) = self.range(sector, sector_size, len)?;
+        let block_size = self.block_size as u64;
+        let mut delta = self.delta.lock();
+        let mut block_buf = vec![0; self.block_size];
+
+        let mut pos = start;
+        while pos < end {
+            let block = pos / block_size;
+            let head = (pos - block * block_size) as usize;
+            let len = (((block + 1) * block_size).min(end) - pos) as usize;
+
+            if len == self.block_size {
+                self.discard_block(&mut delta, block);
+            } else {
+                self.read_block(&delta, block, &mut block_buf)?;
+                block_buf[head..(head + len)].fill(0);
+                self.store_block(&mut delta, block, &block_buf)?;
+            }
+
+            pos += len as u64;
+        }
+
+        Ok(())
+    }
+
+    /// The overlay can always be written, as the writes never reach the base
+    fn permissions(&self) -> Result<Permissions, DiskErr> {
+        Ok(Permissions {
+            read: self.base.disk_infos()?.permissions.read,
+            write: true,
+        })
+    }
+}
+
+impl<B: Disk> Disk for OverlayDisk<B> {
+    fn read_sector(&self, sector: u64, buf: &mut [u8]) -> Result<(), DiskErr> {
+        self.read_sectors(sector, buf.len(), buf)
+    }
+
+    fn write_sector(&self, sector: u64, buf: &[u8]) -> Result<(), DiskErr> {
+        self.write_sectors(sector, buf.len(), buf)
+    }
+
+    fn disk_infos(&self) -> Result<DiskInfos, DiskErr> {
+        Ok(DiskInfos {
+            disk_size: self.disk_size,
+            permissions: self.permissions()?,
+            ..self.base.disk_infos()?
+        })
+    }
+
+    fn read_sectors(&self, sector: u64, sector_size: usize, buf: &mut [u8]) -> Result<(), DiskErr> {
+        self.read_inner(sector, sector_size, buf)
+            .map_err(|e| e.at(Operation::Read, sector).in_layer("OverlayDisk"))
+    }
+
+    fn write_sectors(&self, sector: u64, sector_size: usize, buf: &[u8]) -> Result<(), DiskErr> {
+        self.write_inner(sector, sector_size, buf)
+            .map_err(|e| e.at(Operation::Write, sector).in_layer("OverlayDisk"))
+    }
+
+    fn discard(&self, sector: u64, sector_size: usize, count: u64) -> Result<(), DiskErr> {
+        self.discard_inner(sector, sector_size, count)
+            .map_err(|e| e.at(Operation::Discard, sector).in_layer("OverlayDisk"))
+    }
+
+    /// Flushes the store of the delta, the base is only written by `commit`
+    fn flush(&self) -> Result<(), DiskErr> {
+        match &self.store {
+            Some(store) => store
+                .flush()
+                .map_err(|e| e.during(Operation::Flush).in_layer("OverlayDisk")),
+            None => Ok(()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{SectorSize, memdisk::MemDisk, stats::StatsDisk};
+    use alloc::sync::Arc;
+
+    fn pattern(len: usize) -> Vec<u8> {
+        (0..len).map(|i| (i % 251) as u8).collect()
+    }
+
+    /// An overlay by blocks of 1024 bytes over a base of 16 blocks filled with `pattern`
+    fn overlay() -> OverlayDisk<StatsDisk<MemDisk>> {
+        let base = MemDisk::from_vec(
+            pattern(16 * 1024),
+            SectorSize::AllOf(vec![512, 1024]),
+            Permissions::read_write(),
+        );
+        OverlayDisk::with_block_size(StatsDisk::new(base), 1024).unwrap()
+    }
+
+    fn content(disk: &impl Disk) -> Vec<u8> {
+        let mut buf = vec![0; disk.disk_infos().unwrap().disk_size as usize];
+        disk.read_sectors(0, 1024, &mut buf).unwrap();
+        buf
+    }
+
+    /// Writes and discards through `overlay`, and applies them to `expected`
+    fn modify(overlay: &impl Disk, expected: &mut [u8]) {
+        // (sector, sector size, length, byte)
+        let writes = [
+            // Whole blocks 2 and 3
+            (2, 1024, 2048, 0xAA),
+            // Second half of block 1
+            (3, 512, 512, 0xBB),
+            // Second half of block 10 and first half of block 11
+            (21, 512, 1024, 0xCC),
+        ];
+        for (sector, sector_size, len, byte) in writes {
+            overlay
+                .write_sectors(sector, sector_size, &vec![byte; len])
+                .unwrap();
+            let start = sector as usize * sector_size;
+            expected[start..(start + len)].fill(byte);
+        }
+
+        // Whole blocks 0, 12 and 13, first half of block 4
+        for (sector, sector_size, count) in [(0, 1024, 1), (12, 1024, 2), (8, 512, 1)] {
+            overlay.discard(sector, sector_size, count).unwrap();
+            let start = sector as usize * sector_size;
+            expected[start..(start + count as usize * sector_size)].fill(0);
+        }
+    }
+
+    #[test]
+    fn writes_and_rollback() {
+        let overlay = overlay();
+        let mut expected = pattern(16 * 1024);
+        modify(&overlay, &mut expected);
+
+        assert_eq!(content(&overlay), expected);
+        assert_eq!(overlay.modified_blocks(), 9);
+        assert!(overlay.disk_infos().unwrap().permissions.write);
+
+        // The base is untouched
+        let stats = overlay.base().stats();
+        assert_eq!(stats.writes + stats.discards + stats.flushes, 0);
+        assert_eq!(content(overlay.base()), pattern(16 * 1024));
+
+        overlay.rollback();
+        assert_eq!(overlay.modified_blocks(), 0);
+        assert_eq!(content(&overlay), pattern(16 * 1024));
+    }
+
+    #[test]
+    fn commit() {
+        let overlay = overlay();
+        let mut expected = pattern(16 * 1024);
+        modify(&overlay, &mut expected);
+
+        overlay.base().reset_stats();
+        overlay.commit().unwrap();
+        assert_eq!(overlay.modified_blocks(), 0);
+
+        // Blocks 1 to 4 and 10 to 11 are written, 0 and 12 to 13 discarded
+        let stats = overlay.base().stats();
+        assert_eq!(stats.writes, 2);
+        assert_eq!(stats.bytes_written, 6 * 1024);
+        assert_eq!(stats.discards, 2);
+        assert_eq!(stats.bytes_discarded, 3 * 1024);
+        assert_eq!(stats.flushes, 1);
+
+        assert_eq!(content(overlay.base()), expected);
+        assert_eq!(content(&overlay), expected);
+
+        // A read-only base can't be committed to, the delta is kept
+        let base = MemDisk::new(
+            4096,
+            SectorSize::AllOf(vec![1024]),
+            Permissions::read_only(),
+        );
+        let overlay = OverlayDisk::new(base).unwrap();
+        overlay.write_sectors(1, 1024, &[0xAA; 1024]).unwrap();
+        assert!(overlay.commit().is_err());
+        assert_eq!(overlay.modified_blocks(), 1);
+    }
+
+    #[test]
+    fn store() {
+        let store = Arc::new(MemDisk::new(
+            4 * 1024,
+            SectorSize::AllOf(vec![1024]),
+            Permissions::read_write(),
+        ));
+        let overlay = overlay().with_store(store.clone()).unwrap();
+        let mut expected = pattern(16 * 1024);
+
+        // Blocks 5 and 6 go to the slots 0 and 1, block 7 is completed from the base in slot 2
+        overlay.write_sectors(5, 1024, &[0x11; 2048]).unwrap();
+        overlay.write_sectors(15, 512, &[0x22; 512]).unwrap();
+        expected[(5 * 1024)..(7 * 1024)].fill(0x11);
+        expected[(15 * 512)..(16 * 512)].fill(0x22);
+
+        let slots = content(&*store);
+        assert_eq!(slots[..2048], [0x11; 2048]);
+        assert_eq!(slots[2048..2560], expected[(7 * 1024)..(15 * 512)]);
+        assert_eq!(slots[2560..3072], [0x22; 512]);
+
+        // The slot of a discarded block is used again
+        overlay.discard(5, 1024, 1).unwrap();
+        overlay.write_sectors(8, 1024, &[0x33; 1024]).unwrap();
+        expected[(5 * 1024)..(6 * 1024)].fill(0);
+        expected[(8 * 1024)..(9 * 1024)].fill(0x33);
+        assert_eq!(content(&*store)[..1024], [0x33; 1024]);
+
+        // Once the store is full, the writes fail and leave the overlay unchanged
+        overlay.write_sectors(9, 1024, &[0x44; 1024]).unwrap();
+        expected[(9 * 1024)..(10 * 1024)].fill(0x44);
+        assert!(overlay.write_sectors(10, 1024, &[0x55; 1024]).is_err());
+        assert_eq!(overlay.modified_blocks(), 5);
+        assert_eq!(content(&overlay), expected);
+        assert_eq!(content(overlay.base()), pattern(16 * 1024));
+
+        overlay.commit().unwrap();
+        assert_eq!(content(overlay.base()), expected);
+
+        // The store must support the block size
+        let store = MemDisk::new(
+            4 * 1024,
+            SectorSize::AllOf(vec![512]),
+            Permissions::read_write(),
+        );
+        assert!(self::overlay().with_store(store).is_err());
+    }
+}
@@ -0,0 +1,190 @@
+use crate::{
+    PartitionErr, Permissions,
+    async_disk::{AsyncDisk, AsyncSubDisk},
+    partition_tables::mbr::{PartitionInfos, PartitionType, RawMbr, default_sector_size},
+};
+
+/// The asynchronous version of `GenericMbr`, reading the partition table of an `AsyncDisk`. The
+/// sector size is chosen the same way. Read only: the tables are created with `GenericMbr`.
+pub struct AsyncMbr<D: AsyncDisk> {
+    raw: RawMbr,
+    disk: D,
+    sector_size: usize,
+}
+
+impl<D: AsyncDisk> AsyncMbr<D> {
+    /// Reads a MBR from the given disk. Returns `None` if its signature is invalid.
+    pub async fn read_from_disk(
+        disk: D,
+        sector_size: Option<usize>,
+    ) -> Result<Option<Self>, PartitionErr> {
+        let sector_size = match sector_size {
+            None => match default_sector_size(&disk.disk_infos().await?) {
+                None => return Err(PartitionErr::UnsupportedSectorSize),
+                Some(v) => v,
+            },
+            Some(v) => v,
+        };
+
+        let raw = RawMbr::read_from_async_disk(&disk).await?;
+
+        if raw.signature != 0xAA55 {
+            return Ok(None);
+        }
+
+        Ok(Some(Self {
+            raw,
+            disk,
+            sector_size,
+        }))
+    }
+
+    /// Returnes the partition size (if the partition exists) in sectors
+    pub fn partition_size(&self, partition_index: usize) -> Option<u64> {
+        self.partition_infos(partition_index).map(|v| v.size)
+    }
+
+    /// Returns the lba of the first sector of the partition (if it exists)
+    pub fn partition_start(&self, partition_index: usize) -> Option<u64> {
+        self.partition_infos(partition_index).map(|v| v.lba_start)
+    }
+
+    pub fn partition_type(&self, partition_index: usize) -> Option<PartitionType> {
+        self.partition_infos(partition_index)
+            .map(|v| v.partition_type)
+    }
+
+    pub fn partition_infos(&self, partition_index: usize) -> Option<PartitionInfos> {
+        self.raw
+            .partitions
+            .get(partition_index)
+            .map(|v| PartitionInfos {
+                lba_start: v.lba_first as u64,
+                size: v.sectors as u64,
+                sector_size: self.sector_size,
+                partition_type: v.partition_type,
+            })
+    }
+
+    pub const fn sector_size(&self) -> usize {
+        self.sector_size
+    }
+
+    pub const fn inner(&self) -> &D {
+        &self.disk
+    }
+
+    pub fn into_inner(self) -> D {
+        self.disk
+    }
+
+    /// Returns the partition as a disk borrowing the disk of the table. Unlike
+    /// `GenericMbr::get_partition`, the partitions are not checked for overlaps.
+    pub async fn get_partition(
+        &self,
+        partition_index: usize,
+        permissions: Permissions,
+    ) -> Result<AsyncSubDisk<&D>, PartitionErr> {
+        let partition = match self.raw.partitions.get(partition_index) {
+            None => {
+                return Err(PartitionErr::InvalidPartitionIndex {
+                    index: partition_index,
+                });
+            }
+            Some(v) => v,
+        };
+
+        let lba_first = partition.lba_first as u64;
+        let lba_end = lba_first + partition.sectors as u64;
+
+        match (
+            lba_first.checked_mul(self.sector_size as u64),
+            lba_end.checked_mul(self.sector_size as u64),
+        ) {
+            (Some(start), Some(end)) => {
+                Ok(AsyncSubDisk::new(&self.disk, start, end, permissions).await?)
+            }
+            _ => Err(PartitionErr::OutOfRange {
+                found: lba_end,
+                max: u64::MAX / self.sector_size as u64,
+            }),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        Disk, SectorSize,
+        async_disk::tests::{YieldingDisk, run_local},
+        filesystems::fat12::{Fat12, async_fat12::AsyncFat12},
+        memdisk::MemDisk,
+        partition_tables::mbr::{generic_mbr::GenericMbr, partition_types},
+    };
+    use alloc::{sync::Arc, vec};
+
+    #[test]
+    fn partitions() {
+        let disk = Arc::new(MemDisk::new(
+            4 << 20,
+            SectorSize::AllOf(vec![512, 4096]),
+            Permissions::read_write(),
+        ));
+
+        let mut mbr = GenericMbr::new(disk.clone(), Some(512)).unwrap();
+        mbr.create_partition(0, 2048, 2048, partition_types::FAT12_PRIMARY)
+            .unwrap();
+        mbr.create_partition(2, 4096, 4096, partition_types::FAT32_LBA)
+            .unwrap();
+        mbr.write().unwrap();
+
+        let partition = mbr.get_partition(0, Permissions::read_write()).unwrap();
+        let fat = Fat12::new(partition, 224, 2, 2048, Some(512), Some(1))
+            .unwrap()
+            .unwrap();
+        fat.set_fat_entry(2, 0xFFF).unwrap();
+
+        let disk = YieldingDisk::new(disk);
+        run_local(async {
+            let async_mbr = AsyncMbr::read_from_disk(&disk, Some(512))
+                .await
+                .unwrap()
+                .unwrap();
+
+            for i in 0..4 {
+                assert_eq!(async_mbr.partition_infos(i), mbr.partition_infos(i));
+            }
+            assert_eq!(async_mbr.partition_start(2), Some(4096));
+            assert_eq!(async_mbr.partition_size(2), Some(4096));
+            assert!(matches!(
+                async_mbr.get_partition(4, Permissions::read_only()).await,
+                Err(PartitionErr::InvalidPartitionIndex { index: 4 })
+            ));
+
+            // A FAT12 volume on the first partition, read asynchronously end to end
+            let partition = async_mbr
+                .get_partition(0, Permissions::read_only())
+                .await
+                .unwrap();
+            assert_eq!(partition.disk_infos().await.unwrap().disk_size, 2048 * 512);
+
+            let async_fat = AsyncFat12::read_from_disk(partition, None)
+                .await
+                .unwrap()
+                .unwrap();
+            assert_eq!(async_fat.get_fat_entry(2, 1).await.unwrap(), Some(0xFFF));
+            assert_eq!(async_fat.get_fat_entry(3, 0).await.unwrap(), Some(0));
+        });
+
+        // Every request went through the futures
+        assert!(disk.requests.load(core::sync::atomic::Ordering::SeqCst) > 0);
+
+        disk.disk.write_sectors(0, 512, &[0; 512]).unwrap();
+        assert!(
+            run_local(AsyncMbr::read_from_disk(&disk, None))
+                .unwrap()
+                .is_none()
+        );
+    }
+}
@@ -1,14 +1,14 @@
 use crate::{
-    Disk, DiskErr, Permissions,
-    partition_tables::mbr::{MbrEntry, PartitionInfos, PartitionType, RawMbr},
-    wrappers::{DiskWrapper, SubDisk},
+    Disk, PartitionErr, Permissions,
+    partition_tables::mbr::{MbrEntry, PartitionInfos, PartitionType, RawMbr, default_sector_size},
+    wrappers::{DiskWrapper, SubDisk, gcd},
 };
 use alloc::sync::Arc;
 
 /// This struct allows to choose the sector size. It's always better explicitly specify the sector
-/// size to use, if not specified, will try to use the smallest possible >= 512. For a physical
-/// drive, it's always better to use the physical (or emulated) sector size (often 512B). Note that
-/// MBR is designed to work with 512B sector size.
+/// size to use, if not specified, will use the logical sector size of the device if it's >= 512,
+/// or the smallest possible >= 512. For a physical drive, it's always better to use the physical
+/// (or emulated) sector size (often 512B). Note that MBR is designed to work with 512B sector size.
 #[derive(Clone)]
 pub struct GenericMbr {
     raw: RawMbr,
@@ -18,10 +18,13 @@ pub struct GenericMbr {
 
 impl GenericMbr {
     /// This function creates a new MBR structure in memory (without writing it to the disk)
-    pub fn new<T: Disk + 'static>(disk: T, sector_size: Option<usize>) -> Result<Self, DiskErr> {
+    pub fn new<T: Disk + 'static>(
+        disk: T,
+        sector_size: Option<usize>,
+    ) -> Result<Self, PartitionErr> {
         let sector_size = match sector_size {
-            None => match disk.disk_infos()?.sector_size.minimal_ge(512) {
-                None => return Err(DiskErr::UnsupportedDiskSectorSize),
+            None => match default_sector_size(&disk.disk_infos()?) {
+                None => return Err(PartitionErr::UnsupportedSectorSize),
                 Some(v) => v,
             },
             Some(v) => v,
@@ -42,46 +45,53 @@ impl GenericMbr {
     pub fn read_from_disk<T: Disk + 'static>(
         disk: T,
         sector_size: Option<usize>,
-    ) -> Result<Option<Self>, DiskErr> {
+    ) -> Result<Option<Self>, PartitionErr> {
         let sector_size = match sector_size {
-            None => match disk.disk_infos()?.sector_size.minimal_ge(512) {
-                None => return Err(DiskErr::UnsupportedDiskSectorSize),
+            None => match default_sector_size(&disk.disk_infos()?) {
+                None => return Err(PartitionErr::UnsupportedSectorSize),
                 Some(v) => v,
             },
             Some(v) => v,
         };
 
         let raw = RawMbr::read_from_disk(&disk)?;
+        Ok(Self::from_raw(raw, DiskWrapper::new(disk), sector_size))
+    }
+
+    /// Checks the signature of a MBR read from the disk
+    fn from_raw(raw: RawMbr, disk: Arc<DiskWrapper>, sector_size: usize) -> Option<Self> {
         if raw.signature == 0xAA55 {
-            Ok(Some(Self {
+            Some(Self {
                 raw,
-                disk: DiskWrapper::new(disk),
+                disk,
                 sector_size,
-            }))
+            })
         } else {
-            Ok(None)
+            None
         }
     }
 
-    /// Writes the MBR structure to the disk
-    pub fn write(&self) -> Result<(), DiskErr> {
-        self.raw.write_to_disk(&*self.disk)
+    /// Writes the MBR structure to the disk, and flushes the disk so that the new partition table
+    /// is durable when it returns.
+    pub fn write(&self) -> Result<(), PartitionErr> {
+        self.raw.write_to_disk(&*self.disk)?;
+        Ok(self.disk.flush()?)
     }
 
     /// Returnes the partition size (if the partition exists) in sectors
-    pub fn partition_size(&self, partition_index: usize) -> Option<usize> {
+    pub fn partition_size(&self, partition_index: usize) -> Option<u64> {
         self.raw
             .partitions
             .get(partition_index)
-            .map(|v| v.sectors as usize)
+            .map(|v| v.sectors as u64)
     }
 
     /// Returns the lba of the first sector of the partition (if it exists)
-    pub fn partition_start(&self, partition_index: usize) -> Option<usize> {
+    pub fn partition_start(&self, partition_index: usize) -> Option<u64> {
         self.raw
             .partitions
             .get(partition_index)
-            .map(|v| v.lba_first as usize)
+            .map(|v| v.lba_first as u64)
     }
 
     pub fn partition_type(&self, partition_index: usize) -> Option<PartitionType> {
@@ -96,8 +106,8 @@ impl GenericMbr {
             .partitions
             .get(partition_index)
             .map(|v| PartitionInfos {
-                lba_start: v.lba_first as usize,
-                size: v.sectors as usize,
+                lba_start: v.lba_first as u64,
+                size: v.sectors as u64,
                 sector_size: self.sector_size,
                 partition_type: v.partition_type,
             })
@@ -107,44 +117,87 @@ impl GenericMbr {
         self.sector_size
     }
 
+    /// Returns the first LBA >= `lba` where a partition starts aligned on the physical sectors and
+    /// on the preferred I/O size of the device, so that its accesses don't straddle two physical
+    /// sectors.
+    pub fn first_aligned_sector(&self, lba: u64) -> Result<u64, PartitionErr> {
+        let geometry = self.disk.disk_infos()?.geometry;
+        let sector_size = self.sector_size as u64;
+
+        // The alignment must also be a multiple of the sector size of the table
+        let granularity = geometry.io_granularity() as u64;
+        let granularity = granularity / gcd(granularity, sector_size) * sector_size;
+
+        match lba
+            .checked_mul(sector_size)
+            .and_then(|offset| geometry.align_up(offset, granularity))
+        {
+            Some(offset) => Ok(offset.div_ceil(sector_size)),
+            None => Err(PartitionErr::OutOfRange {
+                found: lba,
+                max: u64::MAX / sector_size,
+            }),
+        }
+    }
+
     /// `start` and `size` are in sector (using self.sector_size)
     pub fn create_partition(
         &mut self,
         partition_index: usize,
-        start: usize,
-        size: usize,
+        start: u64,
+        size: u64,
         partition_type: PartitionType,
-    ) -> Result<(), DiskErr> {
+    ) -> Result<(), PartitionErr> {
         if partition_index >= 4 {
-            return Err(DiskErr::InvalidPartitionIndex);
+            return Err(PartitionErr::InvalidPartitionIndex {
+                index: partition_index,
+            });
         }
 
-        let end = start + size;
+        // The MBR entries store the start and the size on 32 bits
+        let (Ok(lba_first), Ok(sectors)) = (u32::try_from(start), u32::try_from(size)) else {
+            return Err(PartitionErr::OutOfRange {
+                found: start.max(size),
+                max: u32::MAX as u64,
+            });
+        };
+
+        let end = match start.checked_add(size) {
+            Some(v) => v,
+            None => {
+                return Err(PartitionErr::OutOfRange {
+                    found: start,
+                    max: u64::MAX - size,
+                });
+            }
+        };
+
         for p in self.raw.partitions {
-            if ((p.lba_first as usize) <= start && start < (p.lba_first + p.sectors) as usize)
-                || ((p.lba_first as usize) < end && end <= (p.lba_first + p.sectors) as usize)
-            {
-                return Err(DiskErr::SpaceAlreadyInUse);
+            let p_start = p.lba_first as u64;
+            let p_end = p_start + p.sectors as u64;
+
+            if p_start < end && start < p_end {
+                return Err(PartitionErr::SpaceAlreadyInUse { start, end });
             }
         }
 
         if start == 0 {
-            return Err(DiskErr::SpaceAlreadyInUse);
+            return Err(PartitionErr::SpaceAlreadyInUse { start, end });
         }
 
-        let disk_size = self.disk.disk_infos()?.disk_size;
-        if end > disk_size / self.sector_size {
-            return Err(DiskErr::InvalidSectorIndex {
+        let disk_sectors = self.disk.disk_infos()?.disk_size / self.sector_size as u64;
+        if end > disk_sectors {
+            return Err(PartitionErr::OutOfRange {
                 found: end,
-                max: disk_size / self.sector_size,
+                max: disk_sectors,
             });
         }
 
         let entry = MbrEntry {
             chs_last: [0; 3],
             chs_first: [0; 3],
-            lba_first: start as u32,
-            sectors: size as u32,
+            lba_first,
+            sectors,
             status: 0x80,
             partition_type,
         };
@@ -158,14 +211,28 @@ impl GenericMbr {
         &self,
         partition_index: usize,
         permissions: Permissions,
-    ) -> Result<SubDisk, DiskErr> {
-        match self.raw.partitions.get(partition_index) {
-            None => Err(DiskErr::InvalidPartitionIndex),
-            Some(partition) => self.disk.subdisk(
-                (partition.lba_first as usize) * self.sector_size,
-                ((partition.lba_first + partition.sectors) as usize) * self.sector_size,
-                permissions,
-            ),
+    ) -> Result<SubDisk, PartitionErr> {
+        let partition = match self.raw.partitions.get(partition_index) {
+            None => {
+                return Err(PartitionErr::InvalidPartitionIndex {
+                    index: partition_index,
+                });
+            }
+            Some(v) => v,
+        };
+
+        let lba_first = partition.lba_first as u64;
+        let lba_end = lba_first + partition.sectors as u64;
+
+        match (
+            lba_first.checked_mul(self.sector_size as u64),
+            lba_end.checked_mul(self.sector_size as u64),
+        ) {
+            (Some(start), Some(end)) => Ok(self.disk.subdisk(start, end, permissions)?),
+            _ => Err(PartitionErr::OutOfRange {
+                found: lba_end,
+                max: u64::MAX / self.sector_size as u64,
+            }),
         }
     }
 
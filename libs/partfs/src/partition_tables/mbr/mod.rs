@@ -1,6 +1,7 @@
-use crate::{Disk, DiskErr};
+use crate::{Disk, DiskInfos, PartitionErr, async_disk::AsyncDisk};
 use alloc::vec;
 
+pub mod async_mbr;
 pub mod generic_mbr;
 pub mod partition_types;
 
@@ -25,24 +26,47 @@ pub struct MbrEntry {
     sectors: u32,
 }
 
+/// Returns the sector size to use when none is given: the logical sector size of the device if it
+/// is usable (the LBAs of the table are then the ones of the device), otherwise the smallest
+/// supported sector size >= 512.
+pub(crate) fn default_sector_size(infos: &DiskInfos) -> Option<usize> {
+    let logical = infos.geometry.logical_sector_size;
+
+    if logical >= 512 && infos.sector_size.is_supported(logical, infos.disk_size) {
+        Some(logical)
+    } else {
+        infos.sector_size.minimal_ge(512)
+    }
+}
+
 impl RawMbr {
-    pub fn write_to_disk(&self, disk: &dyn Disk) -> Result<(), DiskErr> {
-        if let Some(sector_size) = disk.disk_infos()?.sector_size.minimal_ge(512) {
+    pub fn write_to_disk(&self, disk: &dyn Disk) -> Result<(), PartitionErr> {
+        if let Some(sector_size) = default_sector_size(&disk.disk_infos()?) {
             let mut sector = vec![0; sector_size];
             sector[..512].copy_from_slice(&self.to_bytes());
-            disk.write_sector(0, &sector)
+            Ok(disk.write_sector(0, &sector)?)
         } else {
-            Err(DiskErr::UnsupportedDiskSectorSize)
+            Err(PartitionErr::UnsupportedSectorSize)
         }
     }
 
-    pub fn read_from_disk(disk: &dyn Disk) -> Result<Self, DiskErr> {
-        if let Some(sector_size) = disk.disk_infos()?.sector_size.minimal_ge(512) {
+    pub fn read_from_disk(disk: &dyn Disk) -> Result<Self, PartitionErr> {
+        if let Some(sector_size) = default_sector_size(&disk.disk_infos()?) {
             let mut sector = vec![0; sector_size];
             disk.read_sector(0, &mut sector)?;
-            Ok(Self::from_bytes(&sector).unwrap())
+            Self::from_bytes(&sector).ok_or(PartitionErr::UnsupportedSectorSize)
+        } else {
+            Err(PartitionErr::UnsupportedSectorSize)
+        }
+    }
+
+    pub async fn read_from_async_disk(disk: &impl AsyncDisk) -> Result<Self, PartitionErr> {
+        if let Some(sector_size) = default_sector_size(&disk.disk_infos().await?) {
+            let mut sector = vec![0; sector_size];
+            disk.read_sectors(0, sector_size, &mut sector).await?;
+            Self::from_bytes(&sector).ok_or(PartitionErr::UnsupportedSectorSize)
         } else {
-            Err(DiskErr::UnsupportedDiskSectorSize)
+            Err(PartitionErr::UnsupportedSectorSize)
         }
     }
 
@@ -123,8 +147,8 @@ impl MbrEntry {
 
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub struct PartitionInfos {
-    pub lba_start: usize,
-    pub size: usize,
+    pub lba_start: u64,
+    pub size: u64,
     pub sector_size: usize,
     pub partition_type: PartitionType,
 }
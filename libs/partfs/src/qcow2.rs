@@ -0,0 +1,1225 @@
+use crate::{
+    DeviceId, Disk, DiskErr, DiskErrKind, DiskInfos, Geometry, ImageErr, Operation, Permissions,
+    SectorSize,
+    bytedisk::ByteDisk,
+    std_helpers::{DiskFile, path_hash},
+};
+use mutex::Mutex;
+use std::{
+    collections::BTreeMap,
+    fs::{self, File},
+    io::{self, Read, Seek, SeekFrom, Write},
+    ops::Range,
+    path::{Path, PathBuf},
+};
+
+const MAGIC: [u8; 4] = *b"QFI\xFB";
+
+/// Cluster size of the new images when none is given, the default of qemu-img
+pub const DEFAULT_CLUSTER_SIZE: usize = 64 * 1024;
+
+const V2_HEADER_SIZE: usize = 72;
+const V3_HEADER_SIZE: usize = 104;
+
+/// Position of the refcount table offset in the header, followed by its size in clusters
+const REFCOUNT_TABLE_FIELD: u64 = 48;
+/// Position of the autoclear features in the header
+const AUTOCLEAR_FIELD: u64 = 88;
+
+const INCOMPAT_DIRTY: u64 = 1 << 0;
+const INCOMPAT_CORRUPT: u64 = 1 << 1;
+const INCOMPAT_COMPRESSION_TYPE: u64 = 1 << 3;
+/// The incompatible features which don't prevent opening the image. External data files and
+/// extended L2 entries are not supported.
+const INCOMPAT_SUPPORTED: u64 = INCOMPAT_DIRTY | INCOMPAT_CORRUPT | INCOMPAT_COMPRESSION_TYPE;
+
+const EXT_END: u32 = 0;
+const EXT_BACKING_FORMAT: u32 = 0xE279_2ACA;
+
+/// Host offset of the L1 and L2 entries
+const OFFSET_MASK: u64 = 0x00FF_FFFF_FFFF_FE00;
+/// Host offset of the refcount table entries
+const REFCOUNT_TABLE_MASK: u64 = !0x1FF;
+/// The cluster is referenced once: it can be written in place
+const COPIED: u64 = 1 << 63;
+const COMPRESSED: u64 = 1 << 62;
+/// The cluster reads as zeroes (version 3 only)
+const ZERO: u64 = 1;
+
+/// Limits of qemu on the metadata sizes, to bound the allocations on damaged headers
+const MAX_L1_SIZE: u64 = 32 << 20;
+const MAX_REFCOUNT_TABLE_SIZE: u64 = 8 << 20;
+const MAX_SNAPSHOTS: u32 = 65536;
+const MAX_SNAPSHOT_EXTRA_SIZE: usize = 1024;
+
+/// Count of L2 tables and of refcount blocks kept in memory
+const L2_CACHE_TABLES: usize = 32;
+const REFCOUNT_CACHE_BLOCKS: usize = 32;
+
+/// Longest chain of backing files, to stop on loops
+const MAX_BACKING_DEPTH: usize = 16;
+
+fn be16(raw: &[u8], offset: usize) -> u16 {
+    u16::from_be_bytes(raw[offset..(offset + 2)].try_into().unwrap())
+}
+
+fn be32(raw: &[u8], offset: usize) -> u32 {
+    u32::from_be_bytes(raw[offset..(offset + 4)].try_into().unwrap())
+}
+
+fn be64(raw: &[u8], offset: usize) -> u64 {
+    u64::from_be_bytes(raw[offset..(offset + 8)].try_into().unwrap())
+}
+
+/// An internal snapshot of the image
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Qcow2Snapshot {
+    pub id: String,
+    pub name: String,
+    /// Creation time, since the Unix epoch
+    pub date_sec: u32,
+    pub date_nsec: u32,
+    /// Time of the VM clock when the snapshot was taken, in nanoseconds
+    pub vm_clock_nsec: u64,
+    /// Size of the saved VM state in bytes, 0 for disk-only snapshots
+    pub vm_state_size: u64,
+    /// Virtual size of the disk when the snapshot was taken, in bytes, if recorded
+    pub virtual_size: Option<u64>,
+}
+
+/// A qcow2 disk image (versions 2 and 3). The unallocated clusters are read from the backing file
+/// if there is one, or as zeroes. Writes allocate the clusters and copy the shared ones (with an
+/// internal snapshot), keeping the refcounts consistent: the image stays valid for `qemu-img
+/// check`.
+///
+/// Compressed clusters, encryption, external data files and extended L2 entries are not
+/// supported. The snapshots can be listed, but not created, applied nor deleted.
+pub struct Qcow2Disk {
+    version: u32,
+    cluster_size: usize,
+    virtual_size: u64,
+    permissions: Permissions,
+    backing_file: Option<String>,
+    snapshots: Vec<Qcow2Snapshot>,
+    device_id: Option<DeviceId>,
+    state: Mutex<State>,
+}
+
+impl Disk for Qcow2Disk {
+    fn read_sector(&self, sector: u64, buf: &mut [u8]) -> Result<(), DiskErr> {
+        self.read_sectors(sector, buf.len(), buf)
+    }
+
+    fn write_sector(&self, sector: u64, buf: &[u8]) -> Result<(), DiskErr> {
+        self.write_sectors(sector, buf.len(), buf)
+    }
+
+    fn disk_infos(&self) -> Result<DiskInfos, DiskErr> {
+        Ok(DiskInfos {
+            sector_size: SectorSize::Any,
+            disk_size: self.virtual_size,
+            permissions: self.permissions,
+            geometry: Geometry {
+                optimal_io_size: self.cluster_size,
+                ..Geometry::from_sector_size(&SectorSize::Any, self.virtual_size)
+            },
+            device_id: self.device_id.clone(),
+        })
+    }
+
+    fn read_sectors(&self, sector: u64, sector_size: usize, buf: &mut [u8]) -> Result<(), DiskErr> {
+        self.read_inner(sector, sector_size, buf)
+            .map_err(|e| e.at(Operation::Read, sector).in_layer("Qcow2Disk"))
+    }
+
+    fn write_sectors(&self, sector: u64, sector_size: usize, buf: &[u8]) -> Result<(), DiskErr> {
+        self.write_inner(sector, sector_size, buf)
+            .map_err(|e| e.at(Operation::Write, sector).in_layer("Qcow2Disk"))
+    }
+
+    fn flush(&self) -> Result<(), DiskErr> {
+        self.state.lock().file.sync_data().map_err(|e| {
+            DiskErr::from_io(&e)
+                .during(Operation::Flush)
+                .in_layer("Qcow2Disk")
+        })
+    }
+}
+
+impl Qcow2Disk {
+    /// Opens an existing image, and its backing files (read-only). A relative backing file is
+    /// looked for in the directory of the image.
+    pub fn open(path: PathBuf, permissions: Permissions) -> Result<Self, ImageErr> {
+        Self::open_chain(&path, permissions, 0)
+    }
+
+    /// Creates a new version 3 image of `size` bytes, without backing file, and opens it. The
+    /// cluster size is `DEFAULT_CLUSTER_SIZE` if not given. Will result in an error if the file
+    /// already exists.
+    pub fn create(path: PathBuf, size: u64, cluster_size: Option<usize>) -> Result<Self, ImageErr> {
+        let cluster_size = cluster_size.unwrap_or(DEFAULT_CLUSTER_SIZE);
+
+        if !cluster_size.is_power_of_two() || !(512..=(2 << 20)).contains(&cluster_size) {
+            return Err(ImageErr::Unsupported {
+                feature: format!("{cluster_size}-byte clusters"),
+            });
+        }
+
+        let cs = cluster_size as u64;
+        let l1_size = size.div_ceil(cs * (cs / 8));
+        if l1_size * 8 > MAX_L1_SIZE {
+            return Err(ImageErr::Unsupported {
+                feature: format!("{size}-byte disks with {cluster_size}-byte clusters"),
+            });
+        }
+
+        // ### LAYOUT: HEADER, REFCOUNT TABLE, REFCOUNT BLOCKS, L1 TABLE ###
+
+        // 16-bit refcounts
+        let refblock_entries = cs / 2;
+        let l1_clusters = (l1_size * 8).div_ceil(cs).max(1);
+
+        let mut refblocks = 1;
+        let clusters = loop {
+            let clusters = 2 + refblocks + l1_clusters;
+            let needed = clusters.div_ceil(refblock_entries);
+            if needed <= refblocks {
+                break clusters;
+            }
+            refblocks = needed;
+        };
+
+        if refblocks > cs / 8 {
+            return Err(ImageErr::Unsupported {
+                feature: format!("{size}-byte disks with {cluster_size}-byte clusters"),
+            });
+        }
+
+        let l1_offset = (2 + refblocks) * cs;
+
+        let mut header = vec![0; cluster_size];
+        header[..4].copy_from_slice(&MAGIC);
+        header[4..8].copy_from_slice(&3u32.to_be_bytes());
+        header[20..24].copy_from_slice(&cluster_size.trailing_zeros().to_be_bytes());
+        header[24..32].copy_from_slice(&size.to_be_bytes());
+        header[36..40].copy_from_slice(&(l1_size as u32).to_be_bytes());
+        header[40..48].copy_from_slice(&l1_offset.to_be_bytes());
+        header[48..56].copy_from_slice(&cs.to_be_bytes());
+        header[56..60].copy_from_slice(&1u32.to_be_bytes());
+        header[96..100].copy_from_slice(&4u32.to_be_bytes());
+        header[100..104].copy_from_slice(&(V3_HEADER_SIZE as u32).to_be_bytes());
+        // The header extensions end right after the header, the end marker is made of zeroes
+
+        let mut refcount_table = vec![0; cluster_size];
+        for (i, entry) in refcount_table
+            .chunks_mut(8)
+            .take(refblocks as usize)
+            .enumerate()
+        {
+            entry.copy_from_slice(&((2 + i as u64) * cs).to_be_bytes());
+        }
+
+        let mut refcounts = vec![0; (refblocks * cs) as usize];
+        for entry in refcounts.chunks_mut(2).take(clusters as usize) {
+            entry.copy_from_slice(&1u16.to_be_bytes());
+        }
+
+        let io = |e: io::Error| ImageErr::Disk(DiskErr::from_io(&e).in_layer("Qcow2Disk"));
+
+        let mut file = File::create_new(&path).map_err(io)?;
+        file.write_all(&header)
+            .and_then(|_| file.write_all(&refcount_table))
+            .and_then(|_| file.write_all(&refcounts))
+            .and_then(|_| file.set_len(clusters * cs))
+            .and_then(|_| file.sync_all())
+            .map_err(io)?;
+        drop(file);
+
+        Self::open(path, Permissions::read_write())
+    }
+
+    pub const fn version(&self) -> u32 {
+        self.version
+    }
+
+    pub const fn cluster_size(&self) -> usize {
+        self.cluster_size
+    }
+
+    /// Size of the disk seen by the guest, in bytes
+    pub const fn virtual_size(&self) -> u64 {
+        self.virtual_size
+    }
+
+    /// The backing file, as written in the header
+    pub fn backing_file(&self) -> Option<&str> {
+        self.backing_file.as_deref()
+    }
+
+    pub fn snapshots(&self) -> &[Qcow2Snapshot] {
+        &self.snapshots
+    }
+
+    fn open_chain(path: &Path, permissions: Permissions, depth: usize) -> Result<Self, ImageErr> {
+        let io = |e: io::Error| ImageErr::Disk(DiskErr::from_io(&e).in_layer("Qcow2Disk"));
+        let invalid_header = |reason| ImageErr::InvalidHeader { reason };
+
+        // The metadata is read even on write-only disks
+        let mut file = File::options()
+            .read(true)
+            .write(permissions.write)
+            .open(path)
+            .map_err(io)?;
+
+        // ### HEADER ###
+
+        let mut header = [0; V3_HEADER_SIZE];
+        let len = (&mut file)
+            .take(V3_HEADER_SIZE as u64)
+            .read(&mut header)
+            .map_err(io)?;
+
+        if len < V2_HEADER_SIZE || header[..4] != MAGIC {
+            return Err(invalid_header("not a qcow2 image"));
+        }
+
+        let version = be32(&header, 4);
+        let (incompatible, autoclear, refcount_order, header_size) = match version {
+            2 => (0, 0, 4, V2_HEADER_SIZE),
+            3 if len == V3_HEADER_SIZE => (
+                be64(&header, 72),
+                be64(&header, 88),
+                be32(&header, 96),
+                be32(&header, 100) as usize,
+            ),
+            3 => return Err(invalid_header("truncated header")),
+            _ => {
+                return Err(ImageErr::Unsupported {
+                    feature: format!("qcow2 version {version}"),
+                });
+            }
+        };
+
+        let cluster_bits = be32(&header, 20);
+        let virtual_size = be64(&header, 24);
+        let l1_size = be32(&header, 36) as u64;
+        let l1_offset = be64(&header, 40);
+        let refcount_table_offset = be64(&header, 48);
+        let refcount_table_clusters = be32(&header, 56) as u64;
+
+        if be32(&header, 32) != 0 {
+            return Err(ImageErr::Unsupported {
+                feature: "encrypted images".into(),
+            });
+        }
+
+        if incompatible & !INCOMPAT_SUPPORTED != 0 {
+            return Err(ImageErr::Unsupported {
+                feature: format!("incompatible features {incompatible:#x}"),
+            });
+        }
+
+        // A dirty image has refcounts to repair first (`qemu-img check -r all`)
+        if permissions.write && incompatible & (INCOMPAT_DIRTY | INCOMPAT_CORRUPT) != 0 {
+            return Err(ImageErr::Unsupported {
+                feature: "writes to an image marked dirty or corrupt".into(),
+            });
+        }
+
+        if !(9..=21).contains(&cluster_bits) {
+            return Err(invalid_header("invalid cluster size"));
+        }
+        if refcount_order > 6 {
+            return Err(invalid_header("invalid refcount width"));
+        }
+        if header_size < V3_HEADER_SIZE && version == 3 {
+            return Err(invalid_header("invalid header length"));
+        }
+
+        let cluster_size = 1usize << cluster_bits;
+        let cs = cluster_size as u64;
+
+        if l1_size < virtual_size.div_ceil(cs * (cs / 8))
+            || l1_size * 8 > MAX_L1_SIZE
+            || refcount_table_clusters * cs > MAX_REFCOUNT_TABLE_SIZE
+        {
+            return Err(invalid_header("invalid table sizes"));
+        }
+        if !l1_offset.is_multiple_of(cs) || !refcount_table_offset.is_multiple_of(cs) {
+            return Err(invalid_header("unaligned tables"));
+        }
+
+        // ### HEADER EXTENSIONS AND BACKING FILE NAME, IN THE FIRST CLUSTER ###
+
+        let mut first = Vec::new();
+        file.seek(SeekFrom::Start(0))
+            .and_then(|_| (&mut file).take(cs).read_to_end(&mut first))
+            .map_err(io)?;
+
+        let mut backing_format = None;
+        let mut pos = header_size;
+        while pos + 8 <= first.len() {
+            let kind = be32(&first, pos);
+            let len = be32(&first, pos + 4) as usize;
+            let data = first
+                .get((pos + 8)..(pos + 8 + len))
+                .ok_or(invalid_header("header extension out of the first cluster"))?;
+
+            match kind {
+                EXT_END => break,
+                EXT_BACKING_FORMAT => {
+                    backing_format = Some(String::from_utf8_lossy(data).into_owned())
+                }
+                _ => {}
+            }
+
+            pos += 8 + len.next_multiple_of(8);
+        }
+
+        let backing_file = match (be64(&header, 8), be32(&header, 16) as u64) {
+            (0, _) | (_, 0) => None,
+            (offset, size) if offset + size <= first.len() as u64 && size <= 1023 => Some(
+                String::from_utf8_lossy(&first[(offset as usize)..((offset + size) as usize)])
+                    .into_owned(),
+            ),
+            _ => return Err(invalid_header("invalid backing file name")),
+        };
+
+        // ### TABLES ###
+
+        let read_at = |file: &mut File, offset: u64, len: u64| -> Result<Vec<u8>, ImageErr> {
+            let mut raw = vec![0; len as usize];
+            file.seek(SeekFrom::Start(offset))
+                .and_then(|_| file.read_exact(&mut raw))
+                .map_err(io)?;
+            Ok(raw)
+        };
+
+        let l1 = read_at(&mut file, l1_offset, l1_size * 8)?
+            .chunks(8)
+            .map(|e| be64(e, 0))
+            .collect();
+        let refcount_table = read_at(
+            &mut file,
+            refcount_table_offset,
+            refcount_table_clusters * cs,
+        )?
+        .chunks(8)
+        .map(|e| be64(e, 0))
+        .collect();
+
+        // ### SNAPSHOTS ###
+
+        let snapshot_count = be32(&header, 60);
+        if snapshot_count > MAX_SNAPSHOTS {
+            return Err(invalid_header("too many snapshots"));
+        }
+
+        let mut snapshots = Vec::new();
+        let mut offset = be64(&header, 64);
+        for _ in 0..snapshot_count {
+            let fixed = read_at(&mut file, offset, 40)?;
+            let id_size = be16(&fixed, 12) as usize;
+            let name_size = be16(&fixed, 14) as usize;
+            let extra_size = be32(&fixed, 36) as usize;
+
+            if extra_size > MAX_SNAPSHOT_EXTRA_SIZE {
+                return Err(invalid_header("invalid snapshot table"));
+            }
+
+            let rest = read_at(
+                &mut file,
+                offset + 40,
+                (extra_size + id_size + name_size) as u64,
+            )?;
+            let (extra, strings) = rest.split_at(extra_size);
+
+            snapshots.push(Qcow2Snapshot {
+                id: String::from_utf8_lossy(&strings[..id_size]).into_owned(),
+                name: String::from_utf8_lossy(&strings[id_size..]).into_owned(),
+                date_sec: be32(&fixed, 16),
+                date_nsec: be32(&fixed, 20),
+                vm_clock_nsec: be64(&fixed, 24),
+                vm_state_size: match extra_size >= 8 {
+                    true => be64(extra, 0),
+                    false => be32(&fixed, 32) as u64,
+                },
+                virtual_size: (extra_size >= 16).then(|| be64(extra, 8)),
+            });
+
+            offset += (40 + rest.len() as u64).next_multiple_of(8);
+        }
+
+        // ### BACKING FILE ###
+
+        let backing = match &backing_file {
+            Some(name) => {
+                let dir = path.parent().unwrap_or(Path::new(""));
+                Some(Self::open_backing(
+                    &dir.join(name),
+                    backing_format.as_deref(),
+                    depth,
+                )?)
+            }
+            None => None,
+        };
+
+        // The autoclear features describe data that the writes below don't keep up to date
+        if permissions.write && autoclear != 0 {
+            file.seek(SeekFrom::Start(AUTOCLEAR_FIELD))
+                .and_then(|_| file.write_all(&0u64.to_be_bytes()))
+                .map_err(io)?;
+        }
+
+        let device_id = fs::canonicalize(path)
+            .ok()
+            .map(|p| DeviceId::Image(path_hash(&p)));
+
+        Ok(Self {
+            version,
+            cluster_size,
+            virtual_size,
+            permissions,
+            backing_file,
+            snapshots,
+            device_id,
+            state: Mutex::new(State {
+                file,
+                version,
+                cluster_bits,
+                refcount_order,
+                l1_offset,
+                l1,
+                refcount_table_offset,
+                refcount_table,
+                l2_cache: BTreeMap::new(),
+                refcount_cache: BTreeMap::new(),
+                free_hint: 0,
+                backing,
+            }),
+        })
+    }
+
+    /// Opens a backing file read-only, probing its format if the image doesn't tell it
+    fn open_backing(
+        path: &Path,
+        format: Option<&str>,
+        depth: usize,
+    ) -> Result<ByteDisk<Box<dyn Disk + Send + Sync>>, ImageErr> {
+        let io = |e: io::Error| ImageErr::Disk(DiskErr::from_io(&e).in_layer("Qcow2Disk"));
+
+        if depth >= MAX_BACKING_DEPTH {
+            return Err(ImageErr::Unsupported {
+                feature: format!("backing chains of more than {MAX_BACKING_DEPTH} images"),
+            });
+        }
+
+        let is_qcow2 = match format {
+            Some("qcow2") => true,
+            Some("raw") => false,
+            Some(format) => {
+                return Err(ImageErr::Unsupported {
+                    feature: format!("{format} backing files"),
+                });
+            }
+            None => {
+                let mut magic = [0; 4];
+                let len = File::open(path)
+                    .and_then(|file| file.take(4).read(&mut magic))
+                    .map_err(io)?;
+                len == 4 && magic == MAGIC
+            }
+        };
+
+        let disk: Box<dyn Disk + Send + Sync> = match is_qcow2 {
+            true => Box::new(Self::open_chain(path, Permissions::read_only(), depth + 1)?),
+            false => Box::new(
+                DiskFile::from_file(path.into(), SectorSize::Any, Permissions::read_only())
+                    .map_err(io)?,
+            ),
+        };
+
+        Ok(ByteDisk::with_sector_size(disk, 1)?)
+    }
+
+    fn read_inner(&self, sector: u64, sector_size: usize, buf: &mut [u8]) -> Result<(), DiskErr> {
+        if !self.permissions.read {
+            return Err(DiskErrKind::InvalidPermission {
+                disk_permissions: self.permissions,
+            }
+            .into());
+        }
+
+        let offset = self.offset(sector, sector_size, buf.len() as u64)?;
+        self.state.lock().read_at(offset, buf)
+    }
+
+    fn write_inner(&self, sector: u64, sector_size: usize, buf: &[u8]) -> Result<(), DiskErr> {
+        if !self.permissions.write {
+            return Err(DiskErrKind::InvalidPermission {
+                disk_permissions: self.permissions,
+            }
+            .into());
+        }
+
+        let offset = self.offset(sector, sector_size, buf.len() as u64)?;
+        self.state.lock().write_at(offset, buf)
+    }
+
+    /// Checks a request of `len` bytes starting at the LBA `sector` and returns its offset in the
+    /// virtual disk.
+    fn offset(&self, sector: u64, sector_size: usize, len: u64) -> Result<u64, DiskErr> {
+        if sector_size == 0
+            || sector_size as u64 > self.virtual_size
+            || !len.is_multiple_of(sector_size as u64)
+        {
+            return Err(DiskErrKind::InvalidSectorSize {
+                found: sector_size,
+                supported: SectorSize::Any,
+                start: 0,
+            }
+            .into());
+        }
+
+        match sector.checked_mul(sector_size as u64) {
+            Some(offset)
+                if offset
+                    .checked_add(len)
+                    .is_some_and(|end| end <= self.virtual_size) =>
+            {
+                Ok(offset)
+            }
+            _ => Err(DiskErrKind::InvalidSectorIndex {
+                found: sector,
+                max: (self.virtual_size / sector_size as u64).saturating_sub(1),
+            }
+            .into()),
+        }
+    }
+}
+
+/// Where the data of a guest cluster is, from its L2 entry
+#[derive(Clone, Copy)]
+enum Mapping {
+    /// Read from the backing file
+    Unallocated,
+    /// Reads as zeroes, with a preallocated cluster if `host` is not 0
+    Zero {
+        host: u64,
+        copied: bool,
+    },
+    Data {
+        host: u64,
+        copied: bool,
+    },
+    Compressed,
+}
+
+impl Mapping {
+    fn new(entry: u64, version: u32) -> Self {
+        let host = entry & OFFSET_MASK;
+        let copied = entry & COPIED != 0;
+
+        if entry & COMPRESSED != 0 {
+            Self::Compressed
+        } else if version >= 3 && entry & ZERO != 0 {
+            Self::Zero { host, copied }
+        } else if host == 0 {
+            Self::Unallocated
+        } else {
+            Self::Data { host, copied }
+        }
+    }
+}
+
+fn invalid(reason: &'static str) -> DiskErr {
+    DiskErr::new(DiskErrKind::InvalidImage { reason })
+}
+
+/// The mutable part of the image, behind the lock. The metadata is written through: every change
+/// is on the file when the request returns.
+struct State {
+    file: File,
+    version: u32,
+    cluster_bits: u32,
+    /// The refcounts are `1 << refcount_order` bits wide
+    refcount_order: u32,
+    l1_offset: u64,
+    l1: Vec<u64>,
+    refcount_table_offset: u64,
+    refcount_table: Vec<u64>,
+    /// L2 tables and refcount blocks, by offset in the file
+    l2_cache: BTreeMap<u64, Vec<u64>>,
+    refcount_cache: BTreeMap<u64, Vec<u8>>,
+    /// No cluster before this one is free
+    free_hint: u64,
+    backing: Option<ByteDisk<Box<dyn Disk + Send + Sync>>>,
+}
+
+impl State {
+    fn cluster_size(&self) -> usize {
+        1 << self.cluster_bits
+    }
+
+    fn read_file(&mut self, offset: u64, buf: &mut [u8]) -> Result<(), DiskErr> {
+        self.file
+            .seek(SeekFrom::Start(offset))
+            .and_then(|_| self.file.read_exact(buf))
+            .map_err(|e| DiskErr::from_io(&e))
+    }
+
+    fn write_file(&mut self, offset: u64, buf: &[u8]) -> Result<(), DiskErr> {
+        self.file
+            .seek(SeekFrom::Start(offset))
+            .and_then(|_| self.file.write_all(buf))
+            .map_err(|e| DiskErr::from_io(&e))
+    }
+
+    /// Checks a host offset read from the tables
+    fn check_host(&self, host: u64) -> Result<u64, DiskErr> {
+        match host.is_multiple_of(self.cluster_size() as u64) {
+            true => Ok(host),
+            false => Err(invalid("unaligned cluster offset")),
+        }
+    }
+
+    // ### READS ###
+
+    fn read_at(&mut self, offset: u64, buf: &mut [u8]) -> Result<(), DiskErr> {
+        let cluster_size = self.cluster_size() as u64;
+        let mut done = 0;
+
+        while done < buf.len() {
+            let pos = offset + done as u64;
+            let in_cluster = pos % cluster_size;
+            let len = ((cluster_size - in_cluster) as usize).min(buf.len() - done);
+            let chunk = &mut buf[done..(done + len)];
+
+            match self.mapping(pos)? {
+                Mapping::Data { host, .. } => self.read_file(host + in_cluster, chunk)?,
+                Mapping::Zero { .. } => chunk.fill(0),
+                Mapping::Unallocated => self.read_backing(pos, chunk)?,
+                Mapping::Compressed => {
+                    return Err(invalid("compressed clusters are not supported"));
+                }
+            }
+
+            done += len;
+        }
+
+        Ok(())
+    }
+
+    /// Reads the backing file, which reads as zeroes after its end
+    fn read_backing(&mut self, offset: u64, buf: &mut [u8]) -> Result<(), DiskErr> {
+        let Some(backing) = &self.backing else {
+            buf.fill(0);
+            return Ok(());
+        };
+
+        let available = backing.size()?.saturating_sub(offset);
+        let (head, tail) = buf.split_at_mut((available as usize).min(buf.len()));
+
+        if !head.is_empty() {
+            backing.read_at(offset, head)?;
+        }
+        tail.fill(0);
+
+        Ok(())
+    }
+
+    /// The index of the guest cluster holding `offset` in the L1 table, and in its L2 table
+    fn indexes(&self, offset: u64) -> Result<(usize, usize), DiskErr> {
+        let l2_bits = self.cluster_bits - 3;
+        let l1_index = (offset >> (self.cluster_bits + l2_bits)) as usize;
+        let l2_index = ((offset >> self.cluster_bits) & ((1 << l2_bits) - 1)) as usize;
+
+        match l1_index < self.l1.len() {
+            true => Ok((l1_index, l2_index)),
+            false => Err(invalid("L1 table too small")),
+        }
+    }
+
+    fn mapping(&mut self, offset: u64) -> Result<Mapping, DiskErr> {
+        let (l1_index, l2_index) = self.indexes(offset)?;
+        let l2_offset = self.check_host(self.l1[l1_index] & OFFSET_MASK)?;
+
+        if l2_offset == 0 {
+            return Ok(Mapping::Unallocated);
+        }
+
+        let entry = self.l2_table(l2_offset)?[l2_index];
+        let mapping = Mapping::new(entry, self.version);
+
+        if let Mapping::Data { host, .. } | Mapping::Zero { host, .. } = mapping {
+            self.check_host(host)?;
+        }
+        Ok(mapping)
+    }
+
+    fn l2_table(&mut self, offset: u64) -> Result<&mut Vec<u64>, DiskErr> {
+        if !self.l2_cache.contains_key(&offset) {
+            let mut raw = vec![0; self.cluster_size()];
+            self.read_file(offset, &mut raw)?;
+
+            if self.l2_cache.len() >= L2_CACHE_TABLES {
+                self.l2_cache.pop_first();
+            }
+            self.l2_cache
+                .insert(offset, raw.chunks(8).map(|e| be64(e, 0)).collect());
+        }
+
+        Ok(self.l2_cache.get_mut(&offset).unwrap())
+    }
+
+    // ### WRITES ###
+
+    fn write_at(&mut self, offset: u64, buf: &[u8]) -> Result<(), DiskErr> {
+        let cluster_size = self.cluster_size() as u64;
+        let mut done = 0;
+
+        while done < buf.len() {
+            let pos = offset + done as u64;
+            let in_cluster = (pos % cluster_size) as usize;
+            let len = (cluster_size as usize - in_cluster).min(buf.len() - done);
+
+            self.write_cluster(
+                pos - in_cluster as u64,
+                in_cluster,
+                &buf[done..(done + len)],
+            )?;
+            done += len;
+        }
+
+        Ok(())
+    }
+
+    /// Writes `data` at `in_cluster` in the guest cluster starting at `offset`. The clusters
+    /// referenced once are written in place, the others are copied to a new cluster first.
+    fn write_cluster(
+        &mut self,
+        offset: u64,
+        in_cluster: usize,
+        data: &[u8],
+    ) -> Result<(), DiskErr> {
+        let (l2_offset, l2_index) = self.l2_table_for_write(offset)?;
+        let entry = self.l2_table(l2_offset)?[l2_index];
+        let mapping = Mapping::new(entry, self.version);
+
+        // ### IN PLACE ###
+
+        match mapping {
+            Mapping::Data { host, copied } if copied || self.refcount(host)? == 1 => {
+                self.check_host(host)?;
+                self.write_file(host + in_cluster as u64, data)?;
+
+                if !copied {
+                    self.set_l2_entry(l2_offset, l2_index, host | COPIED)?;
+                }
+                return Ok(());
+            }
+            Mapping::Zero { host, copied }
+                if host != 0 && (copied || self.refcount(host)? == 1) =>
+            {
+                self.check_host(host)?;
+                let mut cluster = vec![0; self.cluster_size()];
+                cluster[in_cluster..(in_cluster + data.len())].copy_from_slice(data);
+                self.write_file(host, &cluster)?;
+
+                return self.set_l2_entry(l2_offset, l2_index, host | COPIED);
+            }
+            Mapping::Compressed => return Err(invalid("compressed clusters are not supported")),
+            _ => {}
+        }
+
+        // ### COPY ON WRITE ###
+
+        let mut cluster = vec![0; self.cluster_size()];
+        if data.len() < cluster.len() {
+            match mapping {
+                Mapping::Data { host, .. } => self.read_file(host, &mut cluster)?,
+                Mapping::Unallocated => self.read_backing(offset, &mut cluster)?,
+                _ => {}
+            }
+        }
+        cluster[in_cluster..(in_cluster + data.len())].copy_from_slice(data);
+
+        let new = self.allocate_cluster()?;
+        self.write_file(new, &cluster)?;
+        self.set_l2_entry(l2_offset, l2_index, new | COPIED)?;
+
+        // The old cluster is still referenced by a snapshot
+        if let Mapping::Data { host, .. } | Mapping::Zero { host, .. } = mapping
+            && host != 0
+        {
+            self.decrease_refcount(host)?;
+        }
+
+        Ok(())
+    }
+
+    /// The L2 table of the guest cluster at `offset`, allocated or copied if it is missing or
+    /// shared with a snapshot, and the index of the cluster in it
+    fn l2_table_for_write(&mut self, offset: u64) -> Result<(u64, usize), DiskErr> {
+        let (l1_index, l2_index) = self.indexes(offset)?;
+        let entry = self.l1[l1_index];
+        let old = self.check_host(entry & OFFSET_MASK)?;
+
+        if old != 0 && (entry & COPIED != 0 || self.refcount(old)? == 1) {
+            if entry & COPIED == 0 {
+                self.set_l1_entry(l1_index, old | COPIED)?;
+            }
+            return Ok((old, l2_index));
+        }
+
+        // The clusters of a copied table are shared with the snapshot
+        let table: Vec<u64> = match old {
+            0 => vec![0; self.cluster_size() / 8],
+            _ => self.l2_table(old)?.iter().map(|e| e & !COPIED).collect(),
+        };
+        let raw: Vec<u8> = table.iter().flat_map(|e| e.to_be_bytes()).collect();
+
+        let new = self.allocate_cluster()?;
+        self.write_file(new, &raw)?;
+        if self.l2_cache.len() >= L2_CACHE_TABLES {
+            self.l2_cache.pop_first();
+        }
+        self.l2_cache.insert(new, table);
+
+        self.set_l1_entry(l1_index, new | COPIED)?;
+        if old != 0 {
+            self.decrease_refcount(old)?;
+        }
+
+        Ok((new, l2_index))
+    }
+
+    fn set_l1_entry(&mut self, index: usize, entry: u64) -> Result<(), DiskErr> {
+        self.l1[index] = entry;
+        self.write_file(self.l1_offset + index as u64 * 8, &entry.to_be_bytes())
+    }
+
+    fn set_l2_entry(&mut self, table: u64, index: usize, entry: u64) -> Result<(), DiskErr> {
+        self.l2_table(table)?[index] = entry;
+        self.write_file(table + index as u64 * 8, &entry.to_be_bytes())
+    }
+
+    // ### REFCOUNTS ###
+
+    /// Count of refcounts in a refcount block
+    fn refblock_entries(&self) -> u64 {
+        (self.cluster_size() as u64 * 8) >> self.refcount_order
+    }
+
+    /// The offset of the refcount block of the host cluster `cluster`, 0 if it has none
+    fn refblock(&self, cluster: u64) -> Result<u64, DiskErr> {
+        let index = cluster / self.refblock_entries();
+
+        match self.refcount_table.get(index as usize) {
+            Some(entry) => self.check_host(entry & REFCOUNT_TABLE_MASK),
+            None => Ok(0),
+        }
+    }
+
+    fn refblock_data(&mut self, offset: u64) -> Result<&mut Vec<u8>, DiskErr> {
+        if !self.refcount_cache.contains_key(&offset) {
+            let mut raw = vec![0; self.cluster_size()];
+            self.read_file(offset, &mut raw)?;
+
+            if self.refcount_cache.len() >= REFCOUNT_CACHE_BLOCKS {
+                self.refcount_cache.pop_first();
+            }
+            self.refcount_cache.insert(offset, raw);
+        }
+
+        Ok(self.refcount_cache.get_mut(&offset).unwrap())
+    }
+
+    fn refcount(&mut self, host: u64) -> Result<u64, DiskErr> {
+        let cluster = host >> self.cluster_bits;
+        let block = self.refblock(cluster)?;
+
+        if block == 0 {
+            return Ok(0);
+        }
+
+        let index = (cluster % self.refblock_entries()) as usize;
+        let order = self.refcount_order;
+        Ok(get_refcount(self.refblock_data(block)?, index, order))
+    }
+
+    fn set_refcount(&mut self, host: u64, value: u64) -> Result<(), DiskErr> {
+        let cluster = host >> self.cluster_bits;
+        let block = self.refblock(cluster)?;
+
+        if block == 0 {
+            return Err(invalid("missing refcount block"));
+        }
+
+        let index = (cluster % self.refblock_entries()) as usize;
+        let order = self.refcount_order;
+        let data = self.refblock_data(block)?;
+        let range = set_refcount(data, index, order, value);
+        let bytes = data[range.clone()].to_vec();
+
+        self.write_file(block + range.start as u64, &bytes)
+    }
+
+    fn decrease_refcount(&mut self, host: u64) -> Result<(), DiskErr> {
+        let refcount = self.refcount(host)?;
+        if refcount == 0 {
+            return Err(invalid("refcount of a cluster in use is 0"));
+        }
+
+        self.set_refcount(host, refcount - 1)?;
+
+        if refcount == 1 {
+            self.l2_cache.remove(&host);
+            self.free_hint = self.free_hint.min(host >> self.cluster_bits);
+        }
+
+        Ok(())
+    }
+
+    /// Finds a free host cluster and sets its refcount to 1. Returns its offset.
+    fn allocate_cluster(&mut self) -> Result<u64, DiskErr> {
+        let entries = self.refblock_entries();
+
+        loop {
+            let mut cluster = self.free_hint;
+            while self.refcount(cluster << self.cluster_bits)? != 0 {
+                cluster += 1;
+            }
+            self.free_hint = cluster;
+
+            let host = cluster << self.cluster_bits;
+            if host & !OFFSET_MASK != 0 {
+                return Err(invalid("no host offset left"));
+            }
+
+            let index = (cluster / entries) as usize;
+            if index >= self.refcount_table.len() {
+                self.grow_refcount_table(cluster)?;
+                continue;
+            }
+
+            // A missing refcount block is put in the free cluster found, and counts itself
+            if self.refblock(cluster)? == 0 {
+                let mut block = vec![0; self.cluster_size()];
+                set_refcount(
+                    &mut block,
+                    (cluster % entries) as usize,
+                    self.refcount_order,
+                    1,
+                );
+                self.write_file(host, &block)?;
+                self.refcount_cache.insert(host, block);
+
+                self.refcount_table[index] = host;
+                let entry = self.refcount_table_offset + index as u64 * 8;
+                self.write_file(entry, &host.to_be_bytes())?;
+                continue;
+            }
+
+            self.set_refcount(host, 1)?;
+            self.free_hint = cluster + 1;
+            return Ok(host);
+        }
+    }
+
+    /// Moves the refcount table to a larger one, covering the host cluster `first_free`. All the
+    /// clusters from `first_free` are free, as they have no refcount block: the new table is put
+    /// there, followed by the refcount blocks describing it.
+    fn grow_refcount_table(&mut self, first_free: u64) -> Result<(), DiskErr> {
+        let cluster_size = self.cluster_size() as u64;
+        let entries = self.refblock_entries();
+        let per_cluster = cluster_size / 8;
+        let old_len = self.refcount_table.len() as u64;
+
+        let mut table_len = (old_len * 2).max(old_len + 1).next_multiple_of(per_cluster);
+        let (table_clusters, blocks) = loop {
+            let table_clusters = table_len / per_cluster;
+
+            let mut blocks = 0;
+            loop {
+                let end = first_free + table_clusters + blocks;
+                let needed = (end - 1) / entries - first_free / entries + 1;
+                if needed == blocks {
+                    break;
+                }
+                blocks = needed;
+            }
+
+            let last = (first_free + table_clusters + blocks - 1) / entries;
+            if last < table_len {
+                break (table_clusters, blocks);
+            }
+            table_len += per_cluster;
+        };
+
+        if table_clusters * cluster_size > MAX_REFCOUNT_TABLE_SIZE {
+            return Err(invalid("refcount table too large"));
+        }
+
+        // ### NEW REFCOUNT BLOCKS AND TABLE ###
+
+        let blocks_start = first_free + table_clusters;
+        let end = blocks_start + blocks;
+        let mut table = self.refcount_table.clone();
+        table.resize(table_len as usize, 0);
+
+        for i in 0..blocks {
+            let index = first_free / entries + i;
+            let offset = (blocks_start + i) << self.cluster_bits;
+
+            let mut block = vec![0; cluster_size as usize];
+            for cluster in (index * entries).max(first_free)..((index + 1) * entries).min(end) {
+                let entry = (cluster - index * entries) as usize;
+                set_refcount(&mut block, entry, self.refcount_order, 1);
+            }
+
+            self.write_file(offset, &block)?;
+            self.refcount_cache.insert(offset, block);
+            table[index as usize] = offset;
+        }
+
+        let table_offset = first_free << self.cluster_bits;
+        let raw: Vec<u8> = table.iter().flat_map(|e| e.to_be_bytes()).collect();
+        self.write_file(table_offset, &raw)?;
+
+        // ### SWITCHES THE HEADER TO THE NEW TABLE, AND FREES THE OLD ONE ###
+
+        let mut field = [0; 12];
+        field[..8].copy_from_slice(&table_offset.to_be_bytes());
+        field[8..].copy_from_slice(&(table_clusters as u32).to_be_bytes());
+        self.file.sync_data().map_err(|e| DiskErr::from_io(&e))?;
+        self.write_file(REFCOUNT_TABLE_FIELD, &field)?;
+
+        let old_offset = self.refcount_table_offset;
+        self.refcount_table = table;
+        self.refcount_table_offset = table_offset;
+
+        for i in 0..(old_len / per_cluster) {
+            self.decrease_refcount(old_offset + i * cluster_size)?;
+        }
+
+        Ok(())
+    }
+}
+
+/// Refcount `index` of a refcount block. The refcounts narrower than a byte start at its least
+/// significant bits.
+fn get_refcount(block: &[u8], index: usize, order: u32) -> u64 {
+    match order {
+        0..3 => {
+            let bits = 1 << order;
+            let bit = index * bits;
+            ((block[bit / 8] >> (bit % 8)) & ((1 << bits) - 1) as u8) as u64
+        }
+        _ => {
+            let size = 1 << (order - 3);
+            block[(index * size)..((index + 1) * size)]
+                .iter()
+                .fold(0, |acc, &b| (acc << 8) | b as u64)
+        }
+    }
+}
+
+/// Sets the refcount `index` of a refcount block, and returns the range of bytes changed
+fn set_refcount(block: &mut [u8], index: usize, order: u32, value: u64) -> Range<usize> {
+    match order {
+        0..3 => {
+            let bits = 1 << order;
+            let bit = index * bits;
+            let mask = (((1u16 << bits) - 1) << (bit % 8)) as u8;
+            block[bit / 8] = (block[bit / 8] & !mask) | (((value as u8) << (bit % 8)) & mask);
+            (bit / 8)..(bit / 8 + 1)
+        }
+        _ => {
+            let size = 1 << (order - 3);
+            let range = (index * size)..((index + 1) * size);
+            block[range.clone()].copy_from_slice(&value.to_be_bytes()[(8 - size)..]);
+            range
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::std_helpers::TempPath;
+
+    #[test]
+    fn create_and_reopen() {
+        let temp = TempPath::new("create.qcow2");
+        let data: Vec<u8> = (0..(3 * 4096)).map(|i| (i % 253) as u8).collect();
+
+        let disk = Qcow2Disk::create(temp.path.clone(), 1 << 20, Some(4096)).unwrap();
+        assert!(Qcow2Disk::create(temp.path.clone(), 1 << 20, None).is_err());
+        disk.write_sectors(1, 512, &data[..4096]).unwrap();
+        disk.write_sectors(4, 4096, &data).unwrap();
+        disk.flush().unwrap();
+        drop(disk);
+
+        let disk = Qcow2Disk::open(temp.path.clone(), Permissions::read_only()).unwrap();
+        assert_eq!(disk.version(), 3);
+        assert_eq!(disk.cluster_size(), 4096);
+        assert_eq!(disk.virtual_size(), 1 << 20);
+        assert_eq!(disk.backing_file(), None);
+
+        let mut buf = vec![0; 3 * 4096];
+        disk.read_sectors(0, 512, &mut buf[..4608]).unwrap();
+        assert_eq!(buf[..512], [0; 512]);
+        assert_eq!(buf[512..4608], data[..4096]);
+        disk.read_sectors(4, 4096, &mut buf).unwrap();
+        assert_eq!(buf, data);
+        disk.read_sectors(255, 4096, &mut buf[..4096]).unwrap();
+        assert_eq!(buf[..4096], [0; 4096]);
+
+        assert!(disk.write_sectors(0, 512, &[0; 512]).is_err());
+        assert!(disk.read_sectors(256, 4096, &mut buf[..4096]).is_err());
+    }
+
+    #[test]
+    fn malformed_headers() {
+        let temp = TempPath::new("malformed.qcow2");
+        drop(Qcow2Disk::create(temp.path.clone(), 1 << 20, Some(4096)).unwrap());
+        let image = fs::read(&temp.path).unwrap();
+
+        let open = |image: &[u8]| {
+            fs::write(&temp.path, image).unwrap();
+            Qcow2Disk::open(temp.path.clone(), Permissions::read_only()).err()
+        };
+
+        assert!(open(&image).is_none());
+
+        let mut bad = image.clone();
+        bad[0] = b'q';
+        assert_eq!(
+            open(&bad),
+            Some(ImageErr::InvalidHeader {
+                reason: "not a qcow2 image"
+            })
+        );
+
+        assert_eq!(
+            open(&image[..80]),
+            Some(ImageErr::InvalidHeader {
+                reason: "truncated header"
+            })
+        );
+
+        let mut bad = image.clone();
+        bad[20..24].copy_from_slice(&30u32.to_be_bytes());
+        assert_eq!(
+            open(&bad),
+            Some(ImageErr::InvalidHeader {
+                reason: "invalid cluster size"
+            })
+        );
+
+        let mut bad = image.clone();
+        bad[4..8].copy_from_slice(&4u32.to_be_bytes());
+        assert!(matches!(open(&bad), Some(ImageErr::Unsupported { .. })));
+    }
+}
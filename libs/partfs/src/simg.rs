@@ -0,0 +1,539 @@
+use crate::{
+    DeviceId, Disk, DiskErr, DiskErrKind, DiskInfos, Geometry, ImageErr, Operation, Permissions,
+    SectorSize, bytedisk::ByteDisk, checksum::crc32_update, std_helpers::path_hash,
+};
+use mutex::Mutex;
+use std::{
+    fs::{self, File},
+    io::{self, Read, Seek, SeekFrom, Write},
+    path::PathBuf,
+};
+
+const MAGIC: u32 = 0xED26_FF3A;
+const MAJOR_VERSION: u16 = 1;
+
+const FILE_HEADER_SIZE: usize = 28;
+const CHUNK_HEADER_SIZE: usize = 12;
+
+const CHUNK_RAW: u16 = 0xCAC1;
+const CHUNK_FILL: u16 = 0xCAC2;
+const CHUNK_DONT_CARE: u16 = 0xCAC3;
+const CHUNK_CRC32: u16 = 0xCAC4;
+
+/// Block size of the exported images when none is given, the one of the Android tools
+pub const DEFAULT_BLOCK_SIZE: usize = 4096;
+
+/// Size of the reads on the exported disk, and of the checksum verification
+const IO_SIZE: usize = 1 << 20;
+
+fn le16(raw: &[u8], offset: usize) -> u16 {
+    u16::from_le_bytes(raw[offset..(offset + 2)].try_into().unwrap())
+}
+
+fn le32(raw: &[u8], offset: usize) -> u32 {
+    u32::from_le_bytes(raw[offset..(offset + 4)].try_into().unwrap())
+}
+
+/// A chunk of the image, covering `size` bytes of the disk from `start`
+struct Chunk {
+    start: u64,
+    size: u64,
+    kind: ChunkKind,
+}
+
+enum ChunkKind {
+    /// The data, at `offset` in the file
+    Raw { offset: u64 },
+    /// A 4-byte pattern, repeated
+    Fill([u8; 4]),
+    /// Left as is by the flashing tools, reads as zeroes
+    DontCare,
+}
+
+/// An Android sparse image, as produced by `img2simg` and flashed by fastboot. Read only.
+pub struct SimgDisk {
+    size: u64,
+    block_size: usize,
+    chunks: Vec<Chunk>,
+    /// CRC32 of the disk up to a position, from the CRC chunks and the file header
+    checksums: Vec<(u64, u32)>,
+    device_id: Option<DeviceId>,
+    file: Mutex<File>,
+}
+
+impl Disk for SimgDisk {
+    fn read_sector(&self, sector: u64, buf: &mut [u8]) -> Result<(), DiskErr> {
+        self.read_sectors(sector, buf.len(), buf)
+    }
+
+    fn write_sector(&self, sector: u64, buf: &[u8]) -> Result<(), DiskErr> {
+        self.write_sectors(sector, buf.len(), buf)
+    }
+
+    fn disk_infos(&self) -> Result<DiskInfos, DiskErr> {
+        Ok(DiskInfos {
+            sector_size: SectorSize::Any,
+            disk_size: self.size,
+            permissions: Permissions::read_only(),
+            geometry: Geometry {
+                optimal_io_size: self.block_size,
+                ..Geometry::from_sector_size(&SectorSize::Any, self.size)
+            },
+            device_id: self.device_id.clone(),
+        })
+    }
+
+    fn read_sectors(&self, sector: u64, sector_size: usize, buf: &mut [u8]) -> Result<(), DiskErr> {
+        self.offset(sector, sector_size, buf.len() as u64)
+            .and_then(|offset| self.read_at(offset, buf))
+            .map_err(|e| e.at(Operation::Read, sector).in_layer("SimgDisk"))
+    }
+
+    fn write_sectors(&self, sector: u64, _: usize, _: &[u8]) -> Result<(), DiskErr> {
+        Err(DiskErr::new(DiskErrKind::InvalidPermission {
+            disk_permissions: Permissions::read_only(),
+        })
+        .at(Operation::Write, sector)
+        .in_layer("SimgDisk"))
+    }
+}
+
+impl SimgDisk {
+    /// Opens an existing image, and reads the headers of all its chunks
+    pub fn open(path: PathBuf) -> Result<Self, ImageErr> {
+        let io = |e: io::Error| ImageErr::Disk(DiskErr::from_io(&e).in_layer("SimgDisk"));
+        let invalid_header = |reason| ImageErr::InvalidHeader { reason };
+
+        let mut file = File::open(&path).map_err(io)?;
+        let file_size = file.seek(SeekFrom::End(0)).map_err(io)?;
+        file.seek(SeekFrom::Start(0)).map_err(io)?;
+
+        let mut header = [0; FILE_HEADER_SIZE];
+        file.read_exact(&mut header)
+            .map_err(|_| invalid_header("not a sparse image"))?;
+
+        if le32(&header, 0) != MAGIC {
+            return Err(invalid_header("not a sparse image"));
+        }
+        if le16(&header, 4) != MAJOR_VERSION {
+            return Err(ImageErr::Unsupported {
+                feature: format!("sparse image version {}", le16(&header, 4)),
+            });
+        }
+
+        let file_header_size = le16(&header, 8) as u64;
+        let chunk_header_size = le16(&header, 10) as u64;
+        let block_size = le32(&header, 12) as u64;
+        let total_blocks = le32(&header, 16) as u64;
+        let total_chunks = le32(&header, 20);
+        let image_checksum = le32(&header, 24);
+
+        if file_header_size < FILE_HEADER_SIZE as u64
+            || chunk_header_size < CHUNK_HEADER_SIZE as u64
+        {
+            return Err(invalid_header("invalid header size"));
+        }
+        if block_size == 0 || !block_size.is_multiple_of(4) {
+            return Err(invalid_header("invalid block size"));
+        }
+
+        // ### CHUNKS ###
+
+        let mut chunks = Vec::new();
+        let mut checksums = Vec::new();
+        let mut position = file_header_size;
+        let mut start = 0;
+
+        for _ in 0..total_chunks {
+            let mut chunk = [0; CHUNK_HEADER_SIZE];
+            file.seek(SeekFrom::Start(position))
+                .and_then(|_| file.read_exact(&mut chunk))
+                .map_err(|_| invalid_header("truncated image"))?;
+
+            let blocks = le32(&chunk, 4) as u64;
+            let total_size = le32(&chunk, 8) as u64;
+            let data_offset = position + chunk_header_size;
+            let data_size = total_size
+                .checked_sub(chunk_header_size)
+                .ok_or(invalid_header("invalid chunk size"))?;
+
+            let mut value = [0; 4];
+            if data_size == 4 {
+                file.seek(SeekFrom::Start(data_offset))
+                    .and_then(|_| file.read_exact(&mut value))
+                    .map_err(|_| invalid_header("truncated image"))?;
+            }
+
+            let size = blocks * block_size;
+            let kind = match (le16(&chunk, 0), data_size) {
+                (CHUNK_RAW, _) if data_size == size => Some(ChunkKind::Raw {
+                    offset: data_offset,
+                }),
+                (CHUNK_FILL, 4) => Some(ChunkKind::Fill(value)),
+                (CHUNK_DONT_CARE, 0) => Some(ChunkKind::DontCare),
+                (CHUNK_CRC32, 4) if size == 0 => {
+                    checksums.push((start, u32::from_le_bytes(value)));
+                    None
+                }
+                (CHUNK_RAW | CHUNK_FILL | CHUNK_DONT_CARE | CHUNK_CRC32, _) => {
+                    return Err(invalid_header("invalid chunk size"));
+                }
+                _ => return Err(invalid_header("invalid chunk type")),
+            };
+
+            if let Some(kind) = kind
+                && size != 0
+            {
+                chunks.push(Chunk { start, size, kind });
+            }
+
+            start += size;
+            position += total_size;
+            if position > file_size {
+                return Err(invalid_header("truncated image"));
+            }
+        }
+
+        if start != total_blocks * block_size {
+            return Err(invalid_header("chunks don't cover the image"));
+        }
+        if image_checksum != 0 {
+            checksums.push((start, image_checksum));
+        }
+
+        let device_id = fs::canonicalize(&path)
+            .ok()
+            .map(|p| DeviceId::Image(path_hash(&p)));
+
+        Ok(Self {
+            size: start,
+            block_size: block_size as usize,
+            chunks,
+            checksums,
+            device_id,
+            file: Mutex::new(file),
+        })
+    }
+
+    pub const fn block_size(&self) -> usize {
+        self.block_size
+    }
+
+    /// Reads the whole image, and checks it against its CRC chunks and the checksum of its header
+    /// if there are some. On a mismatch, the reported LBA is the first block of the checked range.
+    pub fn verify(&self) -> Result<(), DiskErr> {
+        let mut buf = vec![0; IO_SIZE];
+        let mut crc = 0;
+        let mut position = 0;
+
+        for &(end, expected) in &self.checksums {
+            let first_block = position / self.block_size as u64;
+
+            while position < end {
+                let len = ((end - position) as usize).min(IO_SIZE);
+                self.read_at(position, &mut buf[..len])
+                    .map_err(|e| e.at(Operation::Read, position).in_layer("SimgDisk"))?;
+
+                crc = crc32_update(crc, &buf[..len]);
+                position += len as u64;
+            }
+
+            if crc != expected {
+                return Err(
+                    DiskErr::new(DiskErrKind::ChecksumMismatch { lba: first_block })
+                        .in_layer("SimgDisk"),
+                );
+            }
+        }
+
+        Ok(())
+    }
+
+    fn read_at(&self, offset: u64, buf: &mut [u8]) -> Result<(), DiskErr> {
+        let end = offset + buf.len() as u64;
+        let first = self.chunks.partition_point(|c| c.start + c.size <= offset);
+
+        for chunk in self.chunks[first..].iter().take_while(|c| c.start < end) {
+            let from = offset.max(chunk.start);
+            let to = end.min(chunk.start + chunk.size);
+            let part = &mut buf[((from - offset) as usize)..((to - offset) as usize)];
+
+            match chunk.kind {
+                ChunkKind::Raw { offset } => {
+                    let mut file = self.file.lock();
+                    file.seek(SeekFrom::Start(offset + from - chunk.start))
+                        .and_then(|_| file.read_exact(part))
+                        .map_err(|e| DiskErr::from_io(&e))?;
+                }
+                ChunkKind::Fill(pattern) => {
+                    // The pattern is aligned on the blocks, which are multiples of 4 bytes
+                    for (i, b) in part.iter_mut().enumerate() {
+                        *b = pattern[(from - chunk.start + i as u64) as usize % 4];
+                    }
+                }
+                ChunkKind::DontCare => part.fill(0),
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Checks a request of `len` bytes starting at the LBA `sector` and returns its offset in the
+    /// virtual disk.
+    fn offset(&self, sector: u64, sector_size: usize, len: u64) -> Result<u64, DiskErr> {
+        if sector_size == 0
+            || sector_size as u64 > self.size
+            || !len.is_multiple_of(sector_size as u64)
+        {
+            return Err(DiskErrKind::InvalidSectorSize {
+                found: sector_size,
+                supported: SectorSize::Any,
+                start: 0,
+            }
+            .into());
+        }
+
+        match sector.checked_mul(sector_size as u64) {
+            Some(offset) if offset.checked_add(len).is_some_and(|end| end <= self.size) => {
+                Ok(offset)
+            }
+            _ => Err(DiskErrKind::InvalidSectorIndex {
+                found: sector,
+                max: (self.size / sector_size as u64).saturating_sub(1),
+            }
+            .into()),
+        }
+    }
+}
+
+// ### EXPORT ###
+
+/// A run of blocks of the same kind in the exported image
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum Run {
+    Raw,
+    Fill([u8; 4]),
+    DontCare,
+}
+
+/// Writes the content of `disk` to `out` as a sparse image, with blocks of `block_size` bytes (a
+/// multiple of 4, [`DEFAULT_BLOCK_SIZE`] if `None`) dividing the disk size. The all-zero blocks
+/// are left out as don't-care chunks, and the blocks repeating a 4-byte pattern become fill
+/// chunks. The disk is read twice.
+pub fn export<D: Disk, W: Write>(
+    disk: D,
+    mut out: W,
+    block_size: Option<usize>,
+) -> Result<(), ImageErr> {
+    let io = |e: io::Error| ImageErr::Disk(DiskErr::from_io(&e).in_layer("SimgDisk"));
+
+    let block_size = block_size.unwrap_or(DEFAULT_BLOCK_SIZE);
+    let disk_size = disk.disk_infos()?.disk_size;
+    let disk = ByteDisk::new(disk)?;
+
+    if block_size == 0
+        || !block_size.is_multiple_of(4)
+        || !disk_size.is_multiple_of(block_size as u64)
+        || disk.size()? != disk_size
+    {
+        return Err(ImageErr::Unsupported {
+            feature: format!("{block_size}-byte blocks on a {disk_size}-byte disk"),
+        });
+    }
+
+    let total_blocks =
+        u32::try_from(disk_size / block_size as u64).map_err(|_| ImageErr::Unsupported {
+            feature: format!("{block_size}-byte blocks on a {disk_size}-byte disk"),
+        })?;
+
+    // The sizes of the chunks, headers included, are stored on 32 bits
+    let max_raw_blocks = (u32::MAX as usize - CHUNK_HEADER_SIZE) / block_size;
+    let batch_blocks = (IO_SIZE / block_size).max(1);
+
+    // ### CLASSIFICATION ###
+
+    let mut runs: Vec<(Run, u32)> = Vec::new();
+    let mut buf = vec![0; batch_blocks * block_size];
+    let mut crc = 0;
+    let mut block = 0;
+
+    while block < total_blocks {
+        let count = (batch_blocks as u32).min(total_blocks - block);
+        let batch = &mut buf[..(count as usize * block_size)];
+        disk.read_at(block as u64 * block_size as u64, batch)?;
+        crc = crc32_update(crc, batch);
+
+        for data in batch.chunks(block_size) {
+            let pattern: [u8; 4] = data[..4].try_into().unwrap();
+            let run = match data.chunks(4).all(|word| word == pattern) {
+                true if pattern == [0; 4] => Run::DontCare,
+                true => Run::Fill(pattern),
+                false => Run::Raw,
+            };
+
+            match runs.last_mut() {
+                Some((last, len))
+                    if *last == run && (run != Run::Raw || (*len as usize) < max_raw_blocks) =>
+                {
+                    *len += 1
+                }
+                _ => runs.push((run, 1)),
+            }
+        }
+
+        block += count;
+    }
+
+    // ### OUTPUT ###
+
+    let mut header = [0; FILE_HEADER_SIZE];
+    header[..4].copy_from_slice(&MAGIC.to_le_bytes());
+    header[4..6].copy_from_slice(&MAJOR_VERSION.to_le_bytes());
+    header[8..10].copy_from_slice(&(FILE_HEADER_SIZE as u16).to_le_bytes());
+    header[10..12].copy_from_slice(&(CHUNK_HEADER_SIZE as u16).to_le_bytes());
+    header[12..16].copy_from_slice(&(block_size as u32).to_le_bytes());
+    header[16..20].copy_from_slice(&total_blocks.to_le_bytes());
+    header[20..24].copy_from_slice(&(runs.len() as u32).to_le_bytes());
+    header[24..28].copy_from_slice(&crc.to_le_bytes());
+    out.write_all(&header).map_err(io)?;
+
+    let mut block = 0u64;
+    for (run, blocks) in runs {
+        let (chunk_type, data_size) = match run {
+            Run::Raw => (CHUNK_RAW, blocks as usize * block_size),
+            Run::Fill(_) => (CHUNK_FILL, 4),
+            Run::DontCare => (CHUNK_DONT_CARE, 0),
+        };
+
+        let mut chunk = [0; CHUNK_HEADER_SIZE];
+        chunk[..2].copy_from_slice(&chunk_type.to_le_bytes());
+        chunk[4..8].copy_from_slice(&blocks.to_le_bytes());
+        chunk[8..12].copy_from_slice(&((CHUNK_HEADER_SIZE + data_size) as u32).to_le_bytes());
+        out.write_all(&chunk).map_err(io)?;
+
+        match run {
+            Run::Raw => {
+                let end = block + blocks as u64;
+                let mut position = block;
+
+                while position < end {
+                    let count = (batch_blocks as u64).min(end - position);
+                    let batch = &mut buf[..(count as usize * block_size)];
+                    disk.read_at(position * block_size as u64, batch)?;
+                    out.write_all(batch).map_err(io)?;
+                    position += count;
+                }
+            }
+            Run::Fill(pattern) => out.write_all(&pattern).map_err(io)?,
+            Run::DontCare => {}
+        }
+
+        block += blocks as u64;
+    }
+
+    out.flush().map_err(io)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{memdisk::MemDisk, std_helpers::TempPath};
+
+    /// Raw blocks, a fill block, and zeroes
+    fn content() -> Vec<u8> {
+        let mut content = vec![0; 64 << 10];
+        for (i, b) in content[..10000].iter_mut().enumerate() {
+            *b = (i % 253) as u8;
+        }
+        for b in content[(16 << 10)..(24 << 10)].chunks_mut(4) {
+            b.copy_from_slice(&[1, 2, 3, 4]);
+        }
+        content
+    }
+
+    fn export_to_vec(content: Vec<u8>, block_size: Option<usize>) -> Vec<u8> {
+        let disk = MemDisk::from_vec(content, SectorSize::Any, Permissions::read_only());
+        let mut image = Vec::new();
+        export(disk, &mut image, block_size).unwrap();
+        image
+    }
+
+    #[test]
+    fn export_and_open() {
+        let temp = TempPath::new("export.simg");
+        let content = content();
+
+        for block_size in [None, Some(1024)] {
+            let image = export_to_vec(content.clone(), block_size);
+            assert!(image.len() < content.len() / 2);
+            fs::write(&temp.path, &image).unwrap();
+
+            let disk = SimgDisk::open(temp.path.clone()).unwrap();
+            assert_eq!(disk.block_size(), block_size.unwrap_or(DEFAULT_BLOCK_SIZE));
+            assert_eq!(disk.disk_infos().unwrap().disk_size, content.len() as u64);
+            disk.verify().unwrap();
+
+            let mut buf = vec![0; content.len()];
+            disk.read_sectors(0, 512, &mut buf).unwrap();
+            assert_eq!(buf, content);
+            disk.read_sectors(3, 4096, &mut buf[..8192]).unwrap();
+            assert_eq!(buf[..8192], content[(12 << 10)..(20 << 10)]);
+
+            assert!(disk.write_sectors(0, 512, &[0; 512]).is_err());
+            assert!(disk.read_sectors(16, 4096, &mut buf[..4096]).is_err());
+        }
+    }
+
+    #[test]
+    fn malformed_headers() {
+        let temp = TempPath::new("malformed.simg");
+        let open = |image: &[u8]| {
+            fs::write(&temp.path, image).unwrap();
+            SimgDisk::open(temp.path.clone()).err()
+        };
+        let invalid = |reason| Some(ImageErr::InvalidHeader { reason });
+
+        let image = export_to_vec(content(), None);
+        let first_chunk = FILE_HEADER_SIZE;
+        assert_eq!(open(&image), None);
+
+        let mut bad = image.clone();
+        bad[0] = 0;
+        assert_eq!(open(&bad), invalid("not a sparse image"));
+        assert_eq!(open(&image[..20]), invalid("not a sparse image"));
+        assert_eq!(
+            open(&image[..(image.len() - 1)]),
+            invalid("truncated image")
+        );
+
+        let mut bad = image.clone();
+        bad[4] = 2;
+        assert!(matches!(open(&bad), Some(ImageErr::Unsupported { .. })));
+
+        let mut bad = image.clone();
+        bad[12..16].copy_from_slice(&4097u32.to_le_bytes());
+        assert_eq!(open(&bad), invalid("invalid block size"));
+
+        let mut bad = image.clone();
+        bad[16] += 1;
+        assert_eq!(open(&bad), invalid("chunks don't cover the image"));
+
+        let mut bad = image.clone();
+        bad[first_chunk..(first_chunk + 2)].copy_from_slice(&0xCAC5u16.to_le_bytes());
+        assert_eq!(open(&bad), invalid("invalid chunk type"));
+
+        let mut bad = image.clone();
+        bad[first_chunk + 8] += 1;
+        assert_eq!(open(&bad), invalid("invalid chunk size"));
+
+        // The data of the first raw chunk
+        let mut bad = image.clone();
+        bad[first_chunk + CHUNK_HEADER_SIZE + 100] ^= 1;
+        assert_eq!(open(&bad), None);
+        let err = SimgDisk::open(temp.path.clone())
+            .unwrap()
+            .verify()
+            .unwrap_err();
+        assert_eq!(err.kind, DiskErrKind::ChecksumMismatch { lba: 0 });
+    }
+}
@@ -0,0 +1,533 @@
+use crate::{Disk, DiskErr, DiskInfos};
+use alloc::{boxed::Box, collections::BTreeMap};
+use core::time::Duration;
+use mutex::Mutex;
+
+// ### STATISTICS ###
+
+/// Counters of the requests to a `StatsDisk`
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct DiskStats {
+    pub reads: u64,
+    pub writes: u64,
+    pub discards: u64,
+    pub flushes: u64,
+    /// Bytes of the successful reads
+    pub bytes_read: u64,
+    /// Bytes of the successful writes
+    pub bytes_written: u64,
+    /// Bytes of the successful discards
+    pub bytes_discarded: u64,
+    /// Count of reads and writes, by sector size
+    pub sector_sizes: BTreeMap<usize, u64>,
+    /// Reads and writes starting where the previous one ended
+    pub sequential: u64,
+    /// Reads and writes starting anywhere else, including the first one
+    pub random: u64,
+    /// Count of failed requests, by name of the `DiskErrKind` variant
+    pub errors: BTreeMap<&'static str, u64>,
+}
+
+/// A wrapper counting the requests to a `Disk`, to study the access patterns of the code using it
+pub struct StatsDisk<D: Disk> {
+    disk: D,
+    state: Mutex<StatsState>,
+}
+
+#[derive(Default)]
+struct StatsState {
+    stats: DiskStats,
+    /// Offset in bytes of the end of the last read or write
+    position: Option<u64>,
+}
+
+impl<D: Disk> StatsDisk<D> {
+    pub fn new(disk: D) -> Self {
+        Self {
+            disk,
+            state: Mutex::new(StatsState::default()),
+        }
+    }
+
+    /// A copy of the counters
+    pub fn stats(&self) -> DiskStats {
+        self.state.lock().stats.clone()
+    }
+
+    pub fn reset_stats(&self) {
+        *self.state.lock() = StatsState::default();
+    }
+
+    pub const fn inner(&self) -> &D {
+        &self.disk
+    }
+
+    pub fn into_inner(self) -> D {
+        self.disk
+    }
+
+    /// Counts a read or a write of `len` bytes at the LBA `sector`
+    fn count_access(&self, sector: u64, sector_size: usize, len: usize) {
+        let mut state = self.state.lock();
+        let start = sector.saturating_mul(sector_size as u64);
+
+        if state.position == Some(start) {
+            state.stats.sequential += 1;
+        } else {
+            state.stats.random += 1;
+        }
+
+        state.position = Some(start.saturating_add(len as u64));
+        *state.stats.sector_sizes.entry(sector_size).or_insert(0) += 1;
+    }
+
+    /// Counts the error of a failed request
+    fn count_result<T>(&self, result: &Result<T, DiskErr>) {
+        if let Err(e) = result {
+            *self
+                .state
+                .lock()
+                .stats
+                .errors
+                .entry(e.kind.name())
+                .or_insert(0) += 1;
+        }
+    }
+}
+
+impl<D: Disk> Disk for StatsDisk<D> {
+    fn read_sector(&self, sector: u64, buf: &mut [u8]) -> Result<(), DiskErr> {
+        self.read_sectors(sector, buf.len(), buf)
+    }
+
+    fn write_sector(&self, sector: u64, buf: &[u8]) -> Result<(), DiskErr> {
+        self.write_sectors(sector, buf.len(), buf)
+    }
+
+    fn disk_infos(&self) -> Result<DiskInfos, DiskErr> {
+        let result = self.disk.disk_infos();
+        self.count_result(&result);
+        result
+    }
+
+    fn read_sectors(&self, sector: u64, sector_size: usize, buf: &mut [u8]) -> Result<(), DiskErr> {
+        self.state.lock().stats.reads += 1;
+        self.count_access(sector, sector_size, buf.len());
+
+        let result = self.disk.read_sectors(sector, sector_size, buf);
+        if result.is_ok() {
+            self.state.lock().stats.bytes_read += buf.len() as u64;
+        }
+
+        self.count_result(&result);
+        result
+    }
+
+    fn write_sectors(&self, sector: u64, sector_size: usize, buf: &[u8]) -> Result<(), DiskErr> {
+        self.state.lock().stats.writes += 1;
+        self.count_access(sector, sector_size, buf.len());
+
+        let result = self.disk.write_sectors(sector, sector_size, buf);
+        if result.is_ok() {
+            self.state.lock().stats.bytes_written += buf.len() as u64;
+        }
+
+        self.count_result(&result);
+        result
+    }
+
+    fn discard(&self, sector: u64, sector_size: usize, count: u64) -> Result<(), DiskErr> {
+        self.state.lock().stats.discards += 1;
+
+        let result = self.disk.discard(sector, sector_size, count);
+        if result.is_ok() {
+            self.state.lock().stats.bytes_discarded += count.saturating_mul(sector_size as u64);
+        }
+
+        self.count_result(&result);
+        result
+    }
+
+    fn flush(&self) -> Result<(), DiskErr> {
+        self.state.lock().stats.flushes += 1;
+
+        let result = self.disk.flush();
+        self.count_result(&result);
+        result
+    }
+}
+
+// ### LATENCY SIMULATION ###
+
+/// The time taken by the requests to a simulated device
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct LatencyModel {
+    /// Fixed cost of each read, write or discard (command overhead)
+    pub per_request: Duration,
+    /// Transfer rate of the reads and writes, infinite if 0
+    pub bytes_per_second: u64,
+    /// Cost of a flush
+    pub flush: Duration,
+    /// Head movements of a rotational device, `None` for solid state devices
+    pub seek: Option<SeekModel>,
+}
+
+/// Seek time of a rotational device. A request not starting where the head is costs the move of
+/// the head to its track, growing linearly with the count of tracks crossed, plus on average half
+/// a rotation.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SeekModel {
+    /// Bytes under the heads without moving them (a cylinder)
+    pub track_size: u64,
+    /// Count of tracks (cylinders) of the device
+    pub tracks: u64,
+    /// Move of the head to the next track
+    pub track_to_track: Duration,
+    /// Move of the head from the first track to the last one
+    pub full_stroke: Duration,
+    /// Average wait for the sector to come under the head, half a rotation
+    pub rotational_latency: Duration,
+}
+
+impl LatencyModel {
+    /// A 3.5" 1.44 MB floppy drive: 80 cylinders of 2 × 18 sectors, 300 rpm, 500 kbit/s
+    pub const fn floppy() -> Self {
+        Self {
+            per_request: Duration::from_micros(500),
+            bytes_per_second: 62_500,
+            flush: Duration::ZERO,
+            seek: Some(SeekModel {
+                track_size: 2 * 18 * 512,
+                tracks: 80,
+                track_to_track: Duration::from_millis(3),
+                full_stroke: Duration::from_millis(240),
+                rotational_latency: Duration::from_millis(100),
+            }),
+        }
+    }
+
+    /// A 7200 rpm hard disk drive
+    pub const fn hdd() -> Self {
+        Self {
+            per_request: Duration::from_micros(50),
+            bytes_per_second: 150_000_000,
+            flush: Duration::from_millis(10),
+            seek: Some(SeekModel {
+                track_size: 1 << 20,
+                tracks: 1 << 20,
+                track_to_track: Duration::from_millis(1),
+                full_stroke: Duration::from_millis(18),
+                rotational_latency: Duration::from_micros(4170),
+            }),
+        }
+    }
+
+    /// A SATA solid state drive
+    pub const fn ssd() -> Self {
+        Self {
+            per_request: Duration::from_micros(60),
+            bytes_per_second: 500_000_000,
+            flush: Duration::from_micros(500),
+            seek: None,
+        }
+    }
+
+    /// The time taken by a request of `len` bytes at the offset `start`, the head being at the
+    /// offset `position`
+    pub fn request_time(&self, position: Option<u64>, start: u64, len: u64) -> Duration {
+        let mut time = self.per_request;
+
+        if self.bytes_per_second != 0 {
+            let nanos = len as u128 * 1_000_000_000 / self.bytes_per_second as u128;
+            time += Duration::from_nanos(nanos.min(u64::MAX as u128) as u64);
+        }
+
+        if let Some(seek) = &self.seek
+            && position != Some(start)
+        {
+            time += seek.seek_time(position.unwrap_or(0), start);
+        }
+
+        time
+    }
+}
+
+impl SeekModel {
+    /// The time taken to move the head from the offset `from` to the offset `to`, and to wait for
+    /// the sector
+    pub fn seek_time(&self, from: u64, to: u64) -> Duration {
+        let track_size = self.track_size.max(1);
+        let distance = (from / track_size).abs_diff(to / track_size);
+
+        if distance == 0 {
+            return self.rotational_latency;
+        }
+
+        // Linear between a track to track move (distance 1) and a full stroke move (distance
+        // `tracks - 1`)
+        let max_distance = self.tracks.saturating_sub(1);
+        let extra = self.full_stroke.saturating_sub(self.track_to_track);
+        let extra_nanos = if max_distance > 1 {
+            extra.as_nanos() * (distance - 1).min(max_distance - 1) as u128
+                / (max_distance - 1) as u128
+        } else {
+            0
+        };
+
+        self.track_to_track
+            + Duration::from_nanos(extra_nanos.min(u64::MAX as u128) as u64)
+            + self.rotational_latency
+    }
+}
+
+/// A wrapper delaying the requests to a `Disk` like a slower device would. The delays are added
+/// to a simulated clock and passed to a sleep function, which can do nothing to get the
+/// simulated time without waiting.
+pub struct LatencyDisk<D: Disk> {
+    disk: D,
+    model: LatencyModel,
+    sleep: Box<dyn Fn(Duration) + Send + Sync>,
+    state: Mutex<LatencyState>,
+}
+
+#[derive(Default)]
+struct LatencyState {
+    /// Offset in bytes of the head, the end of the last read or write
+    position: Option<u64>,
+    /// Sum of the delays
+    elapsed: Duration,
+}
+
+impl<D: Disk> LatencyDisk<D> {
+    /// Delays the requests to `disk` following `model`, calling `sleep` with each delay
+    pub fn new(
+        disk: D,
+        model: LatencyModel,
+        sleep: impl Fn(Duration) + Send + Sync + 'static,
+    ) -> Self {
+        Self {
+            disk,
+            model,
+            sleep: Box::new(sleep),
+            state: Mutex::new(LatencyState::default()),
+        }
+    }
+
+    /// Delays the requests to `disk` following `model`, putting the thread to sleep
+    #[cfg(feature = "std")]
+    pub fn with_thread_sleep(disk: D, model: LatencyModel) -> Self {
+        Self::new(disk, model, std::thread::sleep)
+    }
+
+    /// Only computes the delays of the requests to `disk`, see `elapsed`
+    pub fn simulated(disk: D, model: LatencyModel) -> Self {
+        Self::new(disk, model, |_| ())
+    }
+
+    pub const fn model(&self) -> &LatencyModel {
+        &self.model
+    }
+
+    /// The sum of the delays of the requests so far
+    pub fn elapsed(&self) -> Duration {
+        self.state.lock().elapsed
+    }
+
+    /// Resets the simulated clock. The head stays where it is.
+    pub fn reset_elapsed(&self) {
+        self.state.lock().elapsed = Duration::ZERO;
+    }
+
+    pub const fn inner(&self) -> &D {
+        &self.disk
+    }
+
+    pub fn into_inner(self) -> D {
+        self.disk
+    }
+
+    /// Moves the head to the end of a request of `len` bytes at the LBA `sector`, and waits for
+    /// the time it takes
+    fn delay_access(&self, sector: u64, sector_size: usize, len: u64) {
+        let start = sector.saturating_mul(sector_size as u64);
+        let delay = {
+            let mut state = self.state.lock();
+            let delay = self.model.request_time(state.position, start, len);
+            state.position = Some(start.saturating_add(len));
+            state.elapsed += delay;
+            delay
+        };
+
+        (self.sleep)(delay);
+    }
+}
+
+impl<D: Disk> Disk for LatencyDisk<D> {
+    fn read_sector(&self, sector: u64, buf: &mut [u8]) -> Result<(), DiskErr> {
+        self.read_sectors(sector, buf.len(), buf)
+    }
+
+    fn write_sector(&self, sector: u64, buf: &[u8]) -> Result<(), DiskErr> {
+        self.write_sectors(sector, buf.len(), buf)
+    }
+
+    fn disk_infos(&self) -> Result<DiskInfos, DiskErr> {
+        let mut infos = self.disk.disk_infos()?;
+        infos.geometry.rotational = self.model.seek.is_some();
+        Ok(infos)
+    }
+
+    fn read_sectors(&self, sector: u64, sector_size: usize, buf: &mut [u8]) -> Result<(), DiskErr> {
+        self.delay_access(sector, sector_size, buf.len() as u64);
+        self.disk.read_sectors(sector, sector_size, buf)
+    }
+
+    fn write_sectors(&self, sector: u64, sector_size: usize, buf: &[u8]) -> Result<(), DiskErr> {
+        self.delay_access(sector, sector_size, buf.len() as u64);
+        self.disk.write_sectors(sector, sector_size, buf)
+    }
+
+    /// Discards only cost the fixed cost of a request, the head doesn't move
+    fn discard(&self, sector: u64, sector_size: usize, count: u64) -> Result<(), DiskErr> {
+        let delay = self.model.per_request;
+        self.state.lock().elapsed += delay;
+        (self.sleep)(delay);
+
+        self.disk.discard(sector, sector_size, count)
+    }
+
+    fn flush(&self) -> Result<(), DiskErr> {
+        let delay = self.model.flush;
+        self.state.lock().elapsed += delay;
+        (self.sleep)(delay);
+
+        self.disk.flush()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{DiskErrKind, Permissions, SectorSize, memdisk::MemDisk};
+    use alloc::{sync::Arc, vec, vec::Vec};
+
+    fn mem_disk() -> MemDisk {
+        MemDisk::new(
+            64 * 4096,
+            SectorSize::AllOf(vec![512, 4096]),
+            Permissions::read_write(),
+        )
+    }
+
+    #[test]
+    fn counters() {
+        let disk = StatsDisk::new(mem_disk());
+
+        disk.write_sectors(0, 512, &[1; 1024]).unwrap();
+        disk.read_sectors(2, 512, &mut [0; 512]).unwrap();
+        disk.read_sectors(1, 4096, &mut [0; 4096]).unwrap();
+        disk.write_sectors(2, 4096, &[2; 4096]).unwrap();
+        disk.discard(4, 4096, 2).unwrap();
+        disk.flush().unwrap();
+        let err = disk.read_sectors(64, 4096, &mut [0; 4096]).unwrap_err();
+        assert!(matches!(err.kind, DiskErrKind::InvalidSectorIndex { .. }));
+        disk.read_sectors(0, 1000, &mut [0; 1000]).unwrap_err();
+
+        let stats = disk.stats();
+        assert_eq!(
+            stats,
+            DiskStats {
+                reads: 4,
+                writes: 2,
+                discards: 1,
+                flushes: 1,
+                bytes_read: 512 + 4096,
+                bytes_written: 1024 + 4096,
+                bytes_discarded: 2 * 4096,
+                sector_sizes: [(512, 2), (1000, 1), (4096, 3)].into_iter().collect(),
+                // The second read and the second write start where the previous access ended
+                sequential: 2,
+                random: 4,
+                errors: [("InvalidSectorIndex", 1), ("InvalidSectorSize", 1)]
+                    .into_iter()
+                    .collect(),
+            }
+        );
+
+        disk.reset_stats();
+        disk.read_sectors(0, 512, &mut [0; 512]).unwrap();
+        assert_eq!(disk.stats().random, 1);
+        assert_eq!(disk.stats().reads, 1);
+    }
+
+    #[test]
+    fn seek_times() {
+        let model = LatencyModel::floppy();
+        let seek = model.seek.unwrap();
+        let track = seek.track_size;
+
+        // Same track, next track, and from the first track to the last one in both directions
+        assert_eq!(seek.seek_time(0, track - 1), Duration::from_millis(100));
+        assert_eq!(seek.seek_time(track, 2 * track), Duration::from_millis(103));
+        assert_eq!(seek.seek_time(0, 79 * track), Duration::from_millis(340));
+        assert_eq!(
+            seek.seek_time(80 * track - 1, 0),
+            Duration::from_millis(340)
+        );
+        // Beyond the last track (a larger disk image): still a full stroke
+        assert_eq!(seek.seek_time(0, 1000 * track), Duration::from_millis(340));
+        // Halfway between the two
+        let half = seek.seek_time(0, 40 * track);
+        assert!(half > Duration::from_millis(220) && half < Duration::from_millis(225));
+
+        // Two tracks: every move is a track to track one
+        let two_tracks = SeekModel { tracks: 2, ..seek };
+        assert_eq!(
+            two_tracks.seek_time(0, 5 * track),
+            Duration::from_millis(103)
+        );
+    }
+
+    #[test]
+    fn latencies() {
+        let delays = Arc::new(Mutex::new(Vec::new()));
+        let recorded = delays.clone();
+        let model = LatencyModel::floppy();
+        let disk = LatencyDisk::new(mem_disk(), model, move |d| recorded.lock().push(d));
+        let track = model.seek.unwrap().track_size;
+
+        // 512 bytes at 62.5 kB/s: 8.192 ms
+        let transfer = Duration::from_micros(8192);
+        let request = model.per_request + transfer;
+
+        disk.read_sectors(0, 512, &mut [0; 512]).unwrap();
+        disk.read_sectors(1, 512, &mut [0; 512]).unwrap();
+        disk.write_sectors(track / 512, 512, &[0; 512]).unwrap();
+        disk.discard(0, 512, 8).unwrap();
+        disk.flush().unwrap();
+
+        let expected = [
+            // The head starts on the first track
+            request + Duration::from_millis(100),
+            request,
+            request + Duration::from_millis(103),
+            model.per_request,
+            Duration::ZERO,
+        ];
+        assert_eq!(*delays.lock(), expected);
+        assert_eq!(disk.elapsed(), expected.iter().sum());
+        assert!(disk.disk_infos().unwrap().geometry.rotational);
+
+        disk.reset_elapsed();
+        assert_eq!(disk.elapsed(), Duration::ZERO);
+
+        let ssd = LatencyDisk::simulated(mem_disk(), LatencyModel::ssd());
+        ssd.read_sectors(0, 4096, &mut [0; 4096]).unwrap();
+        ssd.read_sectors(40, 4096, &mut [0; 4096]).unwrap();
+        assert_eq!(
+            ssd.elapsed(),
+            2 * (Duration::from_micros(60) + Duration::from_nanos(8192))
+        );
+        assert!(!ssd.disk_infos().unwrap().geometry.rotational);
+    }
+}
@@ -1,9 +1,12 @@
-use crate::{Disk, DiskErr, DiskInfos, Permissions, SectorSize};
+use crate::{
+    DeviceId, Disk, DiskErr, DiskErrKind, DiskInfos, Geometry, Operation, Permissions, SectorSize,
+    write_zeroes,
+};
 use mutex::Mutex;
 use std::{
-    fs::File,
+    fs::{self, File, Metadata},
     io::{self, Read, Seek, SeekFrom, Write},
-    path::PathBuf,
+    path::{Path, PathBuf},
 };
 
 /// Wrapper type for a disk image
@@ -11,143 +14,453 @@ use std::{
 pub struct DiskFile {
     sector_size: SectorSize,
     /// Size of the file/disk, in bytes
-    size: usize,
+    size: u64,
     /// Permissions of the disk. The file is opened with the same permissions.
     permissions: Permissions,
+    /// Reported by the kernel for block devices, guessed from the sector sizes for images
+    geometry: Geometry,
+    device_id: Option<DeviceId>,
+    /// The file is evicted from the page cache after each flush
+    drop_cache: bool,
     file: Mutex<File>,
 }
 
 impl Disk for DiskFile {
-    fn read_sector(&self, sector: usize, buf: &mut [u8]) -> Result<(), DiskErr> {
+    fn read_sector(&self, sector: u64, buf: &mut [u8]) -> Result<(), DiskErr> {
+        self.read_sectors(sector, buf.len(), buf)
+    }
+
+    fn write_sector(&self, sector: u64, buf: &[u8]) -> Result<(), DiskErr> {
+        self.write_sectors(sector, buf.len(), buf)
+    }
+
+    fn disk_infos(&self) -> Result<DiskInfos, DiskErr> {
+        Ok(DiskInfos {
+            sector_size: self.sector_size.clone(),
+            disk_size: self.size,
+            permissions: self.permissions,
+            geometry: self.geometry,
+            device_id: self.device_id.clone(),
+        })
+    }
+
+    fn read_sectors(&self, sector: u64, sector_size: usize, buf: &mut [u8]) -> Result<(), DiskErr> {
+        self.read_inner(sector, sector_size, buf)
+            .map_err(|e| e.at(Operation::Read, sector).in_layer("DiskFile"))
+    }
+
+    fn write_sectors(&self, sector: u64, sector_size: usize, buf: &[u8]) -> Result<(), DiskErr> {
+        self.write_inner(sector, sector_size, buf)
+            .map_err(|e| e.at(Operation::Write, sector).in_layer("DiskFile"))
+    }
+
+    fn discard(&self, sector: u64, sector_size: usize, count: u64) -> Result<(), DiskErr> {
+        self.discard_inner(sector, sector_size, count)
+            .map_err(|e| e.at(Operation::Discard, sector).in_layer("DiskFile"))
+    }
+
+    fn flush(&self) -> Result<(), DiskErr> {
+        let file = self.file.lock();
+
+        file.sync_data()
+            .and_then(|_| match self.drop_cache {
+                true => drop_cache(&file),
+                false => Ok(()),
+            })
+            .map_err(|e| {
+                DiskErr::from_io(&e)
+                    .during(Operation::Flush)
+                    .in_layer("DiskFile")
+            })
+    }
+}
+
+impl DiskFile {
+    /// Creates a new file to handle the disk image. Will result in an error if the file already
+    /// exists.
+    pub fn new(
+        path: PathBuf,
+        size: u64,
+        sector_conf: SectorSize,
+        permission: Permissions,
+    ) -> io::Result<Self> {
+        let file = File::create_new(path.clone())?;
+        file.set_len(size)?;
+        drop(file);
+
+        Self::from_file(path, sector_conf, permission)
+    }
+
+    /// Opens an existing file. Will result in an error if the file doesn't exist.
+    pub fn from_file(
+        file: PathBuf,
+        sector_conf: SectorSize,
+        permission: Permissions,
+    ) -> io::Result<Self> {
+        let path = file;
+        let mut file = File::options()
+            .create_new(false)
+            .write(permission.write)
+            .read(permission.read)
+            .open(&path)?;
+        let metadata = file.metadata()?;
+
+        let (size, geometry, device_id) = match block_device_infos(&metadata) {
+            // The size of a block device is not in its metadata
+            Some((geometry, device_id)) => (file.seek(SeekFrom::End(0))?, geometry, device_id),
+            None => {
+                let size = metadata.len();
+                let mut geometry = Geometry::from_sector_size(&sector_conf, size);
+
+                #[cfg(unix)]
+                {
+                    use std::os::unix::fs::MetadataExt;
+                    geometry.optimal_io_size = metadata.blksize() as usize;
+                }
+
+                let device_id = fs::canonicalize(&path)
+                    .ok()
+                    .map(|p| DeviceId::Image(path_hash(&p)));
+
+                (size, geometry, device_id)
+            }
+        };
+
+        Ok(Self {
+            sector_size: sector_conf,
+            size,
+            permissions: permission,
+            geometry,
+            device_id,
+            drop_cache: false,
+            file: Mutex::new(file),
+        })
+    }
+
+    /// Evicts the file from the page cache after each flush, so that the reads following a flush
+    /// come from the device. A `VerifyingDisk` needs it to catch a device dropping writes. Only
+    /// supported on Linux, the flushes fail elsewhere.
+    pub fn with_cache_dropped_on_flush(mut self) -> Self {
+        self.drop_cache = true;
+        self
+    }
+
+    fn read_inner(&self, sector: u64, sector_size: usize, buf: &mut [u8]) -> Result<(), DiskErr> {
         // ### CHECKS FOR INVALID REQUEST ###
 
         if !self.permissions.read {
-            return Err(DiskErr::InvalidPermission {
+            return Err(DiskErrKind::InvalidPermission {
                 disk_permissions: self.permissions,
-            });
+            }
+            .into());
         }
 
-        let sector_size = buf.len();
+        let offset = self.offset(sector, sector_size, buf.len() as u64)?;
 
-        if !self.sector_size.is_supported(sector_size, self.size) {
-            return Err(DiskErr::InvalidSectorSize {
-                found: sector_size,
-                supported: self.sector_size.clone(),
-                start: 0,
-            });
-        }
+        // ### PERFORMS THE READ OPERATION ON THE FILE ###
 
-        let offset = sector_size * sector;
+        let mut file = self.file.lock();
 
-        if offset + sector_size > self.size {
-            return Err(DiskErr::InvalidSectorIndex {
-                found: sector,
-                max: self.size / sector_size,
-            });
-        }
+        file.seek(SeekFrom::Start(offset))
+            .and_then(|_| file.read_exact(buf))
+            .map_err(|e| DiskErr::from_io(&e))
+    }
 
-        // ### PERFORMS THE READ OPERATION ON THE FILE ###
+    fn write_inner(&self, sector: u64, sector_size: usize, buf: &[u8]) -> Result<(), DiskErr> {
+        // ### CHECKS FOR INVALID REQUEST ###
 
-        if self
-            .file
-            .lock()
-            .seek(SeekFrom::Start(offset as u64))
-            .is_err()
-        {
-            return Err(DiskErr::IOErr);
+        if !self.permissions.write {
+            return Err(DiskErrKind::InvalidPermission {
+                disk_permissions: self.permissions,
+            }
+            .into());
         }
 
-        if self.file.lock().read_exact(buf).is_err() {
-            return Err(DiskErr::IOErr);
-        }
+        let offset = self.offset(sector, sector_size, buf.len() as u64)?;
+
+        // ### PERFORMS THE WRITE OPERATION ON THE FILE ###
 
-        Ok(())
+        let mut file = self.file.lock();
+
+        file.seek(SeekFrom::Start(offset))
+            .and_then(|_| file.write_all(buf))
+            .map_err(|e| DiskErr::from_io(&e))
     }
 
-    fn write_sector(&self, sector: usize, buf: &[u8]) -> Result<(), DiskErr> {
+    fn discard_inner(&self, sector: u64, sector_size: usize, count: u64) -> Result<(), DiskErr> {
         // ### CHECKS FOR INVALID REQUEST ###
 
         if !self.permissions.write {
-            return Err(DiskErr::InvalidPermission {
+            return Err(DiskErrKind::InvalidPermission {
                 disk_permissions: self.permissions,
-            });
+            }
+            .into());
         }
 
-        let sector_size = buf.len();
+        let len = count.saturating_mul(sector_size as u64);
+        let offset = self.offset(sector, sector_size, len)?;
+
+        // ### PUNCHES A HOLE IN THE FILE, OR WRITES ZEROES IF NOT SUPPORTED ###
 
-        if !self.sector_size.is_supported(sector_size, self.size) {
-            return Err(DiskErr::InvalidSectorSize {
+        if punch_hole(&self.file.lock(), offset, len) {
+            return Ok(());
+        }
+
+        write_zeroes(self, sector, sector_size, count)
+    }
+
+    /// Checks a request of `len` bytes starting at the LBA `sector` and returns its offset in the
+    /// file.
+    fn offset(&self, sector: u64, sector_size: usize, len: u64) -> Result<u64, DiskErr> {
+        if sector_size == 0
+            || !self.sector_size.is_supported(sector_size, self.size)
+            || !len.is_multiple_of(sector_size as u64)
+        {
+            return Err(DiskErrKind::InvalidSectorSize {
                 found: sector_size,
                 supported: self.sector_size.clone(),
                 start: 0,
-            });
+            }
+            .into());
         }
 
-        let offset = sector_size * sector;
-
-        if offset + sector_size > self.size {
-            return Err(DiskErr::InvalidSectorIndex {
+        match sector.checked_mul(sector_size as u64) {
+            Some(offset) if offset.checked_add(len).is_some_and(|end| end <= self.size) => {
+                Ok(offset)
+            }
+            _ => Err(DiskErrKind::InvalidSectorIndex {
                 found: sector,
-                max: self.size / sector_size,
-            });
+                max: (self.size / sector_size as u64).saturating_sub(1),
+            }
+            .into()),
         }
+    }
+}
 
-        // ### PERFORMS THE WRITE OPERATION ON THE FILE ###
+/// FNV-1a hash of a path. Unlike the std hashers, it is stable across runs and Rust versions.
+pub(crate) fn path_hash(path: &Path) -> u64 {
+    path.as_os_str()
+        .as_encoded_bytes()
+        .iter()
+        .fold(0xcbf29ce484222325, |hash, &b| {
+            (hash ^ b as u64).wrapping_mul(0x100000001b3)
+        })
+}
+
+/// A random GUID (version 4), in the mixed-endian layout of the Microsoft formats. The hashers of
+/// the standard library are randomly keyed, which is enough to make identifiers unique, but not
+/// for cryptographic use.
+pub(crate) fn random_guid() -> [u8; 16] {
+    use std::{
+        hash::{BuildHasher, Hasher, RandomState},
+        time::SystemTime,
+    };
 
-        if self.file.lock().seek(SeekFrom::Start(offset as u64)).is_err() {
-            return Err(DiskErr::IOErr);
+    let nanos = SystemTime::now()
+        .duration_since(SystemTime::UNIX_EPOCH)
+        .map_or(0, |d| d.as_nanos());
+
+    let mut guid = [0; 16];
+    for (i, half) in guid.chunks_mut(8).enumerate() {
+        let mut hasher = RandomState::new().build_hasher();
+        hasher.write_u128(nanos);
+        hasher.write_usize(i);
+        half.copy_from_slice(&hasher.finish().to_le_bytes());
+    }
+
+    // The version is in the high bits of the third field, stored little-endian
+    guid[7] = (guid[7] & 0x0F) | 0x40;
+    guid[8] = (guid[8] & 0x3F) | 0x80;
+    guid
+}
+
+/// A path named `name` in a new directory for the tests, unique to the process. The directory and
+/// everything written in it are removed when dropped.
+#[cfg(test)]
+pub(crate) struct TempPath {
+    pub path: PathBuf,
+}
+
+#[cfg(test)]
+impl TempPath {
+    pub fn new(name: &str) -> Self {
+        let dir = std::env::temp_dir().join(format!("partfs-{}-{name}", std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        Self {
+            path: dir.join(name),
         }
+    }
+}
 
-        if self.file.lock().write_all(buf).is_err() {
-            return Err(DiskErr::IOErr);
+#[cfg(test)]
+impl Drop for TempPath {
+    fn drop(&mut self) {
+        if let Some(dir) = self.path.parent() {
+            let _ = fs::remove_dir_all(dir);
         }
+    }
+}
+
+/// Reads the geometry and the model/serial of a block device from sysfs. Returns `None` if the
+/// file is not a block device.
+#[cfg(target_os = "linux")]
+fn block_device_infos(metadata: &Metadata) -> Option<(Geometry, Option<DeviceId>)> {
+    use std::os::unix::fs::{FileTypeExt, MetadataExt};
 
-        Ok(())
+    if !metadata.file_type().is_block_device() {
+        return None;
     }
 
-    fn disk_infos(&self) -> Result<DiskInfos, DiskErr> {
-        Ok(DiskInfos {
-            sector_size: self.sector_size.clone(),
-            disk_size: self.size,
-            permissions: self.permissions,
-        })
+    let rdev = metadata.rdev();
+    let major = ((rdev >> 32) & 0xFFFF_F000) | ((rdev >> 8) & 0xFFF);
+    let minor = ((rdev >> 12) & 0xFFFF_FF00) | (rdev & 0xFF);
+    let dir = PathBuf::from(format!("/sys/dev/block/{major}:{minor}"));
+
+    // A partition has no queue nor device information, they are in the directory of its disk
+    let is_partition = dir.join("partition").exists();
+    let disk_dir = if is_partition {
+        dir.join("..")
+    } else {
+        dir.clone()
+    };
+
+    let read = |path: PathBuf| -> Option<String> {
+        fs::read_to_string(path).ok().map(|s| s.trim().to_string())
+    };
+    let read_num = |path: PathBuf| -> Option<u64> { read(path)?.parse().ok() };
+    let queue = disk_dir.join("queue");
+
+    let logical_sector_size = read_num(queue.join("logical_block_size")).unwrap_or(512) as usize;
+    let physical_sector_size =
+        read_num(queue.join("physical_block_size")).map_or(logical_sector_size, |v| v as usize);
+
+    let geometry = Geometry {
+        logical_sector_size,
+        physical_sector_size,
+        min_io_size: read_num(queue.join("minimum_io_size"))
+            .map_or(physical_sector_size, |v| v as usize),
+        optimal_io_size: read_num(queue.join("optimal_io_size")).unwrap_or(0) as usize,
+        alignment_offset: read_num(dir.join("alignment_offset")).unwrap_or(0),
+        rotational: read_num(queue.join("rotational")) == Some(1),
+    };
+
+    let model = read(disk_dir.join("device/model"));
+    let serial =
+        read(disk_dir.join("device/serial")).or_else(|| read(disk_dir.join("device/wwid")));
+
+    let device_id = match (model, serial) {
+        (Some(model), Some(serial)) => {
+            let id = DeviceId::Hardware { model, serial };
+
+            // Partitions are identified by their range on the disk
+            match (read_num(dir.join("start")), read_num(dir.join("size"))) {
+                (Some(start), Some(size)) if is_partition => {
+                    Some(id.slice(vec![(start * 512, (start + size) * 512)]))
+                }
+                _ => Some(id),
+            }
+        }
+        _ => None,
+    };
+
+    Some((geometry, device_id))
+}
+
+#[cfg(not(target_os = "linux"))]
+fn block_device_infos(_metadata: &Metadata) -> Option<(Geometry, Option<DeviceId>)> {
+    None
+}
+
+#[cfg(all(target_os = "linux", target_pointer_width = "64"))]
+unsafe extern "C" {
+    fn fallocate(fd: i32, mode: i32, offset: i64, len: i64) -> i32;
+    fn posix_fadvise(fd: i32, offset: i64, len: i64, advice: i32) -> i32;
+}
+
+/// Deallocates `len` bytes of the file starting at `offset`, keeping the file size. The file reads
+/// as zeroes in that range afterwards. Returns false if the platform or the filesystem doesn't
+/// support it.
+#[cfg(all(target_os = "linux", target_pointer_width = "64"))]
+fn punch_hole(file: &File, offset: u64, len: u64) -> bool {
+    use std::os::fd::AsRawFd;
+
+    const FALLOC_FL_KEEP_SIZE: i32 = 0x01;
+    const FALLOC_FL_PUNCH_HOLE: i32 = 0x02;
+
+    if len == 0 {
+        return true;
+    }
+
+    let (Ok(offset), Ok(len)) = (i64::try_from(offset), i64::try_from(len)) else {
+        return false;
+    };
+
+    // SAFETY: the file descriptor is valid as long as `file` is borrowed
+    unsafe {
+        fallocate(
+            file.as_raw_fd(),
+            FALLOC_FL_PUNCH_HOLE | FALLOC_FL_KEEP_SIZE,
+            offset,
+            len,
+        ) == 0
     }
 }
 
-impl DiskFile {
-    /// Creates a new file to handle the disk image. Will result in an error if the file already
-    /// exists.
-    pub fn new(
-        path: PathBuf,
-        size: usize,
-        sector_conf: SectorSize,
-        permission: Permissions,
-    ) -> io::Result<Self> {
-        let file = File::create_new(path.clone())?;
-        file.set_len(size as u64)?;
-        drop(file);
+#[cfg(not(all(target_os = "linux", target_pointer_width = "64")))]
+fn punch_hole(_file: &File, _offset: u64, _len: u64) -> bool {
+    false
+}
 
-        Self::from_file(path, sector_conf, permission)
+/// Evicts the clean pages of the file from the page cache
+#[cfg(all(target_os = "linux", target_pointer_width = "64"))]
+fn drop_cache(file: &File) -> io::Result<()> {
+    use std::os::fd::AsRawFd;
+
+    const POSIX_FADV_DONTNEED: i32 = 4;
+
+    // SAFETY: the file descriptor is valid as long as `file` is borrowed. A length of 0 covers
+    // the whole file.
+    match unsafe { posix_fadvise(file.as_raw_fd(), 0, 0, POSIX_FADV_DONTNEED) } {
+        0 => Ok(()),
+        errno => Err(io::Error::from_raw_os_error(errno)),
     }
+}
 
-    /// Opens an existing file. Will result in an error if the file doesn't exist.
-    pub fn from_file(
-        file: PathBuf,
-        sector_conf: SectorSize,
-        permission: Permissions,
-    ) -> io::Result<Self> {
-        let file = File::options()
-            .create_new(false)
-            .write(permission.write)
-            .read(permission.read)
-            .open(file)?;
-        let size = file.metadata()?.len() as usize;
+#[cfg(not(all(target_os = "linux", target_pointer_width = "64")))]
+fn drop_cache(_file: &File) -> io::Result<()> {
+    Err(io::ErrorKind::Unsupported.into())
+}
 
-        Ok(Self {
-            sector_size: sector_conf,
-            size,
-            permissions: permission,
-            file: Mutex::new(file),
-        })
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::verify::VerifyingDisk;
+
+    #[test]
+    fn verified_writes_without_cache() {
+        let temp = TempPath::new("uncached.img");
+        let file = DiskFile::new(
+            temp.path.clone(),
+            1 << 20,
+            SectorSize::AllOf(vec![512, 4096]),
+            Permissions::read_write(),
+        )
+        .unwrap()
+        .with_cache_dropped_on_flush();
+        let disk = VerifyingDisk::new(file);
+
+        disk.write_sectors(3, 4096, &[7; 8192]).unwrap();
+        disk.discard(1, 512, 2).unwrap();
+        disk.verify_all().unwrap();
+
+        let mut buf = [0; 1024];
+        disk.read_sectors(1, 512, &mut buf).unwrap();
+        assert_eq!(buf, [0; 1024]);
+        drop(disk);
+        assert_eq!(
+            fs::read(&temp.path).unwrap()[(3 * 4096)..(5 * 4096)],
+            [7; 8192]
+        );
     }
 }
@@ -0,0 +1,607 @@
+use crate::{Disk, DiskErr, DiskInfos, Operation, checksum::crc32};
+use alloc::{
+    format,
+    string::{String, ToString},
+    sync::Arc,
+    vec::Vec,
+};
+use core::fmt;
+use mutex::Mutex;
+
+/// Magic number at the start of a binary trace
+const TRACE_MAGIC: &[u8; 8] = b"PFSTRACE";
+
+/// Operation code of the FUA writes in a binary trace, after the codes of the `Operation`s
+const FUA_WRITE_CODE: u8 = 6;
+
+/// One request to a `TracingDisk`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TraceRecord {
+    /// Label of the `TracingDisk` the request went through
+    pub label: String,
+    pub operation: Operation,
+    /// Set for the writes sent with `write_sectors_fua`
+    pub fua: bool,
+    /// LBA of the request, 0 for flushes and disk infos
+    pub sector: u64,
+    /// 0 for flushes and disk infos
+    pub sector_size: usize,
+    /// Size of the request in bytes. For discards, the size of the discarded range.
+    pub len: u64,
+    /// CRC32 of the data read or written, 0 if the request has no data or failed
+    pub checksum: u32,
+    /// The error returned, formatted
+    pub error: Option<String>,
+    /// The data of the writes, kept to replay them
+    pub data: Option<Vec<u8>>,
+}
+
+/// A log of requests, shared by the `TracingDisk`s recording to it. The records are in the order
+/// of the requests: a request to a `SubDisk` comes before the requests it causes on its parent if
+/// both are traced.
+pub struct Trace {
+    records: Mutex<Vec<TraceRecord>>,
+}
+
+/// A wrapper recording the requests to a `Disk` in a `Trace`. Several disks can record to the same
+/// trace, typically a disk and its `SubDisk`s, each with its own label.
+pub struct TracingDisk<D: Disk> {
+    disk: D,
+    trace: Arc<Trace>,
+    label: String,
+}
+
+impl Trace {
+    pub fn new() -> Arc<Self> {
+        Arc::new(Self {
+            records: Mutex::new(Vec::new()),
+        })
+    }
+
+    /// A copy of the records
+    pub fn records(&self) -> Vec<TraceRecord> {
+        self.records.lock().clone()
+    }
+
+    pub fn len(&self) -> usize {
+        self.records.lock().len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    pub fn clear(&self) {
+        self.records.lock().clear();
+    }
+
+    /// Formats the trace, one record per line
+    pub fn to_text(&self) -> String {
+        self.records
+            .lock()
+            .iter()
+            .enumerate()
+            .map(|(i, record)| format!("{i} {record}\n"))
+            .collect()
+    }
+
+    /// Serializes the trace, with the data of the writes. All the integers are little endian.
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut bytes = TRACE_MAGIC.to_vec();
+
+        for record in self.records.lock().iter() {
+            record.encode(&mut bytes);
+        }
+
+        bytes
+    }
+
+    /// Deserializes a trace produced by `to_bytes`. Returns `None` if the trace is invalid.
+    pub fn from_bytes(bytes: &[u8]) -> Option<Arc<Self>> {
+        let mut bytes = bytes.strip_prefix(TRACE_MAGIC)?;
+        let mut records = Vec::new();
+
+        while !bytes.is_empty() {
+            records.push(TraceRecord::decode(&mut bytes)?);
+        }
+
+        Some(Arc::new(Self {
+            records: Mutex::new(records),
+        }))
+    }
+
+    /// Applies the successful writes, discards and flushes recorded through the disk labelled
+    /// `label` to `disk`, in the same order. The FUA writes are replayed as FUA writes. Returns the
+    /// count of requests applied.
+    pub fn replay(&self, label: &str, disk: &dyn Disk) -> Result<usize, DiskErr> {
+        let mut count = 0;
+
+        // The records are copied, as `disk` may record to this trace
+        for record in self.records() {
+            if record.label != label || record.error.is_some() {
+                continue;
+            }
+
+            match (record.operation, record.data) {
+                (Operation::Write, Some(data)) if record.fua => {
+                    disk.write_sectors_fua(record.sector, record.sector_size, &data)?
+                }
+                (Operation::Write, Some(data)) => {
+                    disk.write_sectors(record.sector, record.sector_size, &data)?
+                }
+                (Operation::Discard, _) if record.sector_size != 0 => disk.discard(
+                    record.sector,
+                    record.sector_size,
+                    record.len / record.sector_size as u64,
+                )?,
+                (Operation::Flush, _) => disk.flush()?,
+                _ => continue,
+            }
+
+            count += 1;
+        }
+
+        Ok(count)
+    }
+
+    /// Adds a record for a request in progress and returns its index
+    fn begin(&self, record: TraceRecord) -> usize {
+        let mut records = self.records.lock();
+        records.push(record);
+        records.len() - 1
+    }
+
+    /// Completes the record `index` with the checksum of the data and the result of the request
+    fn finish<T>(&self, index: usize, data: &[u8], result: &Result<T, DiskErr>) {
+        let mut records = self.records.lock();
+
+        // The trace may have been cleared during the request
+        let Some(record) = records.get_mut(index) else {
+            return;
+        };
+
+        match result {
+            Ok(_) => record.checksum = crc32(data),
+            Err(e) => {
+                record.error = Some(e.to_string());
+                record.data = None;
+            }
+        }
+    }
+}
+
+impl TraceRecord {
+    const fn operation_code(operation: Operation) -> u8 {
+        match operation {
+            Operation::Read => 0,
+            Operation::Write => 1,
+            Operation::Flush => 2,
+            Operation::Discard => 3,
+            Operation::DiskInfos => 4,
+            Operation::CreateSubdisk => 5,
+        }
+    }
+
+    const fn operation_from_code(code: u8) -> Option<Operation> {
+        Some(match code {
+            0 => Operation::Read,
+            1 => Operation::Write,
+            2 => Operation::Flush,
+            3 => Operation::Discard,
+            4 => Operation::DiskInfos,
+            5 => Operation::CreateSubdisk,
+            _ => return None,
+        })
+    }
+
+    /// Appends the record to `bytes`. The strings and the data are prefixed with their length on
+    /// 32 bits, and the optional ones with a byte telling if they are present.
+    fn encode(&self, bytes: &mut Vec<u8>) {
+        fn push_bytes(bytes: &mut Vec<u8>, data: Option<&[u8]>) {
+            match data {
+                None => bytes.push(0),
+                Some(data) => {
+                    bytes.push(1);
+                    bytes.extend_from_slice(&(data.len() as u32).to_le_bytes());
+                    bytes.extend_from_slice(data);
+                }
+            }
+        }
+
+        bytes.push(if self.fua {
+            FUA_WRITE_CODE
+        } else {
+            Self::operation_code(self.operation)
+        });
+        bytes.extend_from_slice(&self.sector.to_le_bytes());
+        bytes.extend_from_slice(&(self.sector_size as u64).to_le_bytes());
+        bytes.extend_from_slice(&self.len.to_le_bytes());
+        bytes.extend_from_slice(&self.checksum.to_le_bytes());
+        push_bytes(bytes, Some(self.label.as_bytes()));
+        push_bytes(bytes, self.error.as_ref().map(|e| e.as_bytes()));
+        push_bytes(bytes, self.data.as_deref());
+    }
+
+    /// Reads a record at the start of `bytes` and advances it
+    fn decode(bytes: &mut &[u8]) -> Option<Self> {
+        fn take<'a>(bytes: &mut &'a [u8], len: usize) -> Option<&'a [u8]> {
+            let (head, tail) = bytes.split_at_checked(len)?;
+            *bytes = tail;
+            Some(head)
+        }
+
+        fn take_u64(bytes: &mut &[u8]) -> Option<u64> {
+            Some(u64::from_le_bytes(take(bytes, 8)?.try_into().ok()?))
+        }
+
+        fn take_bytes<'a>(bytes: &mut &'a [u8]) -> Option<Option<&'a [u8]>> {
+            match take(bytes, 1)?[0] {
+                0 => Some(None),
+                1 => {
+                    let len = u32::from_le_bytes(take(bytes, 4)?.try_into().ok()?);
+                    Some(Some(take(bytes, len as usize)?))
+                }
+                _ => None,
+            }
+        }
+
+        fn take_string(bytes: &mut &[u8]) -> Option<Option<String>> {
+            match take_bytes(bytes)? {
+                None => Some(None),
+                Some(s) => Some(Some(String::from(core::str::from_utf8(s).ok()?))),
+            }
+        }
+
+        let (operation, fua) = match take(bytes, 1)?[0] {
+            FUA_WRITE_CODE => (Operation::Write, true),
+            code => (Self::operation_from_code(code)?, false),
+        };
+        let sector = take_u64(bytes)?;
+        let sector_size = take_u64(bytes)?.try_into().ok()?;
+        let len = take_u64(bytes)?;
+        let checksum = u32::from_le_bytes(take(bytes, 4)?.try_into().ok()?);
+
+        Some(Self {
+            label: take_string(bytes)??,
+            operation,
+            fua,
+            sector,
+            sector_size,
+            len,
+            checksum,
+            error: take_string(bytes)?,
+            data: take_bytes(bytes)?.map(|d| d.to_vec()),
+        })
+    }
+}
+
+impl fmt::Display for TraceRecord {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let fua = if self.fua { "FUA " } else { "" };
+        write!(f, "{}: {fua}{}", self.label, self.operation)?;
+
+        match self.operation {
+            Operation::Read | Operation::Write | Operation::Discard => write!(
+                f,
+                " of {} bytes at sector {} ({} bytes sectors)",
+                self.len, self.sector, self.sector_size
+            )?,
+            _ => (),
+        }
+
+        if matches!(self.operation, Operation::Read | Operation::Write) && self.error.is_none() {
+            write!(f, ", crc32 {:08x}", self.checksum)?;
+        }
+
+        match &self.error {
+            None => f.write_str(", ok"),
+            Some(e) => write!(f, ", failed: {e}"),
+        }
+    }
+}
+
+impl<D: Disk> TracingDisk<D> {
+    /// Records the requests to `disk` in `trace`, with the label `label`
+    pub fn new(disk: D, trace: Arc<Trace>, label: &str) -> Self {
+        Self {
+            disk,
+            trace,
+            label: String::from(label),
+        }
+    }
+
+    pub fn trace(&self) -> &Arc<Trace> {
+        &self.trace
+    }
+
+    pub fn label(&self) -> &str {
+        &self.label
+    }
+
+    pub const fn inner(&self) -> &D {
+        &self.disk
+    }
+
+    pub fn into_inner(self) -> D {
+        self.disk
+    }
+
+    fn begin(
+        &self,
+        operation: Operation,
+        fua: bool,
+        sector: u64,
+        sector_size: usize,
+        len: u64,
+        data: Option<&[u8]>,
+    ) -> usize {
+        self.trace.begin(TraceRecord {
+            label: self.label.clone(),
+            operation,
+            fua,
+            sector,
+            sector_size,
+            len,
+            checksum: 0,
+            error: None,
+            data: data.map(|d| d.to_vec()),
+        })
+    }
+}
+
+impl<D: Disk> Disk for TracingDisk<D> {
+    fn read_sector(&self, sector: u64, buf: &mut [u8]) -> Result<(), DiskErr> {
+        self.read_sectors(sector, buf.len(), buf)
+    }
+
+    fn write_sector(&self, sector: u64, buf: &[u8]) -> Result<(), DiskErr> {
+        self.write_sectors(sector, buf.len(), buf)
+    }
+
+    fn disk_infos(&self) -> Result<DiskInfos, DiskErr> {
+        let index = self.begin(Operation::DiskInfos, false, 0, 0, 0, None);
+        let result = self.disk.disk_infos();
+        self.trace.finish(index, &[], &result);
+        result
+    }
+
+    fn read_sectors(&self, sector: u64, sector_size: usize, buf: &mut [u8]) -> Result<(), DiskErr> {
+        let index = self.begin(
+            Operation::Read,
+            false,
+            sector,
+            sector_size,
+            buf.len() as u64,
+            None,
+        );
+        let result = self.disk.read_sectors(sector, sector_size, buf);
+        self.trace.finish(index, buf, &result);
+        result
+    }
+
+    fn write_sectors(&self, sector: u64, sector_size: usize, buf: &[u8]) -> Result<(), DiskErr> {
+        let len = buf.len() as u64;
+        let index = self.begin(Operation::Write, false, sector, sector_size, len, Some(buf));
+        let result = self.disk.write_sectors(sector, sector_size, buf);
+        self.trace.finish(index, buf, &result);
+        result
+    }
+
+    /// Recorded as a single FUA write, and sent to the disk as such
+    fn write_sectors_fua(
+        &self,
+        sector: u64,
+        sector_size: usize,
+        buf: &[u8],
+    ) -> Result<(), DiskErr> {
+        let len = buf.len() as u64;
+        let index = self.begin(Operation::Write, true, sector, sector_size, len, Some(buf));
+        let result = self.disk.write_sectors_fua(sector, sector_size, buf);
+        self.trace.finish(index, buf, &result);
+        result
+    }
+
+    fn discard(&self, sector: u64, sector_size: usize, count: u64) -> Result<(), DiskErr> {
+        let len = count.saturating_mul(sector_size as u64);
+        let index = self.begin(Operation::Discard, false, sector, sector_size, len, None);
+        let result = self.disk.discard(sector, sector_size, count);
+        self.trace.finish(index, &[], &result);
+        result
+    }
+
+    fn flush(&self) -> Result<(), DiskErr> {
+        let index = self.begin(Operation::Flush, false, 0, 0, 0, None);
+        let result = self.disk.flush();
+        self.trace.finish(index, &[], &result);
+        result
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{Permissions, SectorSize, memdisk::MemDisk};
+    use alloc::vec;
+
+    fn image() -> Vec<u8> {
+        (0..16 * 512).map(|i| (i % 251) as u8).collect()
+    }
+
+    fn mem_disk() -> MemDisk {
+        MemDisk::from_vec(
+            image(),
+            SectorSize::AllOf(vec![512, 1024]),
+            Permissions::read_write(),
+        )
+    }
+
+    /// Records some requests of each kind through the disks "disk" and "other"
+    fn record(trace: &Arc<Trace>) -> MemDisk {
+        let disk = TracingDisk::new(mem_disk(), trace.clone(), "disk");
+        let other = TracingDisk::new(mem_disk(), trace.clone(), "other");
+
+        disk.disk_infos().unwrap();
+        disk.write_sectors(1, 512, &[1; 1024]).unwrap();
+        other.write_sectors(0, 512, &[9; 512]).unwrap();
+        disk.read_sectors(0, 1024, &mut [0; 1024]).unwrap();
+        disk.write_sectors_fua(4, 1024, &[2; 1024]).unwrap();
+        disk.write_sectors(16, 512, &[3; 512]).unwrap_err();
+        disk.discard(12, 512, 3).unwrap();
+        disk.flush().unwrap();
+        disk.write_sectors(2, 512, &[4; 512]).unwrap();
+
+        disk.into_inner()
+    }
+
+    #[test]
+    fn records() {
+        let trace = Trace::new();
+        record(&trace);
+
+        let records = trace.records();
+        assert_eq!(records.len(), 9);
+        assert!(records.iter().filter(|r| r.label == "other").count() == 1);
+
+        let fua = &records[4];
+        assert_eq!((fua.operation, fua.fua), (Operation::Write, true));
+        assert_eq!((fua.sector, fua.sector_size, fua.len), (4, 1024, 1024));
+        assert_eq!(fua.checksum, crc32(&[2; 1024]));
+        assert_eq!(fua.data.as_deref(), Some(&[2; 1024][..]));
+
+        let failed = &records[5];
+        assert!(failed.error.is_some());
+        assert_eq!((failed.checksum, &failed.data), (0, &None));
+
+        let read = &records[3];
+        assert_eq!(read.data, None);
+        assert_eq!(read.checksum, crc32(&[&image()[..512], &[1; 512]].concat()));
+        assert_eq!(records[6].len, 3 * 512);
+        assert!(records.iter().all(|r| r.fua == (r == fua)));
+
+        let text = trace.to_text();
+        let lines = text.lines().collect::<Vec<_>>();
+        assert_eq!(lines.len(), 9);
+        assert_eq!(lines[0], "0 disk: disk infos, ok");
+        assert_eq!(
+            lines[4],
+            format!(
+                "4 disk: FUA write of 1024 bytes at sector 4 (1024 bytes sectors), crc32 {:08x}, ok",
+                crc32(&[2; 1024])
+            )
+        );
+        assert!(
+            lines[5].starts_with(
+                "5 disk: write of 512 bytes at sector 16 (512 bytes sectors), failed: "
+            )
+        );
+        assert_eq!(
+            lines[6],
+            "6 disk: discard of 1536 bytes at sector 12 (512 bytes sectors), ok"
+        );
+        assert_eq!(lines[7], "7 disk: flush, ok");
+
+        trace.clear();
+        assert!(trace.is_empty());
+        assert_eq!(trace.to_bytes(), TRACE_MAGIC);
+    }
+
+    #[test]
+    fn binary_round_trip() {
+        let trace = Trace::new();
+        record(&trace);
+
+        let bytes = trace.to_bytes();
+        let decoded = Trace::from_bytes(&bytes).unwrap();
+        assert_eq!(decoded.records(), trace.records());
+        assert_eq!(decoded.to_bytes(), bytes);
+
+        assert!(Trace::from_bytes(TRACE_MAGIC).unwrap().is_empty());
+    }
+
+    #[test]
+    fn invalid_traces() {
+        let trace = Trace::new();
+        record(&trace);
+        let bytes = trace.to_bytes();
+
+        // The offsets where a record ends
+        let mut boundaries = vec![TRACE_MAGIC.len()];
+        let mut encoded = TRACE_MAGIC.to_vec();
+        for record in trace.records() {
+            record.encode(&mut encoded);
+            boundaries.push(encoded.len());
+        }
+        assert_eq!(encoded, bytes);
+
+        for len in 0..bytes.len() {
+            assert_eq!(
+                Trace::from_bytes(&bytes[..len]).is_some(),
+                boundaries.contains(&len),
+                "truncated to {len} bytes"
+            );
+        }
+
+        let mut wrong_magic = bytes.clone();
+        wrong_magic[0] = b'X';
+        assert!(Trace::from_bytes(&wrong_magic).is_none());
+
+        // The first record starts with its operation code, then 3 integers and the checksum, then
+        // the presence byte and the length of the label
+        let code = TRACE_MAGIC.len();
+        let label = code + 1 + 3 * 8 + 4;
+        let corruptions: [(usize, u8); 4] = [
+            (code, FUA_WRITE_CODE + 1),
+            // No label
+            (label, 0),
+            (label, 2),
+            // Not UTF-8
+            (label + 5, 0xFF),
+        ];
+
+        for (offset, value) in corruptions {
+            let mut corrupted = bytes.clone();
+            corrupted[offset] = value;
+            assert!(Trace::from_bytes(&corrupted).is_none(), "{offset}");
+        }
+    }
+
+    #[test]
+    fn replay() {
+        let trace = Trace::new();
+        let recorded = record(&trace);
+
+        // Replayed onto a disk with the same content as the traced one before the requests
+        let replayed = mem_disk();
+        let decoded = Trace::from_bytes(&trace.to_bytes()).unwrap();
+        assert_eq!(decoded.replay("disk", &replayed).unwrap(), 5);
+        assert_eq!(replayed.into_vec(), recorded.into_vec());
+
+        // The FUA write is replayed as such
+        let replay_trace = Trace::new();
+        let disk = TracingDisk::new(mem_disk(), replay_trace.clone(), "replay");
+        assert_eq!(trace.replay("disk", &disk).unwrap(), 5);
+        let operations = replay_trace
+            .records()
+            .iter()
+            .map(|r| (r.operation, r.fua))
+            .collect::<Vec<_>>();
+        assert_eq!(
+            operations,
+            [
+                (Operation::Write, false),
+                (Operation::Write, true),
+                (Operation::Discard, false),
+                (Operation::Flush, false),
+                (Operation::Write, false),
+            ]
+        );
+
+        let other = mem_disk();
+        assert_eq!(trace.replay("other", &other).unwrap(), 1);
+        assert_eq!(
+            other.into_vec()[..513],
+            [&[9; 512], &image()[512..513]].concat()
+        );
+        assert_eq!(trace.replay("missing", &mem_disk()).unwrap(), 0);
+    }
+}
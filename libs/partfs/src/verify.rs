@@ -0,0 +1,367 @@
+use crate::{Disk, DiskErr, DiskErrKind, DiskInfos, Operation, checksum::crc32};
+use alloc::{collections::BTreeMap, vec, vec::Vec};
+use mutex::Mutex;
+
+/// Maximum count of sectors read with a single request by the verify passes
+const VERIFY_BATCH: usize = 64;
+
+/// A wrapper reading back each sector written to a `Disk`, and failing with `VerificationFailed` if
+/// it doesn't hold the written data. Devices dropping writes, like fake-capacity USB sticks, then
+/// make the writes fail instead of silently corrupting the image.
+///
+/// The checksums of the written sectors are kept, so that a full verify pass can check later that
+/// they weren't overwritten: a fake-capacity device typically wraps the writes beyond its real
+/// capacity to its start.
+///
+/// The lower disk is flushed before each read-back, so that a write cache can't hide a device
+/// dropping the writes. The page cache holds the data even after a flush: a `DiskFile` must be
+/// opened `with_cache_dropped_on_flush` for the reads to reach the device.
+pub struct VerifyingDisk<D: Disk> {
+    disk: D,
+    /// Sector size and CRC32 of the written sectors, by offset in bytes. The sectors don't overlap.
+    written: Mutex<BTreeMap<u64, (usize, u32)>>,
+}
+
+impl<D: Disk> VerifyingDisk<D> {
+    pub fn new(disk: D) -> Self {
+        Self {
+            disk,
+            written: Mutex::new(BTreeMap::new()),
+        }
+    }
+
+    /// Count of sectors whose checksum is kept
+    pub fn written_sectors(&self) -> usize {
+        self.written.lock().len()
+    }
+
+    /// Drops the checksums of the written sectors
+    pub fn forget(&self) {
+        self.written.lock().clear();
+    }
+
+    pub const fn inner(&self) -> &D {
+        &self.disk
+    }
+
+    pub fn into_inner(self) -> D {
+        self.disk
+    }
+
+    /// Reads back the sectors written in the `count` sectors of `sector_size` bytes starting at the
+    /// LBA `sector`, and checks they still hold the written data. The sectors never written are
+    /// not checked. Fails with `VerificationFailed` and the LBA of the first bad sector, in
+    /// `sector_size` units.
+    pub fn verify_range(&self, sector: u64, sector_size: usize, count: u64) -> Result<(), DiskErr> {
+        let start = sector.saturating_mul(sector_size as u64);
+        let end = start.saturating_add(count.saturating_mul(sector_size as u64));
+
+        self.verify_bytes(start, end, sector_size)
+            .map_err(|e| e.at(Operation::Read, sector).in_layer("VerifyingDisk"))
+    }
+
+    /// Reads back every written sector and checks it still holds the written data. The LBA of the
+    /// error is in the sector size of the first bad sector.
+    pub fn verify_all(&self) -> Result<(), DiskErr> {
+        self.verify_bytes(0, u64::MAX, 0)
+            .map_err(|e| e.during(Operation::Read).in_layer("VerifyingDisk"))
+    }
+
+    /// Checks the written sectors in the bytes `start..end`. The LBA of the errors is in
+    /// `sector_size` units, or in the size of the bad sector if 0.
+    fn verify_bytes(&self, start: u64, end: u64, sector_size: usize) -> Result<(), DiskErr> {
+        self.disk.flush()?;
+
+        let sectors: Vec<(u64, usize, u32)> = self
+            .written
+            .lock()
+            .range(start..end)
+            .filter(|(offset, (size, _))| **offset + *size as u64 <= end)
+            .map(|(offset, (size, crc))| (*offset, *size, *crc))
+            .collect();
+
+        let mut i = 0;
+        while i < sectors.len() {
+            let (first, size, _) = sectors[i];
+
+            // Contiguous sectors of the same size are read with a single request
+            let mut run = 1;
+            while i + run < sectors.len()
+                && run < VERIFY_BATCH
+                && sectors[i + run].1 == size
+                && sectors[i + run].0 == first + (run * size) as u64
+            {
+                run += 1;
+            }
+
+            let mut buf = vec![0; run * size];
+            self.disk
+                .read_sectors(first / size as u64, size, &mut buf)?;
+
+            for (j, data) in buf.chunks(size).enumerate() {
+                let (offset, _, crc) = sectors[i + j];
+
+                if crc32(data) != crc {
+                    let unit = if sector_size == 0 { size } else { sector_size };
+                    return Err(DiskErrKind::VerificationFailed {
+                        lba: offset / unit as u64,
+                    }
+                    .into());
+                }
+            }
+
+            i += run;
+        }
+
+        Ok(())
+    }
+
+    /// Keeps the checksums of the sectors written with `buf` at the LBA `sector`
+    fn record(&self, sector: u64, sector_size: usize, buf: &[u8]) {
+        let start = sector * sector_size as u64;
+        let end = start + buf.len() as u64;
+        let mut written = self.written.lock();
+
+        // The sectors overlapping the written range are outdated, even partially
+        let outdated: Vec<u64> = written
+            .range(..end)
+            .rev()
+            .take_while(|(offset, (size, _))| **offset + *size as u64 > start)
+            .map(|(offset, _)| *offset)
+            .collect();
+
+        for offset in outdated {
+            written.remove(&offset);
+        }
+
+        for (i, data) in buf.chunks(sector_size).enumerate() {
+            written.insert(start + (i * sector_size) as u64, (sector_size, crc32(data)));
+        }
+    }
+
+    /// Flushes, and reads back the sectors just written with `buf` at the LBA `sector`
+    fn read_back(&self, sector: u64, sector_size: usize, buf: &[u8]) -> Result<(), DiskErr> {
+        self.disk.flush()?;
+
+        let mut read = vec![0; buf.len()];
+        self.disk.read_sectors(sector, sector_size, &mut read)?;
+
+        match read
+            .chunks(sector_size)
+            .zip(buf.chunks(sector_size))
+            .position(|(read, written)| read != written)
+        {
+            Some(i) => Err(DiskErrKind::VerificationFailed {
+                lba: sector + i as u64,
+            }
+            .into()),
+            None => {
+                self.record(sector, sector_size, buf);
+                Ok(())
+            }
+        }
+    }
+
+    fn write_inner(
+        &self,
+        sector: u64,
+        sector_size: usize,
+        buf: &[u8],
+        fua: bool,
+    ) -> Result<(), DiskErr> {
+        if fua {
+            self.disk.write_sectors_fua(sector, sector_size, buf)?;
+        } else {
+            self.disk.write_sectors(sector, sector_size, buf)?;
+        }
+
+        self.read_back(sector, sector_size, buf)
+    }
+}
+
+impl<D: Disk> Disk for VerifyingDisk<D> {
+    fn read_sector(&self, sector: u64, buf: &mut [u8]) -> Result<(), DiskErr> {
+        self.read_sectors(sector, buf.len(), buf)
+    }
+
+    fn write_sector(&self, sector: u64, buf: &[u8]) -> Result<(), DiskErr> {
+        self.write_sectors(sector, buf.len(), buf)
+    }
+
+    fn disk_infos(&self) -> Result<DiskInfos, DiskErr> {
+        self.disk.disk_infos()
+    }
+
+    fn read_sectors(&self, sector: u64, sector_size: usize, buf: &mut [u8]) -> Result<(), DiskErr> {
+        self.disk.read_sectors(sector, sector_size, buf)
+    }
+
+    fn write_sectors(&self, sector: u64, sector_size: usize, buf: &[u8]) -> Result<(), DiskErr> {
+        self.write_inner(sector, sector_size, buf, false)
+            .map_err(|e| e.at(Operation::Write, sector).in_layer("VerifyingDisk"))
+    }
+
+    fn write_sectors_fua(
+        &self,
+        sector: u64,
+        sector_size: usize,
+        buf: &[u8],
+    ) -> Result<(), DiskErr> {
+        self.write_inner(sector, sector_size, buf, true)
+            .map_err(|e| e.at(Operation::Write, sector).in_layer("VerifyingDisk"))
+    }
+
+    /// The discarded sectors are read back too, they must read as zeroes
+    fn discard(&self, sector: u64, sector_size: usize, count: u64) -> Result<(), DiskErr> {
+        self.disk.discard(sector, sector_size, count)?;
+
+        let zeroes = vec![0; VERIFY_BATCH * sector_size];
+        let mut done = 0;
+        while done < count {
+            let batch = (count - done).min(VERIFY_BATCH as u64) as usize;
+            self.read_back(sector + done, sector_size, &zeroes[..(batch * sector_size)])
+                .map_err(|e| e.at(Operation::Discard, sector).in_layer("VerifyingDisk"))?;
+            done += batch as u64;
+        }
+
+        Ok(())
+    }
+
+    fn flush(&self) -> Result<(), DiskErr> {
+        self.disk.flush()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{Permissions, SectorSize, memdisk::MemDisk};
+
+    /// A disk with a write cache, silently dropping the writes past `capacity` bytes when they are
+    /// flushed, like a fake-capacity USB stick
+    struct DroppingDisk {
+        disk: MemDisk,
+        capacity: u64,
+        /// The writes not flushed yet, by offset
+        cache: Mutex<Vec<(u64, Vec<u8>)>>,
+    }
+
+    impl DroppingDisk {
+        fn new(sectors: u64, capacity: u64) -> Self {
+            Self {
+                disk: MemDisk::new(
+                    sectors as usize * 512,
+                    SectorSize::AllOf(vec![512]),
+                    Permissions::read_write(),
+                ),
+                capacity: capacity * 512,
+                cache: Mutex::new(Vec::new()),
+            }
+        }
+    }
+
+    impl Disk for DroppingDisk {
+        fn read_sector(&self, sector: u64, buf: &mut [u8]) -> Result<(), DiskErr> {
+            self.read_sectors(sector, buf.len(), buf)
+        }
+
+        fn write_sector(&self, sector: u64, buf: &[u8]) -> Result<(), DiskErr> {
+            self.write_sectors(sector, buf.len(), buf)
+        }
+
+        fn disk_infos(&self) -> Result<DiskInfos, DiskErr> {
+            self.disk.disk_infos()
+        }
+
+        fn read_sectors(&self, sector: u64, size: usize, buf: &mut [u8]) -> Result<(), DiskErr> {
+            self.disk.read_sectors(sector, size, buf)?;
+
+            let start = sector * size as u64;
+            for (offset, data) in self.cache.lock().iter() {
+                for (i, b) in data.iter().enumerate() {
+                    let position = offset + i as u64;
+                    if (start..(start + buf.len() as u64)).contains(&position) {
+                        buf[(position - start) as usize] = *b;
+                    }
+                }
+            }
+
+            Ok(())
+        }
+
+        fn write_sectors(&self, sector: u64, size: usize, buf: &[u8]) -> Result<(), DiskErr> {
+            // Checks the request
+            self.disk
+                .read_sectors(sector, size, &mut vec![0; buf.len()])?;
+
+            self.cache.lock().push((sector * size as u64, buf.to_vec()));
+            Ok(())
+        }
+
+        fn flush(&self) -> Result<(), DiskErr> {
+            for (offset, data) in self.cache.lock().drain(..) {
+                for (i, sector) in data.chunks(512).enumerate() {
+                    let offset = offset + (i * 512) as u64;
+                    if offset < self.capacity {
+                        self.disk.write_sector(offset / 512, sector)?;
+                    }
+                }
+            }
+
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn dropped_writes() {
+        let disk = VerifyingDisk::new(DroppingDisk::new(64, 16));
+
+        disk.write_sectors(4, 512, &[1; 1024]).unwrap();
+        let err = disk.write_sectors(14, 512, &[2; 2048]).unwrap_err();
+        assert_eq!(err.kind, DiskErrKind::VerificationFailed { lba: 16 });
+        assert_eq!(err.context.operation, Some(Operation::Write));
+        assert_eq!(err.context.lba, Some(14));
+        assert_eq!(err.context.layer, Some("VerifyingDisk"));
+
+        let err = disk.write_sectors_fua(40, 512, &[3; 512]).unwrap_err();
+        assert_eq!(err.kind, DiskErrKind::VerificationFailed { lba: 40 });
+
+        assert_eq!(disk.written_sectors(), 2);
+        disk.verify_all().unwrap();
+    }
+
+    #[test]
+    fn verify_passes() {
+        let disk = VerifyingDisk::new(MemDisk::new(
+            64 * 512,
+            SectorSize::AllOf(vec![512, 4096]),
+            Permissions::read_write(),
+        ));
+
+        disk.write_sectors(0, 512, &[1; 2048]).unwrap();
+        disk.write_sectors(2, 4096, &[2; 4096]).unwrap();
+        disk.discard(3, 512, 1).unwrap();
+        assert_eq!(disk.written_sectors(), 5);
+        disk.verify_all().unwrap();
+
+        // Overwritten behind the wrapper
+        disk.inner().write_sector(2, &[9; 512]).unwrap();
+        disk.inner().write_sector(17, &[9; 512]).unwrap();
+
+        disk.verify_range(0, 512, 2).unwrap();
+        let err = disk.verify_range(0, 512, 64).unwrap_err();
+        assert_eq!(err.kind, DiskErrKind::VerificationFailed { lba: 2 });
+        assert_eq!(err.context.lba, Some(0));
+
+        let err = disk.verify_range(2, 4096, 1).unwrap_err();
+        assert_eq!(err.kind, DiskErrKind::VerificationFailed { lba: 2 });
+
+        disk.write_sector(2, &[1; 512]).unwrap();
+        let err = disk.verify_all().unwrap_err();
+        assert_eq!(err.kind, DiskErrKind::VerificationFailed { lba: 2 });
+        assert_eq!(err.context.operation, Some(Operation::Read));
+
+        disk.forget();
+        disk.verify_all().unwrap();
+    }
+}
@@ -0,0 +1,683 @@
+use crate::{
+    DeviceId, Disk, DiskErr, DiskErrKind, DiskInfos, Geometry, ImageErr, Operation, Permissions,
+    SectorSize,
+    std_helpers::{path_hash, random_guid},
+};
+use mutex::Mutex;
+use std::{
+    fs::{self, File},
+    io::{self, Read, Seek, SeekFrom, Write},
+    path::PathBuf,
+    time::SystemTime,
+};
+
+const FOOTER_SIZE: u64 = 512;
+const FOOTER_COOKIE: [u8; 8] = *b"conectix";
+const DYNAMIC_HEADER_SIZE: u64 = 1024;
+const DYNAMIC_COOKIE: [u8; 8] = *b"cxsparse";
+
+/// Version of the format, in the footer and in the dynamic header
+const FORMAT_VERSION: u32 = 0x0001_0000;
+/// The "reserved" feature, always set
+const FEATURES: u32 = 2;
+
+const TYPE_FIXED: u32 = 2;
+const TYPE_DYNAMIC: u32 = 3;
+const TYPE_DIFFERENCING: u32 = 4;
+
+const UNALLOCATED: u32 = 0xFFFF_FFFF;
+
+/// The sectors of the block bitmaps, always 512 bytes
+const SECTOR_SIZE: u64 = 512;
+
+/// Block size of the new dynamic images, the one of Hyper-V
+const BLOCK_SIZE: u64 = 2 << 20;
+
+/// Largest disk of the format: the CHS geometry of the footer can't describe more
+const MAX_SIZE: u64 = 65535 * 16 * 255 * SECTOR_SIZE;
+
+/// Seconds between the Unix epoch and the one of the format (2000-01-01 00:00:00 UTC)
+const VHD_EPOCH: u64 = 946_684_800;
+
+fn be32(raw: &[u8], offset: usize) -> u32 {
+    u32::from_be_bytes(raw[offset..(offset + 4)].try_into().unwrap())
+}
+
+fn be64(raw: &[u8], offset: usize) -> u64 {
+    u64::from_be_bytes(raw[offset..(offset + 8)].try_into().unwrap())
+}
+
+/// One's complement of the sum of the bytes, the checksum field being zeroed
+fn checksum(raw: &[u8], field: usize) -> u32 {
+    !raw.iter()
+        .enumerate()
+        .filter(|(i, _)| !(field..(field + 4)).contains(i))
+        .fold(0u32, |sum, (_, &b)| sum.wrapping_add(b as u32))
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum VhdType {
+    /// The raw disk, followed by the footer
+    Fixed,
+    /// The disk is split in blocks, allocated on the first write
+    Dynamic,
+}
+
+/// A VHD disk image, fixed or dynamic, as used by Virtual PC and Hyper-V. The sectors of the
+/// dynamic images not written yet read as zeroes.
+///
+/// Differencing images are not supported.
+pub struct VhdDisk {
+    vhd_type: VhdType,
+    size: u64,
+    permissions: Permissions,
+    device_id: Option<DeviceId>,
+    state: Mutex<State>,
+}
+
+impl Disk for VhdDisk {
+    fn read_sector(&self, sector: u64, buf: &mut [u8]) -> Result<(), DiskErr> {
+        self.read_sectors(sector, buf.len(), buf)
+    }
+
+    fn write_sector(&self, sector: u64, buf: &[u8]) -> Result<(), DiskErr> {
+        self.write_sectors(sector, buf.len(), buf)
+    }
+
+    fn disk_infos(&self) -> Result<DiskInfos, DiskErr> {
+        let block_size = self.state.lock().block_size;
+
+        Ok(DiskInfos {
+            sector_size: SectorSize::Any,
+            disk_size: self.size,
+            permissions: self.permissions,
+            geometry: Geometry {
+                optimal_io_size: block_size as usize,
+                ..Geometry::from_sector_size(&SectorSize::Any, self.size)
+            },
+            device_id: self.device_id.clone(),
+        })
+    }
+
+    fn read_sectors(&self, sector: u64, sector_size: usize, buf: &mut [u8]) -> Result<(), DiskErr> {
+        self.read_inner(sector, sector_size, buf)
+            .map_err(|e| e.at(Operation::Read, sector).in_layer("VhdDisk"))
+    }
+
+    fn write_sectors(&self, sector: u64, sector_size: usize, buf: &[u8]) -> Result<(), DiskErr> {
+        self.write_inner(sector, sector_size, buf)
+            .map_err(|e| e.at(Operation::Write, sector).in_layer("VhdDisk"))
+    }
+
+    fn flush(&self) -> Result<(), DiskErr> {
+        self.state.lock().file.sync_data().map_err(|e| {
+            DiskErr::from_io(&e)
+                .during(Operation::Flush)
+                .in_layer("VhdDisk")
+        })
+    }
+}
+
+impl VhdDisk {
+    /// Opens an existing image. The copy of the footer at the start of the dynamic images is used
+    /// if the one at the end is damaged.
+    pub fn open(path: PathBuf, permissions: Permissions) -> Result<Self, ImageErr> {
+        let io = |e: io::Error| ImageErr::Disk(DiskErr::from_io(&e).in_layer("VhdDisk"));
+        let invalid_header = |reason| ImageErr::InvalidHeader { reason };
+
+        // The metadata is read even on write-only disks
+        let mut file = File::options()
+            .read(true)
+            .write(permissions.write)
+            .open(&path)
+            .map_err(io)?;
+
+        let file_size = file.seek(SeekFrom::End(0)).map_err(io)?;
+        if file_size < FOOTER_SIZE {
+            return Err(invalid_header("not a VHD image"));
+        }
+
+        let read_at = |file: &mut File, offset: u64, len: u64| -> Result<Vec<u8>, ImageErr> {
+            let mut raw = vec![0; len as usize];
+            file.seek(SeekFrom::Start(offset))
+                .and_then(|_| file.read_exact(&mut raw))
+                .map_err(io)?;
+            Ok(raw)
+        };
+        let is_valid = |footer: &[u8]| {
+            footer[..8] == FOOTER_COOKIE && be32(footer, 64) == checksum(footer, 64)
+        };
+
+        // ### FOOTER ###
+
+        let mut footer = read_at(&mut file, file_size - FOOTER_SIZE, FOOTER_SIZE)?;
+        if !is_valid(&footer) {
+            let copy = read_at(&mut file, 0, FOOTER_SIZE)?;
+
+            match is_valid(&copy) && be32(&copy, 60) != TYPE_FIXED {
+                true => footer = copy,
+                false if footer[..8] == FOOTER_COOKIE => {
+                    return Err(invalid_header("footer checksum mismatch"));
+                }
+                false => return Err(invalid_header("not a VHD image")),
+            }
+        }
+
+        let size = be64(&footer, 48);
+        if !size.is_multiple_of(SECTOR_SIZE) {
+            return Err(invalid_header("invalid disk size"));
+        }
+
+        let (vhd_type, dynamic) = match be32(&footer, 60) {
+            TYPE_FIXED if size + FOOTER_SIZE <= file_size => (VhdType::Fixed, None),
+            TYPE_FIXED => return Err(invalid_header("truncated image")),
+            TYPE_DYNAMIC => (VhdType::Dynamic, Some(be64(&footer, 16))),
+            TYPE_DIFFERENCING => {
+                return Err(ImageErr::Unsupported {
+                    feature: "differencing VHD images".into(),
+                });
+            }
+            _ => return Err(invalid_header("invalid disk type")),
+        };
+
+        // ### DYNAMIC HEADER AND BLOCK ALLOCATION TABLE ###
+
+        let (block_size, bat_offset, bat) = match dynamic {
+            Some(offset) => {
+                if offset.saturating_add(DYNAMIC_HEADER_SIZE) > file_size {
+                    return Err(invalid_header("invalid dynamic header offset"));
+                }
+
+                let header = read_at(&mut file, offset, DYNAMIC_HEADER_SIZE)?;
+                if header[..8] != DYNAMIC_COOKIE || be32(&header, 36) != checksum(&header, 36) {
+                    return Err(invalid_header("invalid dynamic header"));
+                }
+
+                let bat_offset = be64(&header, 16);
+                let entries = be32(&header, 28) as u64;
+                let block_size = be32(&header, 32) as u64;
+
+                if !block_size.is_power_of_two() || block_size < SECTOR_SIZE {
+                    return Err(invalid_header("invalid block size"));
+                }
+                if entries < size.div_ceil(block_size)
+                    || bat_offset.saturating_add(entries * 4) > file_size
+                {
+                    return Err(invalid_header("invalid block allocation table"));
+                }
+
+                let bat = read_at(&mut file, bat_offset, entries * 4)?
+                    .chunks(4)
+                    .map(|e| be32(e, 0))
+                    .collect();
+                (block_size, bat_offset, bat)
+            }
+            None => (0, 0, Vec::new()),
+        };
+
+        let device_id = fs::canonicalize(&path)
+            .ok()
+            .map(|p| DeviceId::Image(path_hash(&p)));
+
+        Ok(Self {
+            vhd_type,
+            size,
+            permissions,
+            device_id,
+            state: Mutex::new(State {
+                file,
+                footer: footer.try_into().unwrap(),
+                footer_offset: file_size - FOOTER_SIZE,
+                block_size,
+                bitmap_size: (block_size / SECTOR_SIZE)
+                    .div_ceil(8)
+                    .next_multiple_of(SECTOR_SIZE),
+                bat_offset,
+                bat,
+                bitmap: None,
+            }),
+        })
+    }
+
+    /// Creates a new image of `size` bytes, a multiple of 512, and opens it. The blocks of the
+    /// dynamic images are 2 MiB large. Will result in an error if the file already exists.
+    pub fn create(path: PathBuf, size: u64, vhd_type: VhdType) -> Result<Self, ImageErr> {
+        if !size.is_multiple_of(SECTOR_SIZE) || size > MAX_SIZE {
+            return Err(ImageErr::Unsupported {
+                feature: format!("{size}-byte VHD disks"),
+            });
+        }
+
+        let io = |e: io::Error| ImageErr::Disk(DiskErr::from_io(&e).in_layer("VhdDisk"));
+        let mut file = File::create_new(&path).map_err(io)?;
+
+        match vhd_type {
+            VhdType::Fixed => {
+                let footer = footer(size, TYPE_FIXED, u64::MAX);
+
+                file.set_len(size)
+                    .and_then(|_| file.seek(SeekFrom::End(0)))
+                    .and_then(|_| file.write_all(&footer))
+                    .map_err(io)?;
+            }
+            VhdType::Dynamic => {
+                let entries = size.div_ceil(BLOCK_SIZE);
+                let bat_offset = FOOTER_SIZE + DYNAMIC_HEADER_SIZE;
+                let bat_size = (entries * 4).next_multiple_of(SECTOR_SIZE);
+
+                let footer = footer(size, TYPE_DYNAMIC, FOOTER_SIZE);
+
+                let mut header = vec![0; DYNAMIC_HEADER_SIZE as usize];
+                header[..8].copy_from_slice(&DYNAMIC_COOKIE);
+                header[8..16].copy_from_slice(&u64::MAX.to_be_bytes());
+                header[16..24].copy_from_slice(&bat_offset.to_be_bytes());
+                header[24..28].copy_from_slice(&FORMAT_VERSION.to_be_bytes());
+                header[28..32].copy_from_slice(&(entries as u32).to_be_bytes());
+                header[32..36].copy_from_slice(&(BLOCK_SIZE as u32).to_be_bytes());
+                let sum = checksum(&header, 36);
+                header[36..40].copy_from_slice(&sum.to_be_bytes());
+
+                // The unused end of the table is filled like the unallocated entries
+                let bat = vec![0xFF; bat_size as usize];
+
+                file.write_all(&footer)
+                    .and_then(|_| file.write_all(&header))
+                    .and_then(|_| file.write_all(&bat))
+                    .and_then(|_| file.write_all(&footer))
+                    .map_err(io)?;
+            }
+        }
+
+        file.sync_all().map_err(io)?;
+        drop(file);
+
+        Self::open(path, Permissions::read_write())
+    }
+
+    pub const fn vhd_type(&self) -> VhdType {
+        self.vhd_type
+    }
+
+    fn read_inner(&self, sector: u64, sector_size: usize, buf: &mut [u8]) -> Result<(), DiskErr> {
+        if !self.permissions.read {
+            return Err(DiskErrKind::InvalidPermission {
+                disk_permissions: self.permissions,
+            }
+            .into());
+        }
+
+        let offset = self.offset(sector, sector_size, buf.len() as u64)?;
+        self.state.lock().read_at(offset, buf)
+    }
+
+    fn write_inner(&self, sector: u64, sector_size: usize, buf: &[u8]) -> Result<(), DiskErr> {
+        if !self.permissions.write {
+            return Err(DiskErrKind::InvalidPermission {
+                disk_permissions: self.permissions,
+            }
+            .into());
+        }
+
+        let offset = self.offset(sector, sector_size, buf.len() as u64)?;
+        self.state.lock().write_at(offset, buf)
+    }
+
+    /// Checks a request of `len` bytes starting at the LBA `sector` and returns its offset in the
+    /// virtual disk.
+    fn offset(&self, sector: u64, sector_size: usize, len: u64) -> Result<u64, DiskErr> {
+        if sector_size == 0
+            || sector_size as u64 > self.size
+            || !len.is_multiple_of(sector_size as u64)
+        {
+            return Err(DiskErrKind::InvalidSectorSize {
+                found: sector_size,
+                supported: SectorSize::Any,
+                start: 0,
+            }
+            .into());
+        }
+
+        match sector.checked_mul(sector_size as u64) {
+            Some(offset) if offset.checked_add(len).is_some_and(|end| end <= self.size) => {
+                Ok(offset)
+            }
+            _ => Err(DiskErrKind::InvalidSectorIndex {
+                found: sector,
+                max: (self.size / sector_size as u64).saturating_sub(1),
+            }
+            .into()),
+        }
+    }
+}
+
+/// The footer of a new image
+fn footer(size: u64, disk_type: u32, data_offset: u64) -> [u8; FOOTER_SIZE as usize] {
+    let timestamp = SystemTime::now()
+        .duration_since(SystemTime::UNIX_EPOCH)
+        .map_or(0, |d| d.as_secs().saturating_sub(VHD_EPOCH));
+
+    let mut footer = [0; FOOTER_SIZE as usize];
+    footer[..8].copy_from_slice(&FOOTER_COOKIE);
+    footer[8..12].copy_from_slice(&FEATURES.to_be_bytes());
+    footer[12..16].copy_from_slice(&FORMAT_VERSION.to_be_bytes());
+    footer[16..24].copy_from_slice(&data_offset.to_be_bytes());
+    footer[24..28].copy_from_slice(&(timestamp as u32).to_be_bytes());
+    footer[28..32].copy_from_slice(b"pfs ");
+    footer[32..36].copy_from_slice(&FORMAT_VERSION.to_be_bytes());
+    footer[36..40].copy_from_slice(b"Wi2k");
+    footer[40..48].copy_from_slice(&size.to_be_bytes());
+    footer[48..56].copy_from_slice(&size.to_be_bytes());
+    footer[56..60].copy_from_slice(&chs_geometry(size));
+    footer[60..64].copy_from_slice(&disk_type.to_be_bytes());
+    footer[68..84].copy_from_slice(&random_guid());
+
+    let sum = checksum(&footer, 64);
+    footer[64..68].copy_from_slice(&sum.to_be_bytes());
+    footer
+}
+
+/// The CHS geometry of a disk of `size` bytes (cylinders, heads, sectors per track), computed as
+/// the specification of the format does
+fn chs_geometry(size: u64) -> [u8; 4] {
+    let total = (size / SECTOR_SIZE).min(65535 * 16 * 255);
+
+    let (sectors, heads, cylinders_heads) = if total >= 65535 * 16 * 63 {
+        (255, 16, total / 255)
+    } else {
+        let mut sectors = 17;
+        let mut cylinders_heads = total / sectors;
+        let mut heads = cylinders_heads.div_ceil(1024).max(4);
+
+        if cylinders_heads >= heads * 1024 || heads > 16 {
+            sectors = 31;
+            heads = 16;
+            cylinders_heads = total / sectors;
+        }
+        if cylinders_heads >= heads * 1024 {
+            sectors = 63;
+            heads = 16;
+            cylinders_heads = total / sectors;
+        }
+
+        (sectors, heads, cylinders_heads)
+    };
+
+    let cylinders = (cylinders_heads / heads) as u16;
+    let [c0, c1] = cylinders.to_be_bytes();
+    [c0, c1, heads as u8, sectors as u8]
+}
+
+fn invalid(reason: &'static str) -> DiskErr {
+    DiskErr::new(DiskErrKind::InvalidImage { reason })
+}
+
+/// The mutable part of the image, behind the lock. The metadata is written through.
+struct State {
+    file: File,
+    footer: [u8; FOOTER_SIZE as usize],
+    /// Position of the footer at the end of the file, where the new blocks are put
+    footer_offset: u64,
+    /// Dynamic images only: size of the blocks, and of the sector bitmap before each of them
+    block_size: u64,
+    bitmap_size: u64,
+    bat_offset: u64,
+    bat: Vec<u32>,
+    /// The sector bitmap of the last block accessed
+    bitmap: Option<(usize, Vec<u8>)>,
+}
+
+impl State {
+    fn read_file(&mut self, offset: u64, buf: &mut [u8]) -> Result<(), DiskErr> {
+        self.file
+            .seek(SeekFrom::Start(offset))
+            .and_then(|_| self.file.read_exact(buf))
+            .map_err(|e| DiskErr::from_io(&e))
+    }
+
+    fn write_file(&mut self, offset: u64, buf: &[u8]) -> Result<(), DiskErr> {
+        self.file
+            .seek(SeekFrom::Start(offset))
+            .and_then(|_| self.file.write_all(buf))
+            .map_err(|e| DiskErr::from_io(&e))
+    }
+
+    /// Splits a request in the parts of each block: `(block, offset in the block, range of the
+    /// request)`
+    fn blocks(&self, offset: u64, len: usize) -> impl Iterator<Item = (usize, u64, usize, usize)> {
+        let block_size = self.block_size;
+        let mut done = 0;
+
+        core::iter::from_fn(move || {
+            if done == len {
+                return None;
+            }
+
+            let pos = offset + done as u64;
+            let in_block = pos % block_size;
+            let part = ((block_size - in_block) as usize).min(len - done);
+            let start = done;
+            done += part;
+
+            Some(((pos / block_size) as usize, in_block, start, done))
+        })
+    }
+
+    fn bitmap(&mut self, block: usize) -> Result<&mut Vec<u8>, DiskErr> {
+        if self.bitmap.as_ref().is_none_or(|(b, _)| *b != block) {
+            let mut bitmap = vec![0; self.bitmap_size as usize];
+            self.read_file(self.bat[block] as u64 * SECTOR_SIZE, &mut bitmap)?;
+            self.bitmap = Some((block, bitmap));
+        }
+
+        Ok(&mut self.bitmap.as_mut().unwrap().1)
+    }
+
+    // ### READS ###
+
+    fn read_at(&mut self, offset: u64, buf: &mut [u8]) -> Result<(), DiskErr> {
+        if self.block_size == 0 {
+            return self.read_file(offset, buf);
+        }
+
+        let blocks: Vec<_> = self.blocks(offset, buf.len()).collect();
+        for (block, in_block, start, end) in blocks {
+            let chunk = &mut buf[start..end];
+
+            let base = match self.bat.get(block) {
+                Some(&UNALLOCATED) => {
+                    chunk.fill(0);
+                    continue;
+                }
+                Some(&sector) => sector as u64 * SECTOR_SIZE,
+                None => return Err(invalid("block allocation table too small")),
+            };
+
+            self.read_file(base + self.bitmap_size + in_block, chunk)?;
+
+            // The sectors not written yet read as zeroes, whatever the block holds
+            let len = chunk.len();
+            let bitmap = self.bitmap(block)?;
+            let first = in_block / SECTOR_SIZE;
+            for sector in first..(in_block + len as u64).div_ceil(SECTOR_SIZE) {
+                if bitmap[(sector / 8) as usize] & (0x80 >> (sector % 8)) == 0 {
+                    let from = (sector * SECTOR_SIZE).saturating_sub(in_block) as usize;
+                    let to = ((sector + 1) * SECTOR_SIZE - in_block) as usize;
+                    chunk[from..to.min(len)].fill(0);
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    // ### WRITES ###
+
+    fn write_at(&mut self, offset: u64, buf: &[u8]) -> Result<(), DiskErr> {
+        if self.block_size == 0 {
+            return self.write_file(offset, buf);
+        }
+
+        // The bitmaps have a bit per sector: the partial sectors are completed first
+        let start = offset - offset % SECTOR_SIZE;
+        let end = (offset + buf.len() as u64).next_multiple_of(SECTOR_SIZE);
+
+        if start == offset && end == offset + buf.len() as u64 {
+            return self.write_sectors(offset, buf);
+        }
+
+        let mut aligned = vec![0; (end - start) as usize];
+        let last = aligned.len() - SECTOR_SIZE as usize;
+        self.read_at(start, &mut aligned[..(SECTOR_SIZE as usize)])?;
+        self.read_at(end - SECTOR_SIZE, &mut aligned[last..])?;
+
+        let head = (offset - start) as usize;
+        aligned[head..(head + buf.len())].copy_from_slice(buf);
+        self.write_sectors(start, &aligned)
+    }
+
+    /// Writes whole sectors of a dynamic image, allocating the blocks and marking the sectors as
+    /// written in their bitmap
+    fn write_sectors(&mut self, offset: u64, buf: &[u8]) -> Result<(), DiskErr> {
+        let blocks: Vec<_> = self.blocks(offset, buf.len()).collect();
+
+        for (block, in_block, start, end) in blocks {
+            let base = match self.bat.get(block) {
+                Some(&UNALLOCATED) => self.allocate_block(block)?,
+                Some(&sector) => sector as u64 * SECTOR_SIZE,
+                None => return Err(invalid("block allocation table too small")),
+            };
+
+            self.write_file(base + self.bitmap_size + in_block, &buf[start..end])?;
+
+            let first = in_block / SECTOR_SIZE;
+            let last = (in_block + (end - start) as u64) / SECTOR_SIZE;
+            let bitmap = self.bitmap(block)?;
+            let mut changed = false;
+            for sector in first..last {
+                let (byte, bit) = ((sector / 8) as usize, 0x80 >> (sector % 8));
+                changed |= bitmap[byte] & bit == 0;
+                bitmap[byte] |= bit;
+            }
+
+            if changed {
+                let range = ((first / 8) as usize)..((last - 1) / 8 + 1) as usize;
+                let bytes = bitmap[range.clone()].to_vec();
+                self.write_file(base + range.start as u64, &bytes)?;
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Puts a new block in place of the footer, which is moved after it. Returns the position of
+    /// the block.
+    fn allocate_block(&mut self, block: usize) -> Result<u64, DiskErr> {
+        let base = self.footer_offset;
+        let sector = match u32::try_from(base / SECTOR_SIZE) {
+            Ok(v) if v != UNALLOCATED && base.is_multiple_of(SECTOR_SIZE) => v,
+            _ => return Err(invalid("no block offset left")),
+        };
+
+        // The bitmap overwrites the old footer, the data reads as zeroes as it extends the file
+        let end = base + self.bitmap_size + self.block_size;
+        self.write_file(base, &vec![0; self.bitmap_size as usize])?;
+        self.file.set_len(end).map_err(|e| DiskErr::from_io(&e))?;
+        self.write_file(end, &self.footer.clone())?;
+        self.footer_offset = end;
+
+        self.bat[block] = sector;
+        self.write_file(self.bat_offset + block as u64 * 4, &sector.to_be_bytes())?;
+        self.bitmap = Some((block, vec![0; self.bitmap_size as usize]));
+
+        Ok(base)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::std_helpers::TempPath;
+
+    #[test]
+    fn create_and_reopen() {
+        for vhd_type in [VhdType::Fixed, VhdType::Dynamic] {
+            let temp = TempPath::new(&format!("create-{vhd_type:?}.vhd"));
+            let data: Vec<u8> = (0..(3 * 4096)).map(|i| (i % 253) as u8).collect();
+            let size = 3 * BLOCK_SIZE;
+
+            let disk = VhdDisk::create(temp.path.clone(), size, vhd_type).unwrap();
+            assert!(VhdDisk::create(temp.path.clone(), size, vhd_type).is_err());
+            disk.write_sectors(1, 512, &data[..4096]).unwrap();
+            // Across a block boundary
+            disk.write_sectors(BLOCK_SIZE / 4096 - 1, 4096, &data)
+                .unwrap();
+            disk.flush().unwrap();
+            drop(disk);
+
+            let disk = VhdDisk::open(temp.path.clone(), Permissions::read_only()).unwrap();
+            assert_eq!(disk.vhd_type(), vhd_type);
+            assert_eq!(disk.disk_infos().unwrap().disk_size, size);
+
+            let mut buf = vec![0; 3 * 4096];
+            disk.read_sectors(0, 512, &mut buf[..4608]).unwrap();
+            assert_eq!(buf[..512], [0; 512]);
+            assert_eq!(buf[512..4608], data[..4096]);
+            disk.read_sectors(BLOCK_SIZE / 4096 - 1, 4096, &mut buf)
+                .unwrap();
+            assert_eq!(buf, data);
+            disk.read_sectors(size / 4096 - 1, 4096, &mut buf[..4096])
+                .unwrap();
+            assert_eq!(buf[..4096], [0; 4096]);
+
+            assert!(disk.write_sectors(0, 512, &[0; 512]).is_err());
+            assert!(
+                disk.read_sectors(size / 4096, 4096, &mut buf[..4096])
+                    .is_err()
+            );
+        }
+    }
+
+    #[test]
+    fn malformed_headers() {
+        let temp = TempPath::new("malformed.vhd");
+        let open = |image: &[u8]| {
+            fs::write(&temp.path, image).unwrap();
+            VhdDisk::open(temp.path.clone(), Permissions::read_only()).err()
+        };
+        let invalid = |reason| Some(ImageErr::InvalidHeader { reason });
+
+        drop(VhdDisk::create(temp.path.clone(), BLOCK_SIZE, VhdType::Fixed).unwrap());
+        let fixed = fs::read(&temp.path).unwrap();
+        fs::remove_file(&temp.path).unwrap();
+        drop(VhdDisk::create(temp.path.clone(), BLOCK_SIZE, VhdType::Dynamic).unwrap());
+        let dynamic = fs::read(&temp.path).unwrap();
+        let end = fixed.len() - FOOTER_SIZE as usize;
+
+        assert_eq!(open(&fixed[..100]), invalid("not a VHD image"));
+        assert_eq!(open(&fixed[end..]), invalid("truncated image"));
+
+        let mut bad = fixed.clone();
+        bad[end + 20] ^= 1;
+        assert_eq!(open(&bad), invalid("footer checksum mismatch"));
+        bad[end] = b'C';
+        assert_eq!(open(&bad), invalid("not a VHD image"));
+
+        // The copy of the footer at the start replaces the damaged one
+        let mut bad = dynamic.clone();
+        let end = dynamic.len() - FOOTER_SIZE as usize;
+        bad[end + 20] ^= 1;
+        assert_eq!(open(&bad), None);
+
+        let mut bad = dynamic.clone();
+        bad[FOOTER_SIZE as usize + 40] ^= 1;
+        assert_eq!(open(&bad), invalid("invalid dynamic header"));
+
+        let mut bad = dynamic.clone();
+        for footer in [0, end] {
+            bad[footer + 60..footer + 64].copy_from_slice(&7u32.to_be_bytes());
+            let sum = checksum(&bad[footer..footer + 512], 64);
+            bad[footer + 64..footer + 68].copy_from_slice(&sum.to_be_bytes());
+        }
+        assert_eq!(open(&bad), invalid("invalid disk type"));
+    }
+}
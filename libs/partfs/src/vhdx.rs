@@ -0,0 +1,966 @@
+use crate::{
+    DeviceId, Disk, DiskErr, DiskErrKind, DiskInfos, Geometry, ImageErr, Operation, Permissions,
+    SectorSize,
+    checksum::crc32c,
+    std_helpers::{path_hash, random_guid},
+};
+use mutex::Mutex;
+use std::{
+    fs::{self, File},
+    io::{self, Read, Seek, SeekFrom, Write},
+    path::PathBuf,
+};
+
+const FILE_SIGNATURE: [u8; 8] = *b"vhdxfile";
+const HEADER_SIGNATURE: [u8; 4] = *b"head";
+const REGION_SIGNATURE: [u8; 4] = *b"regi";
+const METADATA_SIGNATURE: [u8; 8] = *b"metadata";
+const ENTRY_SIGNATURE: [u8; 4] = *b"loge";
+const ZERO_SIGNATURE: [u8; 4] = *b"zero";
+const DESCRIPTOR_SIGNATURE: [u8; 4] = *b"desc";
+const DATA_SIGNATURE: [u8; 4] = *b"data";
+
+/// The two copies of the header and of the region table
+const HEADER_OFFSETS: [u64; 2] = [64 << 10, 128 << 10];
+const REGION_TABLE_OFFSETS: [u64; 2] = [192 << 10, 256 << 10];
+
+const HEADER_SIZE: usize = 4096;
+const REGION_TABLE_SIZE: usize = 64 << 10;
+const METADATA_TABLE_SIZE: usize = 64 << 10;
+const MAX_REGIONS: usize = 2047;
+const MAX_METADATA_ITEMS: usize = 2047;
+
+/// Size of the log sectors, and alignment of the log entries
+const LOG_SECTOR_SIZE: usize = 4096;
+const LOG_ENTRY_HEADER_SIZE: usize = 64;
+const LOG_DESCRIPTOR_SIZE: usize = 32;
+
+/// Alignment of the regions and of the payload blocks
+const ALIGNMENT: u64 = 1 << 20;
+
+const REGION_BAT: [u8; 16] = [
+    0x66, 0x77, 0xC2, 0x2D, 0x23, 0xF6, 0x00, 0x42, 0x9D, 0x64, 0x11, 0x5E, 0x9B, 0xFD, 0x4A, 0x08,
+];
+const REGION_METADATA: [u8; 16] = [
+    0x06, 0xA2, 0x7C, 0x8B, 0x90, 0x47, 0x9A, 0x4B, 0xB8, 0xFE, 0x57, 0x5F, 0x05, 0x0F, 0x88, 0x6E,
+];
+
+const ITEM_FILE_PARAMETERS: [u8; 16] = [
+    0x37, 0x67, 0xA1, 0xCA, 0x36, 0xFA, 0x43, 0x4D, 0xB3, 0xB6, 0x33, 0xF0, 0xAA, 0x44, 0xE7, 0x6B,
+];
+const ITEM_VIRTUAL_DISK_SIZE: [u8; 16] = [
+    0x24, 0x42, 0xA5, 0x2F, 0x1B, 0xCD, 0x76, 0x48, 0xB2, 0x11, 0x5D, 0xBE, 0xD8, 0x3B, 0xF4, 0xB8,
+];
+const ITEM_VIRTUAL_DISK_ID: [u8; 16] = [
+    0xAB, 0x12, 0xCA, 0xBE, 0xE6, 0xB2, 0x23, 0x45, 0x93, 0xEF, 0xC3, 0x09, 0xE0, 0x00, 0xC7, 0x46,
+];
+const ITEM_LOGICAL_SECTOR_SIZE: [u8; 16] = [
+    0x1D, 0xBF, 0x41, 0x81, 0x6F, 0xA9, 0x09, 0x47, 0xBA, 0x47, 0xF2, 0x33, 0xA8, 0xFA, 0xAB, 0x5F,
+];
+const ITEM_PHYSICAL_SECTOR_SIZE: [u8; 16] = [
+    0xC7, 0x48, 0xA3, 0xCD, 0x5D, 0x44, 0x71, 0x44, 0x9C, 0xC9, 0xE9, 0x88, 0x52, 0x51, 0xC5, 0x56,
+];
+
+const REGION_REQUIRED: u32 = 1 << 0;
+const ITEM_IS_VIRTUAL_DISK: u32 = 1 << 1;
+const ITEM_IS_REQUIRED: u32 = 1 << 2;
+const PARAMETERS_HAS_PARENT: u32 = 1 << 1;
+
+/// States of the payload blocks in the BAT
+const BLOCK_NOT_PRESENT: u64 = 0;
+const BLOCK_UNDEFINED: u64 = 1;
+const BLOCK_ZERO: u64 = 2;
+const BLOCK_UNMAPPED: u64 = 3;
+const BLOCK_FULLY_PRESENT: u64 = 6;
+const BAT_STATE_MASK: u64 = 7;
+const BAT_OFFSET_MASK: u64 = !(ALIGNMENT - 1);
+
+/// Block size of the new images when none is given, the default of Hyper-V
+pub const DEFAULT_BLOCK_SIZE: usize = 32 << 20;
+
+const MIN_BLOCK_SIZE: u64 = 1 << 20;
+const MAX_BLOCK_SIZE: u64 = 256 << 20;
+const MAX_SIZE: u64 = 64 << 40;
+
+/// Layout of the new images: the log, the metadata region and the BAT, each 1 MiB aligned
+const LOG_OFFSET: u64 = 1 << 20;
+const LOG_LENGTH: u32 = 1 << 20;
+const METADATA_OFFSET: u64 = 2 << 20;
+const METADATA_LENGTH: u32 = 1 << 20;
+const BAT_OFFSET: u64 = 3 << 20;
+
+fn le16(raw: &[u8], offset: usize) -> u16 {
+    u16::from_le_bytes(raw[offset..(offset + 2)].try_into().unwrap())
+}
+
+fn le32(raw: &[u8], offset: usize) -> u32 {
+    u32::from_le_bytes(raw[offset..(offset + 4)].try_into().unwrap())
+}
+
+fn le64(raw: &[u8], offset: usize) -> u64 {
+    u64::from_le_bytes(raw[offset..(offset + 8)].try_into().unwrap())
+}
+
+/// CRC32C of `raw`, the checksum field at offset 4 being zeroed
+fn checksum(raw: &[u8]) -> u32 {
+    let mut copy = raw.to_vec();
+    copy[4..8].fill(0);
+    crc32c(&copy)
+}
+
+fn with_checksum(raw: &mut [u8]) {
+    let sum = checksum(raw);
+    raw[4..8].copy_from_slice(&sum.to_le_bytes());
+}
+
+/// A VHDX disk image, as used by Hyper-V. The blocks not written yet read as zeroes. A log left by
+/// an interrupted update is replayed when the image is opened.
+///
+/// The metadata updates are written in place, the log of the image is not used for them.
+/// Differencing images are not supported.
+pub struct VhdxDisk {
+    size: u64,
+    block_size: u64,
+    logical_sector_size: u32,
+    physical_sector_size: u32,
+    disk_id: [u8; 16],
+    permissions: Permissions,
+    device_id: Option<DeviceId>,
+    state: Mutex<State>,
+}
+
+impl Disk for VhdxDisk {
+    fn read_sector(&self, sector: u64, buf: &mut [u8]) -> Result<(), DiskErr> {
+        self.read_sectors(sector, buf.len(), buf)
+    }
+
+    fn write_sector(&self, sector: u64, buf: &[u8]) -> Result<(), DiskErr> {
+        self.write_sectors(sector, buf.len(), buf)
+    }
+
+    fn disk_infos(&self) -> Result<DiskInfos, DiskErr> {
+        Ok(DiskInfos {
+            sector_size: SectorSize::Any,
+            disk_size: self.size,
+            permissions: self.permissions,
+            geometry: Geometry {
+                logical_sector_size: self.logical_sector_size as usize,
+                physical_sector_size: self.physical_sector_size as usize,
+                optimal_io_size: self.block_size as usize,
+                ..Geometry::from_sector_size(&SectorSize::Any, self.size)
+            },
+            device_id: self.device_id.clone(),
+        })
+    }
+
+    fn read_sectors(&self, sector: u64, sector_size: usize, buf: &mut [u8]) -> Result<(), DiskErr> {
+        self.read_inner(sector, sector_size, buf)
+            .map_err(|e| e.at(Operation::Read, sector).in_layer("VhdxDisk"))
+    }
+
+    fn write_sectors(&self, sector: u64, sector_size: usize, buf: &[u8]) -> Result<(), DiskErr> {
+        self.write_inner(sector, sector_size, buf)
+            .map_err(|e| e.at(Operation::Write, sector).in_layer("VhdxDisk"))
+    }
+
+    fn flush(&self) -> Result<(), DiskErr> {
+        self.state.lock().file.sync_data().map_err(|e| {
+            DiskErr::from_io(&e)
+                .during(Operation::Flush)
+                .in_layer("VhdxDisk")
+        })
+    }
+}
+
+impl VhdxDisk {
+    /// Opens an existing image. If the log of the image holds updates not applied yet, they are
+    /// replayed first, which needs the write permission.
+    pub fn open(path: PathBuf, permissions: Permissions) -> Result<Self, ImageErr> {
+        let io = |e: io::Error| ImageErr::Disk(DiskErr::from_io(&e).in_layer("VhdxDisk"));
+        let invalid_header = |reason| ImageErr::InvalidHeader { reason };
+
+        // The metadata is read even on write-only disks
+        let mut file = File::options()
+            .read(true)
+            .write(permissions.write)
+            .open(&path)
+            .map_err(io)?;
+
+        if read_at(&mut file, 0, 8)? != FILE_SIGNATURE {
+            return Err(invalid_header("not a VHDX image"));
+        }
+
+        // ### HEADERS ###
+
+        // The valid header with the highest sequence number is the current one
+        let mut current: Option<(usize, Vec<u8>)> = None;
+        for (slot, offset) in HEADER_OFFSETS.into_iter().enumerate() {
+            let raw = read_at(&mut file, offset, HEADER_SIZE)?;
+            let valid = raw[..4] == HEADER_SIGNATURE
+                && le32(&raw, 4) == checksum(&raw)
+                && le16(&raw, 66) == 1;
+
+            if valid
+                && current
+                    .as_ref()
+                    .is_none_or(|(_, h)| le64(&raw, 8) > le64(h, 8))
+            {
+                current = Some((slot, raw));
+            }
+        }
+        let (mut slot, header) = current.ok_or(invalid_header("no valid header"))?;
+        let mut header: [u8; HEADER_SIZE] = header.try_into().unwrap();
+
+        // ### LOG REPLAY ###
+
+        if header[48..64] != [0; 16] {
+            if !permissions.write {
+                return Err(ImageErr::Unsupported {
+                    feature: "replaying the log of a read-only VHDX image".into(),
+                });
+            }
+
+            let (log_offset, log_length) = (le64(&header, 72), le32(&header, 68) as usize);
+            if !log_offset.is_multiple_of(ALIGNMENT)
+                || log_length == 0
+                || !(log_length as u64).is_multiple_of(ALIGNMENT)
+            {
+                return Err(invalid_header("invalid log location"));
+            }
+
+            let log = read_at(&mut file, log_offset, log_length)?;
+            replay_log(&mut file, &log, &header[48..64]).map_err(|e| match e {
+                Some(e) => io(e),
+                None => invalid_header("damaged log"),
+            })?;
+
+            update_header(&mut file, &mut header, &mut slot, [0; 16]).map_err(io)?;
+        }
+
+        // ### REGION TABLE ###
+
+        let mut regions = None;
+        for offset in REGION_TABLE_OFFSETS {
+            let raw = read_at(&mut file, offset, REGION_TABLE_SIZE)?;
+            if raw[..4] == REGION_SIGNATURE
+                && le32(&raw, 4) == checksum(&raw)
+                && le32(&raw, 8) as usize <= MAX_REGIONS
+            {
+                regions = Some(raw);
+                break;
+            }
+        }
+        let regions = regions.ok_or(invalid_header("no valid region table"))?;
+
+        let (mut bat_region, mut metadata_region) = (None, None);
+        for entry in regions[16..].chunks(32).take(le32(&regions, 8) as usize) {
+            let location = (le64(entry, 16), le32(entry, 24) as usize);
+
+            match entry[..16].try_into().unwrap() {
+                REGION_BAT => bat_region = Some(location),
+                REGION_METADATA => metadata_region = Some(location),
+                _ if le32(entry, 28) & REGION_REQUIRED != 0 => {
+                    return Err(ImageErr::Unsupported {
+                        feature: "unknown required VHDX region".into(),
+                    });
+                }
+                _ => {}
+            }
+        }
+        let (bat_offset, bat_length) = bat_region.ok_or(invalid_header("no BAT region"))?;
+        let (metadata_offset, metadata_length) =
+            metadata_region.ok_or(invalid_header("no metadata region"))?;
+
+        // ### METADATA ###
+
+        if metadata_length < METADATA_TABLE_SIZE {
+            return Err(invalid_header("invalid metadata region"));
+        }
+        let metadata = read_at(&mut file, metadata_offset, METADATA_TABLE_SIZE)?;
+        let count = le16(&metadata, 10) as usize;
+        if metadata[..8] != METADATA_SIGNATURE || count > MAX_METADATA_ITEMS {
+            return Err(invalid_header("invalid metadata table"));
+        }
+
+        let mut items: [Option<Vec<u8>>; 5] = Default::default();
+        for entry in metadata[32..].chunks(32).take(count) {
+            let (offset, length) = (le32(entry, 16) as usize, le32(entry, 20) as usize);
+            let index = match entry[..16].try_into().unwrap() {
+                ITEM_FILE_PARAMETERS => 0,
+                ITEM_VIRTUAL_DISK_SIZE => 1,
+                ITEM_VIRTUAL_DISK_ID => 2,
+                ITEM_LOGICAL_SECTOR_SIZE => 3,
+                ITEM_PHYSICAL_SECTOR_SIZE => 4,
+                _ if le32(entry, 24) & ITEM_IS_REQUIRED != 0 => {
+                    return Err(ImageErr::Unsupported {
+                        feature: "unknown required VHDX metadata item".into(),
+                    });
+                }
+                _ => continue,
+            };
+
+            const ITEM_SIZES: [usize; 5] = [8, 8, 16, 4, 4];
+            if offset < METADATA_TABLE_SIZE
+                || length < ITEM_SIZES[index]
+                || offset.saturating_add(length) > metadata_length
+            {
+                return Err(invalid_header("invalid metadata item"));
+            }
+            items[index] = Some(read_at(
+                &mut file,
+                metadata_offset + offset as u64,
+                ITEM_SIZES[index],
+            )?);
+        }
+        let [
+            Some(parameters),
+            Some(size),
+            Some(disk_id),
+            Some(lss),
+            Some(pss),
+        ] = items
+        else {
+            return Err(invalid_header("missing metadata item"));
+        };
+
+        let block_size = le32(&parameters, 0) as u64;
+        let size = le64(&size, 0);
+        let logical_sector_size = le32(&lss, 0);
+        let physical_sector_size = le32(&pss, 0);
+
+        if le32(&parameters, 4) & PARAMETERS_HAS_PARENT != 0 {
+            return Err(ImageErr::Unsupported {
+                feature: "differencing VHDX images".into(),
+            });
+        }
+        if !block_size.is_power_of_two() || !(MIN_BLOCK_SIZE..=MAX_BLOCK_SIZE).contains(&block_size)
+        {
+            return Err(invalid_header("invalid block size"));
+        }
+        if !matches!(logical_sector_size, 512 | 4096) || !matches!(physical_sector_size, 512 | 4096)
+        {
+            return Err(invalid_header("invalid sector size"));
+        }
+        if size == 0 || size > MAX_SIZE || !size.is_multiple_of(logical_sector_size as u64) {
+            return Err(invalid_header("invalid disk size"));
+        }
+
+        // ### BLOCK ALLOCATION TABLE ###
+
+        // A sector bitmap entry follows each chunk of payload entries
+        let chunk_ratio = (1 << 23) * logical_sector_size as u64 / block_size;
+        let blocks = size.div_ceil(block_size);
+        let entries = blocks + (blocks - 1) / chunk_ratio;
+
+        if (bat_length as u64) < entries * 8 {
+            return Err(invalid_header("BAT region too small"));
+        }
+        let bat = read_at(&mut file, bat_offset, (entries * 8) as usize)?
+            .chunks(8)
+            .map(|e| le64(e, 0))
+            .collect();
+
+        let file_size = file.seek(SeekFrom::End(0)).map_err(io)?;
+        let device_id = fs::canonicalize(&path)
+            .ok()
+            .map(|p| DeviceId::Image(path_hash(&p)));
+
+        Ok(Self {
+            size,
+            block_size,
+            logical_sector_size,
+            physical_sector_size,
+            disk_id: disk_id.try_into().unwrap(),
+            permissions,
+            device_id,
+            state: Mutex::new(State {
+                file,
+                file_size,
+                header,
+                slot,
+                header_updated: false,
+                block_size,
+                chunk_ratio,
+                bat_offset,
+                bat,
+            }),
+        })
+    }
+
+    /// Creates a new image of `size` bytes, a multiple of 512, with 512-byte logical sectors, and
+    /// opens it. The block size must be a power of two between 1 MiB and 256 MiB. Will result in
+    /// an error if the file already exists.
+    pub fn create(path: PathBuf, size: u64, block_size: Option<usize>) -> Result<Self, ImageErr> {
+        let block_size = block_size.unwrap_or(DEFAULT_BLOCK_SIZE) as u64;
+        if !block_size.is_power_of_two() || !(MIN_BLOCK_SIZE..=MAX_BLOCK_SIZE).contains(&block_size)
+        {
+            return Err(ImageErr::Unsupported {
+                feature: format!("{block_size}-byte VHDX blocks"),
+            });
+        }
+        if size == 0 || size > MAX_SIZE || !size.is_multiple_of(512) {
+            return Err(ImageErr::Unsupported {
+                feature: format!("{size}-byte VHDX disks"),
+            });
+        }
+
+        let chunk_ratio = (1 << 23) * 512 / block_size;
+        let blocks = size.div_ceil(block_size);
+        let bat_length = ((blocks + (blocks - 1) / chunk_ratio) * 8).next_multiple_of(ALIGNMENT);
+
+        // ### FILE IDENTIFIER AND HEADERS ###
+
+        let mut identifier = vec![0; 64 << 10];
+        identifier[..8].copy_from_slice(&FILE_SIGNATURE);
+        for (i, c) in "partfs".encode_utf16().enumerate() {
+            identifier[(8 + 2 * i)..(10 + 2 * i)].copy_from_slice(&c.to_le_bytes());
+        }
+
+        let headers: [[u8; HEADER_SIZE]; 2] = core::array::from_fn(|i| {
+            let mut header = [0; HEADER_SIZE];
+            header[..4].copy_from_slice(&HEADER_SIGNATURE);
+            header[8..16].copy_from_slice(&(i as u64).to_le_bytes());
+            header[16..32].copy_from_slice(&random_guid());
+            header[66..68].copy_from_slice(&1u16.to_le_bytes());
+            header[68..72].copy_from_slice(&LOG_LENGTH.to_le_bytes());
+            header[72..80].copy_from_slice(&LOG_OFFSET.to_le_bytes());
+            with_checksum(&mut header);
+            header
+        });
+
+        // ### REGION TABLE ###
+
+        let mut regions = vec![0; REGION_TABLE_SIZE];
+        regions[..4].copy_from_slice(&REGION_SIGNATURE);
+        regions[8..12].copy_from_slice(&2u32.to_le_bytes());
+        let region_entries = [
+            (REGION_BAT, BAT_OFFSET, bat_length as u32),
+            (REGION_METADATA, METADATA_OFFSET, METADATA_LENGTH),
+        ];
+        for (i, (guid, offset, length)) in region_entries.into_iter().enumerate() {
+            let entry = &mut regions[(16 + 32 * i)..(48 + 32 * i)];
+            entry[..16].copy_from_slice(&guid);
+            entry[16..24].copy_from_slice(&offset.to_le_bytes());
+            entry[24..28].copy_from_slice(&length.to_le_bytes());
+            entry[28..32].copy_from_slice(&REGION_REQUIRED.to_le_bytes());
+        }
+        with_checksum(&mut regions);
+
+        // ### METADATA ###
+
+        let mut parameters = [0; 8];
+        parameters[..4].copy_from_slice(&(block_size as u32).to_le_bytes());
+
+        // All the items but the file parameters describe the virtual disk
+        let disk_item = ITEM_IS_VIRTUAL_DISK | ITEM_IS_REQUIRED;
+        let items: [([u8; 16], u32, Vec<u8>); 5] = [
+            (ITEM_FILE_PARAMETERS, ITEM_IS_REQUIRED, parameters.to_vec()),
+            (
+                ITEM_VIRTUAL_DISK_SIZE,
+                disk_item,
+                size.to_le_bytes().to_vec(),
+            ),
+            (ITEM_VIRTUAL_DISK_ID, disk_item, random_guid().to_vec()),
+            (
+                ITEM_LOGICAL_SECTOR_SIZE,
+                disk_item,
+                512u32.to_le_bytes().to_vec(),
+            ),
+            (
+                ITEM_PHYSICAL_SECTOR_SIZE,
+                disk_item,
+                512u32.to_le_bytes().to_vec(),
+            ),
+        ];
+
+        let mut metadata = vec![0; METADATA_TABLE_SIZE];
+        metadata[..8].copy_from_slice(&METADATA_SIGNATURE);
+        metadata[10..12].copy_from_slice(&(items.len() as u16).to_le_bytes());
+
+        let mut item_offset = METADATA_TABLE_SIZE;
+        for (i, (guid, flags, value)) in items.iter().enumerate() {
+            let entry = &mut metadata[(32 + 32 * i)..(64 + 32 * i)];
+            entry[..16].copy_from_slice(guid);
+            entry[16..20].copy_from_slice(&(item_offset as u32).to_le_bytes());
+            entry[20..24].copy_from_slice(&(value.len() as u32).to_le_bytes());
+            entry[24..28].copy_from_slice(&flags.to_le_bytes());
+            item_offset += value.len();
+        }
+        for (_, _, value) in &items {
+            metadata.extend_from_slice(value);
+        }
+
+        let io = |e: io::Error| ImageErr::Disk(DiskErr::from_io(&e).in_layer("VhdxDisk"));
+        let mut file = File::create_new(&path).map_err(io)?;
+
+        let parts: [(u64, &[u8]); 7] = [
+            (0, &identifier),
+            (HEADER_OFFSETS[0], &headers[0]),
+            (HEADER_OFFSETS[1], &headers[1]),
+            (REGION_TABLE_OFFSETS[0], &regions),
+            (REGION_TABLE_OFFSETS[1], &regions),
+            (METADATA_OFFSET, &metadata),
+            (BAT_OFFSET + bat_length, &[]),
+        ];
+        for (offset, raw) in parts {
+            file.seek(SeekFrom::Start(offset))
+                .and_then(|_| file.write_all(raw))
+                .map_err(io)?;
+        }
+
+        // The log and the BAT are zeroed: the log is empty and no block is present
+        file.set_len(BAT_OFFSET + bat_length)
+            .and_then(|_| file.sync_all())
+            .map_err(io)?;
+        drop(file);
+
+        Self::open(path, Permissions::read_write())
+    }
+
+    pub const fn block_size(&self) -> usize {
+        self.block_size as usize
+    }
+
+    pub const fn logical_sector_size(&self) -> u32 {
+        self.logical_sector_size
+    }
+
+    /// The virtual disk id, as stored in the metadata
+    pub const fn disk_id(&self) -> [u8; 16] {
+        self.disk_id
+    }
+
+    fn read_inner(&self, sector: u64, sector_size: usize, buf: &mut [u8]) -> Result<(), DiskErr> {
+        if !self.permissions.read {
+            return Err(DiskErrKind::InvalidPermission {
+                disk_permissions: self.permissions,
+            }
+            .into());
+        }
+
+        let offset = self.offset(sector, sector_size, buf.len() as u64)?;
+        self.state.lock().read_at(offset, buf)
+    }
+
+    fn write_inner(&self, sector: u64, sector_size: usize, buf: &[u8]) -> Result<(), DiskErr> {
+        if !self.permissions.write {
+            return Err(DiskErrKind::InvalidPermission {
+                disk_permissions: self.permissions,
+            }
+            .into());
+        }
+
+        let offset = self.offset(sector, sector_size, buf.len() as u64)?;
+        self.state.lock().write_at(offset, buf)
+    }
+
+    /// Checks a request of `len` bytes starting at the LBA `sector` and returns its offset in the
+    /// virtual disk.
+    fn offset(&self, sector: u64, sector_size: usize, len: u64) -> Result<u64, DiskErr> {
+        if sector_size == 0
+            || sector_size as u64 > self.size
+            || !len.is_multiple_of(sector_size as u64)
+        {
+            return Err(DiskErrKind::InvalidSectorSize {
+                found: sector_size,
+                supported: SectorSize::Any,
+                start: 0,
+            }
+            .into());
+        }
+
+        match sector.checked_mul(sector_size as u64) {
+            Some(offset) if offset.checked_add(len).is_some_and(|end| end <= self.size) => {
+                Ok(offset)
+            }
+            _ => Err(DiskErrKind::InvalidSectorIndex {
+                found: sector,
+                max: (self.size / sector_size as u64).saturating_sub(1),
+            }
+            .into()),
+        }
+    }
+}
+
+/// Writes the next version of the current header in the other slot, with new write GUIDs and the
+/// log GUID `log_guid`, and makes it the current one
+fn update_header(
+    file: &mut File,
+    header: &mut [u8; HEADER_SIZE],
+    slot: &mut usize,
+    log_guid: [u8; 16],
+) -> io::Result<()> {
+    let sequence = le64(header, 8).wrapping_add(1);
+    header[8..16].copy_from_slice(&sequence.to_le_bytes());
+    header[16..32].copy_from_slice(&random_guid());
+    header[32..48].copy_from_slice(&random_guid());
+    header[48..64].copy_from_slice(&log_guid);
+    with_checksum(header);
+
+    *slot = 1 - *slot;
+    file.seek(SeekFrom::Start(HEADER_OFFSETS[*slot]))?;
+    file.write_all(header)?;
+    file.sync_data()
+}
+
+/// Reads `len` bytes of metadata at `offset`, which must be in the file
+fn read_at(file: &mut File, offset: u64, len: usize) -> Result<Vec<u8>, ImageErr> {
+    let io = |e: io::Error| ImageErr::Disk(DiskErr::from_io(&e).in_layer("VhdxDisk"));
+
+    let file_size = file.seek(SeekFrom::End(0)).map_err(io)?;
+    if offset.saturating_add(len as u64) > file_size {
+        return Err(ImageErr::InvalidHeader {
+            reason: "structure out of the file",
+        });
+    }
+
+    let mut raw = vec![0; len];
+    file.seek(SeekFrom::Start(offset))
+        .and_then(|_| file.read_exact(&mut raw))
+        .map_err(io)?;
+    Ok(raw)
+}
+
+fn invalid(reason: &'static str) -> DiskErr {
+    DiskErr::new(DiskErrKind::InvalidImage { reason })
+}
+
+// ### LOG ###
+
+/// A valid entry of the log
+struct LogEntry {
+    sequence: u64,
+    /// Position of the oldest entry of the sequence this one ends, and of this one, in the log
+    tail: usize,
+    length: usize,
+    last_file_offset: u64,
+    raw: Vec<u8>,
+}
+
+/// `len` bytes of the circular log, starting at `offset`
+fn log_bytes(log: &[u8], offset: usize, len: usize) -> Vec<u8> {
+    (0..len).map(|i| log[(offset + i) % log.len()]).collect()
+}
+
+/// Parses the entry at `offset` in the log, if it is valid and belongs to the log `log_guid`
+fn log_entry(log: &[u8], offset: usize, log_guid: &[u8]) -> Option<LogEntry> {
+    let header = log_bytes(log, offset, LOG_ENTRY_HEADER_SIZE);
+    let length = le32(&header, 8) as usize;
+    let tail = le32(&header, 12) as usize;
+    let sequence = le64(&header, 16);
+    let descriptors = le32(&header, 24) as usize;
+
+    if header[..4] != ENTRY_SIGNATURE
+        || header[32..48] != *log_guid
+        || length == 0
+        || length > log.len()
+        || !length.is_multiple_of(LOG_SECTOR_SIZE)
+        || tail >= log.len()
+        || !tail.is_multiple_of(LOG_SECTOR_SIZE)
+    {
+        return None;
+    }
+
+    let descriptor_sectors =
+        (LOG_ENTRY_HEADER_SIZE + descriptors * LOG_DESCRIPTOR_SIZE).div_ceil(LOG_SECTOR_SIZE);
+    if descriptor_sectors * LOG_SECTOR_SIZE > length {
+        return None;
+    }
+
+    let raw = log_bytes(log, offset, length);
+    if le32(&raw, 4) != checksum(&raw) {
+        return None;
+    }
+
+    // Each data descriptor has its sector, following the descriptors
+    let mut data_sectors = 0;
+    for i in 0..descriptors {
+        let descriptor = &raw[(LOG_ENTRY_HEADER_SIZE + i * LOG_DESCRIPTOR_SIZE)..];
+        if le64(descriptor, 24) != sequence {
+            return None;
+        }
+
+        match descriptor[..4].try_into().unwrap() {
+            ZERO_SIGNATURE => {}
+            DESCRIPTOR_SIGNATURE => {
+                let start = (descriptor_sectors + data_sectors) * LOG_SECTOR_SIZE;
+                data_sectors += 1;
+                let data = raw.get(start..(start + LOG_SECTOR_SIZE))?;
+
+                if data[..4] != DATA_SIGNATURE
+                    || (((le32(data, 4) as u64) << 32) | le32(data, 4092) as u64) != sequence
+                {
+                    return None;
+                }
+            }
+            _ => return None,
+        }
+    }
+
+    ((descriptor_sectors + data_sectors) * LOG_SECTOR_SIZE == length).then_some(LogEntry {
+        sequence,
+        tail,
+        length,
+        last_file_offset: le64(&header, 56),
+        raw,
+    })
+}
+
+/// Applies the active sequence of the log to the file: the longest chain of consecutive entries
+/// ending at the valid entry with the highest sequence number whose tail leads to it. Returns
+/// `Err(None)` if no entry forms such a chain.
+fn replay_log(file: &mut File, log: &[u8], log_guid: &[u8]) -> Result<(), Option<io::Error>> {
+    let mut heads: Vec<_> = (0..log.len())
+        .step_by(LOG_SECTOR_SIZE)
+        .filter_map(|offset| log_entry(log, offset, log_guid).map(|e| (offset, e)))
+        .collect();
+    heads.sort_by_key(|(_, e)| core::cmp::Reverse(e.sequence));
+
+    let sequence = heads.iter().find_map(|(head, entry)| {
+        let mut sequence = Vec::new();
+        let mut offset = entry.tail;
+
+        for _ in 0..(log.len() / LOG_SECTOR_SIZE) {
+            let entry = log_entry(log, offset, log_guid)?;
+            if sequence
+                .last()
+                .is_some_and(|prev: &LogEntry| prev.sequence.wrapping_add(1) != entry.sequence)
+            {
+                return None;
+            }
+
+            let next = (offset + entry.length) % log.len();
+            sequence.push(entry);
+            if offset == *head {
+                return Some(sequence);
+            }
+            offset = next;
+        }
+
+        None
+    });
+    let sequence = sequence.ok_or(None)?;
+
+    for entry in &sequence {
+        let raw = &entry.raw;
+        let descriptors = le32(raw, 24) as usize;
+        let descriptor_sectors =
+            (LOG_ENTRY_HEADER_SIZE + descriptors * LOG_DESCRIPTOR_SIZE).div_ceil(LOG_SECTOR_SIZE);
+        let mut data_sectors = 0;
+
+        for i in 0..descriptors {
+            let descriptor = &raw[(LOG_ENTRY_HEADER_SIZE + i * LOG_DESCRIPTOR_SIZE)..];
+            let file_offset = le64(descriptor, 16);
+            file.seek(SeekFrom::Start(file_offset))?;
+
+            if descriptor[..4] == ZERO_SIGNATURE {
+                let zeroes = vec![0; LOG_SECTOR_SIZE];
+                for _ in 0..(le64(descriptor, 8) / LOG_SECTOR_SIZE as u64) {
+                    file.write_all(&zeroes)?;
+                }
+            } else {
+                // The first 8 and the last 4 bytes of the sector are kept in the descriptor
+                let data = &raw[((descriptor_sectors + data_sectors) * LOG_SECTOR_SIZE)..];
+                data_sectors += 1;
+
+                file.write_all(&descriptor[8..16])?;
+                file.write_all(&data[8..4092])?;
+                file.write_all(&descriptor[4..8])?;
+            }
+        }
+    }
+
+    let last_file_offset = sequence.last().unwrap().last_file_offset;
+    if file.seek(SeekFrom::End(0))? < last_file_offset {
+        file.set_len(last_file_offset)?;
+    }
+    file.sync_all().map_err(Some)
+}
+
+// ### BLOCKS ###
+
+/// The mutable part of the image, behind the lock. The metadata is written through.
+struct State {
+    file: File,
+    file_size: u64,
+    header: [u8; HEADER_SIZE],
+    /// Slot of the current header
+    slot: usize,
+    /// Whether the header was updated for this session, before the first write
+    header_updated: bool,
+    block_size: u64,
+    chunk_ratio: u64,
+    bat_offset: u64,
+    bat: Vec<u64>,
+}
+
+impl State {
+    fn read_file(&mut self, offset: u64, buf: &mut [u8]) -> Result<(), DiskErr> {
+        self.file
+            .seek(SeekFrom::Start(offset))
+            .and_then(|_| self.file.read_exact(buf))
+            .map_err(|e| DiskErr::from_io(&e))
+    }
+
+    fn write_file(&mut self, offset: u64, buf: &[u8]) -> Result<(), DiskErr> {
+        self.file
+            .seek(SeekFrom::Start(offset))
+            .and_then(|_| self.file.write_all(buf))
+            .map_err(|e| DiskErr::from_io(&e))
+    }
+
+    /// Splits a request in the parts of each block: `(index of the BAT entry, offset in the block,
+    /// range of the request)`
+    fn blocks(&self, offset: u64, len: usize) -> Vec<(usize, u64, usize, usize)> {
+        let mut blocks = Vec::new();
+        let mut done = 0;
+
+        while done < len {
+            let pos = offset + done as u64;
+            let (block, in_block) = (pos / self.block_size, pos % self.block_size);
+            let part = ((self.block_size - in_block) as usize).min(len - done);
+
+            let index = block + block / self.chunk_ratio;
+            blocks.push((index as usize, in_block, done, done + part));
+            done += part;
+        }
+
+        blocks
+    }
+
+    fn read_at(&mut self, offset: u64, buf: &mut [u8]) -> Result<(), DiskErr> {
+        for (index, in_block, start, end) in self.blocks(offset, buf.len()) {
+            let entry = self.bat[index];
+
+            match entry & BAT_STATE_MASK {
+                BLOCK_FULLY_PRESENT => {
+                    let base = entry & BAT_OFFSET_MASK;
+                    self.read_file(base + in_block, &mut buf[start..end])?;
+                }
+                BLOCK_NOT_PRESENT | BLOCK_UNDEFINED | BLOCK_ZERO | BLOCK_UNMAPPED => {
+                    buf[start..end].fill(0);
+                }
+                _ => return Err(invalid("invalid payload block state")),
+            }
+        }
+
+        Ok(())
+    }
+
+    fn write_at(&mut self, offset: u64, buf: &[u8]) -> Result<(), DiskErr> {
+        // The write GUIDs change before the first update of the file
+        if !self.header_updated {
+            let log_guid = self.header[48..64].try_into().unwrap();
+            update_header(&mut self.file, &mut self.header, &mut self.slot, log_guid)
+                .map_err(|e| DiskErr::from_io(&e))?;
+            self.header_updated = true;
+        }
+
+        for (index, in_block, start, end) in self.blocks(offset, buf.len()) {
+            let entry = self.bat[index];
+
+            let base = match entry & BAT_STATE_MASK {
+                BLOCK_FULLY_PRESENT => entry & BAT_OFFSET_MASK,
+                BLOCK_NOT_PRESENT | BLOCK_UNDEFINED | BLOCK_ZERO | BLOCK_UNMAPPED => {
+                    self.allocate_block(index)?
+                }
+                _ => return Err(invalid("invalid payload block state")),
+            };
+
+            self.write_file(base + in_block, &buf[start..end])?;
+        }
+
+        Ok(())
+    }
+
+    /// Puts a new zeroed block at the end of the file, and returns its position. The block is
+    /// zeroed by the extension of the file.
+    fn allocate_block(&mut self, index: usize) -> Result<u64, DiskErr> {
+        let base = self.file_size.next_multiple_of(ALIGNMENT);
+        let end = base + self.block_size;
+
+        self.file.set_len(end).map_err(|e| DiskErr::from_io(&e))?;
+        self.file_size = end;
+
+        let entry = base | BLOCK_FULLY_PRESENT;
+        self.bat[index] = entry;
+        self.write_file(self.bat_offset + index as u64 * 8, &entry.to_le_bytes())?;
+
+        Ok(base)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::std_helpers::TempPath;
+
+    #[test]
+    fn create_and_reopen() {
+        let temp = TempPath::new("create.vhdx");
+        let data: Vec<u8> = (0..(3 * 4096)).map(|i| (i % 253) as u8).collect();
+        let size = 4 << 20;
+
+        let disk = VhdxDisk::create(temp.path.clone(), size, Some(1 << 20)).unwrap();
+        assert!(VhdxDisk::create(temp.path.clone(), size, None).is_err());
+        disk.write_sectors(1, 512, &data[..4096]).unwrap();
+        disk.flush().unwrap();
+        drop(disk);
+
+        // Across a block boundary, after a reopening
+        let disk = VhdxDisk::open(temp.path.clone(), Permissions::read_write()).unwrap();
+        disk.write_sectors(255, 4096, &data).unwrap();
+        disk.flush().unwrap();
+        drop(disk);
+
+        let disk = VhdxDisk::open(temp.path.clone(), Permissions::read_only()).unwrap();
+        assert_eq!(disk.block_size(), 1 << 20);
+        assert_eq!(disk.logical_sector_size(), 512);
+        assert_eq!(disk.disk_infos().unwrap().disk_size, size);
+
+        let mut buf = vec![0; 3 * 4096];
+        disk.read_sectors(0, 512, &mut buf[..4608]).unwrap();
+        assert_eq!(buf[..512], [0; 512]);
+        assert_eq!(buf[512..4608], data[..4096]);
+        disk.read_sectors(255, 4096, &mut buf).unwrap();
+        assert_eq!(buf, data);
+        disk.read_sectors(1023, 4096, &mut buf[..4096]).unwrap();
+        assert_eq!(buf[..4096], [0; 4096]);
+
+        assert!(disk.write_sectors(0, 512, &[0; 512]).is_err());
+        assert!(disk.read_sectors(1024, 4096, &mut buf[..4096]).is_err());
+    }
+
+    #[test]
+    fn malformed_headers() {
+        let temp = TempPath::new("malformed.vhdx");
+        let open = |image: &[u8]| {
+            fs::write(&temp.path, image).unwrap();
+            VhdxDisk::open(temp.path.clone(), Permissions::read_only()).err()
+        };
+        let invalid = |reason| Some(ImageErr::InvalidHeader { reason });
+
+        drop(VhdxDisk::create(temp.path.clone(), 4 << 20, None).unwrap());
+        let image = fs::read(&temp.path).unwrap();
+        let [header_0, header_1] = HEADER_OFFSETS.map(|o| o as usize + 100);
+        let [regions_0, regions_1] = REGION_TABLE_OFFSETS.map(|o| o as usize + 100);
+
+        let mut bad = image.clone();
+        bad[0] = b'V';
+        assert_eq!(open(&bad), invalid("not a VHDX image"));
+
+        // Either copy of the header or of the region table is enough
+        let mut bad = image.clone();
+        bad[header_0] ^= 1;
+        bad[regions_1] ^= 1;
+        assert_eq!(open(&bad), None);
+
+        bad[header_1] ^= 1;
+        assert_eq!(open(&bad), invalid("no valid header"));
+
+        let mut bad = image.clone();
+        bad[regions_0] ^= 1;
+        bad[regions_1] ^= 1;
+        assert_eq!(open(&bad), invalid("no valid region table"));
+    }
+}
@@ -0,0 +1,1112 @@
+use crate::{
+    DeviceId, Disk, DiskErr, DiskErrKind, DiskInfos, Geometry, ImageErr, Operation, Permissions,
+    SectorSize,
+    std_helpers::{path_hash, random_guid},
+};
+use mutex::Mutex;
+use std::{
+    collections::BTreeMap,
+    fs::{self, File},
+    io::{self, Read, Seek, SeekFrom, Write},
+    path::{Path, PathBuf},
+};
+
+const SPARSE_MAGIC: [u8; 4] = *b"KDMV";
+const SECTOR_SIZE: u64 = 512;
+
+const FLAG_NEWLINE_DETECTION: u32 = 1 << 0;
+const FLAG_REDUNDANT_TABLES: u32 = 1 << 1;
+const FLAG_COMPRESSED: u32 = 1 << 16;
+const FLAG_MARKERS: u32 = 1 << 17;
+
+/// The grain directory is after the grains (stream-optimized images)
+const GD_AT_END: u64 = u64::MAX;
+
+/// The grain reads as zeroes, without being allocated
+const GRAIN_ZERO: u32 = 1;
+
+/// Layout of the new sparse extents, the one of VMware: 64 KiB grains, 512 entries per grain
+/// table, and room for the embedded descriptor
+const GRAIN_SECTORS: u64 = 128;
+const GTES_PER_GT: u64 = 512;
+const DESCRIPTOR_SECTORS: u64 = 20;
+
+/// Size of the extents of the split images
+const SPLIT_SIZE: u64 = 2047 << 20;
+
+/// Largest descriptor file read, to bound the allocation on something else than a descriptor
+const MAX_DESCRIPTOR_SIZE: u64 = 1 << 20;
+
+/// Count of grain tables kept in memory, by extent
+const GT_CACHE_TABLES: usize = 64;
+
+fn le32(raw: &[u8], offset: usize) -> u32 {
+    u32::from_le_bytes(raw[offset..(offset + 4)].try_into().unwrap())
+}
+
+fn le64(raw: &[u8], offset: usize) -> u64 {
+    u64::from_le_bytes(raw[offset..(offset + 8)].try_into().unwrap())
+}
+
+/// The layouts of the images this library creates
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum VmdkType {
+    /// A descriptor file, and the raw disk in a single extent file
+    MonolithicFlat,
+    /// A single sparse extent file, with the descriptor embedded
+    MonolithicSparse,
+    /// A descriptor file, and the raw disk split in extent files of 2047 MiB
+    TwoGbMaxExtentFlat,
+    /// A descriptor file, and sparse extent files of 2047 MiB
+    TwoGbMaxExtentSparse,
+}
+
+impl VmdkType {
+    /// The `createType` of the descriptor
+    pub const fn name(&self) -> &'static str {
+        match self {
+            Self::MonolithicFlat => "monolithicFlat",
+            Self::MonolithicSparse => "monolithicSparse",
+            Self::TwoGbMaxExtentFlat => "twoGbMaxExtentFlat",
+            Self::TwoGbMaxExtentSparse => "twoGbMaxExtentSparse",
+        }
+    }
+
+    const fn is_sparse(&self) -> bool {
+        matches!(self, Self::MonolithicSparse | Self::TwoGbMaxExtentSparse)
+    }
+}
+
+/// A VMDK disk image, as used by VMware: a text descriptor listing flat, sparse or zero extents,
+/// which can be embedded in the only sparse extent. The grains of the sparse extents not written
+/// yet read as zeroes.
+///
+/// Images with a parent, compressed and stream-optimized extents are not supported.
+pub struct VmdkDisk {
+    create_type: String,
+    size: u64,
+    grain_size: usize,
+    permissions: Permissions,
+    device_id: Option<DeviceId>,
+    state: Mutex<State>,
+}
+
+impl Disk for VmdkDisk {
+    fn read_sector(&self, sector: u64, buf: &mut [u8]) -> Result<(), DiskErr> {
+        self.read_sectors(sector, buf.len(), buf)
+    }
+
+    fn write_sector(&self, sector: u64, buf: &[u8]) -> Result<(), DiskErr> {
+        self.write_sectors(sector, buf.len(), buf)
+    }
+
+    fn disk_infos(&self) -> Result<DiskInfos, DiskErr> {
+        Ok(DiskInfos {
+            sector_size: SectorSize::Any,
+            disk_size: self.size,
+            permissions: self.permissions,
+            geometry: Geometry {
+                optimal_io_size: self.grain_size,
+                ..Geometry::from_sector_size(&SectorSize::Any, self.size)
+            },
+            device_id: self.device_id.clone(),
+        })
+    }
+
+    fn read_sectors(&self, sector: u64, sector_size: usize, buf: &mut [u8]) -> Result<(), DiskErr> {
+        self.read_inner(sector, sector_size, buf)
+            .map_err(|e| e.at(Operation::Read, sector).in_layer("VmdkDisk"))
+    }
+
+    fn write_sectors(&self, sector: u64, sector_size: usize, buf: &[u8]) -> Result<(), DiskErr> {
+        self.write_inner(sector, sector_size, buf)
+            .map_err(|e| e.at(Operation::Write, sector).in_layer("VmdkDisk"))
+    }
+
+    fn flush(&self) -> Result<(), DiskErr> {
+        let mut state = self.state.lock();
+
+        for extent in &mut state.extents {
+            let file = match &mut extent.kind {
+                ExtentKind::Flat { file, .. } => file,
+                ExtentKind::Sparse(sparse) => &mut sparse.file,
+                ExtentKind::Zero => continue,
+            };
+
+            file.sync_data().map_err(|e| {
+                DiskErr::from_io(&e)
+                    .during(Operation::Flush)
+                    .in_layer("VmdkDisk")
+            })?;
+        }
+
+        Ok(())
+    }
+}
+
+impl VmdkDisk {
+    /// Opens an existing image, from its descriptor file or from its sparse extent holding the
+    /// descriptor. The paths of the extents are relative to the directory of `path`.
+    pub fn open(path: PathBuf, permissions: Permissions) -> Result<Self, ImageErr> {
+        let io = |e: io::Error| ImageErr::Disk(DiskErr::from_io(&e).in_layer("VmdkDisk"));
+        let invalid_header = |reason| ImageErr::InvalidHeader { reason };
+
+        // ### DESCRIPTOR ###
+
+        let mut file = File::open(&path).map_err(io)?;
+        let file_size = file.seek(SeekFrom::End(0)).map_err(io)?;
+
+        let mut magic = [0; 4];
+        file.seek(SeekFrom::Start(0))
+            .and_then(|_| file.read_exact(&mut magic))
+            .map_err(|_| invalid_header("not a VMDK image"))?;
+
+        // Position of the descriptor in its file
+        let (descriptor_offset, descriptor_size) = match magic {
+            SPARSE_MAGIC => {
+                let header = read_header(&mut file)?;
+                (
+                    le64(&header, 28) * SECTOR_SIZE,
+                    le64(&header, 36) * SECTOR_SIZE,
+                )
+            }
+            _ if file_size <= MAX_DESCRIPTOR_SIZE => (0, file_size),
+            _ => return Err(invalid_header("not a VMDK image")),
+        };
+
+        if descriptor_size == 0 {
+            return Err(invalid_header("no embedded descriptor"));
+        }
+        if descriptor_size > MAX_DESCRIPTOR_SIZE
+            || descriptor_offset.saturating_add(descriptor_size) > file_size
+        {
+            return Err(invalid_header("invalid descriptor"));
+        }
+
+        let mut raw = vec![0; descriptor_size as usize];
+        file.seek(SeekFrom::Start(descriptor_offset))
+            .and_then(|_| file.read_exact(&mut raw))
+            .map_err(io)?;
+
+        // The embedded descriptors are padded with zeroes
+        let len = raw.iter().position(|&b| b == 0).unwrap_or(raw.len());
+        let text = core::str::from_utf8(&raw[..len])
+            .map_err(|_| invalid_header("invalid descriptor encoding"))?;
+        let descriptor = Descriptor::parse(text)?;
+
+        if descriptor
+            .parent_cid
+            .as_ref()
+            .is_some_and(|cid| !cid.eq_ignore_ascii_case("ffffffff"))
+        {
+            return Err(ImageErr::Unsupported {
+                feature: "VMDK images with a parent".into(),
+            });
+        }
+
+        // ### EXTENTS ###
+
+        let dir = path.parent().unwrap_or(Path::new(""));
+        let mut extents = Vec::new();
+        let mut size = 0;
+        let mut grain_size = 0;
+
+        for line in &descriptor.extents {
+            match line.access {
+                ExtentAccess::ReadWrite => {}
+                ExtentAccess::ReadOnly if !permissions.write => {}
+                ExtentAccess::ReadOnly => {
+                    return Err(ImageErr::Unsupported {
+                        feature: "writing read-only VMDK extents".into(),
+                    });
+                }
+                ExtentAccess::Denied => {
+                    return Err(ImageErr::Unsupported {
+                        feature: "VMDK extents without access".into(),
+                    });
+                }
+            }
+
+            let open_extent = || {
+                let file_name = line
+                    .file_name
+                    .as_ref()
+                    .ok_or(invalid_header("extent without file"))?;
+
+                File::options()
+                    .read(true)
+                    .write(permissions.write)
+                    .open(dir.join(file_name))
+                    .map_err(io)
+            };
+
+            let kind = match line.kind.as_str() {
+                "FLAT" | "VMFS" => ExtentKind::Flat {
+                    file: open_extent()?,
+                    offset: line.offset * SECTOR_SIZE,
+                },
+                "SPARSE" => {
+                    let sparse = SparseExtent::open(open_extent()?)?;
+                    if sparse.capacity < line.sectors * SECTOR_SIZE {
+                        return Err(invalid_header("sparse extent smaller than described"));
+                    }
+
+                    grain_size = grain_size.max(sparse.grain_size);
+                    ExtentKind::Sparse(sparse)
+                }
+                "ZERO" => ExtentKind::Zero,
+                kind => {
+                    return Err(ImageErr::Unsupported {
+                        feature: format!("{kind} VMDK extents"),
+                    });
+                }
+            };
+
+            let extent_size = line
+                .sectors
+                .checked_mul(SECTOR_SIZE)
+                .ok_or(invalid_header("invalid extent size"))?;
+            extents.push(Extent {
+                start: size,
+                size: extent_size,
+                kind,
+            });
+            size = size
+                .checked_add(extent_size)
+                .ok_or(invalid_header("invalid extent size"))?;
+        }
+
+        if extents.is_empty() {
+            return Err(invalid_header("no extent"));
+        }
+
+        let cid = descriptor
+            .cid_offset
+            .map(|offset| (path.clone(), descriptor_offset + offset as u64));
+
+        let device_id = fs::canonicalize(&path)
+            .ok()
+            .map(|p| DeviceId::Image(path_hash(&p)));
+
+        Ok(Self {
+            create_type: descriptor.create_type,
+            size,
+            grain_size: grain_size as usize,
+            permissions,
+            device_id,
+            state: Mutex::new(State {
+                extents,
+                cid,
+                cid_updated: false,
+            }),
+        })
+    }
+
+    /// Creates a new image of `size` bytes, a multiple of 512, and opens it. `path` is the
+    /// descriptor, the extent files are put next to it. Will result in an error if one of the
+    /// files already exists.
+    pub fn create(path: PathBuf, size: u64, vmdk_type: VmdkType) -> Result<Self, ImageErr> {
+        if size == 0 || !size.is_multiple_of(SECTOR_SIZE) {
+            return Err(ImageErr::Unsupported {
+                feature: format!("{size}-byte VMDK disks"),
+            });
+        }
+
+        let io = |e: io::Error| ImageErr::Disk(DiskErr::from_io(&e).in_layer("VmdkDisk"));
+
+        let stem = path
+            .file_stem()
+            .and_then(|s| s.to_str())
+            .ok_or(ImageErr::InvalidHeader {
+                reason: "invalid image name",
+            })?;
+        let dir = path.parent().unwrap_or(Path::new(""));
+
+        // ### EXTENT FILES ###
+
+        let extent_sizes: Vec<u64> = match vmdk_type {
+            VmdkType::MonolithicFlat | VmdkType::MonolithicSparse => vec![size],
+            VmdkType::TwoGbMaxExtentFlat | VmdkType::TwoGbMaxExtentSparse => (0..size)
+                .step_by(SPLIT_SIZE as usize)
+                .map(|start| (size - start).min(SPLIT_SIZE))
+                .collect(),
+        };
+
+        let mut extent_lines = String::new();
+        for (i, &extent_size) in extent_sizes.iter().enumerate() {
+            let file_name = match vmdk_type {
+                VmdkType::MonolithicFlat => format!("{stem}-flat.vmdk"),
+                VmdkType::MonolithicSparse => format!("{stem}.vmdk"),
+                VmdkType::TwoGbMaxExtentFlat => format!("{stem}-f{:03}.vmdk", i + 1),
+                VmdkType::TwoGbMaxExtentSparse => format!("{stem}-s{:03}.vmdk", i + 1),
+            };
+
+            match vmdk_type.is_sparse() {
+                true => extent_lines.push_str(&format!(
+                    "RW {} SPARSE \"{file_name}\"\n",
+                    extent_size / SECTOR_SIZE
+                )),
+                false => extent_lines.push_str(&format!(
+                    "RW {} FLAT \"{file_name}\" 0\n",
+                    extent_size / SECTOR_SIZE
+                )),
+            }
+
+            // The monolithic sparse extent is written with its descriptor
+            if vmdk_type == VmdkType::MonolithicSparse {
+                continue;
+            }
+
+            let mut file = File::create_new(dir.join(&file_name)).map_err(io)?;
+            match vmdk_type.is_sparse() {
+                true => write_sparse_extent(&mut file, extent_size, None).map_err(io)?,
+                false => file.set_len(extent_size).map_err(io)?,
+            }
+            file.sync_all().map_err(io)?;
+        }
+
+        // ### DESCRIPTOR ###
+
+        let cid = u32::from_le_bytes(random_guid()[..4].try_into().unwrap());
+        let cylinders = (size / SECTOR_SIZE / (16 * 63)).min(16383);
+        let descriptor = format!(
+            "# Disk DescriptorFile\n\
+             version=1\n\
+             CID={cid:08x}\n\
+             parentCID=ffffffff\n\
+             createType=\"{}\"\n\
+             \n\
+             # Extent description\n\
+             {extent_lines}\
+             \n\
+             # The Disk Data Base\n\
+             #DDB\n\
+             \n\
+             ddb.virtualHWVersion = \"4\"\n\
+             ddb.geometry.cylinders = \"{cylinders}\"\n\
+             ddb.geometry.heads = \"16\"\n\
+             ddb.geometry.sectors = \"63\"\n\
+             ddb.adapterType = \"ide\"\n",
+            vmdk_type.name()
+        );
+
+        let mut file = File::create_new(&path).map_err(io)?;
+        match vmdk_type {
+            VmdkType::MonolithicSparse => {
+                write_sparse_extent(&mut file, size, Some(descriptor.as_bytes())).map_err(io)?
+            }
+            _ => file.write_all(descriptor.as_bytes()).map_err(io)?,
+        }
+        file.sync_all().map_err(io)?;
+        drop(file);
+
+        Self::open(path, Permissions::read_write())
+    }
+
+    /// The `createType` of the descriptor
+    pub fn create_type(&self) -> &str {
+        &self.create_type
+    }
+
+    pub fn extent_count(&self) -> usize {
+        self.state.lock().extents.len()
+    }
+
+    fn read_inner(&self, sector: u64, sector_size: usize, buf: &mut [u8]) -> Result<(), DiskErr> {
+        if !self.permissions.read {
+            return Err(DiskErrKind::InvalidPermission {
+                disk_permissions: self.permissions,
+            }
+            .into());
+        }
+
+        let offset = self.offset(sector, sector_size, buf.len() as u64)?;
+        self.state.lock().read_at(offset, buf)
+    }
+
+    fn write_inner(&self, sector: u64, sector_size: usize, buf: &[u8]) -> Result<(), DiskErr> {
+        if !self.permissions.write {
+            return Err(DiskErrKind::InvalidPermission {
+                disk_permissions: self.permissions,
+            }
+            .into());
+        }
+
+        let offset = self.offset(sector, sector_size, buf.len() as u64)?;
+        self.state.lock().write_at(offset, buf)
+    }
+
+    /// Checks a request of `len` bytes starting at the LBA `sector` and returns its offset in the
+    /// virtual disk.
+    fn offset(&self, sector: u64, sector_size: usize, len: u64) -> Result<u64, DiskErr> {
+        if sector_size == 0
+            || sector_size as u64 > self.size
+            || !len.is_multiple_of(sector_size as u64)
+        {
+            return Err(DiskErrKind::InvalidSectorSize {
+                found: sector_size,
+                supported: SectorSize::Any,
+                start: 0,
+            }
+            .into());
+        }
+
+        match sector.checked_mul(sector_size as u64) {
+            Some(offset) if offset.checked_add(len).is_some_and(|end| end <= self.size) => {
+                Ok(offset)
+            }
+            _ => Err(DiskErrKind::InvalidSectorIndex {
+                found: sector,
+                max: (self.size / sector_size as u64).saturating_sub(1),
+            }
+            .into()),
+        }
+    }
+}
+
+fn invalid(reason: &'static str) -> DiskErr {
+    DiskErr::new(DiskErrKind::InvalidImage { reason })
+}
+
+// ### DESCRIPTOR ###
+
+/// The access of an extent: `RW`, `RDONLY` or `NOACCESS`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum ExtentAccess {
+    ReadWrite,
+    ReadOnly,
+    Denied,
+}
+
+/// A line of the extent description: `RW 2097152 SPARSE "disk.vmdk"`. The offset of the flat
+/// extents in their file follows their name.
+struct ExtentLine {
+    access: ExtentAccess,
+    sectors: u64,
+    kind: String,
+    file_name: Option<String>,
+    offset: u64,
+}
+
+struct Descriptor {
+    create_type: String,
+    parent_cid: Option<String>,
+    /// Position of the value of `CID` in the text, if it can be replaced in place
+    cid_offset: Option<usize>,
+    extents: Vec<ExtentLine>,
+}
+
+impl Descriptor {
+    fn parse(text: &str) -> Result<Self, ImageErr> {
+        let invalid_header = |reason| ImageErr::InvalidHeader { reason };
+
+        let mut descriptor = Self {
+            create_type: String::new(),
+            parent_cid: None,
+            cid_offset: None,
+            extents: Vec::new(),
+        };
+
+        let mut line_start = 0;
+        for raw_line in text.split_inclusive('\n') {
+            let start = line_start;
+            line_start += raw_line.len();
+
+            let line = raw_line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+
+            let mut words = line.split_ascii_whitespace();
+            let access = match words.next() {
+                Some("RW") => Some(ExtentAccess::ReadWrite),
+                Some("RDONLY") => Some(ExtentAccess::ReadOnly),
+                Some("NOACCESS") => Some(ExtentAccess::Denied),
+                _ => None,
+            };
+
+            if let Some(access) = access {
+                let sectors = words
+                    .next()
+                    .and_then(|s| s.parse().ok())
+                    .ok_or(invalid_header("invalid extent size"))?;
+                let kind = words
+                    .next()
+                    .ok_or(invalid_header("invalid extent type"))?
+                    .to_string();
+
+                // The name is quoted, and can hold spaces
+                let (file_name, offset) = match line.split_once('"') {
+                    Some((_, rest)) => {
+                        let (name, rest) = rest
+                            .split_once('"')
+                            .ok_or(invalid_header("invalid extent file name"))?;
+                        let offset = match rest.split_ascii_whitespace().next() {
+                            Some(offset) => offset
+                                .parse()
+                                .map_err(|_| invalid_header("invalid extent offset"))?,
+                            None => 0,
+                        };
+                        (Some(name.to_string()), offset)
+                    }
+                    None => (None, 0),
+                };
+
+                descriptor.extents.push(ExtentLine {
+                    access,
+                    sectors,
+                    kind,
+                    file_name,
+                    offset,
+                });
+                continue;
+            }
+
+            let Some((key, value)) = line.split_once('=') else {
+                return Err(invalid_header("invalid descriptor line"));
+            };
+            let value = value.trim().trim_matches('"');
+
+            match key.trim() {
+                "createType" => descriptor.create_type = value.to_string(),
+                "parentCID" => descriptor.parent_cid = Some(value.to_string()),
+                "CID" if value.len() == 8 && value.bytes().all(|b| b.is_ascii_hexdigit()) => {
+                    descriptor.cid_offset = raw_line.find(value).map(|position| start + position);
+                }
+                _ => {}
+            }
+        }
+
+        Ok(descriptor)
+    }
+}
+
+// ### EXTENTS ###
+
+struct Extent {
+    /// Position of the extent in the virtual disk, in bytes
+    start: u64,
+    size: u64,
+    kind: ExtentKind,
+}
+
+enum ExtentKind {
+    /// The raw disk, at `offset` in the file
+    Flat {
+        file: File,
+        offset: u64,
+    },
+    Sparse(SparseExtent),
+    /// Reads as zeroes, can't be written
+    Zero,
+}
+
+/// Reads and checks the header of a sparse extent
+fn read_header(file: &mut File) -> Result<[u8; 512], ImageErr> {
+    let invalid_header = |reason| ImageErr::InvalidHeader { reason };
+
+    let mut header = [0; 512];
+    file.seek(SeekFrom::Start(0))
+        .and_then(|_| file.read_exact(&mut header))
+        .map_err(|_| invalid_header("not a VMDK sparse extent"))?;
+
+    if header[..4] != SPARSE_MAGIC {
+        return Err(invalid_header("not a VMDK sparse extent"));
+    }
+
+    let version = le32(&header, 4);
+    if !(1..=3).contains(&version) {
+        return Err(ImageErr::Unsupported {
+            feature: format!("VMDK sparse extent version {version}"),
+        });
+    }
+
+    let flags = le32(&header, 8);
+    if flags & (FLAG_COMPRESSED | FLAG_MARKERS) != 0 || le64(&header, 56) == GD_AT_END {
+        return Err(ImageErr::Unsupported {
+            feature: "compressed VMDK extents".into(),
+        });
+    }
+
+    // Catches the images transferred in text mode
+    if flags & FLAG_NEWLINE_DETECTION != 0 && header[73..77] != *b"\n \r\n" {
+        return Err(invalid_header("damaged newline detection characters"));
+    }
+
+    Ok(header)
+}
+
+/// Writes a new sparse extent of `size` bytes, with all its grain tables, and the descriptor if
+/// it is embedded
+fn write_sparse_extent(file: &mut File, size: u64, descriptor: Option<&[u8]>) -> io::Result<()> {
+    let capacity = size / SECTOR_SIZE;
+    let descriptor_sectors = descriptor.map_or(0, |_| DESCRIPTOR_SECTORS);
+
+    let tables = capacity.div_ceil(GRAIN_SECTORS * GTES_PER_GT);
+    let gd_sectors = (tables * 4).div_ceil(SECTOR_SIZE);
+    let gt_sectors = GTES_PER_GT * 4 / SECTOR_SIZE;
+
+    // The redundant directory and its tables, then the main ones
+    let rgd_offset = 1 + descriptor_sectors;
+    let gd_offset = rgd_offset + gd_sectors + tables * gt_sectors;
+    let overhead = (gd_offset + gd_sectors + tables * gt_sectors).next_multiple_of(GRAIN_SECTORS);
+
+    let mut header = [0; 512];
+    header[..4].copy_from_slice(&SPARSE_MAGIC);
+    header[4..8].copy_from_slice(&1u32.to_le_bytes());
+    header[8..12].copy_from_slice(&(FLAG_NEWLINE_DETECTION | FLAG_REDUNDANT_TABLES).to_le_bytes());
+    header[12..20].copy_from_slice(&capacity.to_le_bytes());
+    header[20..28].copy_from_slice(&GRAIN_SECTORS.to_le_bytes());
+    header[28..36].copy_from_slice(&descriptor.map_or(0u64, |_| 1).to_le_bytes());
+    header[36..44].copy_from_slice(&descriptor_sectors.to_le_bytes());
+    header[44..48].copy_from_slice(&(GTES_PER_GT as u32).to_le_bytes());
+    header[48..56].copy_from_slice(&rgd_offset.to_le_bytes());
+    header[56..64].copy_from_slice(&gd_offset.to_le_bytes());
+    header[64..72].copy_from_slice(&overhead.to_le_bytes());
+    header[73..77].copy_from_slice(b"\n \r\n");
+
+    file.seek(SeekFrom::Start(0))?;
+    file.write_all(&header)?;
+
+    if let Some(descriptor) = descriptor {
+        if descriptor.len() as u64 > DESCRIPTOR_SECTORS * SECTOR_SIZE {
+            return Err(io::ErrorKind::InvalidInput.into());
+        }
+        file.write_all(descriptor)?;
+    }
+
+    // The grain tables follow their directory, and are empty
+    for directory in [rgd_offset, gd_offset] {
+        let first_table = directory + gd_sectors;
+        let entries: Vec<u8> = (0..tables)
+            .flat_map(|i| ((first_table + i * gt_sectors) as u32).to_le_bytes())
+            .collect();
+
+        file.seek(SeekFrom::Start(directory * SECTOR_SIZE))?;
+        file.write_all(&entries)?;
+    }
+
+    file.set_len(overhead * SECTOR_SIZE)
+}
+
+/// A hosted sparse extent: a directory of grain tables, whose entries give the sector of each
+/// grain in the file
+struct SparseExtent {
+    file: File,
+    /// Where the new grains and tables are put
+    file_end: u64,
+    /// Size of the virtual disk stored by the extent, and of its grains, in bytes
+    capacity: u64,
+    grain_size: u64,
+    gtes_per_gt: u64,
+    gd: Vec<u32>,
+    /// The redundant copy of the directory, whose tables are kept in sync with the main ones
+    rgd: Option<Vec<u32>>,
+    gd_offset: u64,
+    rgd_offset: u64,
+    tables: BTreeMap<usize, Vec<u32>>,
+}
+
+impl SparseExtent {
+    fn open(mut file: File) -> Result<Self, ImageErr> {
+        let io = |e: io::Error| ImageErr::Disk(DiskErr::from_io(&e).in_layer("VmdkDisk"));
+        let invalid_header = |reason| ImageErr::InvalidHeader { reason };
+
+        let header = read_header(&mut file)?;
+        let file_size = file.seek(SeekFrom::End(0)).map_err(io)?;
+
+        let capacity = le64(&header, 12);
+        let grain_sectors = le64(&header, 20);
+        let gtes_per_gt = le32(&header, 44) as u64;
+
+        if !grain_sectors.is_power_of_two() || grain_sectors > 1 << 16 {
+            return Err(invalid_header("invalid grain size"));
+        }
+        if gtes_per_gt == 0 || gtes_per_gt > 1 << 16 {
+            return Err(invalid_header("invalid grain table size"));
+        }
+        let capacity = capacity
+            .checked_mul(SECTOR_SIZE)
+            .ok_or(invalid_header("invalid capacity"))?;
+
+        let tables = capacity.div_ceil(grain_sectors * SECTOR_SIZE * gtes_per_gt);
+        let mut read_directory = |offset: u64| -> Result<Vec<u32>, ImageErr> {
+            let offset = offset
+                .checked_mul(SECTOR_SIZE)
+                .filter(|o| o.saturating_add(tables * 4) <= file_size)
+                .ok_or(invalid_header("grain directory out of the file"))?;
+
+            let mut raw = vec![0; (tables * 4) as usize];
+            file.seek(SeekFrom::Start(offset))
+                .and_then(|_| file.read_exact(&mut raw))
+                .map_err(io)?;
+            Ok(raw.chunks(4).map(|e| le32(e, 0)).collect())
+        };
+
+        let gd_offset = le64(&header, 56);
+        let rgd_offset = le64(&header, 48);
+        let gd = read_directory(gd_offset)?;
+        let rgd = match le32(&header, 8) & FLAG_REDUNDANT_TABLES {
+            0 => None,
+            _ => Some(read_directory(rgd_offset)?),
+        };
+
+        Ok(Self {
+            file,
+            file_end: file_size.next_multiple_of(SECTOR_SIZE),
+            capacity,
+            grain_size: grain_sectors * SECTOR_SIZE,
+            gtes_per_gt,
+            gd,
+            rgd,
+            gd_offset: gd_offset * SECTOR_SIZE,
+            rgd_offset: rgd_offset * SECTOR_SIZE,
+            tables: BTreeMap::new(),
+        })
+    }
+
+    fn read_file(&mut self, offset: u64, buf: &mut [u8]) -> Result<(), DiskErr> {
+        self.file
+            .seek(SeekFrom::Start(offset))
+            .and_then(|_| self.file.read_exact(buf))
+            .map_err(|e| DiskErr::from_io(&e))
+    }
+
+    fn write_file(&mut self, offset: u64, buf: &[u8]) -> Result<(), DiskErr> {
+        self.file
+            .seek(SeekFrom::Start(offset))
+            .and_then(|_| self.file.write_all(buf))
+            .map_err(|e| DiskErr::from_io(&e))
+    }
+
+    /// The grain table `index`, read through the cache. Returns `None` if it isn't allocated.
+    fn table(&mut self, index: usize) -> Result<Option<&mut Vec<u32>>, DiskErr> {
+        let sector = *self
+            .gd
+            .get(index)
+            .ok_or(invalid("grain out of the extent"))?;
+        if sector == 0 {
+            return Ok(None);
+        }
+
+        if !self.tables.contains_key(&index) {
+            if self.tables.len() >= GT_CACHE_TABLES {
+                self.tables.clear();
+            }
+
+            let mut raw = vec![0; (self.gtes_per_gt * 4) as usize];
+            self.read_file(sector as u64 * SECTOR_SIZE, &mut raw)?;
+            let table = raw.chunks(4).map(|e| le32(e, 0)).collect();
+            self.tables.insert(index, table);
+        }
+
+        Ok(self.tables.get_mut(&index))
+    }
+
+    /// Sector of the grain `grain` in the file, `0` if it isn't allocated, [`GRAIN_ZERO`] if it
+    /// reads as zeroes
+    fn grain(&mut self, grain: u64) -> Result<u32, DiskErr> {
+        let gtes_per_gt = self.gtes_per_gt;
+        let table = self.table((grain / gtes_per_gt) as usize)?;
+        Ok(table.map_or(0, |t| t[(grain % gtes_per_gt) as usize]))
+    }
+
+    /// Reserves `size` bytes at the end of the file, zeroed, and returns their sector
+    fn allocate(&mut self, size: u64) -> Result<u32, DiskErr> {
+        let offset = self.file_end;
+        let sector = u32::try_from(offset / SECTOR_SIZE)
+            .ok()
+            .filter(|&s| s > GRAIN_ZERO)
+            .ok_or(invalid("no sector offset left"))?;
+
+        self.file_end = (offset + size).next_multiple_of(SECTOR_SIZE);
+        self.file
+            .set_len(self.file_end)
+            .map_err(|e| DiskErr::from_io(&e))?;
+
+        Ok(sector)
+    }
+
+    /// Sets the entry of the grain `grain` in the grain table and in its redundant copy,
+    /// allocating the tables if needed
+    fn set_grain(&mut self, grain: u64, sector: u32) -> Result<(), DiskErr> {
+        let index = (grain / self.gtes_per_gt) as usize;
+        let entry = grain % self.gtes_per_gt;
+        let table_size = self.gtes_per_gt * 4;
+
+        for (directory_offset, redundant) in [(self.gd_offset, false), (self.rgd_offset, true)] {
+            let mut table_sector = match (redundant, &self.rgd) {
+                (false, _) => self.gd[index],
+                (true, Some(rgd)) => rgd[index],
+                (true, None) => continue,
+            };
+            if table_sector == 0 {
+                table_sector = self.allocate(table_size)?;
+                self.write_file(
+                    directory_offset + index as u64 * 4,
+                    &table_sector.to_le_bytes(),
+                )?;
+
+                match redundant {
+                    false => self.gd[index] = table_sector,
+                    true => self.rgd.as_mut().unwrap()[index] = table_sector,
+                }
+            }
+
+            self.write_file(
+                table_sector as u64 * SECTOR_SIZE + entry * 4,
+                &sector.to_le_bytes(),
+            )?;
+        }
+
+        if let Some(table) = self.tables.get_mut(&index) {
+            table[entry as usize] = sector;
+        }
+
+        Ok(())
+    }
+
+    /// Splits a request in the parts of each grain: `(grain, offset in the grain, range of the
+    /// request)`
+    fn grains(&self, offset: u64, len: usize) -> Vec<(u64, u64, usize, usize)> {
+        let mut grains = Vec::new();
+        let mut done = 0;
+
+        while done < len {
+            let pos = offset + done as u64;
+            let in_grain = pos % self.grain_size;
+            let part = ((self.grain_size - in_grain) as usize).min(len - done);
+
+            grains.push((pos / self.grain_size, in_grain, done, done + part));
+            done += part;
+        }
+
+        grains
+    }
+
+    fn read_at(&mut self, offset: u64, buf: &mut [u8]) -> Result<(), DiskErr> {
+        for (grain, in_grain, start, end) in self.grains(offset, buf.len()) {
+            match self.grain(grain)? {
+                0 | GRAIN_ZERO => buf[start..end].fill(0),
+                sector => {
+                    let offset = sector as u64 * SECTOR_SIZE + in_grain;
+                    self.read_file(offset, &mut buf[start..end])?;
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    fn write_at(&mut self, offset: u64, buf: &[u8]) -> Result<(), DiskErr> {
+        for (grain, in_grain, start, end) in self.grains(offset, buf.len()) {
+            // The new grains are zeroed by the extension of the file, then written, then linked
+            let sector = match self.grain(grain)? {
+                0 | GRAIN_ZERO => {
+                    let sector = self.allocate(self.grain_size)?;
+                    self.write_file(sector as u64 * SECTOR_SIZE + in_grain, &buf[start..end])?;
+                    self.set_grain(grain, sector)?;
+                    continue;
+                }
+                sector => sector,
+            };
+
+            self.write_file(sector as u64 * SECTOR_SIZE + in_grain, &buf[start..end])?;
+        }
+
+        Ok(())
+    }
+}
+
+/// The mutable part of the image, behind the lock. The metadata is written through.
+struct State {
+    extents: Vec<Extent>,
+    /// File and position of the content id of the descriptor, changed before the first write
+    cid: Option<(PathBuf, u64)>,
+    cid_updated: bool,
+}
+
+impl State {
+    /// Splits a request in the parts of each extent: `(extent, offset in the extent, range of the
+    /// request)`
+    fn extents(&self, offset: u64, len: usize) -> Vec<(usize, u64, usize, usize)> {
+        let end = offset + len as u64;
+
+        self.extents
+            .iter()
+            .enumerate()
+            .filter(|(_, e)| e.start < end && offset < e.start + e.size)
+            .map(|(i, e)| {
+                let from = offset.max(e.start);
+                let to = end.min(e.start + e.size);
+                (
+                    i,
+                    from - e.start,
+                    (from - offset) as usize,
+                    (to - offset) as usize,
+                )
+            })
+            .collect()
+    }
+
+    fn read_at(&mut self, offset: u64, buf: &mut [u8]) -> Result<(), DiskErr> {
+        for (index, in_extent, start, end) in self.extents(offset, buf.len()) {
+            let buf = &mut buf[start..end];
+
+            match &mut self.extents[index].kind {
+                ExtentKind::Flat { file, offset } => file
+                    .seek(SeekFrom::Start(*offset + in_extent))
+                    .and_then(|_| file.read_exact(buf))
+                    .map_err(|e| DiskErr::from_io(&e))?,
+                ExtentKind::Sparse(sparse) => sparse.read_at(in_extent, buf)?,
+                ExtentKind::Zero => buf.fill(0),
+            }
+        }
+
+        Ok(())
+    }
+
+    fn write_at(&mut self, offset: u64, buf: &[u8]) -> Result<(), DiskErr> {
+        if !self.cid_updated {
+            self.update_cid()?;
+        }
+
+        for (index, in_extent, start, end) in self.extents(offset, buf.len()) {
+            let buf = &buf[start..end];
+
+            match &mut self.extents[index].kind {
+                ExtentKind::Flat { file, offset } => file
+                    .seek(SeekFrom::Start(*offset + in_extent))
+                    .and_then(|_| file.write_all(buf))
+                    .map_err(|e| DiskErr::from_io(&e))?,
+                ExtentKind::Sparse(sparse) => sparse.write_at(in_extent, buf)?,
+                ExtentKind::Zero => return Err(invalid("write to a zero extent")),
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Gives a new content id to the image, which tells its children it was modified
+    fn update_cid(&mut self) -> Result<(), DiskErr> {
+        if let Some((path, offset)) = &self.cid {
+            let cid = u32::from_le_bytes(random_guid()[..4].try_into().unwrap());
+
+            File::options()
+                .write(true)
+                .open(path)
+                .and_then(|mut file| {
+                    file.seek(SeekFrom::Start(*offset))?;
+                    file.write_all(format!("{cid:08x}").as_bytes())?;
+                    file.sync_data()
+                })
+                .map_err(|e| DiskErr::from_io(&e))?;
+        }
+
+        self.cid_updated = true;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::std_helpers::TempPath;
+
+    #[test]
+    fn create_and_reopen() {
+        let data: Vec<u8> = (0..(3 * 4096)).map(|i| (i % 253) as u8).collect();
+        let size = 1 << 20;
+
+        for vmdk_type in [
+            VmdkType::MonolithicFlat,
+            VmdkType::MonolithicSparse,
+            VmdkType::TwoGbMaxExtentFlat,
+            VmdkType::TwoGbMaxExtentSparse,
+        ] {
+            let temp = TempPath::new(&format!("{}.vmdk", vmdk_type.name()));
+
+            let disk = VmdkDisk::create(temp.path.clone(), size, vmdk_type).unwrap();
+            assert!(VmdkDisk::create(temp.path.clone(), size, vmdk_type).is_err());
+            disk.write_sectors(1, 512, &data[..4096]).unwrap();
+            // Across a grain boundary
+            disk.write_sectors(15, 4096, &data).unwrap();
+            disk.flush().unwrap();
+            drop(disk);
+
+            let disk = VmdkDisk::open(temp.path.clone(), Permissions::read_only()).unwrap();
+            assert_eq!(disk.create_type(), vmdk_type.name());
+            assert_eq!(disk.extent_count(), 1);
+            assert_eq!(disk.disk_infos().unwrap().disk_size, size);
+
+            let mut buf = vec![0; 3 * 4096];
+            disk.read_sectors(0, 512, &mut buf[..4608]).unwrap();
+            assert_eq!(buf[..512], [0; 512]);
+            assert_eq!(buf[512..4608], data[..4096]);
+            disk.read_sectors(15, 4096, &mut buf).unwrap();
+            assert_eq!(buf, data);
+            disk.read_sectors(255, 4096, &mut buf[..4096]).unwrap();
+            assert_eq!(buf[..4096], [0; 4096]);
+
+            assert!(disk.write_sectors(0, 512, &[0; 512]).is_err());
+            assert!(disk.read_sectors(256, 4096, &mut buf[..4096]).is_err());
+        }
+    }
+
+    #[test]
+    fn malformed_headers() {
+        let temp = TempPath::new("malformed.vmdk");
+        let invalid = |reason| Some(ImageErr::InvalidHeader { reason });
+
+        drop(VmdkDisk::create(temp.path.clone(), 1 << 20, VmdkType::TwoGbMaxExtentSparse).unwrap());
+        let descriptor = fs::read_to_string(&temp.path).unwrap();
+        let extent_path = temp.path.with_file_name("malformed-s001.vmdk");
+        let extent = fs::read(&extent_path).unwrap();
+
+        let open = |descriptor: &str, extent: &[u8]| {
+            fs::write(&temp.path, descriptor).unwrap();
+            fs::write(&extent_path, extent).unwrap();
+            VmdkDisk::open(temp.path.clone(), Permissions::read_only()).err()
+        };
+
+        assert_eq!(open(&descriptor, &extent), None);
+        assert_eq!(
+            open(&descriptor.replace("createType", "createType "), &extent),
+            None
+        );
+
+        assert_eq!(
+            open(&descriptor.replace("CID=", "CID "), &extent),
+            invalid("invalid descriptor line")
+        );
+        assert_eq!(
+            open(&descriptor.replace("RW 2048", "RW many"), &extent),
+            invalid("invalid extent size")
+        );
+        assert_eq!(
+            open(&descriptor.replace("RW 2048", "RW 4096"), &extent),
+            invalid("sparse extent smaller than described")
+        );
+        let no_extent: String = descriptor
+            .lines()
+            .filter(|l| !l.starts_with("RW"))
+            .map(|l| format!("{l}\n"))
+            .collect();
+        assert_eq!(open(&no_extent, &extent), invalid("no extent"));
+
+        let mut bad = extent.clone();
+        bad[0] = b'k';
+        assert_eq!(open(&descriptor, &bad), invalid("not a VMDK sparse extent"));
+
+        let mut bad = extent.clone();
+        bad[20..28].copy_from_slice(&100u64.to_le_bytes());
+        assert_eq!(open(&descriptor, &bad), invalid("invalid grain size"));
+
+        let mut bad = extent.clone();
+        bad[4..8].copy_from_slice(&9u32.to_le_bytes());
+        assert!(matches!(
+            open(&descriptor, &bad),
+            Some(ImageErr::Unsupported { .. })
+        ));
+    }
+}
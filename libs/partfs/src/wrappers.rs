@@ -1,19 +1,35 @@
-use crate::{Disk, DiskErr, DiskInfos, Permissions, SectorSize};
+use crate::{
+    DeviceId, Disk, DiskErr, DiskErrKind, DiskInfos, Geometry, Operation, Permissions, SectorSize,
+};
 use alloc::{
     boxed::Box,
     sync::{Arc, Weak},
+    vec,
     vec::Vec,
 };
 use mutex::Mutex;
 
+/// Greatest common divisor, `gcd(0, b) == b`
+pub(crate) fn gcd(mut a: u64, mut b: u64) -> u64 {
+    while b != 0 {
+        (a, b) = (b, a % b);
+    }
+    a
+}
+
+/// Checks if any of the borrowed ranges `borrows` overlaps [start, end[
+fn overlaps(borrows: &[(u64, u64)], start: u64, end: u64) -> bool {
+    borrows.iter().any(|&(s, e)| s < end && start < e)
+}
+
 /// A global wrapper that can be created from any `Disk`. Is used only for creating `SubDisk`s
 pub struct DiskWrapper {
     /// The disk from where it has been created
     disk: Mutex<Box<dyn Disk>>,
     /// The space borrowed for reading [start, end[
-    r_borrows: Mutex<Vec<(usize, usize)>>,
+    r_borrows: Mutex<Vec<(u64, u64)>>,
     /// The space borrowed for writing [start, end[
-    w_borrows: Mutex<Vec<(usize, usize)>>,
+    w_borrows: Mutex<Vec<(u64, u64)>>,
     /// A weak reference to self, used to give access to the `DiskWrapper` to all the `SubDisk`s
     /// created from it
     weak_self: Mutex<Weak<Self>>,
@@ -39,25 +55,15 @@ impl DiskWrapper {
     /// Checks if a specific range of space is borrowed for reading. If any part of the range is
     /// borrowed for reading, returns true. There is not possibility to fail for this function, so
     /// it will correctly return even if the disk is not available.
-    pub fn is_r_borrowed(&self, start: usize, end: usize) -> bool {
-        for i in &*self.r_borrows.lock() {
-            if (i.0 <= start && start < i.1) || (i.0 < end && end <= i.1) {
-                return true;
-            }
-        }
-        false
+    pub fn is_r_borrowed(&self, start: u64, end: u64) -> bool {
+        overlaps(&self.r_borrows.lock(), start, end)
     }
 
     /// Checks if a specific range of space is borrowed for writing. If any part of the range is
-    /// borrowed for reading, returns true. There is not possibility to fail for this function, so
+    /// borrowed for writing, returns true. There is not possibility to fail for this function, so
     /// it will correctly return even if the disk is not available.
-    pub fn is_w_borrowed(&self, start: usize, end: usize) -> bool {
-        for i in &*self.w_borrows.lock() {
-            if (i.0 <= start && start < i.1) || (i.0 < end && end <= i.1) {
-                return true;
-            }
-        }
-        false
+    pub fn is_w_borrowed(&self, start: u64, end: u64) -> bool {
+        overlaps(&self.w_borrows.lock(), start, end)
     }
 
     /// Creates a new subdisk in a given range of space, with the specified permissions. This
@@ -66,18 +72,22 @@ impl DiskWrapper {
     /// read/write) borrow or unlimited immutable borrows (read only).
     pub fn subdisk(
         &self,
-        start: usize,
-        end: usize,
+        start: u64,
+        end: u64,
         permissions: Permissions,
     ) -> Result<SubDisk, DiskErr> {
         // ### CHECKS IF THE SPACE IS AVAILABLE ###
 
         if self.is_w_borrowed(start, end) || (self.is_r_borrowed(start, end) && permissions.write) {
-            return Err(DiskErr::Busy);
+            return Err(DiskErr::new(DiskErrKind::Busy)
+                .during(Operation::CreateSubdisk)
+                .in_layer("DiskWrapper"));
         }
 
         if end > self.disk.lock().disk_infos()?.disk_size {
-            return Err(DiskErr::InvalidDiskSize);
+            return Err(DiskErr::new(DiskErrKind::InvalidDiskSize)
+                .during(Operation::CreateSubdisk)
+                .in_layer("DiskWrapper"));
         }
 
         // ### REGISTERS THE SPACE AS USED ###
@@ -92,21 +102,24 @@ impl DiskWrapper {
 
         // ### CREATES THE SUBDISK ###
 
-        let sector_size = self.disk_infos()?.sector_size;
+        let infos = self.disk_infos()?;
         let parent = self.weak_self.lock().clone();
 
         Ok(SubDisk {
             parent,
             start,
             end,
-            sector_size,
+            // The sectors of the subdisk must be sectors of the parent disk
+            sector_size: infos.sector_size.aligned_to(start),
             permissions,
+            geometry: infos.geometry.slice(start),
+            device_id: infos.device_id.map(|id| id.slice(vec![(start, end)])),
         })
     }
 
     pub fn fragmented_subdisk(
         &self,
-        parts: Vec<(usize, usize)>,
+        parts: Vec<(u64, u64)>,
         permissions: Permissions,
     ) -> Result<FragmentedSubDisk, DiskErr> {
         let mut w_borrows = self.w_borrows.lock();
@@ -117,33 +130,18 @@ impl DiskWrapper {
 
         for &(start, end) in &parts {
             if start > end || end > disk_size {
-                return Err(DiskErr::InvalidDiskSize);
+                return Err(DiskErr::new(DiskErrKind::InvalidDiskSize)
+                    .during(Operation::CreateSubdisk)
+                    .in_layer("DiskWrapper"));
             }
 
-            if permissions.read
-                && (|| {
-                    for i in r_borrows.clone() {
-                        if (i.0 <= start && start < i.1) || (i.0 < end && end <= i.1) {
-                            return true;
-                        }
-                    }
-                    false
-                })()
+            // Same rules as `subdisk`
+            if overlaps(&w_borrows, start, end)
+                || (permissions.write && overlaps(&r_borrows, start, end))
             {
-                return Err(DiskErr::SpaceAlreadyInUse);
-            }
-
-            if permissions.write
-                && (|| {
-                    for i in w_borrows.clone() {
-                        if (i.0 <= start && start < i.1) || (i.0 < end && end <= i.1) {
-                            return true;
-                        }
-                    }
-                    false
-                })()
-            {
-                return Err(DiskErr::SpaceAlreadyInUse);
+                return Err(DiskErr::new(DiskErrKind::Busy)
+                    .during(Operation::CreateSubdisk)
+                    .in_layer("DiskWrapper"));
             }
 
             size += end - start;
@@ -157,163 +155,383 @@ impl DiskWrapper {
             w_borrows.extend_from_slice(&parts);
         }
 
-        let sector_size = self.disk_infos()?.sector_size;
+        let infos = self.disk_infos()?;
         let parent = self.weak_self.lock().clone();
 
+        // Each part must start and end on a sector of the parent disk
+        let alignment = parts
+            .iter()
+            .flat_map(|&(start, end)| [start, end - start])
+            .fold(0, gcd);
+
         Ok(FragmentedSubDisk {
             parent,
+            geometry: infos
+                .geometry
+                .slice(parts.first().map_or(0, |&(start, _)| start)),
+            device_id: infos.device_id.map(|id| id.slice(parts.clone())),
             parts,
             size,
-            sector_size,
+            sector_size: infos.sector_size.aligned_to(alignment),
             permissions,
         })
     }
 }
 
 impl Disk for DiskWrapper {
-    fn read_sector(&self, sector: usize, buf: &mut [u8]) -> Result<(), DiskErr> {
+    fn read_sector(&self, sector: u64, buf: &mut [u8]) -> Result<(), DiskErr> {
+        self.read_sectors(sector, buf.len(), buf)
+    }
+
+    fn write_sector(&self, sector: u64, buf: &[u8]) -> Result<(), DiskErr> {
+        self.write_sectors(sector, buf.len(), buf)
+    }
+
+    fn disk_infos(&self) -> Result<DiskInfos, DiskErr> {
+        self.disk.lock().disk_infos()
+    }
+
+    fn read_sectors(&self, sector: u64, sector_size: usize, buf: &mut [u8]) -> Result<(), DiskErr> {
         // ### VERIFIES IF THE SECTION IS CURRENTLY BORROWED ###
 
-        let start = sector * buf.len();
-        let end = start + buf.len();
+        let start = sector.saturating_mul(sector_size as u64);
+        let end = start.saturating_add(buf.len() as u64);
 
         if self.is_w_borrowed(start, end) {
-            return Err(DiskErr::Busy);
+            return Err(DiskErr::new(DiskErrKind::Busy)
+                .at(Operation::Read, sector)
+                .in_layer("DiskWrapper"));
         }
 
-        self.disk.lock().read_sector(sector, buf)
+        self.disk.lock().read_sectors(sector, sector_size, buf)
     }
 
-    fn write_sector(&self, sector: usize, buf: &[u8]) -> Result<(), DiskErr> {
+    fn write_sectors(&self, sector: u64, sector_size: usize, buf: &[u8]) -> Result<(), DiskErr> {
         // ### VERIFIES IF THE SECTION IS CURRENTLY BORROWED ###
 
-        let start = sector * buf.len();
-        let end = start + buf.len();
+        let start = sector.saturating_mul(sector_size as u64);
+        let end = start.saturating_add(buf.len() as u64);
 
         if self.is_w_borrowed(start, end) || self.is_r_borrowed(start, end) {
-            return Err(DiskErr::Busy);
+            return Err(DiskErr::new(DiskErrKind::Busy)
+                .at(Operation::Write, sector)
+                .in_layer("DiskWrapper"));
         }
 
-        self.disk.lock().write_sector(sector, buf)
+        self.disk.lock().write_sectors(sector, sector_size, buf)
     }
 
-    fn disk_infos(&self) -> Result<crate::DiskInfos, DiskErr> {
-        self.disk.lock().disk_infos()
+    fn flush(&self) -> Result<(), DiskErr> {
+        self.disk.lock().flush()
+    }
+
+    fn discard(&self, sector: u64, sector_size: usize, count: u64) -> Result<(), DiskErr> {
+        // ### VERIFIES IF THE SECTION IS CURRENTLY BORROWED ###
+
+        let start = sector.saturating_mul(sector_size as u64);
+        let end = start.saturating_add(count.saturating_mul(sector_size as u64));
+
+        if self.is_w_borrowed(start, end) || self.is_r_borrowed(start, end) {
+            return Err(DiskErr::new(DiskErrKind::Busy)
+                .at(Operation::Discard, sector)
+                .in_layer("DiskWrapper"));
+        }
+
+        self.disk.lock().discard(sector, sector_size, count)
+    }
+
+    fn write_sectors_fua(
+        &self,
+        sector: u64,
+        sector_size: usize,
+        buf: &[u8],
+    ) -> Result<(), DiskErr> {
+        // ### VERIFIES IF THE SECTION IS CURRENTLY BORROWED ###
+
+        let start = sector.saturating_mul(sector_size as u64);
+        let end = start.saturating_add(buf.len() as u64);
+
+        if self.is_w_borrowed(start, end) || self.is_r_borrowed(start, end) {
+            return Err(DiskErr::new(DiskErrKind::Busy)
+                .at(Operation::Write, sector)
+                .in_layer("DiskWrapper"));
+        }
+
+        self.disk.lock().write_sectors_fua(sector, sector_size, buf)
+    }
+
+    fn read_sectors_vectored(
+        &self,
+        sector_size: usize,
+        requests: &mut [(u64, &mut [u8])],
+    ) -> Result<(), DiskErr> {
+        // ### VERIFIES IF THE SECTIONS ARE CURRENTLY BORROWED ###
+
+        for (sector, buf) in requests.iter() {
+            let start = sector.saturating_mul(sector_size as u64);
+            let end = start.saturating_add(buf.len() as u64);
+
+            if self.is_w_borrowed(start, end) {
+                return Err(DiskErr::new(DiskErrKind::Busy)
+                    .at(Operation::Read, *sector)
+                    .in_layer("DiskWrapper"));
+            }
+        }
+
+        self.disk
+            .lock()
+            .read_sectors_vectored(sector_size, requests)
+    }
+
+    fn write_sectors_vectored(
+        &self,
+        sector_size: usize,
+        requests: &[(u64, &[u8])],
+    ) -> Result<(), DiskErr> {
+        // ### VERIFIES IF THE SECTIONS ARE CURRENTLY BORROWED ###
+
+        for (sector, buf) in requests {
+            let start = sector.saturating_mul(sector_size as u64);
+            let end = start.saturating_add(buf.len() as u64);
+
+            if self.is_w_borrowed(start, end) || self.is_r_borrowed(start, end) {
+                return Err(DiskErr::new(DiskErrKind::Busy)
+                    .at(Operation::Write, *sector)
+                    .in_layer("DiskWrapper"));
+            }
+        }
+
+        self.disk
+            .lock()
+            .write_sectors_vectored(sector_size, requests)
     }
 }
 
 #[derive(Debug)]
 pub struct SubDisk {
     parent: Weak<DiskWrapper>,
-    start: usize,
-    end: usize,
+    start: u64,
+    end: u64,
     sector_size: SectorSize,
     permissions: Permissions,
+    /// The geometry of the parent disk, seen from the start of the subdisk
+    geometry: Geometry,
+    device_id: Option<DeviceId>,
 }
 
-impl Disk for SubDisk {
-    fn read_sector(&self, sector: usize, buf: &mut [u8]) -> Result<(), DiskErr> {
-        // ### GETS THE PARENT ###
-
-        let parent = match self.parent.upgrade() {
-            Some(v) => v,
-            None => return Err(DiskErr::UnreachableDisk),
-        };
-
-        // ### CHECKS THE PERMISSIONS ###
-
-        if !self.permissions.read {
-            return Err(DiskErr::InvalidPermission {
-                disk_permissions: self.permissions,
-            });
+impl SubDisk {
+    /// Gets the `DiskWrapper` the subdisk has been created from
+    fn parent(&self, operation: Operation) -> Result<Arc<DiskWrapper>, DiskErr> {
+        match self.parent.upgrade() {
+            Some(v) => Ok(v),
+            None => Err(DiskErr::new(DiskErrKind::UnreachableDisk)
+                .during(operation)
+                .in_layer("SubDisk")),
         }
+    }
 
+    /// Checks a request of `len` bytes starting at the LBA `sector` of the subdisk and returns
+    /// the corresponding LBA on the parent disk.
+    fn parent_sector(
+        &self,
+        operation: Operation,
+        sector: u64,
+        sector_size: usize,
+        len: u64,
+    ) -> Result<u64, DiskErr> {
         // ### VERIFIES THE SECTOR SIZE ###
 
-        let sector_size = buf.len();
-
-        if !self
-            .sector_size
-            .is_supported(sector_size, self.end - self.start)
-            || !self.start.is_multiple_of(sector_size)
+        if sector_size == 0
+            || !self
+                .sector_size
+                .is_supported(sector_size, self.end - self.start)
+            || !self.start.is_multiple_of(sector_size as u64)
+            || !len.is_multiple_of(sector_size as u64)
         {
-            return Err(DiskErr::InvalidSectorSize {
+            return Err(DiskErr::new(DiskErrKind::InvalidSectorSize {
                 found: sector_size,
                 supported: self.sector_size.clone(),
                 start: self.start,
-            });
+            })
+            .at(operation, sector)
+            .in_layer("SubDisk"));
         }
 
-        // ### VERIFIES IF THE SECTOR IS IN THE SUBDISK RANGE ###
-
-        let offset = self.start + sector_size * sector;
+        // ### VERIFIES IF THE SECTORS ARE IN THE SUBDISK RANGE ###
 
-        if offset >= self.end {
-            return Err(DiskErr::InvalidSectorIndex {
+        match sector
+            .checked_mul(sector_size as u64)
+            .and_then(|v| v.checked_add(self.start))
+        {
+            Some(offset) if offset.checked_add(len).is_some_and(|end| end <= self.end) => {
+                Ok(offset / sector_size as u64)
+            }
+            _ => Err(DiskErr::new(DiskErrKind::InvalidSectorIndex {
                 found: sector,
-                max: (self.end - self.start) / sector_size,
-            });
+                max: ((self.end - self.start) / sector_size as u64).saturating_sub(1),
+            })
+            .at(operation, sector)
+            .in_layer("SubDisk")),
         }
+    }
+}
 
-        let sector = offset / sector_size;
+impl Disk for SubDisk {
+    fn read_sector(&self, sector: u64, buf: &mut [u8]) -> Result<(), DiskErr> {
+        self.read_sectors(sector, buf.len(), buf)
+    }
 
-        parent.disk.lock().read_sector(sector, buf)
+    fn write_sector(&self, sector: u64, buf: &[u8]) -> Result<(), DiskErr> {
+        self.write_sectors(sector, buf.len(), buf)
     }
 
-    fn write_sector(&self, sector: usize, buf: &[u8]) -> Result<(), DiskErr> {
-        // ### GETS THE PARENT ###
+    fn disk_infos(&self) -> Result<DiskInfos, DiskErr> {
+        Ok(DiskInfos {
+            sector_size: self.sector_size.clone(),
+            disk_size: self.end - self.start,
+            permissions: self.permissions,
+            geometry: self.geometry,
+            device_id: self.device_id.clone(),
+        })
+    }
 
-        let parent = match self.parent.upgrade() {
-            Some(v) => v,
-            None => return Err(DiskErr::UnreachableDisk),
-        };
+    fn read_sectors(&self, sector: u64, sector_size: usize, buf: &mut [u8]) -> Result<(), DiskErr> {
+        let parent = self.parent(Operation::Read)?;
 
-        // ### CHECKS THE PERMISSIONS ###
+        if !self.permissions.read {
+            return Err(DiskErr::new(DiskErrKind::InvalidPermission {
+                disk_permissions: self.permissions,
+            })
+            .at(Operation::Read, sector)
+            .in_layer("SubDisk"));
+        }
+
+        let sector = self.parent_sector(Operation::Read, sector, sector_size, buf.len() as u64)?;
+
+        parent.disk.lock().read_sectors(sector, sector_size, buf)
+    }
+
+    fn write_sectors(&self, sector: u64, sector_size: usize, buf: &[u8]) -> Result<(), DiskErr> {
+        let parent = self.parent(Operation::Write)?;
 
         if !self.permissions.write {
-            return Err(DiskErr::InvalidPermission {
+            return Err(DiskErr::new(DiskErrKind::InvalidPermission {
                 disk_permissions: self.permissions,
-            });
+            })
+            .at(Operation::Write, sector)
+            .in_layer("SubDisk"));
         }
 
-        // ### VERIFIES THE SECTOR SIZE ###
+        let sector = self.parent_sector(Operation::Write, sector, sector_size, buf.len() as u64)?;
 
-        let sector_size = buf.len();
+        parent.disk.lock().write_sectors(sector, sector_size, buf)
+    }
 
-        if !self
-            .sector_size
-            .is_supported(sector_size, self.end - self.start)
-            || !self.start.is_multiple_of(sector_size)
-        {
-            return Err(DiskErr::InvalidSectorSize {
-                found: sector_size,
-                supported: self.sector_size.clone(),
-                start: self.start,
-            });
+    fn flush(&self) -> Result<(), DiskErr> {
+        self.parent(Operation::Flush)?.disk.lock().flush()
+    }
+
+    fn discard(&self, sector: u64, sector_size: usize, count: u64) -> Result<(), DiskErr> {
+        let parent = self.parent(Operation::Discard)?;
+
+        if !self.permissions.write {
+            return Err(DiskErr::new(DiskErrKind::InvalidPermission {
+                disk_permissions: self.permissions,
+            })
+            .at(Operation::Discard, sector)
+            .in_layer("SubDisk"));
         }
 
-        // ### VERIFIES IF THE SECTOR IS IN THE SUBDISK RANGE ###
+        let sector = self.parent_sector(
+            Operation::Discard,
+            sector,
+            sector_size,
+            count.saturating_mul(sector_size as u64),
+        )?;
 
-        let offset = self.start + sector_size * sector;
+        parent.disk.lock().discard(sector, sector_size, count)
+    }
 
-        if offset >= self.end {
-            return Err(DiskErr::InvalidSectorIndex {
-                found: sector,
-                max: (self.end - self.start) / sector_size,
-            });
+    fn write_sectors_fua(
+        &self,
+        sector: u64,
+        sector_size: usize,
+        buf: &[u8],
+    ) -> Result<(), DiskErr> {
+        let parent = self.parent(Operation::Write)?;
+
+        if !self.permissions.write {
+            return Err(DiskErr::new(DiskErrKind::InvalidPermission {
+                disk_permissions: self.permissions,
+            })
+            .at(Operation::Write, sector)
+            .in_layer("SubDisk"));
         }
 
-        let sector = offset / sector_size;
+        let sector = self.parent_sector(Operation::Write, sector, sector_size, buf.len() as u64)?;
 
-        parent.disk.lock().write_sector(sector, buf)
+        parent
+            .disk
+            .lock()
+            .write_sectors_fua(sector, sector_size, buf)
     }
 
-    fn disk_infos(&self) -> Result<DiskInfos, DiskErr> {
-        Ok(DiskInfos {
-            sector_size: self.sector_size.clone(),
-            disk_size: self.end - self.start,
-            permissions: self.permissions,
-        })
+    fn read_sectors_vectored(
+        &self,
+        sector_size: usize,
+        requests: &mut [(u64, &mut [u8])],
+    ) -> Result<(), DiskErr> {
+        let parent = self.parent(Operation::Read)?;
+
+        if !self.permissions.read {
+            return Err(DiskErr::new(DiskErrKind::InvalidPermission {
+                disk_permissions: self.permissions,
+            })
+            .during(Operation::Read)
+            .in_layer("SubDisk"));
+        }
+
+        let mut translated = Vec::with_capacity(requests.len());
+        for (sector, buf) in requests.iter_mut() {
+            translated.push((
+                self.parent_sector(Operation::Read, *sector, sector_size, buf.len() as u64)?,
+                &mut **buf,
+            ));
+        }
+
+        parent
+            .disk
+            .lock()
+            .read_sectors_vectored(sector_size, &mut translated)
+    }
+
+    fn write_sectors_vectored(
+        &self,
+        sector_size: usize,
+        requests: &[(u64, &[u8])],
+    ) -> Result<(), DiskErr> {
+        let parent = self.parent(Operation::Write)?;
+
+        if !self.permissions.write {
+            return Err(DiskErr::new(DiskErrKind::InvalidPermission {
+                disk_permissions: self.permissions,
+            })
+            .during(Operation::Write)
+            .in_layer("SubDisk"));
+        }
+
+        let mut translated = Vec::with_capacity(requests.len());
+        for &(sector, buf) in requests {
+            translated.push((
+                self.parent_sector(Operation::Write, sector, sector_size, buf.len() as u64)?,
+                buf,
+            ));
+        }
+
+        parent
+            .disk
+            .lock()
+            .write_sectors_vectored(sector_size, &translated)
     }
 }
 
@@ -347,137 +565,211 @@ impl Drop for SubDisk {
 pub struct FragmentedSubDisk {
     parent: Weak<DiskWrapper>,
     /// In sectors
-    parts: Vec<(usize, usize)>,
-    size: usize,
+    parts: Vec<(u64, u64)>,
+    size: u64,
     sector_size: SectorSize,
     permissions: Permissions,
+    /// The geometry of the parent disk, seen from the start of the subdisk
+    geometry: Geometry,
+    device_id: Option<DeviceId>,
 }
 
-impl Disk for FragmentedSubDisk {
-    fn read_sector(&self, sector: usize, buf: &mut [u8]) -> Result<(), DiskErr> {
-        // ### GETS THE PARENT ###
-
-        let parent = match self.parent.upgrade() {
-            Some(v) => v,
-            None => return Err(DiskErr::UnreachableDisk),
-        };
-
-        // ### CHECKS THE PERMISSIONS ###
-
-        if !self.permissions.read {
-            return Err(DiskErr::InvalidPermission {
-                disk_permissions: self.permissions,
-            });
+impl FragmentedSubDisk {
+    /// Gets the `DiskWrapper` the subdisk has been created from
+    fn parent(&self, operation: Operation) -> Result<Arc<DiskWrapper>, DiskErr> {
+        match self.parent.upgrade() {
+            Some(v) => Ok(v),
+            None => Err(DiskErr::new(DiskErrKind::UnreachableDisk)
+                .during(operation)
+                .in_layer("FragmentedSubDisk")),
         }
+    }
 
+    /// Checks a request of `len` bytes starting at the LBA `sector` of the subdisk and splits it
+    /// into runs on the parent disk. Each run is `(parent_lba, size_in_bytes)`.
+    fn parent_runs(
+        &self,
+        operation: Operation,
+        sector: u64,
+        sector_size: usize,
+        len: u64,
+    ) -> Result<Vec<(u64, u64)>, DiskErr> {
         if self.parts.is_empty() {
-            return Err(DiskErr::InvalidSectorIndex {
+            return Err(DiskErr::new(DiskErrKind::InvalidSectorIndex {
                 found: sector,
                 max: 0,
-            });
+            })
+            .at(operation, sector)
+            .in_layer("FragmentedSubDisk"));
         }
 
         // ### VERIFIES THE SECTOR SIZE ###
 
-        let sector_size = buf.len();
+        let invalid_size = DiskErr::new(DiskErrKind::InvalidSectorSize {
+            found: sector_size,
+            supported: self.sector_size.clone(),
+            start: self.parts[0].0,
+        })
+        .at(operation, sector)
+        .in_layer("FragmentedSubDisk");
 
-        if !self.sector_size.is_supported(sector_size, self.size) {
-            return Err(DiskErr::InvalidSectorSize {
-                found: sector_size,
-                supported: self.sector_size.clone(),
-                start: self.parts[0].0,
-            });
+        if sector_size == 0
+            || !self.sector_size.is_supported(sector_size, self.size)
+            || !len.is_multiple_of(sector_size as u64)
+        {
+            return Err(invalid_size);
         }
 
-        let mut offset = 0;
-        let mut current_sector = 0;
+        // ### VERIFIES IF THE SECTORS ARE IN THE SUBDISK RANGE ###
+
+        let out_of_range = DiskErr::new(DiskErrKind::InvalidSectorIndex {
+            found: sector,
+            max: (self.size / sector_size as u64).saturating_sub(1),
+        })
+        .at(operation, sector)
+        .in_layer("FragmentedSubDisk");
+
+        let mut offset = match sector.checked_mul(sector_size as u64) {
+            Some(v) => v,
+            None => return Err(out_of_range),
+        };
+
+        if offset.checked_add(len).is_none_or(|end| end > self.size) {
+            return Err(out_of_range);
+        }
+
+        // ### SPLITS THE REQUEST ACROSS THE PARTS ###
+
+        let mut remaining = len;
+        let mut runs = Vec::new();
 
         for &(start, end) in &self.parts {
             let size = end - start;
-            if size % sector_size != 0 || start % sector_size != 0 {
-                return Err(DiskErr::InvalidSectorSize {
-                    found: sector_size,
-                    supported: self.sector_size.clone(),
-                    start: self.parts[0].0,
-                });
+            if !size.is_multiple_of(sector_size as u64) || !start.is_multiple_of(sector_size as u64)
+            {
+                return Err(invalid_size);
             }
 
-            if current_sector + size / sector_size > sector {
-                offset = start + (sector - current_sector) * sector_size;
+            if remaining == 0 {
                 break;
             }
 
-            current_sector += size / sector_size;
+            if offset >= size {
+                offset -= size;
+                continue;
+            }
+
+            let run = remaining.min(size - offset);
+            runs.push(((start + offset) / sector_size as u64, run));
+            remaining -= run;
+            offset = 0;
         }
 
-        parent.disk.lock().read_sector(offset / sector_size, buf)
+        Ok(runs)
     }
+}
 
-    fn write_sector(&self, sector: usize, buf: &[u8]) -> Result<(), DiskErr> {
-        // ### GETS THE PARENT ###
+impl Disk for FragmentedSubDisk {
+    fn read_sector(&self, sector: u64, buf: &mut [u8]) -> Result<(), DiskErr> {
+        self.read_sectors(sector, buf.len(), buf)
+    }
 
-        let parent = match self.parent.upgrade() {
-            Some(v) => v,
-            None => return Err(DiskErr::UnreachableDisk),
-        };
+    fn write_sector(&self, sector: u64, buf: &[u8]) -> Result<(), DiskErr> {
+        self.write_sectors(sector, buf.len(), buf)
+    }
+
+    fn disk_infos(&self) -> Result<DiskInfos, DiskErr> {
+        Ok(DiskInfos {
+            sector_size: self.sector_size.clone(),
+            disk_size: self.size,
+            permissions: self.permissions,
+            geometry: self.geometry,
+            device_id: self.device_id.clone(),
+        })
+    }
 
-        // ### CHECKS THE PERMISSIONS ###
+    fn read_sectors(&self, sector: u64, sector_size: usize, buf: &mut [u8]) -> Result<(), DiskErr> {
+        let parent = self.parent(Operation::Read)?;
 
-        if !self.permissions.write {
-            return Err(DiskErr::InvalidPermission {
+        if !self.permissions.read {
+            return Err(DiskErr::new(DiskErrKind::InvalidPermission {
                 disk_permissions: self.permissions,
-            });
+            })
+            .at(Operation::Read, sector)
+            .in_layer("FragmentedSubDisk"));
         }
 
-        if self.parts.is_empty() {
-            return Err(DiskErr::InvalidSectorIndex {
-                found: sector,
-                max: 0,
-            });
+        let runs = self.parent_runs(Operation::Read, sector, sector_size, buf.len() as u64)?;
+
+        let mut requests = Vec::with_capacity(runs.len());
+        let mut rest = buf;
+        for (sector, len) in runs {
+            let (head, tail) = rest.split_at_mut(len as usize);
+            requests.push((sector, head));
+            rest = tail;
         }
 
-        // ### VERIFIES THE SECTOR SIZE ###
+        parent
+            .disk
+            .lock()
+            .read_sectors_vectored(sector_size, &mut requests)
+    }
 
-        let sector_size = buf.len();
+    fn write_sectors(&self, sector: u64, sector_size: usize, buf: &[u8]) -> Result<(), DiskErr> {
+        let parent = self.parent(Operation::Write)?;
 
-        if !self.sector_size.is_supported(sector_size, self.size) {
-            return Err(DiskErr::InvalidSectorSize {
-                found: sector_size,
-                supported: self.sector_size.clone(),
-                start: self.parts[0].0,
-            });
+        if !self.permissions.write {
+            return Err(DiskErr::new(DiskErrKind::InvalidPermission {
+                disk_permissions: self.permissions,
+            })
+            .at(Operation::Write, sector)
+            .in_layer("FragmentedSubDisk"));
         }
 
-        let mut offset = 0;
-        let mut current_sector = 0;
+        let runs = self.parent_runs(Operation::Write, sector, sector_size, buf.len() as u64)?;
 
-        for &(start, end) in &self.parts {
-            let size = end - start;
-            if size % sector_size != 0 || start % sector_size != 0 {
-                return Err(DiskErr::InvalidSectorSize {
-                    found: sector_size,
-                    supported: self.sector_size.clone(),
-                    start: self.parts[0].0,
-                });
-            }
+        let mut requests = Vec::with_capacity(runs.len());
+        let mut rest = buf;
+        for (sector, len) in runs {
+            let (head, tail) = rest.split_at(len as usize);
+            requests.push((sector, head));
+            rest = tail;
+        }
 
-            if current_sector + size / sector_size > sector {
-                offset = start + (sector - current_sector) * sector_size;
-                break;
-            }
+        parent
+            .disk
+            .lock()
+            .write_sectors_vectored(sector_size, &requests)
+    }
+
+    fn flush(&self) -> Result<(), DiskErr> {
+        self.parent(Operation::Flush)?.disk.lock().flush()
+    }
 
-            current_sector += size / sector_size;
+    fn discard(&self, sector: u64, sector_size: usize, count: u64) -> Result<(), DiskErr> {
+        let parent = self.parent(Operation::Discard)?;
+
+        if !self.permissions.write {
+            return Err(DiskErr::new(DiskErrKind::InvalidPermission {
+                disk_permissions: self.permissions,
+            })
+            .at(Operation::Discard, sector)
+            .in_layer("FragmentedSubDisk"));
         }
 
-        parent.disk.lock().write_sector(offset / sector_size, buf)
-    }
+        let runs = self.parent_runs(
+            Operation::Discard,
+            sector,
+            sector_size,
+            count.saturating_mul(sector_size as u64),
+        )?;
 
-    fn disk_infos(&self) -> Result<DiskInfos, DiskErr> {
-        Ok(DiskInfos {
-            sector_size: self.sector_size.clone(),
-            disk_size: self.size,
-            permissions: self.permissions,
-        })
+        let disk = parent.disk.lock();
+        for (sector, len) in runs {
+            disk.discard(sector, sector_size, len / sector_size as u64)?;
+        }
+
+        Ok(())
     }
 }
 
@@ -506,3 +798,71 @@ impl Drop for FragmentedSubDisk {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::memdisk::MemDisk;
+
+    fn wrapper() -> Arc<DiskWrapper> {
+        DiskWrapper::new(MemDisk::new(
+            64 * 512,
+            SectorSize::AllOf(vec![512]),
+            Permissions::read_write(),
+        ))
+    }
+
+    fn is_busy<T>(result: Result<T, DiskErr>) -> bool {
+        matches!(result, Err(e) if e.kind == DiskErrKind::Busy)
+    }
+
+    #[test]
+    fn subdisk_borrows() {
+        let disk = wrapper();
+
+        let r1 = disk.subdisk(0, 4096, Permissions::read_only()).unwrap();
+        let _r2 = disk.subdisk(2048, 8192, Permissions::read_only()).unwrap();
+        assert!(is_busy(disk.subdisk(1024, 2048, Permissions::read_write())));
+        drop(r1);
+
+        let _w = disk
+            .subdisk(8192, 16384, Permissions::read_write())
+            .unwrap();
+        assert!(is_busy(disk.subdisk(
+            12288,
+            13312,
+            Permissions::read_only()
+        )));
+        assert!(is_busy(disk.subdisk(
+            15872,
+            20480,
+            Permissions::write_only()
+        )));
+        disk.subdisk(16384, 20480, Permissions::read_only())
+            .unwrap();
+    }
+
+    #[test]
+    fn fragmented_subdisk_borrows() {
+        let disk = wrapper();
+
+        let _r = disk
+            .fragmented_subdisk(vec![(0, 1024), (4096, 5120)], Permissions::read_only())
+            .unwrap();
+        disk.fragmented_subdisk(vec![(512, 1024)], Permissions::read_only())
+            .unwrap();
+        assert!(is_busy(disk.fragmented_subdisk(
+            vec![(2048, 2560), (4608, 5120)],
+            Permissions::write_only()
+        )));
+
+        let _w = disk
+            .fragmented_subdisk(vec![(8192, 9216)], Permissions::write_only())
+            .unwrap();
+        assert!(is_busy(disk.fragmented_subdisk(
+            vec![(1024, 1536), (8704, 9216)],
+            Permissions::read_only()
+        )));
+        assert!(is_busy(disk.subdisk(8192, 8704, Permissions::read_only())));
+    }
+}